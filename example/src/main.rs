@@ -3,4 +3,5 @@ use shell::Shell;
 fn main() {
     let mut shell = Shell::new();
     shell.eval().unwrap();
+    std::process::exit(shell.last_status);
 }
@@ -0,0 +1,285 @@
+use std::{collections::BTreeMap, io, path::Path, sync::{atomic::{AtomicBool, Ordering}, Mutex}};
+
+use tokenizer::{TokenType, Tokenizer};
+
+use crate::command::{self, Opt, Options};
+
+static ABBREVIATIONS: Mutex<BTreeMap<String, Abbreviation>> = Mutex::new(BTreeMap::new());
+static PERSISTENT: AtomicBool = AtomicBool::new(false);
+
+/// Where in a command line an abbreviation expands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    /// Only as the command word.
+    Command,
+    /// As any word.
+    Anywhere,
+}
+
+impl std::str::FromStr for Position {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(Position::Command),
+            "anywhere" => Ok(Position::Anywhere),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A word that the line editor replaces with `expansion` as it is typed,
+/// e.g. `abbr gco 'git checkout'`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Abbreviation {
+    pub name: String,
+    pub expansion: String,
+    pub position: Position,
+}
+
+impl Abbreviation {
+    /// The `abbr` command that defines this abbreviation.
+    pub fn definition(&self) -> String {
+        let position = match self.position {
+            Position::Command => "",
+            Position::Anywhere => " --position anywhere",
+        };
+        format!("abbr -a{} -- {} '{}'", position, self.name, self.expansion.replace('\'', r"'\''"))
+    }
+}
+
+/// What an `abbr` invocation asks for.
+#[derive(Debug, PartialEq)]
+pub enum AbbrAction {
+    Add(Abbreviation),
+    Erase(Vec<String>),
+    Query(Vec<String>),
+    /// Print the names only.
+    List,
+    /// Print every definition.
+    Show,
+}
+
+impl AbbrAction {
+    /// Parses the arguments of the `abbr` builtin. Words after the name of
+    /// a new abbreviation are its expansion, joined by spaces.
+    pub fn parse(words: &[String]) -> Result<Self, String> {
+        let mut position = Position::Command;
+        let (mut add, mut erase, mut query, mut list) = (false, false, false, false);
+        let mut names = Vec::new();
+
+        let mut options = Options::new(words);
+        while let Some(opt) = options.next() {
+            match opt {
+                Opt::Flag("-a" | "--add") => add = true,
+                Opt::Flag("-e" | "--erase") => erase = true,
+                Opt::Flag("-q" | "--query") => query = true,
+                Opt::Flag("-l" | "--list") => list = true,
+                Opt::Flag("-s" | "--show") => {}
+                Opt::Flag(name @ ("-p" | "--position")) => {
+                    let value = options.value(name)?;
+                    position = value.parse().map_err(|_| format!("Invalid position: {}", value))?;
+                }
+                Opt::Flag(other) => return Err(format!("Unknown option: {}", other)),
+                Opt::Operand(operand) => {
+                    names.push(operand.to_string());
+                    if !(erase || query) {
+                        names.extend(options.rest());
+                        break;
+                    }
+                }
+            }
+        }
+
+        if erase || query {
+            if names.is_empty() {
+                return Err("No abbreviation names given".to_string());
+            }
+            return Ok(if erase { AbbrAction::Erase(names) } else { AbbrAction::Query(names) });
+        }
+        if list {
+            return Ok(AbbrAction::List);
+        }
+        let Some((name, expansion)) = names.split_first() else {
+            if add {
+                return Err("No abbreviation name given".to_string());
+            }
+            return Ok(AbbrAction::Show);
+        };
+        if !crate::alias::is_valid_name(name) {
+            return Err(format!("Invalid abbreviation name: {}", name));
+        }
+        if expansion.is_empty() {
+            return Err(format!("No expansion given for {}", name));
+        }
+        Ok(AbbrAction::Add(Abbreviation { name: name.clone(), expansion: expansion.join(" "), position }))
+    }
+}
+
+pub fn get(name: &str) -> Option<Abbreviation> {
+    ABBREVIATIONS.lock().unwrap_or_else(|e| e.into_inner()).get(name).cloned()
+}
+
+/// Every abbreviation by name.
+pub fn all() -> Vec<Abbreviation> {
+    ABBREVIATIONS.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+}
+
+/// Defines `abbreviation`, and records it in the rc file if changes are
+/// persistent.
+pub fn add(abbreviation: Abbreviation) -> io::Result<()> {
+    let name = abbreviation.name.clone();
+    ABBREVIATIONS.lock().unwrap_or_else(|e| e.into_inner()).insert(name.clone(), abbreviation.clone());
+    persist(&name, Some(&abbreviation))
+}
+
+/// Removes the abbreviation `name`, from the rc file too if changes are
+/// persistent. Returns whether there was one.
+pub fn erase(name: &str) -> io::Result<bool> {
+    if ABBREVIATIONS.lock().unwrap_or_else(|e| e.into_inner()).remove(name).is_none() {
+        return Ok(false);
+    }
+    persist(name, None)?;
+    Ok(true)
+}
+
+/// Whether `abbr` changes are written to the rc file. The shell turns
+/// this on once the rc file itself has been read.
+pub fn set_persistent(enable: bool) {
+    PERSISTENT.store(enable, Ordering::Relaxed);
+}
+
+fn persist(name: &str, abbreviation: Option<&Abbreviation>) -> io::Result<()> {
+    if !PERSISTENT.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    save(&command::rc_path(), name, abbreviation)
+}
+
+/// Rewrites the file at `path` so that it defines `name` as `abbreviation`,
+/// or not at all if `None`. Existing definitions of `name` are removed;
+/// the new one is added at the end.
+pub fn save(path: &Path, name: &str, abbreviation: Option<&Abbreviation>) -> io::Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut lines: Vec<String> =
+        contents.lines().filter(|line| defined_name(line).as_deref() != Some(name)).map(String::from).collect();
+    if let Some(abbreviation) = abbreviation {
+        lines.push(abbreviation.definition());
+    }
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    std::fs::write(path, contents)
+}
+
+/// The name a line of the rc file defines an abbreviation for, if any.
+fn defined_name(line: &str) -> Option<String> {
+    let mut tokenizer = Tokenizer::new(line.trim().to_string());
+    tokenizer.scan_tokens();
+    let words: Vec<String> =
+        tokenizer.tokens.into_iter().map(|token| token.lexeme).filter(|lexeme| !lexeme.is_empty()).collect();
+    match words.split_first() {
+        Some((first, rest)) if first == "abbr" => match AbbrAction::parse(rest) {
+            Ok(AbbrAction::Add(abbreviation)) => Some(abbreviation.name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The abbreviation to expand for the word that ends at `cursor`, as the
+/// byte range it replaces and its expansion.
+pub fn expand_at(text: &str, cursor: usize) -> Option<(usize, usize, String)> {
+    let abbreviations = ABBREVIATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    if abbreviations.is_empty() {
+        return None;
+    }
+    expand_at_with(text, cursor, |name| abbreviations.get(name).cloned())
+}
+
+/// Like [`expand_at`], looking abbreviations up with `abbreviation`. Only
+/// unquoted words expand, and abbreviations limited to the command
+/// position only in the command word.
+pub fn expand_at_with(
+    text: &str,
+    cursor: usize,
+    abbreviation: impl Fn(&str) -> Option<Abbreviation>,
+) -> Option<(usize, usize, String)> {
+    let mut tokenizer = Tokenizer::new(text.to_string());
+    tokenizer.scan_tokens();
+    let token = tokenizer.tokens.into_iter().find(|token| token.end == cursor && token.kind != TokenType::Eof)?;
+    if token.start >= token.end || text[token.start..token.end] != token.lexeme {
+        return None;
+    }
+    let abbreviation = abbreviation(&token.lexeme)?;
+    if abbreviation.position == Position::Command && token.kind != TokenType::Cmd {
+        return None;
+    }
+    Some((token.start, token.end, abbreviation.expansion))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn abbreviation(name: &str, expansion: &str, position: Position) -> Abbreviation {
+        Abbreviation { name: name.to_string(), expansion: expansion.to_string(), position }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            AbbrAction::parse(&words("gco git checkout")),
+            Ok(AbbrAction::Add(abbreviation("gco", "git checkout", Position::Command)))
+        );
+        assert_eq!(
+            AbbrAction::parse(&words("-a --position anywhere -- L | less -R")),
+            Ok(AbbrAction::Add(abbreviation("L", "| less -R", Position::Anywhere)))
+        );
+        assert_eq!(AbbrAction::parse(&words("-e gco L")), Ok(AbbrAction::Erase(words("gco L"))));
+        assert_eq!(AbbrAction::parse(&[]), Ok(AbbrAction::Show));
+        assert_eq!(AbbrAction::parse(&words("--list")), Ok(AbbrAction::List));
+        assert!(AbbrAction::parse(&words("gco")).is_err());
+        assert!(AbbrAction::parse(&words("-p nowhere gco x")).is_err());
+    }
+
+    #[test]
+    fn test_expand_at() {
+        let lookup = |name: &str| match name {
+            "gco" => Some(abbreviation("gco", "git checkout", Position::Command)),
+            "L" => Some(abbreviation("L", "| less", Position::Anywhere)),
+            _ => None,
+        };
+        assert_eq!(expand_at_with("gco", 3, lookup), Some((0, 3, "git checkout".to_string())));
+        assert_eq!(expand_at_with("ls; gco", 7, lookup), Some((4, 7, "git checkout".to_string())));
+        assert_eq!(expand_at_with("echo gco", 8, lookup), None);
+        assert_eq!(expand_at_with("gco", 2, lookup), None);
+        assert_eq!(expand_at_with("'gco'", 5, lookup), None);
+        assert_eq!(expand_at_with("cat x L", 7, lookup), Some((6, 7, "| less".to_string())));
+    }
+
+    #[test]
+    fn test_save_rewrites_definitions() {
+        let path = std::env::temp_dir().join(format!("msh-abbr-{}", std::process::id()));
+        std::fs::write(&path, "alias ll='ls -la'\nabbr gco 'git co'\nabbr gs git status\n").unwrap();
+
+        let gco = abbreviation("gco", "git checkout", Position::Command);
+        save(&path, "gco", Some(&gco)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "alias ll='ls -la'\nabbr gs git status\nabbr -a -- gco 'git checkout'\n"
+        );
+        save(&path, "gs", None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "alias ll='ls -la'\nabbr -a -- gco 'git checkout'\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::{BTreeMap, HashSet}, sync::Mutex};
+
+use tokenizer::{TokenType, Tokenizer};
+
+static ALIASES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The text the alias `name` stands for.
+pub fn get(name: &str) -> Option<String> {
+    ALIASES.lock().unwrap_or_else(|e| e.into_inner()).get(name).cloned()
+}
+
+pub fn set(name: &str, value: &str) {
+    ALIASES.lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_string(), value.to_string());
+}
+
+/// Removes the alias `name`. Returns whether there was one.
+pub fn remove(name: &str) -> bool {
+    ALIASES.lock().unwrap_or_else(|e| e.into_inner()).remove(name).is_some()
+}
+
+pub fn clear() {
+    ALIASES.lock().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// Every alias by name.
+pub fn all() -> Vec<(String, String)> {
+    ALIASES.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|(name, value)| (name.clone(), value.clone())).collect()
+}
+
+/// Whether `name` can be an alias: a non-empty word without quotes,
+/// `/`, `=` or characters that separate commands.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "/='\"\\$`|&;<>()".contains(c))
+}
+
+/// The `alias` command that defines `name` as `value`.
+pub fn definition(name: &str, value: &str) -> String {
+    format!("alias {}='{}'", name, value.replace('\'', r"'\''"))
+}
+
+/// Replaces aliases in the command words of `line`.
+pub fn expand(line: &str) -> String {
+    let aliases = ALIASES.lock().unwrap_or_else(|e| e.into_inner());
+    if aliases.is_empty() {
+        return line.to_string();
+    }
+    expand_with(line, |name| aliases.get(name).cloned())
+}
+
+/// Text inserted for an alias and the aliases it came from, which are not
+/// expanded again inside it.
+struct Expansion {
+    start: usize,
+    end: usize,
+    names: HashSet<String>,
+}
+
+/// Replaces each unquoted command word of `line` that `alias` knows with
+/// its value, and expands the first word of the result again. An alias is
+/// not expanded inside its own expansion, so `alias ls='ls -F'` works and
+/// aliases that refer to each other stop. If a value ends with a space,
+/// the word after it is checked for an alias too.
+pub fn expand_with(line: &str, alias: impl Fn(&str) -> Option<String>) -> String {
+    let mut line = line.to_string();
+    let mut expansions: Vec<Expansion> = Vec::new();
+    // Where to look for the next word, and where a word may follow a
+    // value that ended with a space.
+    let mut pos = 0;
+    let mut chained: Option<usize> = None;
+    loop {
+        let mut tokenizer = Tokenizer::new(line.clone());
+        tokenizer.scan_tokens();
+        let Some(token) = tokenizer.tokens.into_iter().find(|token| token.start >= pos && token.kind != TokenType::Eof) else {
+            break;
+        };
+        let follows_chain = chained.is_some_and(|chained| token.start >= chained);
+        if follows_chain {
+            chained = None;
+        }
+        let is_command_word = token.kind == TokenType::Cmd || (follows_chain && token.kind == TokenType::Arg);
+        // Quoted or escaped words are written differently from their text.
+        let unquoted = line[token.start..token.end] == token.lexeme;
+        let active: HashSet<String> = expansions
+            .iter()
+            .filter(|expansion| expansion.start <= token.start && token.start < expansion.end)
+            .flat_map(|expansion| expansion.names.iter().cloned())
+            .collect();
+        let value = match alias(&token.lexeme) {
+            Some(value) if is_command_word && unquoted && !active.contains(&token.lexeme) => value,
+            _ => {
+                pos = token.end.max(token.start + 1);
+                continue;
+            }
+        };
+
+        let (start, end) = (token.start, token.end);
+        let delta = value.len() as isize - (end - start) as isize;
+        let shift = |offset: usize| (offset as isize + delta) as usize;
+        for expansion in &mut expansions {
+            if expansion.start >= end {
+                expansion.start = shift(expansion.start);
+            }
+            if expansion.end >= end {
+                expansion.end = shift(expansion.end);
+            }
+        }
+        chained = chained.map(|chained| if chained >= end { shift(chained) } else { chained });
+        if value.ends_with([' ', '\t']) {
+            chained = Some(start + value.len());
+        }
+        let mut names = active;
+        names.insert(token.lexeme.clone());
+        expansions.push(Expansion { start, end: start + value.len(), names });
+        line.replace_range(start..end, &value);
+        // The value is scanned again from its first word.
+        pos = start;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn expand_all(line: &str, aliases: &[(&str, &str)]) -> String {
+        let aliases: HashMap<String, String> = aliases.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect();
+        expand_with(line, |name| aliases.get(name).cloned())
+    }
+
+    #[test]
+    fn test_expands_command_words() {
+        let aliases = [("ll", "ls -la"), ("gs", "git status")];
+        assert_eq!(expand_all("ll /tmp", &aliases), "ls -la /tmp");
+        assert_eq!(expand_all("gs; ll | gs && echo ll", &aliases), "git status; ls -la | git status && echo ll");
+        assert_eq!(expand_all("'ll' \\ll", &aliases), "'ll' \\ll");
+        assert_eq!(expand_all("if ll\nthen gs\nfi", &aliases), "if ls -la\nthen git status\nfi");
+    }
+
+    #[test]
+    fn test_recursion_stops() {
+        assert_eq!(expand_all("ls x", &[("ls", "ls -F")]), "ls -F x");
+        assert_eq!(expand_all("a", &[("a", "b"), ("b", "a")]), "a");
+        assert_eq!(expand_all("a", &[("a", "b; a")]), "b; a");
+        assert_eq!(expand_all("ll", &[("ll", "l -l"), ("l", "ls")]), "ls -l");
+    }
+
+    #[test]
+    fn test_trailing_space_chains() {
+        let aliases = [("sudo", "sudo "), ("ll", "ls -la"), ("watch", "watch -n1")];
+        assert_eq!(expand_all("sudo ll /root", &aliases), "sudo  ls -la /root");
+        assert_eq!(expand_all("sudo sudo ll", &aliases), "sudo  sudo  ls -la");
+        assert_eq!(expand_all("watch ll", &aliases), "watch -n1 ll");
+    }
+
+    #[test]
+    fn test_definition_round_trips_quotes() {
+        assert_eq!(definition("say", "echo 'hi'"), r"alias say='echo '\''hi'\'''");
+        assert!(is_valid_name("ll"));
+        assert!(!is_valid_name("a/b"));
+        assert!(!is_valid_name("a=b"));
+    }
+}
@@ -1,553 +1,4278 @@
-use tokenizer::{Token, TokenType};
+use tokenizer::{Token, TokenType, Tokenizer};
+use std::cell::RefCell;
 use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 
-use crate::History;
+use crate::abbr::{self, AbbrAction};
+use crate::alias;
+use crate::conditional;
+use crate::cwd;
+use crate::job;
+use crate::format::{self, Escapes};
+use crate::prompt::abbreviate_home;
+use crate::read::{self, Ending, ReadOptions};
+use crate::trap::{self, Condition};
+use crate::vars;
+use crate::expansion;
+use crate::frecency::{self, Database, Order};
+use crate::history::{self, HistoryEntry, HistoryFilter};
+use regex::Regex;
+use crate::completion::{self, CompletionSpec, SpecAction};
+use crate::lookup::{self, Resolution};
+use crate::keymap::{self, Action, Binding, EditMode, KeymapName};
 
+/// An error that carries the exit status the shell should report for it.
+#[derive(Debug)]
+pub struct ExitStatusError {
+    pub status: i32,
+    pub message: String,
+}
 
+impl std::fmt::Display for ExitStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
 
-pub struct CommandParser {
-    pub tokens: Vec<Token>
+impl std::error::Error for ExitStatusError {}
+
+/// Stops the commands that are running: `exit` ends the shell and
+/// `return` the file being sourced. Without a status, the status of the
+/// command before is used.
+#[derive(Debug, PartialEq)]
+pub enum Unwind {
+    Exit(Option<i32>),
+    Return(Option<i32>),
+    /// Output went to a pipe that nobody reads any more, which ends a
+    /// stage of a pipeline the way SIGPIPE ends a program.
+    BrokenPipe,
+    /// Ctrl+C stopped a program, which abandons the command line.
+    Interrupt,
 }
 
-impl CommandParser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens }
+impl Unwind {
+    pub fn status(&self) -> Option<i32> {
+        match self {
+            Unwind::Exit(status) | Unwind::Return(status) => *status,
+            Unwind::BrokenPipe => Some(128 + libc::SIGPIPE),
+            Unwind::Interrupt => Some(128 + libc::SIGINT),
+        }
     }
+}
 
-    fn append_args(&mut self, cmd: &mut Box<dyn Command>) {
-        for token in self.tokens.iter().skip(1) {
-            match token.kind {
-                TokenType::Arg => cmd.get_args_mut().push(token.lexeme.clone()),
-                _ => {}
-            }
-        }
+impl std::fmt::Display for Unwind {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Ok(())
     }
+}
 
-    fn append_flags(&mut self, cmd: &mut Box<dyn Command>) {
-        for token in self.tokens.iter().skip(1) {
-            match token.kind {
-                TokenType::Flag => cmd.get_flags_mut().push(Flag { 
-                    ident: FlagIdent::new(Some(token.lexeme.clone()), None), 
-                    value: None 
-                }),
-                TokenType::LongFlag => cmd.get_flags_mut().push(Flag { 
-                    ident: FlagIdent::new(None, Some(token.lexeme.clone())), 
-                    value: None 
-                }),
-                TokenType::LongFlagWithValue => {
-                    let parts: Vec<&str> = token.lexeme.splitn(2, '=').collect();
-                    cmd.get_flags_mut().push(Flag { 
-                        ident: FlagIdent::new(None, Some(parts[0].to_string())), 
-                        value: Some(parts[1].to_string()) 
-                    })
-                },
-                _ => {}
+impl std::error::Error for Unwind {}
+
+/// Whether `result` stops the commands around it, see [`Unwind`].
+pub fn is_unwind(result: &Result<(), Box<dyn std::error::Error>>) -> bool {
+    result.as_ref().is_err_and(|e| e.is::<Unwind>())
+}
+
+/// Gives an [`Unwind`] in `result` without a status `previous`, the
+/// status of the command that ran before it, and turns a write to a broken
+/// pipe into one.
+fn unwind_with(result: Result<(), Box<dyn std::error::Error>>, previous: i32) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Err(e) => match e.downcast::<Unwind>() {
+            Ok(unwind) => Err(match *unwind {
+                Unwind::Exit(status) => Unwind::Exit(status.or(Some(previous))),
+                Unwind::Return(status) => Unwind::Return(status.or(Some(previous))),
+                unwind => unwind,
             }
+            .into()),
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) => {
+                Err(Unwind::BrokenPipe.into())
+            }
+            Err(e) => Err(e),
+        },
+        Ok(()) => Ok(()),
+    }
+}
+
+/// The exit status for the result of running a command: 0 on success, the
+/// status carried by an [`ExitStatusError`] or [`Unwind`], or 1 for any
+/// other error.
+pub fn exit_status(result: &Result<(), Box<dyn std::error::Error>>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => match e.downcast_ref::<Unwind>() {
+            Some(unwind) => unwind.status().unwrap_or(0),
+            None => e.downcast_ref::<ExitStatusError>().map_or(1, |e| e.status),
+        },
+    }
+}
+
+/// Prints the error a command failed with. Errors that only carry an exit
+/// status, because the command already explained itself, print nothing.
+pub fn report_error(error: &dyn std::error::Error) {
+    let message = error.to_string();
+    if !message.is_empty() {
+        println!("Error: {}", message);
+    }
+}
+
+/// The exit status of a command that could not be found.
+pub const NOT_FOUND_STATUS: i32 = 127;
+/// The exit status of a command that was found but could not be run.
+pub const NOT_EXECUTABLE_STATUS: i32 = 126;
+/// The command run in place of one that is not found.
+pub const NOT_FOUND_HANDLER: &str = "command_not_found_handler";
+
+/// The exit status and message for a command that failed to start, if
+/// the failure is one the shell explains itself.
+fn launch_error(name: &str, error: &std::io::Error) -> Option<(i32, String)> {
+    match error.kind() {
+        std::io::ErrorKind::NotFound if name.contains('/') => {
+            Some((NOT_FOUND_STATUS, format!("msh: no such file or directory: {}", name)))
+        }
+        std::io::ErrorKind::NotFound => Some((NOT_FOUND_STATUS, lookup::not_found_message(name))),
+        std::io::ErrorKind::PermissionDenied => Some((NOT_EXECUTABLE_STATUS, format!("msh: permission denied: {}", name))),
+        _ if error.raw_os_error() == Some(libc::ENOEXEC) => {
+            Some((NOT_EXECUTABLE_STATUS, format!("msh: exec format error: {}", name)))
         }
+        _ => None,
     }
+}
 
-    fn parse_single_command(&mut self, start: usize, end: usize) -> Result<Box<dyn Command>, String> {
-        let cmd_token = &self.tokens[start];
-        if cmd_token.kind != TokenType::Cmd {
-            return Err(format!("Expected command, got: {}", cmd_token.lexeme));
+/// Turns an error spawning `name` into the one the shell reports.
+fn spawn_failed(name: &str, error: std::io::Error) -> Box<dyn std::error::Error> {
+    match launch_error(name, &error) {
+        Some((status, message)) => {
+            eprintln!("{}", message);
+            ExitStatusError { status, message: String::new() }.into()
         }
+        None => error.into(),
+    }
+}
 
-        let cmd: Box<dyn Command> = match cmd_token.lexeme.as_str() {
-            "cd" => Box::new(ChangeDirCommand::new()),
-            "history" => Box::new(HistoryCommand::new()),
-            "pwd" => Box::new(PwdCommand::new()),
-            _ => Box::new(SystemCommand::new(cmd_token.lexeme.clone())),
-        };
+/// Names of the commands implemented by the shell itself.
+pub const BUILTINS: &[&str] = &[
+    "[", "abbr", "alias", "bind", "cd", "command", "complete", "dirs", "echo", "exec", "exit", "hash", "history", "j",
+    "popd", "printf", "pushd", "pwd", "quit", "read", "return", "set", "test", "trap", "type", "unalias", "which", "z",
+];
 
-        let mut cmd = cmd;
-        
-        // Parse args and flags for this command segment
-        for token in self.tokens[start + 1..end].iter() {
-            match token.kind {
-                TokenType::Arg => cmd.get_args_mut().push(token.lexeme.clone()),
-                TokenType::Flag => cmd.get_flags_mut().push(Flag { 
-                    ident: FlagIdent::new(Some(token.lexeme.clone()), None), 
-                    value: None 
-                }),
-                TokenType::LongFlag => cmd.get_flags_mut().push(Flag { 
-                    ident: FlagIdent::new(None, Some(token.lexeme.clone())), 
-                    value: None 
-                }),
-                TokenType::LongFlagWithValue => {
-                    let parts: Vec<&str> = token.lexeme.splitn(2, '=').collect();
-                    cmd.get_flags_mut().push(Flag { 
-                        ident: FlagIdent::new(None, Some(parts[0].to_string())), 
-                        value: Some(parts[1].to_string()) 
-                    })
-                },
-                _ => {}
-            }
+/// Creates the builtin command called `name`, if there is one.
+pub fn builtin(name: &str) -> Option<Box<dyn Command>> {
+    match name {
+        "abbr" => Some(Box::new(AbbrCommand::new())),
+        "alias" => Some(Box::new(AliasCommand::new())),
+        "bind" => Some(Box::new(BindCommand::new())),
+        "cd" => Some(Box::new(ChangeDirCommand::new())),
+        "command" => Some(Box::new(CommandCommand::new())),
+        "complete" => Some(Box::new(CompleteCommand::new())),
+        "dirs" => Some(Box::new(DirsCommand::new())),
+        "echo" => Some(Box::new(EchoCommand::new())),
+        "exec" => Some(Box::new(ExecCommand::new())),
+        "exit" | "quit" => Some(Box::new(ExitCommand::new(name))),
+        "hash" => Some(Box::new(HashCommand::new())),
+        "history" => Some(Box::new(HistoryCommand::new())),
+        "popd" => Some(Box::new(PopdCommand::new())),
+        "printf" => Some(Box::new(PrintfCommand::new())),
+        "pushd" => Some(Box::new(PushdCommand::new())),
+        "pwd" => Some(Box::new(PwdCommand::new())),
+        "read" => Some(Box::new(ReadCommand::new())),
+        "return" => Some(Box::new(ReturnCommand::new())),
+        "set" => Some(Box::new(SetCommand::new())),
+        "test" | "[" => Some(Box::new(TestCommand::new(name))),
+        "trap" => Some(Box::new(TrapCommand::new())),
+        "type" => Some(Box::new(TypeCommand::new())),
+        "unalias" => Some(Box::new(UnaliasCommand::new())),
+        "which" => Some(Box::new(WhichCommand::new())),
+        "z" | "j" => Some(Box::new(JumpCommand::new(name))),
+        _ => None,
+    }
+}
+
+/// Why a line could not be parsed.
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The input stops in the middle of a command, e.g. after `|` or inside
+    /// an unclosed `if`. More lines can complete it.
+    Incomplete,
+    Syntax(String),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "unexpected end of input"),
+            ParseError::Syntax(message) => write!(f, "{}", message),
         }
+    }
+}
+
+impl std::error::Error for ParseError {}
 
-        Ok(cmd)
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        ParseError::Syntax(message)
     }
+}
 
-    pub fn parse(&mut self) -> Result<Box<dyn Command>, String> {
-        if self.tokens.is_empty() {
-            return Err("No command provided".to_string());
+/// Expands aliases, then tokenizes and parses a complete command line.
+pub fn parse_line(line: &str) -> Result<Box<dyn Command>, ParseError> {
+    let line = &alias::expand(line);
+    let mut tokenizer = Tokenizer::new(line.to_string());
+    tokenizer.scan_tokens();
+    if tokenizer.is_incomplete() {
+        return Err(ParseError::Incomplete);
+    }
+    let mut tokens = tokenizer.tokens;
+    for token in &mut tokens {
+        // Only a tilde that was typed unquoted at the start of a word expands.
+        if matches!(token.kind, TokenType::Cmd | TokenType::Arg) && line[token.start..].starts_with('~') {
+            token.lexeme = cwd::expand_tilde(&token.lexeme);
         }
+    }
+    CommandParser::with_source(tokens, line).parse()
+}
 
-        // Check if we have any pipes
-        let pipe_positions: Vec<usize> = self.tokens.iter()
-            .enumerate()
-            .filter(|(_, token)| token.kind == TokenType::Pipe)
-            .map(|(i, _)| i)
-            .collect();
+/// Whether `line` needs more input before it can run: it ends inside a
+/// quote, after a backslash, `|`, `&&` or `||`, or inside an `if` or `{`.
+pub fn is_incomplete(line: &str) -> bool {
+    matches!(parse_line(line), Err(ParseError::Incomplete))
+}
 
-        // If no pipes, parse as a single command
-        if pipe_positions.is_empty() {
-            return self.parse_single_command(0, self.tokens.len());
-        }
+/// The startup file: `$MSH_RC`, or `~/.mshrc`.
+pub fn rc_path() -> PathBuf {
+    match std::env::var_os("MSH_RC") {
+        Some(path) => PathBuf::from(path),
+        None => completion::expand_tilde("~/.mshrc"),
+    }
+}
 
-        // Create a pipeline for multiple commands
-        let mut pipeline = Pipeline::new();
-        let mut start = 0;
+/// How many files are being sourced, inside one another.
+static SOURCE_DEPTH: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
 
-        // Parse each command in the pipeline
-        for &pipe_pos in &pipe_positions {
-            let cmd = self.parse_single_command(start, pipe_pos)?;
-            pipeline.add_command(cmd);
-            start = pipe_pos + 1;
+/// Runs every command in the file at `path`, such as `alias` definitions.
+/// Blank lines and lines starting with `#` are skipped, and a command may
+/// continue over several lines. `return` stops reading the file and `exit`
+/// the shell. Returns a message for every command that failed; a missing
+/// file is not an error.
+pub fn source(path: &Path) -> Vec<String> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    SOURCE_DEPTH.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let errors = source_lines(path, &contents);
+    SOURCE_DEPTH.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    errors
+}
+
+fn source_lines(path: &Path, contents: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut pending = String::new();
+    let mut first_line = 0;
+    let mut status = 0;
+    for (number, line) in contents.lines().enumerate() {
+        if pending.is_empty() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            first_line = number + 1;
+        } else {
+            pending.push('\n');
+        }
+        pending.push_str(line);
+        let result = match parse_line(&pending) {
+            Err(ParseError::Incomplete) => continue,
+            Err(e) => Err(e.to_string()),
+            Ok(cmd) => {
+                let mut result = unwind_with(cmd.execute(), status);
+                if !is_unwind(&result) && exit_status(&result) != 0 {
+                    result = unwind_with(trap::run_err(), status).and(result);
+                }
+                status = exit_status(&result);
+                if let Err(e) = &result
+                    && let Some(unwind) = e.downcast_ref::<Unwind>()
+                {
+                    if let Unwind::Exit(_) = unwind {
+                        crate::request_exit(status);
+                    }
+                    pending.clear();
+                    break;
+                }
+                result.map_err(|e| e.to_string())
+            }
+        };
+        if let Err(e) = result
+            && !e.is_empty()
+        {
+            errors.push(format!("{}:{}: {}", path.display(), first_line, e));
         }
+        pending.clear();
+    }
+    if !pending.is_empty() {
+        errors.push(format!("{}:{}: {}", path.display(), first_line, ParseError::Incomplete));
+    }
+    errors
+}
 
-        // Parse the last command after the last pipe
-        let last_cmd = self.parse_single_command(start, self.tokens.len())?;
-        pipeline.add_command(last_cmd);
+/// Whether a file is being sourced, where `return` may stop it.
+pub fn is_sourcing() -> bool {
+    SOURCE_DEPTH.load(std::sync::atomic::Ordering::SeqCst) > 0
+}
 
-        Ok(Box::new(pipeline))
-    }
+pub struct CommandParser {
+    pub tokens: Vec<Token>,
+    /// The text the tokens were read from, for the words that expand
+    /// variables; empty if there is none.
+    source: String,
+    pos: usize,
 }
 
-// Make Pipeline implement Command trait
-impl Command for Pipeline {
-    fn get_name(&self) -> &str {
-        "pipeline"
+impl CommandParser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self::with_source(tokens, "")
     }
 
-    fn get_args(&self) -> &[String] {
-        &[]
+    pub fn with_source(tokens: Vec<Token>, source: &str) -> Self {
+        Self { tokens, source: source.to_string(), pos: 0 }
     }
 
-    fn get_flags(&self) -> &[Flag] {
-        &[]
+    /// The word `token` as typed, if a variable in it expands.
+    fn expansion(&self, token: &Token) -> Option<vars::Word> {
+        let raw = self.source.get(token.start..token.end)?;
+        let mut word = vars::Word::parse(raw)?;
+        if raw.starts_with('~') {
+            word.map_prefix(cwd::expand_tilde);
+        }
+        Some(word)
     }
 
-    fn get_args_mut(&mut self) -> &mut Vec<String> {
-        unimplemented!("Pipeline does not support mutable arguments")
+    fn parse_single_command(&mut self, start: usize, end: usize) -> Result<Box<dyn Command>, String> {
+        let cmd_token = &self.tokens[start];
+        if cmd_token.kind != TokenType::Cmd {
+            return Err(format!("Expected command, got: {}", cmd_token.lexeme));
+        }
+
+        let (redirections, words) = Redirection::parse(&self.tokens[start + 1..end])?;
+        let words: Vec<&Token> = std::iter::once(cmd_token)
+            .chain(words.into_iter().filter(|token| {
+                matches!(token.kind, TokenType::Arg | TokenType::Flag | TokenType::LongFlag | TokenType::LongFlagWithValue)
+            }))
+            .collect();
+        let expansions: Vec<Option<vars::Word>> = words.iter().map(|token| self.expansion(token)).collect();
+        if expansions.iter().any(Option::is_some) {
+            let words = words
+                .iter()
+                .zip(expansions)
+                .map(|(token, word)| word.unwrap_or_else(|| vars::Word::literal(&token.lexeme)))
+                .collect();
+            return Ok(Box::new(ExpandedCommand::new(cmd_token.lexeme.clone(), words, redirections)));
+        }
+        let argv = words[1..].iter().map(|token| token.lexeme.clone()).collect();
+        Ok(simple_command(&cmd_token.lexeme, argv, redirections))
     }
 
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag> {
-        unimplemented!("Pipeline does not support mutable flags")
+    /// Parses the tokens into a command. Lists, pipelines and compound
+    /// commands that consist of a single command return that command.
+    pub fn parse(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        self.pos = 0;
+        self.skip_newlines();
+        if self.at_end() {
+            return Err(ParseError::Syntax("No command provided".to_string()));
+        }
+        let command = self.parse_list(&[])?;
+        match self.peek() {
+            Some(token) if token.kind != TokenType::Eof => {
+                Err(ParseError::Syntax(format!("Unexpected token: {}", token.lexeme)))
+            }
+            _ => Ok(command),
+        }
     }
 
-    fn get_io_redirection(&mut self) -> &mut IoRedirection {
-        unimplemented!("Pipeline handles I/O internally")
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
     }
 
-    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if self.commands.is_empty() {
-            return Ok(());
+    fn at_end(&self) -> bool {
+        self.peek().is_none_or(|token| token.kind == TokenType::Eof)
+    }
+
+    fn at_keyword(&self, keywords: &[&str]) -> bool {
+        self.peek().is_some_and(|token| token.kind == TokenType::Keyword && keywords.contains(&token.lexeme.as_str()))
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().is_some_and(|token| token.kind == TokenType::Newline) {
+            self.pos += 1;
         }
+    }
 
-        // Single command case - no pipes needed
-        if self.commands.len() == 1 {
-            return self.commands[0].execute();
+    /// Consumes the keyword `word`, or reports what was found instead.
+    fn expect_keyword(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.at_keyword(&[word]) {
+            self.pos += 1;
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) if token.kind != TokenType::Eof => {
+                Err(ParseError::Syntax(format!("Expected '{}', got: {}", word, token.lexeme)))
+            }
+            _ => Err(ParseError::Incomplete),
         }
+    }
 
-        // Multiple commands case - need to set up pipes
-        use std::process::{Command as ProcessCommand, Stdio};
-        
-        let mut handles = Vec::new();
-        
-        // Create all the necessary pipes
-        for i in 0..self.commands.len() - 1 {
-            let cmd = &self.commands[i];
-            let system_cmd = match cmd.get_name() {
-                "cd" | "pwd" | "history" => {
-                    return Err("Built-in commands cannot be used in pipes".into());
-                }
-                name => name,
+    /// Parses commands joined by `;`, newlines, `&&` and `||` until the end
+    /// of input or one of the `terminators` keywords. A trailing `&` is
+    /// rejected, since there are no background jobs to run it as.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<Box<dyn Command>, ParseError> {
+        let mut list = CommandList::new();
+        let mut connector = Connector::Sequence;
+        loop {
+            self.skip_newlines();
+            list.add_command(connector, self.parse_pipeline()?);
+            connector = match self.peek().map(|token| &token.kind) {
+                Some(TokenType::And) => Connector::And,
+                Some(TokenType::Or) => Connector::Or,
+                Some(TokenType::Semicolon | TokenType::Newline) => Connector::Sequence,
+                Some(TokenType::Background) => {
+                    return Err(ParseError::Syntax("background jobs are not supported".to_string()));
+                }
+                _ => break,
             };
-
-            let mut process = ProcessCommand::new(system_cmd);
-            process.args(cmd.get_args());
-            process.stdout(Stdio::piped());
-            
-            if i > 0 {
-                process.stdin(Stdio::piped());
+            self.pos += 1;
+            if connector != Connector::Sequence {
+                continue;
             }
-            
-            handles.push(process);
+            self.skip_newlines();
+            if self.at_end() || self.at_keyword(terminators) {
+                break;
+            }
+        }
+        Ok(list.into_command())
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        let mut pipeline = Pipeline::new();
+        pipeline.add_command(self.parse_command()?);
+        while self.peek().is_some_and(|token| token.kind == TokenType::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            pipeline.add_command(self.parse_command()?);
         }
+        if pipeline.commands.len() == 1 {
+            return Ok(pipeline.commands.remove(0).into_inner());
+        }
+        Ok(Box::new(pipeline))
+    }
 
-        // Handle the last command separately
-        let last_cmd = &self.commands[self.commands.len() - 1];
-        let system_cmd = match last_cmd.get_name() {
-            "cd" | "pwd" | "history" => {
-                return Err("Built-in commands cannot be used in pipes".into());
+    fn parse_command(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        let Some(token) = self.peek().filter(|token| token.kind != TokenType::Eof) else {
+            return Err(ParseError::Incomplete);
+        };
+        let command = match token.kind {
+            TokenType::Keyword if token.lexeme == "if" => self.parse_if()?,
+            TokenType::Keyword if token.lexeme == "while" || token.lexeme == "until" => self.parse_loop()?,
+            TokenType::Keyword if token.lexeme == "[[" => self.parse_extended_test()?,
+            TokenType::Keyword if token.lexeme == "{" => {
+                self.pos += 1;
+                let body = self.parse_list(&["}"])?;
+                self.expect_keyword("}")?;
+                body
             }
-            name => name,
+            TokenType::Cmd => {
+                let start = self.pos;
+                self.skip_to_separator();
+                return Ok(self.parse_single_command(start, self.pos)?);
+            }
+            _ => return Err(ParseError::Syntax(format!("Expected command, got: {}", token.lexeme))),
         };
-        
-        let mut last_process = ProcessCommand::new(system_cmd);
-        last_process.args(last_cmd.get_args());
-        last_process.stdin(Stdio::piped());
-        handles.push(last_process);
+        self.parse_redirections(command)
+    }
 
-        // Execute the pipeline
-        let mut previous_child: Option<std::process::Child> = None;
-        
-        for (i, mut process) in handles.into_iter().enumerate() {
-            if let Some(prev) = previous_child {
-                process.stdin(Stdio::from(prev.stdout.unwrap()));
-            }
-            
-            let child = process.spawn()?;
-            previous_child = Some(child);
+    /// Moves to the end of the command: the next `|`, `&&`, `||`, `;`,
+    /// `&`, newline or the end of input.
+    fn skip_to_separator(&mut self) {
+        while !self.peek().is_none_or(|token| {
+            matches!(
+                token.kind,
+                TokenType::Pipe
+                    | TokenType::And
+                    | TokenType::Or
+                    | TokenType::Semicolon
+                    | TokenType::Background
+                    | TokenType::Newline
+                    | TokenType::Eof
+            )
+        }) {
+            self.pos += 1;
         }
+    }
 
-        // Wait for the last process to complete
-        if let Some(mut last_child) = previous_child {
-            let status = last_child.wait()?;
-            if !status.success() {
-                return Err(format!("Pipeline failed with status: {}", status).into());
-            }
+    /// Parses the redirections after a compound command, such as
+    /// `done < file` or `} > file`, which apply to every run of it.
+    fn parse_redirections(&mut self, command: Box<dyn Command>) -> Result<Box<dyn Command>, ParseError> {
+        let start = self.pos;
+        self.skip_to_separator();
+        let (redirections, words) = Redirection::parse(&self.tokens[start..self.pos]).map_err(ParseError::Syntax)?;
+        if let Some(word) = words.first() {
+            return Err(ParseError::Syntax(format!("Unexpected token: {}", word.lexeme)));
+        }
+        if redirections.is_empty() {
+            return Ok(command);
         }
+        Ok(Box::new(Redirected::new(command, redirections)))
+    }
 
-        Ok(())
+    /// Parses `[[ expression ]]`. The words keep whether they were quoted,
+    /// which decides if they are operators and patterns.
+    fn parse_extended_test(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        self.pos += 1;
+        let mut words = Vec::new();
+        loop {
+            let Some(token) = self.peek().filter(|token| token.kind != TokenType::Eof) else {
+                return Err(ParseError::Incomplete);
+            };
+            match token.kind {
+                TokenType::Keyword if token.lexeme == "]]" => break,
+                TokenType::Newline => {}
+                TokenType::Pipe | TokenType::Semicolon | TokenType::Background => {
+                    return Err(ParseError::Syntax(format!("[[: unexpected token: {}", token.lexeme)));
+                }
+                _ => words.push(conditional::Word {
+                    text: token.lexeme.clone(),
+                    quoted: token.end - token.start != token.lexeme.len(),
+                }),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        let expr = conditional::parse(&words).map_err(|e| ParseError::Syntax(format!("[[: {}", e)))?;
+        Ok(Box::new(ExtendedTestCommand { expr, io_redirection: IoRedirection::default() }))
     }
 
-    fn get_help(&self) -> CommandHelp {
-        CommandHelp {
-            short_desc: "A pipeline of commands".to_string(),
-            long_desc: "Executes multiple commands in sequence, connecting their standard output to standard input.".to_string(),
-            usage: "command1 | command2 [| command3 ...]".to_string(),
-            flags: vec![],
+    fn parse_if(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        let mut command = IfCommand::default();
+        let mut keyword = "if";
+        while keyword != "else" {
+            self.expect_keyword(keyword)?;
+            let condition = self.parse_list(&["then"])?;
+            self.expect_keyword("then")?;
+            let body = self.parse_list(&["elif", "else", "fi"])?;
+            command.branches.push((condition, body));
+            keyword = match self.peek() {
+                Some(token) if token.kind == TokenType::Keyword && token.lexeme == "elif" => "elif",
+                Some(token) if token.kind == TokenType::Keyword && token.lexeme == "else" => "else",
+                _ => break,
+            };
+        }
+        if keyword == "else" {
+            self.pos += 1;
+            command.otherwise = Some(self.parse_list(&["fi"])?);
         }
+        self.expect_keyword("fi")?;
+        Ok(Box::new(command))
+    }
+
+    fn parse_loop(&mut self) -> Result<Box<dyn Command>, ParseError> {
+        let until = self.peek().is_some_and(|token| token.lexeme == "until");
+        self.pos += 1;
+        let condition = self.parse_list(&["do"])?;
+        self.expect_keyword("do")?;
+        let body = self.parse_list(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(Box::new(LoopCommand { until, condition, body }))
     }
 }
 
-#[derive(Default)]
-pub struct IoRedirection {
-    pub from: Option<Box<dyn std::io::Read>>,
-    pub to: Option<Box<dyn std::io::Write>>,
-    pub error: Option<Box<dyn std::io::Write>>,
+/// How a command in a [`CommandList`] depends on the one before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connector {
+    /// Runs unconditionally (`;` or a newline).
+    Sequence,
+    /// Runs if the previous command succeeded (`&&`).
+    And,
+    /// Runs if the previous command failed (`||`).
+    Or,
 }
 
-pub struct CommandHelp {
-    pub short_desc: String,
-    pub long_desc: String,
-    pub usage: String,
-    pub flags: Vec<(String, String)>, // (flag, description)
+/// Commands separated by `;`, newlines, `&&` and `||`.
+#[derive(Default)]
+pub struct CommandList {
+    commands: Vec<(Connector, Box<dyn Command>)>,
 }
 
-pub trait Command {
-    fn get_name(&self) -> &str;
-    fn get_args(&self) -> &[String];
-    fn get_args_len(&self) -> usize {
-        self.get_args().len()
-    }
-    fn get_flags(&self) -> &[Flag];
-    fn get_flag(&self, flag: &str) -> Option<&Flag> {
-        let flag_ident = FlagIdent::try_from(flag.to_string()).ok()?;
-        self.get_flags().iter().find(|f| f.ident == flag_ident)
+impl CommandList {
+    pub fn new() -> Self {
+        Self::default()
     }
-    fn get_io_redirection(&mut self) -> &mut IoRedirection;
-    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
-        self.get_io_redirection().to = Some(output);
+
+    pub fn add_command(&mut self, connector: Connector, command: Box<dyn Command>) {
+        self.commands.push((connector, command));
     }
-    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
-        self.get_io_redirection().error = Some(error);
+
+    /// The only command of the list, or the list itself.
+    fn into_command(mut self) -> Box<dyn Command> {
+        if self.commands.len() == 1 {
+            return self.commands.remove(0).1;
+        }
+        Box::new(self)
     }
-    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
-        self.get_io_redirection().from = Some(input);
+}
+
+impl Command for CommandList {
+    fn get_name(&self) -> &str {
+        "list"
     }
-    fn get_input_mut(&mut self) -> &mut Box<dyn std::io::Read> {
-        self.get_io_redirection().from.as_mut().unwrap()
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        unimplemented!("CommandList handles I/O internally")
     }
-    fn get_output_mut(&mut self) -> &mut Box<dyn std::io::Write> {
-        self.get_io_redirection().to.as_mut().unwrap()
+
+    fn get_io(&self) -> &IoRedirection {
+        unimplemented!("CommandList handles I/O internally")
     }
-    fn get_error_mut(&mut self) -> &mut Box<dyn std::io::Write> {
-        self.get_io_redirection().error.as_mut().unwrap()
+
+    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
+        share_output(self.commands.iter_mut().map(|(_, command)| command), Output::Writer(output), false);
     }
-    fn get_args_mut(&mut self) -> &mut Vec<String>;
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag>;
-    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Check for help flag first
-        if self.get_flag("--help").is_some() || self.get_flag("-h").is_some() {
-            self.print_help();
-            return Ok(());
-        }
-        self.execute_impl()
+
+    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
+        share_output(self.commands.iter_mut().map(|(_, command)| command), Output::Writer(error), true);
     }
-    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>>;
-    fn get_help(&self) -> CommandHelp;
-    fn print_help(&self) {
-        let help = self.get_help();
-        println!("{}:", self.get_name().to_uppercase());
-        println!("  {}\n", help.short_desc);
-        println!("Description:");
-        println!("  {}\n", help.long_desc);
-        println!("Usage:");
-        println!("  {}\n", help.usage);
-        if !help.flags.is_empty() {
-            println!("Flags:");
-            for (flag, desc) in help.flags {
-                println!("  {:<20} {}", flag, desc);
-            }
-        }
+
+    fn set_output_file(&mut self, file: std::fs::File) {
+        share_output(self.commands.iter_mut().map(|(_, command)| command), Output::File(file), false);
     }
-}
 
-impl ToString for dyn Command {
-    fn to_string(&self) -> String {
-        let mut result = String::new();
-        result.push_str(self.get_name());
-        result.push_str(" ");
-        result.push_str(self.get_args().join(" ").as_str());
-        result.push_str(self.get_flags().iter().map(|flag| flag.ident.to_string()).collect::<Vec<String>>().join(" ").as_str());
-        result
+    fn set_error_file(&mut self, file: std::fs::File) {
+        share_output(self.commands.iter_mut().map(|(_, command)| command), Output::File(file), true);
     }
-}
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct FlagIdent {
-    pub short: Option<String>, 
-    pub long: Option<String>,
-}
-
-impl TryFrom<String> for FlagIdent {
-    type Error = String;
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.starts_with("--") {
-            Ok(Self { short: None, long: Some(value) })
-        } else if value.starts_with("-") {
-            Ok(Self { short: Some(value), long: None })
-        } else {
-            Err(format!("Invalid flag: {}", value))
-        }
+    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
+        set_compound_input(self, input);
     }
-}
 
-impl TryFrom<&str> for FlagIdent {
-    type Error = String;
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value.starts_with("--") {
-            Ok(Self { short: None, long: Some(value.to_string()) })
-        } else if value.starts_with("-"){
-            Ok(Self { short: Some(value.to_string()), long: None })
-        } else {
-            Err(format!("Invalid flag: {}", value))
-        }
+    fn set_input_file(&mut self, file: std::fs::File) {
+        share_input(self.commands.iter_mut().map(|(_, command)| command), file);
     }
-}
 
-impl ToString for FlagIdent {
-    fn to_string(&self) -> String {
-        let mut result = String::new();
-        if let Some(short) = self.short.clone() {
-            result.push_str(short.as_str());
-            return result;
-        }
-        if let Some(long) = self.long.clone() {
-            result.push_str(long.as_str());
-            return result;
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut result = Ok(());
+        for (connector, command) in &self.commands {
+            let run = match connector {
+                Connector::Sequence => true,
+                Connector::And => exit_status(&result) == 0,
+                Connector::Or => exit_status(&result) != 0,
+            };
+            if !run {
+                continue;
+            }
+            // `&&` and `||` handle a failure; after `;` it is reported here,
+            // as the caller only sees the status of the last command.
+            if *connector == Connector::Sequence
+                && let Err(e) = &result
+            {
+                report_error(e.as_ref());
+            }
+            trap::run_pending()?;
+            job::check_interrupt()?;
+            let previous = exit_status(&result);
+            result = unwind_with(command.execute(), previous);
+            if is_unwind(&result) {
+                break;
+            }
         }
         result
     }
-}
 
-impl FlagIdent {
-    pub fn new(short: Option<String>, long: Option<String>) -> Self {
-        Self { short, long }
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "A list of commands".to_string(),
+            long_desc: "Executes commands one after another. A command after && only runs if the previous one succeeded, a command after || only if it failed.".to_string(),
+            usage: "command1 ; command2 && command3 || command4".to_string(),
+            flags: vec![],
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Flag {
-    pub ident: FlagIdent, 
-    pub value: Option<String>,
-}
-
-pub struct PwdCommand {
-    pub name: String,
-    pub io_redirection: IoRedirection,
+/// `if list; then list; [elif list; then list;] ... [else list;] fi`
+#[derive(Default)]
+pub struct IfCommand {
+    branches: Vec<(Box<dyn Command>, Box<dyn Command>)>,
+    otherwise: Option<Box<dyn Command>>,
 }
 
-impl PwdCommand {
-    pub fn new() -> Self {
-        Self { name: "pwd".to_string(), io_redirection: IoRedirection::default() }
+impl IfCommand {
+    fn commands_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn Command>> {
+        self.branches
+            .iter_mut()
+            .flat_map(|(condition, body)| [condition, body])
+            .chain(self.otherwise.as_mut())
     }
 }
 
-impl Command for PwdCommand {
+impl Command for IfCommand {
     fn get_name(&self) -> &str {
-        &self.name
+        "if"
     }
-    
-    fn get_args(&self) -> &[String] {
-        &[]
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        unimplemented!("IfCommand handles I/O internally")
     }
-    
-    fn get_flags(&self) -> &[Flag] {
-        &[]
+
+    fn get_io(&self) -> &IoRedirection {
+        unimplemented!("IfCommand handles I/O internally")
     }
-    
+
+    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
+        share_output(self.commands_mut(), Output::Writer(output), false);
+    }
+
+    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
+        share_output(self.commands_mut(), Output::Writer(error), true);
+    }
+
+    fn set_output_file(&mut self, file: std::fs::File) {
+        share_output(self.commands_mut(), Output::File(file), false);
+    }
+
+    fn set_error_file(&mut self, file: std::fs::File) {
+        share_output(self.commands_mut(), Output::File(file), true);
+    }
+
+    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
+        set_compound_input(self, input);
+    }
+
+    fn set_input_file(&mut self, file: std::fs::File) {
+        share_input(self.commands_mut(), file);
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (condition, body) in &self.branches {
+            match condition.execute() {
+                Ok(()) => return body.execute(),
+                Err(e) if e.is::<Unwind>() => return Err(e),
+                // A failing condition only selects the next branch.
+                Err(e) if e.is::<ExitStatusError>() => {}
+                Err(e) => report_error(e.as_ref()),
+            }
+        }
+        match &self.otherwise {
+            Some(body) => body.execute(),
+            None => Ok(()),
+        }
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Run commands depending on a condition".to_string(),
+            long_desc: "Runs the body of the first branch whose condition succeeds, or the else branch if none does.".to_string(),
+            usage: "if condition; then commands; [elif condition; then commands;] [else commands;] fi".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+/// `while list; do list; done` and `until list; do list; done`
+pub struct LoopCommand {
+    /// Whether the body runs until the condition succeeds rather than
+    /// while it does.
+    until: bool,
+    condition: Box<dyn Command>,
+    body: Box<dyn Command>,
+}
+
+impl Command for LoopCommand {
+    fn get_name(&self) -> &str {
+        if self.until { "until" } else { "while" }
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        unimplemented!("LoopCommand handles I/O internally")
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        unimplemented!("LoopCommand handles I/O internally")
+    }
+
+    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
+        share_output([&mut self.condition, &mut self.body], Output::Writer(output), false);
+    }
+
+    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
+        share_output([&mut self.condition, &mut self.body], Output::Writer(error), true);
+    }
+
+    fn set_output_file(&mut self, file: std::fs::File) {
+        share_output([&mut self.condition, &mut self.body], Output::File(file), false);
+    }
+
+    fn set_error_file(&mut self, file: std::fs::File) {
+        share_output([&mut self.condition, &mut self.body], Output::File(file), true);
+    }
+
+    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
+        set_compound_input(self, input);
+    }
+
+    fn set_input_file(&mut self, file: std::fs::File) {
+        share_input([&mut self.condition, &mut self.body], file);
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // The status of the last run of the body, or success if it never ran.
+        let mut result: Result<(), Box<dyn std::error::Error>> = Ok(());
+        loop {
+            trap::run_pending()?;
+            job::check_interrupt()?;
+            match unwind_with(self.condition.execute(), exit_status(&result)) {
+                Ok(()) if self.until => break,
+                Ok(()) => {}
+                Err(e) if e.is::<Unwind>() => return Err(e),
+                Err(e) if e.is::<ExitStatusError>() => {
+                    if !self.until {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    report_error(e.as_ref());
+                    break;
+                }
+            }
+            if let Err(e) = &result {
+                report_error(e.as_ref());
+            }
+            result = unwind_with(self.body.execute(), exit_status(&result));
+            if is_unwind(&result) {
+                break;
+            }
+        }
+        result
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Run commands repeatedly".to_string(),
+            long_desc: "Runs the body as long as the condition succeeds (while) or until it succeeds (until). \
+                       The status is that of the last run of the body.".to_string(),
+            usage: "while condition; do commands; done | until condition; do commands; done".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+// Make Pipeline implement Command trait
+impl Command for Pipeline {
+    fn get_name(&self) -> &str {
+        "pipeline"
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        unimplemented!("Pipeline handles I/O internally")
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        unimplemented!("Pipeline handles I/O internally")
+    }
+
+    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
+        if let Some(last) = self.commands.last_mut() {
+            last.get_mut().set_output(output);
+        }
+    }
+
+    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
+        share_output(self.commands.iter_mut().map(RefCell::get_mut), Output::Writer(error), true);
+    }
+
+    fn set_output_file(&mut self, file: std::fs::File) {
+        if let Some(last) = self.commands.last_mut() {
+            last.get_mut().set_output_file(file);
+        }
+    }
+
+    fn set_error_file(&mut self, file: std::fs::File) {
+        share_output(self.commands.iter_mut().map(RefCell::get_mut), Output::File(file), true);
+    }
+
+    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
+        if let Some(first) = self.commands.first_mut() {
+            first.get_mut().set_input(input);
+        }
+    }
+
+    fn set_input_file(&mut self, file: std::fs::File) {
+        if let Some(first) = self.commands.first_mut() {
+            first.get_mut().set_input_file(file);
+        }
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.commands.is_empty() {
+            return Ok(());
+        }
+
+        // Single command case - no pipes needed
+        if self.commands.len() == 1 {
+            return self.commands[0].borrow().execute();
+        }
+
+        // Every program joins the first one's process group, which gets the
+        // terminal for as long as the pipeline runs, unless a pipeline
+        // around this one already has it.
+        let nested = job::terminal_given();
+        let mut children = Vec::new();
+        let mut result = self.start_and_run(&mut children);
+        let last = self.commands.len() - 1;
+        for (i, mut child) in std::mem::take(&mut children) {
+            let status = child.wait();
+            if i != last || result.is_err() {
+                continue;
+            }
+            result = match status {
+                Ok(status) if !status.success() => {
+                    job::killed_by(status);
+                    Err(Box::new(ExitStatusError {
+                        status: job::status_code(status),
+                        message: match status.signal() {
+                            Some(signal) if signal != libc::SIGINT => format!("Pipeline failed with status: {}", status),
+                            _ => String::new(),
+                        },
+                    }))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+        }
+        if !nested {
+            job::take_terminal();
+        }
+        result
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "A pipeline of commands".to_string(),
+            long_desc: "Executes multiple commands in sequence, connecting their standard output to standard input.".to_string(),
+            usage: "command1 | command2 [| command3 ...]".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+/// What a command reads: a file, which programs read themselves, or
+/// anything else, which the shell feeds them.
+pub enum Input {
+    File(std::fs::File),
+    Reader(Box<dyn std::io::Read>),
+}
+
+impl std::io::Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Reader(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Where a command writes: a file, which programs write to themselves, or
+/// anything else, which the shell collects their output for.
+pub enum Output {
+    File(std::fs::File),
+    Writer(Box<dyn Write>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            Output::Writer(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            Output::Writer(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Where a command reads and writes; unset streams are the shell's own.
+/// The streams sit in cells so that a running command, which only has
+/// `&self`, can use them.
+#[derive(Default)]
+pub struct IoRedirection {
+    pub from: RefCell<Option<Input>>,
+    pub to: RefCell<Option<Output>>,
+    pub error: RefCell<Option<Output>>,
+}
+
+impl IoRedirection {
+    /// Writes `text` to the command's output, or to stdout.
+    pub fn write_out(&self, text: &str) -> std::io::Result<()> {
+        self.write_out_bytes(text.as_bytes())
+    }
+
+    pub fn write_out_bytes(&self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.to.borrow_mut().as_mut() {
+            Some(out) => out.write_all(bytes).and_then(|()| out.flush()),
+            None => {
+                let mut out = std::io::stdout().lock();
+                out.write_all(bytes)?;
+                out.flush()
+            }
+        }
+    }
+
+    /// Writes `text` to the command's error output, or to stderr.
+    pub fn write_err(&self, text: &str) -> std::io::Result<()> {
+        self.write_err_bytes(text.as_bytes())
+    }
+
+    pub fn write_err_bytes(&self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.error.borrow_mut().as_mut() {
+            Some(out) => out.write_all(bytes).and_then(|()| out.flush()),
+            None => std::io::stderr().write_all(bytes),
+        }
+    }
+
+    /// Reads the rest of the command's input, if it was given one. The
+    /// input stays in place, so a command that runs again, as in a loop,
+    /// finds it at its end.
+    pub fn read_input(&self) -> std::io::Result<Option<Vec<u8>>> {
+        use std::io::Read;
+
+        let mut from = self.from.borrow_mut();
+        let Some(input) = from.as_mut() else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+/// An output shared by the commands inside a compound command.
+#[derive(Clone)]
+struct SharedOutput(std::rc::Rc<RefCell<Box<dyn Write>>>);
+
+impl SharedOutput {
+    fn new(output: Box<dyn Write>) -> Self {
+        Self(std::rc::Rc::new(RefCell::new(output)))
+    }
+
+    /// The output, once no other copy of it is left.
+    fn into_inner(self) -> Option<Box<dyn Write>> {
+        std::rc::Rc::try_unwrap(self.0).ok().map(RefCell::into_inner)
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Copies `input` to a file that is already unlinked. Commands that share
+/// it, programs included, read from one position, so each leaves what it
+/// did not read for the next, e.g. every `read` of a `while` loop.
+fn input_file(mut input: Box<dyn std::io::Read>) -> std::io::Result<std::fs::File> {
+    use std::io::{Seek, SeekFrom};
+    static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("msh-input-{}-{}", std::process::id(), count));
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let _ = std::fs::remove_file(&path);
+    std::io::copy(&mut input, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Gives a compound command `input`, as a file shared by what it runs.
+fn set_compound_input(command: &mut dyn Command, input: Box<dyn std::io::Read>) {
+    match input_file(input) {
+        Ok(file) => command.set_input_file(file),
+        Err(e) => eprintln!("msh: cannot buffer input: {}", e),
+    }
+}
+
+/// Gives every command in `commands` the same input file.
+fn share_input<'a>(commands: impl IntoIterator<Item = &'a mut Box<dyn Command>>, file: std::fs::File) {
+    for command in commands {
+        match file.try_clone() {
+            Ok(file) => command.set_input_file(file),
+            Err(e) => eprintln!("msh: cannot share input: {}", e),
+        }
+    }
+}
+
+/// Gives every command in `commands` the same output, or error output if
+/// `error`. A file goes to each of them, for programs to write to.
+fn share_output<'a>(commands: impl IntoIterator<Item = &'a mut Box<dyn Command>>, output: Output, error: bool) {
+    let output = match output {
+        Output::File(file) => {
+            for command in commands {
+                match (file.try_clone(), error) {
+                    (Ok(file), false) => command.set_output_file(file),
+                    (Ok(file), true) => command.set_error_file(file),
+                    (Err(e), _) => eprintln!("msh: cannot share output: {}", e),
+                }
+            }
+            return;
+        }
+        Output::Writer(output) => SharedOutput::new(output),
+    };
+    for command in commands {
+        if error {
+            command.set_error(Box::new(output.clone()));
+        } else {
+            command.set_output(Box::new(output.clone()));
+        }
+    }
+}
+
+/// An output that can be read back after handing it to a command, such as
+/// one stage of a pipeline.
+#[derive(Clone, Default)]
+struct SharedBuffer(std::rc::Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+
+    #[cfg(test)]
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where a stage of a [`Pipeline`] reads from the stage before it.
+enum StageInput {
+    Pipe(std::fs::File),
+    /// Both stages run inside the shell, one after the other.
+    Buffer(SharedBuffer),
+}
+
+/// Where a stage of a [`Pipeline`] writes for the stage after it.
+enum StageOutput {
+    Pipe(std::io::PipeWriter),
+    Buffer(SharedBuffer),
+}
+
+/// Hands on what `source` delivers through a new pipe, reading it on a
+/// thread as soon as it arrives and keeping what the reader of the pipe
+/// has not taken yet.
+fn relay(mut source: std::fs::File) -> std::io::Result<std::fs::File> {
+    use std::io::Read;
+
+    let (reader, mut writer) = std::io::pipe()?;
+    let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+    // Signals are for the shell's own thread, where `read` and the traps
+    // wait for them.
+    let block_signals = || {
+        // SAFETY: only changes the signal mask of the calling thread.
+        unsafe {
+            let mut signals = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigfillset(&mut signals);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        }
+    };
+    std::thread::spawn(move || {
+        block_signals();
+        let mut buffer = [0; 8192];
+        loop {
+            match source.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send(buffer[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    std::thread::spawn(move || {
+        block_signals();
+        for chunk in receiver {
+            if writer.write_all(&chunk).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(std::fs::File::from(std::os::fd::OwnedFd::from(reader)))
+}
+
+pub struct CommandHelp {
+    pub short_desc: String,
+    pub long_desc: String,
+    pub usage: String,
+    pub flags: Vec<(String, String)>, // (flag, description)
+}
+
+pub trait Command {
+    fn get_name(&self) -> &str;
+    /// The words after the command name, in the order they were typed.
+    fn get_argv(&self) -> &[String] {
+        &[]
+    }
+    fn get_io_redirection(&mut self) -> &mut IoRedirection;
+    fn get_io(&self) -> &IoRedirection;
+    fn set_output(&mut self, output: Box<dyn std::io::Write>) {
+        *self.get_io_redirection().to.get_mut() = Some(Output::Writer(output));
+    }
+    fn set_error(&mut self, error: Box<dyn std::io::Write>) {
+        *self.get_io_redirection().error.get_mut() = Some(Output::Writer(error));
+    }
+    fn set_input(&mut self, input: Box<dyn std::io::Read>) {
+        *self.get_io_redirection().from.get_mut() = Some(Input::Reader(input));
+    }
+    /// Gives the command a file as its input. Programs read it themselves,
+    /// so what they leave unread stays for the commands after them.
+    fn set_input_file(&mut self, file: std::fs::File) {
+        *self.get_io_redirection().from.get_mut() = Some(Input::File(file));
+    }
+    /// Gives the command a file as its output, which programs write to
+    /// themselves.
+    fn set_output_file(&mut self, file: std::fs::File) {
+        *self.get_io_redirection().to.get_mut() = Some(Output::File(file));
+    }
+    /// Gives the command a file as its error output, like
+    /// [`Command::set_output_file`].
+    fn set_error_file(&mut self, file: std::fs::File) {
+        *self.get_io_redirection().error.get_mut() = Some(Output::File(file));
+    }
+    fn get_input_mut(&mut self) -> &mut Input {
+        self.get_io_redirection().from.get_mut().as_mut().unwrap()
+    }
+    fn get_output_mut(&mut self) -> &mut Output {
+        self.get_io_redirection().to.get_mut().as_mut().unwrap()
+    }
+    fn get_error_mut(&mut self) -> &mut Output {
+        self.get_io_redirection().error.get_mut().as_mut().unwrap()
+    }
+    /// The program this runs, ready to start on the shell's own files, if
+    /// it is not code inside the shell and the shell does not have to feed
+    /// it or collect its output.
+    fn process(&self) -> Option<std::process::Command> {
+        None
+    }
+    /// Receives the words after the command name in the order they were
+    /// typed. Compound commands have none.
+    fn set_argv(&mut self, _argv: Vec<String>) {}
+    /// Offers the command the redirections written after its name, such as
+    /// `3>file`. Returns the ones it does not apply itself, which the shell
+    /// applies around every run of the command.
+    fn take_redirections(&mut self, redirections: Vec<Redirection>) -> Vec<Redirection> {
+        redirections
+    }
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // Check for help flag first
+        if self.get_argv().iter().any(|word| word == "--help" || word == "-h") {
+            self.print_help()?;
+            return Ok(());
+        }
+        self.execute_impl()
+    }
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>>;
+    fn get_help(&self) -> CommandHelp;
+    fn print_help(&self) -> std::io::Result<()> {
+        let help = self.get_help();
+        let mut text = format!("{}:\n", self.get_name().to_uppercase());
+        text += &format!("  {}\n\n", help.short_desc);
+        text += &format!("Description:\n  {}\n\n", help.long_desc);
+        text += &format!("Usage:\n  {}\n\n", help.usage);
+        if !help.flags.is_empty() {
+            text += "Flags:\n";
+            for (flag, desc) in help.flags {
+                text += &format!("  {:<20} {}\n", flag, desc);
+            }
+        }
+        self.get_io().write_out(&text)
+    }
+}
+
+impl std::fmt::Display for dyn Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut result = String::new();
+        result.push_str(self.get_name());
+        for word in self.get_argv() {
+            result.push(' ');
+            result.push_str(word);
+        }
+        write!(f, "{}", result)
+    }
+}
+
+/// What a [`Redirection`] does with its file descriptor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectionKind {
+    /// `<file`
+    Input,
+    /// `>file` or `>|file`
+    Output,
+    /// `>>file`
+    Append,
+    /// `<>file`
+    ReadWrite,
+    /// `>&n` or `<&n` copies descriptor `n`; `>&-` closes the descriptor.
+    Duplicate,
+}
+
+/// A redirection such as `2>&1` or `3>log`: descriptor `fd` is opened on
+/// `target`, a copy of it, or closed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirection {
+    pub fd: i32,
+    pub kind: RedirectionKind,
+    pub target: String,
+}
+
+impl Redirection {
+    /// Separates the redirections in the words of a simple command from
+    /// the other words. A number written right before the operator names
+    /// the descriptor; without one `<` redirects stdin and `>` stdout.
+    pub fn parse(tokens: &[Token]) -> Result<(Vec<Redirection>, Vec<&Token>), String> {
+        let mut redirections = Vec::new();
+        let mut words: Vec<&Token> = Vec::new();
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            if !matches!(token.kind, TokenType::InputRedir | TokenType::OutputRedir) {
+                words.push(token);
+                continue;
+            }
+            let fd = match words.last() {
+                Some(word)
+                    if word.end == token.start
+                        && !word.lexeme.is_empty()
+                        && word.lexeme.bytes().all(|b| b.is_ascii_digit()) =>
+                {
+                    let fd = word.lexeme.parse().map_err(|_| format!("{}: bad file descriptor", word.lexeme))?;
+                    words.pop();
+                    fd
+                }
+                _ if token.kind == TokenType::InputRedir => 0,
+                _ => 1,
+            };
+            let kind = match token.lexeme.as_str() {
+                "<" => RedirectionKind::Input,
+                ">>" => RedirectionKind::Append,
+                "<>" => RedirectionKind::ReadWrite,
+                "<&" | ">&" => RedirectionKind::Duplicate,
+                _ => RedirectionKind::Output,
+            };
+            let target = match tokens.next() {
+                Some(target) if matches!(target.kind, TokenType::Arg | TokenType::Flag | TokenType::LongFlag | TokenType::LongFlagWithValue) => target,
+                _ => return Err(format!("Expected a file after {}", token.lexeme)),
+            };
+            redirections.push(Redirection { fd, kind, target: target.lexeme.clone() });
+        }
+        Ok((redirections, words))
+    }
+}
+
+impl Redirection {
+    /// Points the shell's own descriptor `fd` at the target, so that
+    /// everything the shell runs afterwards inherits it.
+    pub fn apply_to_shell(&self) -> std::io::Result<()> {
+        use std::os::fd::IntoRawFd;
+
+        let file = match self.kind {
+            RedirectionKind::Duplicate if self.target == "-" => {
+                // SAFETY: closing a descriptor has no memory effects.
+                unsafe { libc::close(self.fd) };
+                return Ok(());
+            }
+            RedirectionKind::Duplicate => {
+                let source = self.target.parse::<i32>().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad file descriptor")
+                })?;
+                return dup_to(source, self.fd);
+            }
+            _ => self.open()?,
+        };
+        let source = file.into_raw_fd();
+        if source == self.fd {
+            // Opened on the descriptor itself, which must stay open for
+            // programs the shell runs.
+            // SAFETY: the descriptor was just opened and is owned here.
+            unsafe { libc::fcntl(source, libc::F_SETFD, 0) };
+            return Ok(());
+        }
+        let result = dup_to(source, self.fd);
+        // SAFETY: the descriptor was just opened and is owned here.
+        unsafe { libc::close(source) };
+        result
+    }
+}
+
+impl Redirection {
+    /// Opens the file a redirection other than [`RedirectionKind::Duplicate`]
+    /// names.
+    fn open(&self) -> std::io::Result<std::fs::File> {
+        match self.kind {
+            RedirectionKind::Input | RedirectionKind::Duplicate => std::fs::File::open(&self.target),
+            RedirectionKind::Output => std::fs::File::create(&self.target),
+            RedirectionKind::Append => std::fs::OpenOptions::new().append(true).create(true).open(&self.target),
+            RedirectionKind::ReadWrite => {
+                std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.target)
+            }
+        }
+    }
+}
+
+/// Makes `fd` a copy of `source`.
+fn dup_to(source: i32, fd: i32) -> std::io::Result<()> {
+    // SAFETY: dup2 only changes the descriptor table.
+    if unsafe { libc::dup2(source, fd) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A copy of the shell's descriptor `fd`, which the programs it runs do
+/// not inherit.
+fn dup(fd: i32) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: fcntl only changes the descriptor table, and the new
+    // descriptor is owned by nothing else.
+    unsafe {
+        let copy = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10);
+        if copy < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(std::os::fd::OwnedFd::from_raw_fd(copy))
+    }
+}
+
+/// Where a standard stream of a [`Redirected`] command goes.
+enum Target {
+    /// The stream the command was given for this descriptor, or else the
+    /// shell's own.
+    Given(usize),
+    File(std::fs::File),
+    Closed,
+}
+
+impl Target {
+    fn try_clone(&self) -> std::io::Result<Target> {
+        Ok(match self {
+            Target::Given(fd) => Target::Given(*fd),
+            Target::File(file) => Target::File(file.try_clone()?),
+            Target::Closed => Target::Closed,
+        })
+    }
+}
+
+/// A command with redirections such as `>out`, `<in` or `2>&1`, which may
+/// be a compound command like `while ...; done <in`. The files are opened
+/// every time the command runs, and its standard streams point at them
+/// for that run. Other descriptors, such as `3>log`, are redirected in the
+/// shell around the run, for programs to inherit.
+pub struct Redirected {
+    name: String,
+    /// In a cell, to hand it its streams for each run.
+    command: RefCell<Box<dyn Command>>,
+    redirections: Vec<Redirection>,
+    /// The streams given to the command as a whole, which the
+    /// redirections start from.
+    io_redirection: IoRedirection,
+}
+
+/// An output given to a [`Redirected`] command while redirections such as
+/// `2>&1` copy it.
+enum Given {
+    File(std::fs::File),
+    Shared(SharedOutput),
+}
+
+impl Given {
+    fn new(output: Output) -> Self {
+        match output {
+            Output::File(file) => Given::File(file),
+            Output::Writer(writer) => Given::Shared(SharedOutput::new(writer)),
+        }
+    }
+
+    fn into_output(self) -> Option<Output> {
+        match self {
+            Given::File(file) => Some(Output::File(file)),
+            Given::Shared(output) => output.into_inner().map(Output::Writer),
+        }
+    }
+}
+
+impl Redirected {
+    pub fn new(command: Box<dyn Command>, redirections: Vec<Redirection>) -> Self {
+        Self {
+            name: command.get_name().to_string(),
+            command: RefCell::new(command),
+            redirections,
+            io_redirection: IoRedirection::default(),
+        }
+    }
+
+    /// Opens the redirections in the order they were written. Returns
+    /// where the standard streams go, after redirecting the other
+    /// descriptors in the shell and keeping what they were in `saved`.
+    fn open(&self, saved: &mut Vec<(i32, Option<std::os::fd::OwnedFd>)>) -> Result<[Target; 3], (&Redirection, std::io::Error)> {
+        let mut targets = [Target::Given(0), Target::Given(1), Target::Given(2)];
+        for redirection in &self.redirections {
+            let fail = |e| (redirection, e);
+            let fd = redirection.fd;
+            if !(0..=2).contains(&fd) {
+                if !saved.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+                    // A descriptor that is not open is closed again.
+                    saved.push((fd, dup(fd).ok()));
+                }
+                redirection.apply_to_shell().map_err(fail)?;
+                continue;
+            }
+            targets[fd as usize] = match redirection.kind {
+                RedirectionKind::Duplicate if redirection.target == "-" => Target::Closed,
+                RedirectionKind::Duplicate => {
+                    let source = redirection.target.parse::<i32>().map_err(|_| {
+                        fail(std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad file descriptor"))
+                    })?;
+                    // Inputs copy inputs and outputs outputs of the
+                    // command; anything else is the shell's descriptor.
+                    if (0..=2).contains(&source) && (source == 0) == (fd == 0) {
+                        targets[source as usize].try_clone().map_err(fail)?
+                    } else {
+                        Target::File(dup(source).map_err(fail)?.into())
+                    }
+                }
+                _ => Target::File(redirection.open().map_err(fail)?),
+            };
+        }
+        Ok(targets)
+    }
+
+    /// Runs the command with its standard streams on `targets`. The
+    /// streams it gets for the run are taken away again afterwards, which
+    /// closes the files.
+    fn run(&self, targets: [Target; 3]) -> Result<(), Box<dyn std::error::Error>> {
+        let io = &self.io_redirection;
+        let mut command = self.command.borrow_mut();
+        // Files are copied, anything else shared, as `2>&1` makes one
+        // stream the target of both.
+        let given = [None, io.to.take().map(Given::new), io.error.take().map(Given::new)];
+        let mut lent = [false; 3];
+        let lend = |command: &mut Box<dyn Command>| -> std::io::Result<()> {
+            let [input, output, error] = targets;
+            let input = match input {
+                Target::Given(_) => {
+                    // Read by every run from where the last one stopped.
+                    if let Some(Input::Reader(reader)) = io.from.take() {
+                        *io.from.borrow_mut() = Some(Input::File(input_file(reader)?));
+                    }
+                    match &*io.from.borrow() {
+                        Some(Input::File(file)) => Some(file.try_clone()?),
+                        _ => None,
+                    }
+                }
+                Target::File(file) => Some(file),
+                Target::Closed => Some(std::fs::File::open("/dev/null")?),
+            };
+            if let Some(file) = input {
+                command.set_input_file(file);
+                lent[0] = true;
+            }
+            for (fd, target) in [(1, output), (2, error)] {
+                let output = match target {
+                    Target::Given(source) => match &given[source] {
+                        Some(Given::File(file)) => Output::File(file.try_clone()?),
+                        Some(Given::Shared(output)) => Output::Writer(Box::new(output.clone())),
+                        None if source == fd => continue,
+                        None => Output::File(dup(source as i32)?.into()),
+                    },
+                    Target::File(file) => Output::File(file),
+                    Target::Closed => Output::Writer(Box::new(std::io::sink())),
+                };
+                match (output, fd) {
+                    (Output::File(file), 1) => command.set_output_file(file),
+                    (Output::File(file), _) => command.set_error_file(file),
+                    (Output::Writer(output), 1) => command.set_output(output),
+                    (Output::Writer(output), _) => command.set_error(output),
+                }
+                lent[fd] = true;
+            }
+            Ok(())
+        };
+        let result = match lend(&mut command) {
+            Ok(()) => command.execute(),
+            Err(e) => Err(e.into()),
+        };
+
+        if lent[0] {
+            match std::fs::File::open("/dev/null") {
+                Ok(file) => command.set_input_file(file),
+                Err(_) => command.set_input(Box::new(std::io::empty())),
+            }
+        }
+        if lent[1] {
+            command.set_output(Box::new(std::io::sink()));
+        }
+        if lent[2] {
+            command.set_error(Box::new(std::io::sink()));
+        }
+        let [_, output, error] = given;
+        *io.to.borrow_mut() = output.and_then(Given::into_output);
+        *io.error.borrow_mut() = error.and_then(Given::into_output);
+        result
+    }
+}
+
+impl Command for Redirected {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.command.get_mut().set_argv(argv);
+    }
+
+    // The command checks for --help itself, with its streams redirected.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut saved = Vec::new();
+        let result = match self.open(&mut saved) {
+            Ok(targets) => self.run(targets),
+            Err((redirection, e)) => {
+                self.io_redirection.write_err(&format!("msh: {}: {}\n", redirection.target, e))?;
+                Err(ExitStatusError { status: 1, message: String::new() }.into())
+            }
+        };
+        for (fd, file) in saved.into_iter().rev() {
+            use std::os::fd::AsRawFd;
+
+            match file {
+                Some(file) => {
+                    let _ = dup_to(file.as_raw_fd(), fd);
+                }
+                // SAFETY: closing a descriptor has no memory effects.
+                None => unsafe {
+                    libc::close(fd);
+                },
+            }
+        }
+        result
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        self.command.borrow().get_help()
+    }
+}
+
+/// A simple command with variables in its words. The words are expanded
+/// every time it runs, e.g. `echo $line` in the body of a `while read`
+/// loop, and name the command that runs with the rest as its words.
+pub struct ExpandedCommand {
+    /// The name as typed.
+    name: String,
+    /// The words, the name first.
+    words: Vec<vars::Word>,
+    redirections: Vec<Redirection>,
+    io_redirection: IoRedirection,
+}
+
+impl ExpandedCommand {
+    pub fn new(name: String, words: Vec<vars::Word>, redirections: Vec<Redirection>) -> Self {
+        Self { name, words, redirections, io_redirection: IoRedirection::default() }
+    }
+
+    /// The command the words expand to, or `None` if they expand to
+    /// nothing.
+    fn expand(&self) -> Option<Box<dyn Command>> {
+        let mut words = self.words.iter().flat_map(vars::Word::expand);
+        let name = words.next()?;
+        Some(simple_command(&name, words.collect(), self.redirections.clone()))
+    }
+}
+
+impl Command for ExpandedCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn process(&self) -> Option<std::process::Command> {
+        let io = &self.io_redirection;
+        let feeds = matches!(*io.from.borrow(), Some(Input::Reader(_)));
+        let collects = [&io.to, &io.error].iter().any(|output| matches!(*output.borrow(), Some(Output::Writer(_))));
+        if feeds || collects {
+            return None;
+        }
+        let mut command = self.expand()?;
+        if let Some(Input::File(file)) = &*io.from.borrow() {
+            command.set_input_file(file.try_clone().ok()?);
+        }
+        if let Some(Output::File(file)) = &*io.to.borrow() {
+            command.set_output_file(file.try_clone().ok()?);
+        }
+        if let Some(Output::File(file)) = &*io.error.borrow() {
+            command.set_error_file(file.try_clone().ok()?);
+        }
+        command.process()
+    }
+
+    // The command the words expand to checks for --help itself.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(mut command) = self.expand() else {
+            return Ok(());
+        };
+        lend_streams(&self.io_redirection, command.as_mut());
+        let result = command.execute();
+        return_streams(&self.io_redirection, command.as_mut());
+        result
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "A command with variables in its words".to_string(),
+            long_desc: "Expands $name, ${name} and ${name[index]} in the words each time it runs, \
+                       then runs the command they name.".to_string(),
+            usage: "name $variable ...".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+/// An option or operand produced by [`Options`].
+#[derive(Debug, PartialEq)]
+pub enum Opt<'a> {
+    Flag(&'a str),
+    Operand(&'a str),
+}
+
+/// Walks a command's words in order for builtins whose options take
+/// values, e.g. `complete -c git -a 'status commit'`. A `--name=value`
+/// word yields `--name` and keeps `value` for the next [`Options::value`].
+pub struct Options<'a> {
+    words: std::slice::Iter<'a, String>,
+    inline_value: Option<&'a str>,
+    only_operands: bool,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(words: &'a [String]) -> Self {
+        Self { words: words.iter(), inline_value: None, only_operands: false }
+    }
+
+    /// Returns the value of `flag`, either given inline or as the next word.
+    pub fn value(&mut self, flag: &str) -> Result<&'a str, String> {
+        self.inline_value
+            .take()
+            .or_else(|| self.words.next().map(String::as_str))
+            .ok_or_else(|| format!("{} requires a value", flag))
+    }
+
+    /// Returns all remaining words as operands.
+    pub fn rest(self) -> Vec<String> {
+        self.words.cloned().collect()
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = Opt<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inline_value = None;
+        let word = self.words.next()?.as_str();
+        if self.only_operands || word == "-" || !word.starts_with('-') {
+            return Some(Opt::Operand(word));
+        }
+        if word == "--" {
+            self.only_operands = true;
+            return self.next();
+        }
+        match word.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                self.inline_value = Some(value);
+                Some(Opt::Flag(flag))
+            }
+            _ => Some(Opt::Flag(word)),
+        }
+    }
+}
+
+pub struct PwdCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl PwdCommand {
+    pub fn new() -> Self {
+        Self { name: "pwd".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Default for PwdCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Command for PwdCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+    
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+    
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut physical = false;
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-L") => physical = false,
+                Opt::Flag("-P") => physical = true,
+                Opt::Flag(other) => return Err(format!("pwd: unknown option: {}", other).into()),
+                Opt::Operand(_) => return Err("pwd: too many arguments".into()),
+            }
+        }
+        let path = if physical { cwd::physical()? } else { cwd::logical() };
+        self.io_redirection.write_out(&format!("{}\n", path.display()))?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Print the current working directory".to_string(),
+            long_desc: "Display the full path of the current working directory, as it was reached \
+                       through symbolic links (-L, the default) or with them resolved (-P).".to_string(),
+            usage: "pwd [-L | -P]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-L".to_string(), "Print the logical directory, from $PWD".to_string()),
+                ("-P".to_string(), "Print the physical directory, without symbolic links".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct ChangeDirCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl ChangeDirCommand {
+    pub fn new() -> Self {
+        Self { name: "cd".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Default for ChangeDirCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Command for ChangeDirCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut physical = false;
+        let mut dirs = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-L") => physical = false,
+                Opt::Flag("-P") => physical = true,
+                Opt::Flag(other) => return Err(format!("cd: unknown option: {}", other).into()),
+                Opt::Operand(dir) => dirs.push(dir),
+            }
+        }
+        let (dir, print) = match dirs.as_slice() {
+            [] => match std::env::var("HOME") {
+                Ok(home) if !home.is_empty() => (home, false),
+                _ => return Err("cd: HOME not set".into()),
+            },
+            ["-"] => match std::env::var("OLDPWD") {
+                Ok(old) if !old.is_empty() => (old, true),
+                _ => return Err("cd: OLDPWD not set".into()),
+            },
+            [dir] => (dir.to_string(), false),
+            _ => return Err("cd: too many arguments".into()),
+        };
+
+        let cdpath = vars::get("CDPATH");
+        let candidates = cwd::candidates(&dir, cdpath.as_deref());
+        let last = candidates.len() - 1;
+        for (i, candidate) in candidates.iter().enumerate() {
+            match cwd::change_dir(candidate, physical) {
+                Ok(pwd) => {
+                    // Like `cd -`, a directory found through CDPATH is shown.
+                    if print || (i < last && candidate != &PathBuf::from(".").join(&dir)) {
+                        self.io_redirection.write_out(&format!("{}\n", pwd.display()))?;
+                    }
+                    return Ok(());
+                }
+                Err(e) if i == last => return Err(format!("cd: {}: {}", dir, cwd::error_text(&e)).into()),
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+    
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Change the current working directory".to_string(),
+            long_desc: "Change the shell's current working directory to the specified path, or to \
+                       $HOME without one. `cd -` changes back to $OLDPWD and prints it. A relative \
+                       path that does not start with . or .. is also looked up in the colon-separated \
+                       directories of $CDPATH. $PWD and $OLDPWD are updated on every change.".to_string(),
+            usage: "cd [-L | -P] [path | -]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-L".to_string(), "Follow symbolic links logically, so .. goes back over them (default)".to_string()),
+                ("-P".to_string(), "Resolve symbolic links before changing directory".to_string()),
+            ],
+        }
+    }
+}
+
+/// The directory stack on one line, as `dirs` prints it.
+fn format_stack(dirs: &[PathBuf]) -> String {
+    let dirs: Vec<String> = dirs.iter().map(|dir| abbreviate_home(dir)).collect();
+    format!("{}\n", dirs.join(" "))
+}
+
+pub struct PushdCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl PushdCommand {
+    fn new() -> Self {
+        Self { name: "pushd".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for PushdCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut no_change = false;
+        let mut target = None;
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-n") => no_change = true,
+                Opt::Flag(n) if n[1..].parse::<usize>().is_ok() => target = Some(n),
+                Opt::Flag(other) => return Err(format!("pushd: unknown option: {}", other).into()),
+                Opt::Operand(_) if target.is_some() => return Err("pushd: too many arguments".into()),
+                Opt::Operand(dir) => target = Some(dir),
+            }
+        }
+
+        let stack = cwd::stack();
+        let mut saved = stack.lock().unwrap_or_else(|e| e.into_inner());
+        let current = cwd::logical();
+        match target {
+            // Without an argument the top two directories change places.
+            None => {
+                let Some(top) = saved.first().cloned() else {
+                    return Err("pushd: no other directory".into());
+                };
+                if !no_change {
+                    cwd::change_dir(&top, false).map_err(|e| format!("pushd: {}: {}", top.display(), cwd::error_text(&e)))?;
+                }
+                saved[0] = current;
+            }
+            // `+N` and `-N` rotate the stack until that entry is on top.
+            Some(spec) if spec.starts_with('+') || spec.starts_with('-') => {
+                let mut dirs = vec![current];
+                dirs.extend(saved.iter().cloned());
+                let index = cwd::stack_index(spec, dirs.len())
+                    .ok_or_else(|| format!("pushd: {}: directory stack index out of range", spec))?;
+                dirs.rotate_left(index);
+                if !no_change {
+                    cwd::change_dir(&dirs[0], false)
+                        .map_err(|e| format!("pushd: {}: {}", dirs[0].display(), cwd::error_text(&e)))?;
+                }
+                *saved = dirs.split_off(1);
+            }
+            Some(dir) => {
+                if no_change {
+                    saved.insert(0, cwd::resolve(&current, Path::new(dir), false));
+                } else {
+                    cwd::change_dir(Path::new(dir), false).map_err(|e| format!("pushd: {}: {}", dir, cwd::error_text(&e)))?;
+                    saved.insert(0, current);
+                }
+            }
+        }
+        drop(saved);
+        self.io_redirection.write_out(&format_stack(&cwd::dir_stack()))?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Save the current directory and change to another".to_string(),
+            long_desc: "Push the current directory onto the directory stack and change to DIR. \
+                       Without DIR the top two directories are exchanged; with +N or -N the stack \
+                       is rotated so that entry N, counted from the top or the bottom of `dirs`, \
+                       becomes the current directory. The stack is printed afterwards.".to_string(),
+            usage: "pushd [-n] [dir | +N | -N]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-n".to_string(), "Only change the stack, not the current directory".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct PopdCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl PopdCommand {
+    fn new() -> Self {
+        Self { name: "popd".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for PopdCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut no_change = false;
+        let mut index = 0;
+        let stack = cwd::stack();
+        let mut saved = stack.lock().unwrap_or_else(|e| e.into_inner());
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-n") => no_change = true,
+                Opt::Flag(spec) | Opt::Operand(spec) if spec.starts_with(['+', '-']) && spec.len() > 1 => {
+                    index = cwd::stack_index(spec, saved.len() + 1)
+                        .ok_or_else(|| format!("popd: {}: directory stack index out of range", spec))?;
+                }
+                Opt::Flag(other) => return Err(format!("popd: unknown option: {}", other).into()),
+                Opt::Operand(other) => return Err(format!("popd: {}: invalid argument", other).into()),
+            }
+        }
+        if saved.is_empty() {
+            return Err("popd: directory stack empty".into());
+        }
+
+        if index == 0 {
+            // The current directory is dropped and the next one becomes current.
+            if !no_change {
+                let top = &saved[0];
+                cwd::change_dir(top, false).map_err(|e| format!("popd: {}: {}", top.display(), cwd::error_text(&e)))?;
+            }
+            saved.remove(0);
+        } else {
+            saved.remove(index - 1);
+        }
+        drop(saved);
+        self.io_redirection.write_out(&format_stack(&cwd::dir_stack()))?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Return to the directory saved by pushd".to_string(),
+            long_desc: "Remove the top of the directory stack and change to the new top. With +N \
+                       or -N, entry N counted from the top or the bottom of `dirs` is removed \
+                       instead. The stack is printed afterwards.".to_string(),
+            usage: "popd [-n] [+N | -N]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-n".to_string(), "Only change the stack, not the current directory".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct DirsCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl DirsCommand {
+    fn new() -> Self {
+        Self { name: "dirs".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for DirsCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut long, mut per_line, mut verbose) = (false, false, false);
+        let mut entry = None;
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-c") => {
+                    cwd::stack().lock().unwrap_or_else(|e| e.into_inner()).clear();
+                    return Ok(());
+                }
+                Opt::Flag("-l") => long = true,
+                Opt::Flag("-p") => per_line = true,
+                Opt::Flag("-v") => verbose = true,
+                Opt::Flag(spec) | Opt::Operand(spec) if spec.starts_with(['+', '-']) && spec.len() > 1 => entry = Some(spec),
+                Opt::Flag(other) => return Err(format!("dirs: unknown option: {}", other).into()),
+                Opt::Operand(other) => return Err(format!("dirs: {}: invalid argument", other).into()),
+            }
+        }
+
+        let dirs = cwd::dir_stack();
+        let show = |dir: &PathBuf| if long { dir.display().to_string() } else { abbreviate_home(dir) };
+        if let Some(spec) = entry {
+            let index = cwd::stack_index(spec, dirs.len())
+                .ok_or_else(|| format!("dirs: {}: directory stack index out of range", spec))?;
+            self.io_redirection.write_out(&format!("{}\n", show(&dirs[index])))?;
+            return Ok(());
+        }
+        let out = if verbose {
+            dirs.iter().enumerate().map(|(i, dir)| format!("{:>2}  {}\n", i, show(dir))).collect()
+        } else if per_line {
+            dirs.iter().map(|dir| format!("{}\n", show(dir))).collect()
+        } else if long {
+            format!("{}\n", dirs.iter().map(show).collect::<Vec<_>>().join(" "))
+        } else {
+            format_stack(&dirs)
+        };
+        self.io_redirection.write_out(&out)?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Show the directory stack".to_string(),
+            long_desc: "Print the directory stack, the current directory first. Entries can also be \
+                       used as ~N, ~+N and ~-N in commands.".to_string(),
+            usage: "dirs [-c] [-l] [-p | -v] [+N | -N]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-c".to_string(), "Clear the directory stack".to_string()),
+                ("-l".to_string(), "Show full paths instead of abbreviating the home directory with ~".to_string()),
+                ("-p".to_string(), "Show one entry per line".to_string()),
+                ("-v".to_string(), "Show one entry per line with its position".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct HistoryCommand {
+    pub name: String, 
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+impl HistoryCommand {
+    fn new() -> Self {
+        Self { name: "history".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for HistoryCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut filter = HistoryFilter::default();
+        let mut count = None;
+        let mut delete = None;
+        let (mut clear, mut write, mut read, mut json, mut time) = (false, false, false, false, false);
+        let mut options = Options::new(&self.argv);
+        while let Some(opt) = options.next() {
+            match opt {
+                Opt::Flag("-c" | "--clear") => clear = true,
+                Opt::Flag("-d" | "--delete") => delete = Some(options.value("-d")?),
+                Opt::Flag("-w" | "--write") => write = true,
+                Opt::Flag("-r" | "--read") => read = true,
+                Opt::Flag("-t" | "--time") => time = true,
+                Opt::Flag("--json") => json = true,
+                Opt::Flag(name @ "--contains") => filter.contains = Some(options.value(name)?.to_string()),
+                Opt::Flag(name @ "--regex") => {
+                    let pattern = options.value(name)?;
+                    filter.regex = Some(Regex::new(pattern).map_err(|e| format!("history: invalid regex: {}", e))?);
+                }
+                Opt::Flag(name @ "--dir") => {
+                    let dir = options.value(name)?;
+                    filter.dir = Some(std::fs::canonicalize(dir).map_err(|e| format!("history: {}: {}", dir, e))?);
+                }
+                Opt::Flag(name @ "--since") => filter.since = Some(history::parse_time(options.value(name)?)?),
+                Opt::Flag(name @ "--until") => filter.until = Some(history::parse_time(options.value(name)?)?),
+                Opt::Flag(name @ "--status") => {
+                    let status = options.value(name)?;
+                    filter.status = Some(status.parse().map_err(|_| format!("history: invalid status: {}", status))?);
+                }
+                Opt::Flag("--failed") => filter.failed = true,
+                Opt::Flag(other) => return Err(format!("history: unknown option: {}", other).into()),
+                Opt::Operand(n) => {
+                    count = Some(n.parse::<usize>().map_err(|_| format!("history: numeric argument required: {}", n))?)
+                }
+            }
+        }
+
+        let shared = history::shared();
+        let mut history = shared.lock().unwrap_or_else(|e| e.into_inner());
+        if clear {
+            history.clear_all()?;
+        }
+        if let Some(offset) = delete {
+            let len = history.entries.len() as i64;
+            let number = match offset.parse::<i64>() {
+                Ok(n) if n < 0 => len + n + 1,
+                Ok(n) => n,
+                Err(_) => return Err(format!("history: {}: numeric argument required", offset).into()),
+            };
+            if history.delete(usize::try_from(number).unwrap_or_default())?.is_none() {
+                return Err(format!("history: {}: history position out of range", offset).into());
+            }
+        }
+        if read {
+            history.reload()?;
+        }
+        if write {
+            history.save()?;
+        }
+        if clear || delete.is_some() || read || write {
+            return Ok(());
+        }
+
+        let matches: Vec<(usize, &HistoryEntry)> = history
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i + 1, entry))
+            .filter(|(_, entry)| filter.matches(entry))
+            .collect();
+        let shown = &matches[matches.len() - count.unwrap_or(matches.len()).min(matches.len())..];
+        let time_format = vars::get("HISTTIMEFORMAT").unwrap_or_else(|| "%F %T ".to_string());
+        for (number, entry) in shown {
+            if json {
+                let mut value = entry.to_json();
+                value["id"] = (*number).into();
+                self.io_redirection.write_out(&format!("{}\n", value))?;
+            } else if time {
+                let started = entry.started.map(|started| history::format_timestamp(started, &time_format));
+                self.io_redirection.write_out(&format!("{:>5}  {}{}\n", number, started.unwrap_or_default(), entry.command))?;
+            } else {
+                self.io_redirection.write_out(&format!("{:>5}  {}\n", number, entry.command))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Display or manage the command history".to_string(),
+            long_desc: "Show the numbered history of commands, oldest first, or only the last N entries. \
+                       Entries can be filtered by text, directory, time and exit status, deleted, \
+                       or the history file written and re-read.".to_string(),
+            usage: "history [flags] [N]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("--clear, -c".to_string(), "Clear the command history".to_string()),
+                ("--delete, -d OFFSET".to_string(), "Delete the entry at OFFSET; negative offsets count from the end".to_string()),
+                ("--write, -w".to_string(), "Write the history to the history file".to_string()),
+                ("--read, -r".to_string(), "Re-read the history from the history file".to_string()),
+                ("--time, -t".to_string(), "Show when each command started, formatted with $HISTTIMEFORMAT".to_string()),
+                ("--json".to_string(), "Print one JSON object per entry".to_string()),
+                ("--contains TEXT".to_string(), "Only entries containing TEXT".to_string()),
+                ("--regex PATTERN".to_string(), "Only entries matching the regular expression".to_string()),
+                ("--dir DIR".to_string(), "Only entries run in DIR".to_string()),
+                ("--since TIME, --until TIME".to_string(), "Only entries started in the time range".to_string()),
+                ("--status N".to_string(), "Only entries that exited with status N".to_string()),
+                ("--failed".to_string(), "Only entries that exited with a non-zero status".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct CompleteCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl CompleteCommand {
+    fn new() -> Self {
+        Self { name: "complete".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for CompleteCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.argv.is_empty() {
+            for spec in completion::specs().iter() {
+                self.io_redirection.write_out(&format!("{}\n", spec))?;
+            }
+            return Ok(());
+        }
+
+        match CompletionSpec::parse(&self.argv)? {
+            SpecAction::Add(spec) => completion::add_spec(spec),
+            SpecAction::Erase(command) => completion::specs().retain(|spec| spec.command != command),
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Define how the arguments of a command are completed".to_string(),
+            long_desc: "Adds a completion spec for a command. Candidates come from a word list, \
+                       the lines printed by a command, or a completion function registered by \
+                       the embedding program. A spec can be limited to one argument position or \
+                       to the value of a flag. Without arguments, all specs are listed.".to_string(),
+            usage: "complete -c <command> [-p <position>] [-f <flag>] (-a <words> | -C <command> | -F <function>)\n  \
+                    complete -e -c <command>".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("--command, -c".to_string(), "The command the spec applies to".to_string()),
+                ("--arguments, -a".to_string(), "Complete from a whitespace-separated word list".to_string()),
+                ("--output-of, -C".to_string(), "Complete from the lines printed by a command".to_string()),
+                ("--function, -F".to_string(), "Complete using a registered completion function".to_string()),
+                ("--position, -p".to_string(), "Only complete the argument at this position".to_string()),
+                ("--flag, -f".to_string(), "Only complete the value of this flag".to_string()),
+                ("--erase, -e".to_string(), "Remove all specs for the command".to_string()),
+            ],
+        }
+    }
+}
+
+/// The options `set -o` knows about.
+const SHELL_OPTIONS: &[&str] = &["emacs", "histexpand", "share_history", "verbose", "vi"];
+
+fn shell_option(name: &str) -> Option<bool> {
+    match name {
+        "emacs" => Some(keymap::edit_mode() == EditMode::Emacs),
+        "histexpand" => Some(expansion::histexpand()),
+        "share_history" => Some(history::share_history()),
+        "verbose" => Some(crate::verbose()),
+        "vi" => Some(keymap::edit_mode() == EditMode::Vi),
+        _ => None,
+    }
+}
+
+fn set_shell_option(name: &str, enable: bool) -> Result<(), String> {
+    let vi = match name {
+        "histexpand" => {
+            expansion::set_histexpand(enable);
+            return Ok(());
+        }
+        "share_history" => {
+            history::set_share_history(enable);
+            return Ok(());
+        }
+        "verbose" => {
+            crate::set_verbose(enable);
+            return Ok(());
+        }
+        "vi" => enable,
+        "emacs" => !enable,
+        _ => return Err(format!("set: unknown option: {}", name)),
+    };
+    keymap::set_edit_mode(if vi { EditMode::Vi } else { EditMode::Emacs });
+    Ok(())
+}
+
+pub struct SetCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl SetCommand {
+    fn new() -> Self {
+        Self { name: "set".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+
+    /// Prints every option as a table, or as the `set` commands that
+    /// restore the current state.
+    fn list_options(&self, table: bool) -> std::io::Result<()> {
+        for name in SHELL_OPTIONS {
+            let enabled = shell_option(name).unwrap_or_default();
+            if table {
+                self.io_redirection.write_out(&format!("{:<15} {}\n", name, if enabled { "on" } else { "off" }))?;
+            } else {
+                self.io_redirection.write_out(&format!("set {}o {}\n", if enabled { '-' } else { '+' }, name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Command for SetCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut words = self.argv.iter();
+        let enable = match words.next().map(String::as_str) {
+            None => {
+                self.list_options(true)?;
+                return Ok(());
+            }
+            Some("-o") => true,
+            Some("+o") => false,
+            Some(other) => return Err(format!("set: unsupported argument: {}", other).into()),
+        };
+        let names: Vec<&String> = words.collect();
+        if names.is_empty() {
+            self.list_options(enable)?;
+        }
+        for name in names {
+            set_shell_option(name, enable)?;
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Set shell options".to_string(),
+            long_desc: "Turns shell options on with -o and off with +o. `set -o vi` and \
+                       `set -o emacs` choose the key bindings of the line editor. Without an \
+                       option name, -o shows the state of every option and +o prints the \
+                       commands that restore it.".to_string(),
+            usage: "set (-o | +o) [option...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-o <option>".to_string(), "Turn an option on".to_string()),
+                ("+o <option>".to_string(), "Turn an option off".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct JumpCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl JumpCommand {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+
+    /// Prints the numbered candidates and reads which one to jump to.
+    fn choose<'a>(&self, candidates: &[&'a Path]) -> Result<Option<&'a Path>, Box<dyn std::error::Error>> {
+        for (i, path) in candidates.iter().enumerate() {
+            println!("{:>3}  {}", i + 1, path.display());
+        }
+        print!("{}: choose [1-{}]: ", self.name, candidates.len());
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(None);
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => Ok(Some(candidates[n - 1])),
+            _ => Err(format!("{}: invalid choice: {}", self.name, answer).into()),
+        }
+    }
+}
+
+impl Command for JumpCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let name = &self.name;
+        let mut order = Order::Frecency;
+        let (mut list, mut echo, mut interactive, mut remove, mut prune) = (false, false, false, false, false);
+        let mut fragments = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-l" | "--list") => list = true,
+                Opt::Flag("-e" | "--echo") => echo = true,
+                Opt::Flag("-i" | "--interactive") => interactive = true,
+                Opt::Flag("-r" | "--rank") => order = Order::Rank,
+                Opt::Flag("-t" | "--recent") => order = Order::Recent,
+                Opt::Flag("-x" | "--remove") => remove = true,
+                Opt::Flag("--prune") => prune = true,
+                Opt::Flag(other) => return Err(format!("{}: unknown option: {}", name, other).into()),
+                Opt::Operand(fragment) => fragments.push(fragment.to_string()),
+            }
+        }
+
+        let path = frecency::database_path().ok_or_else(|| format!("{}: directory database is disabled", name))?;
+        if remove {
+            let dir = cwd::logical();
+            frecency::update(&path, |database| database.remove(&dir))?;
+            return Ok(());
+        }
+        // Directories that no longer exist are forgotten whenever they are looked up.
+        frecency::update(&path, Database::prune)?;
+        if prune {
+            return Ok(());
+        }
+
+        // A path that exists is changed into directly.
+        if let [fragment] = fragments.as_slice()
+            && !list
+            && !interactive
+            && Path::new(fragment).is_dir()
+        {
+            let dir = std::fs::canonicalize(fragment)?;
+            if echo {
+                self.io_redirection.write_out(&format!("{}\n", dir.display()))?;
+                return Ok(());
+            }
+            cwd::change_dir(&dir, false)?;
+            return Ok(());
+        }
+
+        let database = frecency::load(&path)?;
+        let matches = database.matches(&fragments, order, frecency::now());
+        if list || (fragments.is_empty() && !interactive) {
+            for (score, entry) in matches.iter().rev() {
+                self.io_redirection.write_out(&format!("{:<10.1} {}\n", score, entry.path.display()))?;
+            }
+            return Ok(());
+        }
+        if matches.is_empty() {
+            return Err(ExitStatusError { status: 1, message: format!("{}: no match for {}", name, fragments.join(" ")) }.into());
+        }
+        let candidates: Vec<&Path> = matches.iter().map(|(_, entry)| entry.path.as_path()).collect();
+        let dir = if interactive {
+            match self.choose(&candidates)? {
+                Some(dir) => dir,
+                None => return Ok(()),
+            }
+        } else {
+            candidates[0]
+        };
+        if echo {
+            self.io_redirection.write_out(&format!("{}\n", dir.display()))?;
+            return Ok(());
+        }
+        cwd::change_dir(dir, false)?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Jump to a frequently used directory".to_string(),
+            long_desc: "Change to the most frecent directory, ranked by how often and how recently it \
+                       was visited, whose path contains all the given fragments in order. The last \
+                       fragment has to match the directory's own name. Directories are recorded \
+                       by cd in $MSH_Z_DATA, or .msh_z next to the history file; the home \
+                       directory and paths matching the colon-separated globs in MSH_Z_EXCLUDE are not. Without \
+                       fragments the known directories are listed.".to_string(),
+            usage: format!("{} [flags] [fragment...]", self.name),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("--list, -l".to_string(), "List the matching directories with their scores".to_string()),
+                ("--interactive, -i".to_string(), "Choose from a numbered list of matches".to_string()),
+                ("--echo, -e".to_string(), "Print the best match instead of changing to it".to_string()),
+                ("--rank, -r".to_string(), "Rank by visits only".to_string()),
+                ("--recent, -t".to_string(), "Rank by the last visit only".to_string()),
+                ("--remove, -x".to_string(), "Forget the current directory".to_string()),
+                ("--prune".to_string(), "Forget directories that no longer exist".to_string()),
+            ],
+        }
+    }
+}
+
+/// Builds the simple command `name` with the words `argv`. The shell
+/// applies the `redirections` the command does not take itself around it.
+fn simple_command(name: &str, argv: Vec<String>, redirections: Vec<Redirection>) -> Box<dyn Command> {
+    let mut command = builtin(name).unwrap_or_else(|| Box::new(SystemCommand::new(name.to_string())));
+    let redirections = command.take_redirections(redirections);
+    command.set_argv(argv);
+    if !redirections.is_empty() {
+        command = Box::new(Redirected::new(command, redirections));
+    }
+    command
+}
+
+/// Moves the streams of `io` to `command`, which runs in place of the
+/// command they were given to.
+fn lend_streams(io: &IoRedirection, command: &mut dyn Command) {
+    match io.from.take() {
+        Some(Input::File(file)) => command.set_input_file(file),
+        Some(Input::Reader(input)) => command.set_input(input),
+        None => {}
+    }
+    match io.to.take() {
+        Some(Output::File(file)) => command.set_output_file(file),
+        Some(Output::Writer(output)) => command.set_output(output),
+        None => {}
+    }
+    match io.error.take() {
+        Some(Output::File(file)) => command.set_error_file(file),
+        Some(Output::Writer(error)) => command.set_error(error),
+        None => {}
+    }
+}
+
+/// Takes back the streams [`lend_streams`] gave `command`, so that the
+/// command they were given to can run again, as in a loop.
+fn return_streams(io: &IoRedirection, command: &mut dyn Command) {
+    let lent = command.get_io_redirection();
+    *io.from.borrow_mut() = lent.from.get_mut().take();
+    *io.to.borrow_mut() = lent.to.get_mut().take();
+    *io.error.borrow_mut() = lent.error.get_mut().take();
+}
+
+/// How `type` and `command -V` describe what `name` resolves to.
+fn describe(name: &str, resolution: &Resolution) -> String {
+    match resolution {
+        Resolution::Alias(value) => format!("{} is aliased to `{}'", name, value),
+        Resolution::Keyword => format!("{} is a shell keyword", name),
+        Resolution::Builtin => format!("{} is a shell builtin", name),
+        Resolution::File { path, hashed: true } => format!("{} is hashed ({})", name, path.display()),
+        Resolution::File { path, hashed: false } => format!("{} is {}", name, path.display()),
+    }
+}
+
+pub struct HashCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl HashCommand {
+    fn new() -> Self {
+        Self { name: "hash".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for HashCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut reset, mut delete, mut show, mut reusable) = (false, false, false, false);
+        let mut path = None;
+        let mut names = Vec::new();
+        let mut options = Options::new(&self.argv);
+        while let Some(opt) = options.next() {
+            match opt {
+                Opt::Flag("-r") => reset = true,
+                Opt::Flag("-d") => delete = true,
+                Opt::Flag("-t") => show = true,
+                Opt::Flag("-l") => reusable = true,
+                Opt::Flag(name @ "-p") => path = Some(PathBuf::from(options.value(name)?)),
+                Opt::Flag(other) => return Err(format!("hash: unknown option: {}", other).into()),
+                Opt::Operand(name) => names.push(name),
+            }
+        }
+
+        if reset {
+            lookup::clear();
+        }
+        if let Some(path) = path {
+            let [name] = names.as_slice() else {
+                return Err("hash: -p requires exactly one name".into());
+            };
+            lookup::remember(name, path);
+            return Ok(());
+        }
+        if names.is_empty() {
+            let hashed = lookup::hashed();
+            if reusable {
+                for (name, entry) in &hashed {
+                    self.io_redirection.write_out(&format!("hash -p {} {}\n", completion::quote(&entry.path.to_string_lossy(), None), name))?;
+                }
+            } else if hashed.is_empty() && !reset {
+                self.io_redirection.write_out("hash: hash table empty\n")?;
+            } else if !hashed.is_empty() {
+                self.io_redirection.write_out("hits\tcommand\n")?;
+                for (_, entry) in &hashed {
+                    self.io_redirection.write_out(&format!("{:>4}\t{}\n", entry.hits, entry.path.display()))?;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut missing = Vec::new();
+        for name in names {
+            if delete {
+                if !lookup::forget(name) {
+                    missing.push(name);
+                }
+            } else if show {
+                match lookup::hashed().into_iter().find(|(hashed, _)| hashed == name) {
+                    Some((_, entry)) => self.io_redirection.write_out(&format!("{}\n", entry.path.display()))?,
+                    None => missing.push(name),
+                }
+            } else if BUILTINS.contains(&name) {
+                continue;
+            } else if lookup::hash(name).is_none() {
+                missing.push(name);
+            }
+        }
+        for name in &missing {
+            self.io_redirection.write_err(&format!("hash: {}: not found\n", name))?;
+        }
+        if !missing.is_empty() {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Remember where commands are found".to_string(),
+            long_desc: "Look up each NAME on PATH and remember where it was found, so it is not \
+                       searched for again until PATH changes. Without names, list the remembered \
+                       commands and how often each was run.".to_string(),
+            usage: "hash [-r] [-l] [-p path] [-d | -t] [name...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-r".to_string(), "Forget every remembered location".to_string()),
+                ("-d".to_string(), "Forget the remembered location of each name".to_string()),
+                ("-t".to_string(), "Print the remembered location of each name".to_string()),
+                ("-p <path>".to_string(), "Remember path as the location of name".to_string()),
+                ("-l".to_string(), "List remembered locations as hash commands".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct TypeCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl TypeCommand {
+    fn new() -> Self {
+        Self { name: "type".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for TypeCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut all, mut kind_only, mut path_only) = (false, false, false);
+        let mut names = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-a") => all = true,
+                Opt::Flag("-t") => kind_only = true,
+                Opt::Flag("-p" | "-P") => path_only = true,
+                Opt::Flag(other) => return Err(format!("type: unknown option: {}", other).into()),
+                Opt::Operand(name) => names.push(name),
+            }
+        }
+
+        let mut failed = false;
+        for name in names {
+            let resolutions: Vec<Resolution> =
+                if all { lookup::resolve_all(name) } else { lookup::resolve(name).into_iter().collect() };
+            if resolutions.is_empty() {
+                failed = true;
+                if !kind_only && !path_only {
+                    self.io_redirection.write_err(&format!("type: {}: not found\n", name))?;
+                }
+                continue;
+            }
+            for resolution in &resolutions {
+                let line = match resolution {
+                    Resolution::File { path, .. } if path_only => path.display().to_string(),
+                    _ if path_only => continue,
+                    Resolution::Alias(_) if kind_only => "alias".to_string(),
+                    Resolution::Keyword if kind_only => "keyword".to_string(),
+                    Resolution::Builtin if kind_only => "builtin".to_string(),
+                    Resolution::File { .. } if kind_only => "file".to_string(),
+                    resolution => describe(name, resolution),
+                };
+                self.io_redirection.write_out(&format!("{}\n", line))?;
+            }
+        }
+        if failed {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Show how a name would be run".to_string(),
+            long_desc: "Report whether each NAME is a shell keyword, a builtin or an executable \
+                       file, and where the file is.".to_string(),
+            usage: "type [-a] [-t | -p] name...".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-a".to_string(), "Show every match, including each file on PATH".to_string()),
+                ("-t".to_string(), "Print only keyword, builtin or file".to_string()),
+                ("-p".to_string(), "Print only the path of files".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct WhichCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl WhichCommand {
+    fn new() -> Self {
+        Self { name: "which".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for WhichCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut all = false;
+        let mut names = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-a") => all = true,
+                Opt::Flag(other) => return Err(format!("which: unknown option: {}", other).into()),
+                Opt::Operand(name) => names.push(name),
+            }
+        }
+
+        let mut failed = false;
+        for name in names {
+            let paths = if all { lookup::find_all(name) } else { lookup::find(name).into_iter().collect() };
+            if paths.is_empty() {
+                failed = true;
+            }
+            for path in paths {
+                self.io_redirection.write_out(&format!("{}\n", path.display()))?;
+            }
+        }
+        if failed {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Locate executables on PATH".to_string(),
+            long_desc: "Print the path of the executable each NAME runs. The exit status is 1 if \
+                       any name is not found.".to_string(),
+            usage: "which [-a] name...".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-a".to_string(), "Print every match on PATH, not only the first".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct CommandCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl CommandCommand {
+    fn new() -> Self {
+        Self { name: "command".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for CommandCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    // Options are only read before the name; the rest belongs to the command.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut short, mut verbose) = (false, false);
+        let mut start = self.argv.len();
+        for (i, word) in self.argv.iter().enumerate() {
+            match word.as_str() {
+                "-v" => short = true,
+                "-V" => verbose = true,
+                "-h" | "--help" => {
+                    self.print_help()?;
+                    return Ok(());
+                }
+                "--" => {
+                    start = i + 1;
+                    break;
+                }
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(format!("command: unknown option: {}", flag).into());
+                }
+                _ => {
+                    start = i;
+                    break;
+                }
+            }
+        }
+        let words = &self.argv[start..];
+        let Some(name) = words.first() else {
+            return Ok(());
+        };
+
+        if short || verbose {
+            let mut failed = false;
+            for name in words {
+                match lookup::resolve(name) {
+                    Some(Resolution::File { path, .. }) if short => {
+                        self.io_redirection.write_out(&format!("{}\n", path.display()))?
+                    }
+                    Some(Resolution::Alias(value)) if short => {
+                        self.io_redirection.write_out(&format!("{}\n", alias::definition(name, &value)))?
+                    }
+                    Some(_) if short => self.io_redirection.write_out(&format!("{}\n", name))?,
+                    Some(resolution) => self.io_redirection.write_out(&format!("{}\n", describe(name, &resolution)))?,
+                    None => {
+                        failed = true;
+                        if verbose {
+                            self.io_redirection.write_err(&format!("command: {}: not found\n", name))?;
+                        }
+                    }
+                }
+            }
+            if failed {
+                return Err(ExitStatusError { status: 1, message: String::new() }.into());
+            }
+            return Ok(());
+        }
+
+        if tokenizer::KEYWORDS.contains(&name.as_str()) {
+            return Err(format!("command: {}: is a shell keyword", name).into());
+        }
+        let mut command = simple_command(&words[0], words[1..].to_vec(), Vec::new());
+        // The command runs in place of this one, so it gets its streams.
+        lend_streams(&self.io_redirection, command.as_mut());
+        let result = command.execute();
+        return_streams(&self.io_redirection, command.as_mut());
+        result
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Run a builtin or executable directly".to_string(),
+            long_desc: "Run NAME with its arguments as a builtin or an executable on PATH, skipping \
+                       any other meaning the name has. With -v or -V, describe what each name \
+                       runs as instead.".to_string(),
+            usage: "command [-v | -V] name [args...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-v".to_string(), "Print the path or name each name runs as".to_string()),
+                ("-V".to_string(), "Describe what each name runs as, like type".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct AliasCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl AliasCommand {
+    fn new() -> Self {
+        Self { name: "alias".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for AliasCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut list = false;
+        let mut operands = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-p") => list = true,
+                Opt::Flag(other) => return Err(format!("alias: unknown option: {}", other).into()),
+                Opt::Operand(operand) => operands.push(operand),
+            }
+        }
+
+        if list || operands.is_empty() {
+            for (name, value) in alias::all() {
+                self.io_redirection.write_out(&format!("{}\n", alias::definition(&name, &value)))?;
+            }
+        }
+        let mut failed = false;
+        for operand in operands {
+            match operand.split_once('=') {
+                Some((name, _)) if !alias::is_valid_name(name) => {
+                    self.io_redirection.write_err(&format!("alias: `{}': invalid alias name\n", name))?;
+                    failed = true;
+                }
+                Some((name, value)) => alias::set(name, value),
+                None => match alias::get(operand) {
+                    Some(value) => self.io_redirection.write_out(&format!("{}\n", alias::definition(operand, &value)))?,
+                    None => {
+                        self.io_redirection.write_err(&format!("alias: {}: not found\n", operand))?;
+                        failed = true;
+                    }
+                },
+            }
+        }
+        if failed {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Define or list aliases".to_string(),
+            long_desc: "Define each NAME=VALUE as an alias, so that NAME at the start of a command \
+                       is replaced by VALUE. If VALUE ends with a space, the word after it is \
+                       checked for an alias too. Without arguments, list every alias in a form \
+                       that can be read back by the shell.".to_string(),
+            usage: "alias [-p] [name[=value]...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-p".to_string(), "List every alias".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct UnaliasCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl UnaliasCommand {
+    fn new() -> Self {
+        Self { name: "unalias".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for UnaliasCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut all = false;
+        let mut names = Vec::new();
+        for opt in Options::new(&self.argv) {
+            match opt {
+                Opt::Flag("-a") => all = true,
+                Opt::Flag(other) => return Err(format!("unalias: unknown option: {}", other).into()),
+                Opt::Operand(name) => names.push(name),
+            }
+        }
+
+        if all {
+            alias::clear();
+            return Ok(());
+        }
+        if names.is_empty() {
+            return Err("unalias: usage: unalias [-a] name [name...]".into());
+        }
+        let missing: Vec<&str> = names.into_iter().filter(|name| !alias::remove(name)).collect();
+        for name in &missing {
+            self.io_redirection.write_err(&format!("unalias: {}: not found\n", name))?;
+        }
+        if !missing.is_empty() {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Remove aliases".to_string(),
+            long_desc: "Remove each NAME from the defined aliases.".to_string(),
+            usage: "unalias [-a] name [name...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-a".to_string(), "Remove every alias".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct AbbrCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl AbbrCommand {
+    fn new() -> Self {
+        Self { name: "abbr".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for AbbrCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let action = AbbrAction::parse(&self.argv).map_err(|e| format!("abbr: {}", e))?;
+        let save_error = |e: std::io::Error| format!("abbr: could not update {}: {}", rc_path().display(), e);
+        match action {
+            AbbrAction::Add(abbreviation) => abbr::add(abbreviation).map_err(save_error)?,
+            AbbrAction::Erase(names) => {
+                let mut missing = Vec::new();
+                for name in names {
+                    if !abbr::erase(&name).map_err(&save_error)? {
+                        missing.push(name);
+                    }
+                }
+                for name in &missing {
+                    self.io_redirection.write_err(&format!("abbr: {}: not found\n", name))?;
+                }
+                if !missing.is_empty() {
+                    return Err(ExitStatusError { status: 1, message: String::new() }.into());
+                }
+            }
+            AbbrAction::Query(names) => {
+                if !names.iter().any(|name| abbr::get(name).is_some()) {
+                    return Err(ExitStatusError { status: 1, message: String::new() }.into());
+                }
+            }
+            AbbrAction::List => {
+                for abbreviation in abbr::all() {
+                    self.io_redirection.write_out(&format!("{}\n", abbreviation.name))?;
+                }
+            }
+            AbbrAction::Show => {
+                for abbreviation in abbr::all() {
+                    self.io_redirection.write_out(&format!("{}\n", abbreviation.definition()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Manage abbreviations".to_string(),
+            long_desc: "Define NAME as an abbreviation for EXPANSION. When NAME is typed as the \
+                       command and followed by Space or Enter, the line editor replaces it with \
+                       EXPANSION, so history records the full command. Changes made at the prompt \
+                       are saved to the rc file. Without arguments, list every abbreviation.".to_string(),
+            usage: "abbr [-a] [--position command|anywhere] name expansion... | abbr -e name... | abbr [-l | -s]"
+                .to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("--add, -a".to_string(), "Define an abbreviation".to_string()),
+                ("--position, -p <where>".to_string(), "Expand only as the command (default) or anywhere".to_string()),
+                ("--erase, -e".to_string(), "Remove each named abbreviation".to_string()),
+                ("--query, -q".to_string(), "Succeed if any name is an abbreviation".to_string()),
+                ("--list, -l".to_string(), "Print the name of every abbreviation".to_string()),
+                ("--show, -s".to_string(), "Print every abbreviation as an abbr command".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct EchoCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl EchoCommand {
+    fn new() -> Self {
+        Self { name: "echo".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for EchoCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    // `echo --help` prints its argument like any other word.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut newline, mut escapes) = (true, false);
+        // Only words made of option letters are options; the first other
+        // word starts the text.
+        let mut start = 0;
+        for word in &self.argv {
+            let Some(letters) = word.strip_prefix('-').filter(|letters| {
+                !letters.is_empty() && letters.chars().all(|c| matches!(c, 'n' | 'e' | 'E'))
+            }) else {
+                break;
+            };
+            for letter in letters.chars() {
+                match letter {
+                    'n' => newline = false,
+                    'e' => escapes = true,
+                    _ => escapes = false,
+                }
+            }
+            start += 1;
+        }
+
+        let mut text = self.argv[start..].join(" ");
+        if escapes {
+            let (unescaped, stop) = format::unescape(&text, Escapes::Echo);
+            text = unescaped;
+            newline &= !stop;
+        }
+        if newline {
+            text.push('\n');
+        }
+        self.io_redirection.write_out(&text)?;
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Print arguments".to_string(),
+            long_desc: "Print the arguments separated by spaces and followed by a newline.".to_string(),
+            usage: "echo [-neE] [arg...]".to_string(),
+            flags: vec![
+                ("-n".to_string(), "Do not print the trailing newline".to_string()),
+                ("-e".to_string(), "Interpret backslash escapes such as \\n and \\t; \\c stops the output".to_string()),
+                ("-E".to_string(), "Do not interpret backslash escapes (default)".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct PrintfCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl PrintfCommand {
+    fn new() -> Self {
+        Self { name: "printf".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for PrintfCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
     fn get_io_redirection(&mut self) -> &mut IoRedirection {
         &mut self.io_redirection
     }
-    fn get_args_mut(&mut self) -> &mut Vec<String> {
-        unimplemented!("PwdCommand does not support mutable arguments")
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
     }
-    
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag> {
-        unimplemented!("PwdCommand does not support mutable flags") 
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
     }
-    
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut words = self.argv.as_slice();
+        let mut variable = None;
+        if let [flag, name, rest @ ..] = words
+            && flag == "-v"
+        {
+            variable = Some(name);
+            words = rest;
+        }
+        if words.first().is_some_and(|word| word == "--") {
+            words = &words[1..];
+        }
+        let Some((format, args)) = words.split_first() else {
+            return Err("printf: usage: printf [-v var] format [arguments]".into());
+        };
+
+        let printed = format::printf(format, args).map_err(|e| format!("printf: {}", e))?;
+        match variable {
+            Some(name) => vars::set(name, &printed.output),
+            None => self.io_redirection.write_out(&printed.output)?,
+        }
+        for error in &printed.errors {
+            self.io_redirection.write_err(&format!("printf: {}\n", error))?;
+        }
+        if !printed.errors.is_empty() {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        Ok(())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Print arguments under control of a format".to_string(),
+            long_desc: "Print ARGUMENTS as described by FORMAT, which may contain backslash escapes \
+                       and conversions: %s, %b (with escapes), %q (quoted for the shell), %c, %d, \
+                       %i, %o, %u, %x, %X, %f, %e, %g and %%. The format is reused until every \
+                       argument has been printed.".to_string(),
+            usage: "printf [-v var] format [arguments]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-v <var>".to_string(), "Assign the output to the variable var instead of printing it".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct ReadCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl ReadCommand {
+    fn new() -> Self {
+        Self { name: "read".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for ReadCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let options = ReadOptions::parse(&self.argv).map_err(|e| format!("read: {}", e))?;
+        let mut from = self.io_redirection.from.borrow_mut();
+        let line = match from.as_mut() {
+            Some(input) => read::read_line(&options, || read::next_byte(input))?,
+            None if read::stdin_is_terminal() => {
+                // The prompt is only for someone typing the answer.
+                if let Some(prompt) = &options.prompt {
+                    self.io_redirection.write_err(prompt)?;
+                }
+                let mut terminal = read::Terminal::open(&options);
+                read::read_line(&options, || terminal.next_byte())?
+            }
+            None => {
+                let mut stdin = std::io::stdin().lock();
+                read::read_line(&options, || read::next_byte(&mut stdin))?
+            }
+        };
+        // Ctrl+C leaves the variables alone.
+        if line.ending != Ending::Interrupt {
+            read::assign(&options, &line);
+        }
+        let status = match line.ending {
+            Ending::Delimiter | Ending::Count => return Ok(()),
+            Ending::Eof => 1,
+            Ending::Timeout => 128 + libc::SIGALRM,
+            Ending::Interrupt => {
+                self.io_redirection.write_err("\n")?;
+                128 + libc::SIGINT
+            }
+        };
+        Err(ExitStatusError { status, message: String::new() }.into())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Read a line into variables".to_string(),
+            long_desc: "Read a line from the input and split it into fields at the characters of $IFS \
+                       (space, tab and newline by default). Each name gets one field and the last name \
+                       the rest of the line; without names the line goes to REPLY. Unless -r is given, \
+                       a backslash escapes the next character and joins lines. The status is 1 at the \
+                       end of input, greater than 128 when the timeout expires and 130 after Ctrl+C. \
+                       The variables belong to the shell and are not exported to the programs it runs, \
+                       unless they came from the environment.".to_string(),
+            usage: "read [-rs] [-a array] [-d delim] [-n nchars] [-N nchars] [-p prompt] [-t timeout] [name...]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-r".to_string(), "Do not treat backslashes as escapes".to_string()),
+                ("-s".to_string(), "Do not echo what is typed, e.g. for passwords".to_string()),
+                ("-a <array>".to_string(), "Store the fields in the elements of array".to_string()),
+                ("-d <delim>".to_string(), "Stop at the first character of delim instead of a newline".to_string()),
+                ("-n <nchars>".to_string(), "Stop after nchars characters or at the delimiter".to_string()),
+                ("-N <nchars>".to_string(), "Read exactly nchars characters, ignoring the delimiter".to_string()),
+                ("-p <prompt>".to_string(), "Print prompt first when reading from a terminal".to_string()),
+                ("-t <timeout>".to_string(), "Give up after timeout seconds at a terminal".to_string()),
+            ],
+        }
+    }
+}
+
+/// Parses the status argument of `exit` or `return`, which wraps around
+/// to 0-255. A word that is not a number is reported and gives status 2.
+fn status_argument(name: &str, argv: &[String], io: &IoRedirection) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    match argv {
+        [] => Ok(None),
+        [word] => match word.parse::<i64>() {
+            Ok(status) => Ok(Some(status.rem_euclid(256) as i32)),
+            Err(_) => {
+                io.write_err(&format!("{}: {}: numeric argument required\n", name, word))?;
+                Ok(Some(2))
+            }
+        },
+        _ => {
+            io.write_err(&format!("{}: too many arguments\n", name))?;
+            Err(ExitStatusError { status: 1, message: String::new() }.into())
+        }
+    }
+}
+
+/// `exit` and its `quit` form.
+pub struct ExitCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl ExitCommand {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for ExitCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
     fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let path = std::env::current_dir()?;
-        println!("{}", path.display());
+        let status = status_argument(&self.name, &self.argv, &self.io_redirection)?;
+        Err(Unwind::Exit(status).into())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Exit the shell".to_string(),
+            long_desc: "Exit the shell with status N, or the status of the last command, after running the EXIT trap. \
+                       In a pipeline only that stage ends.".to_string(),
+            usage: format!("{} [n]", self.name),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct ReturnCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl ReturnCommand {
+    fn new() -> Self {
+        Self { name: "return".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+}
+
+impl Command for ReturnCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !is_sourcing() {
+            self.io_redirection.write_err("return: can only `return' from a sourced file\n")?;
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
+        let status = status_argument(&self.name, &self.argv, &self.io_redirection)?;
+        Err(Unwind::Return(status).into())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Stop reading a sourced file".to_string(),
+            long_desc: "Stop running the file being sourced, such as ~/.mshrc, with status N or the status of the last command.".to_string(),
+            usage: "return [n]".to_string(),
+            flags: vec![
+                ("--help, -h".to_string(), "Show this help message".to_string()),
+            ],
+        }
+    }
+}
+
+pub struct ExecCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub redirections: Vec<Redirection>,
+    pub io_redirection: IoRedirection,
+}
+
+impl ExecCommand {
+    fn new() -> Self {
+        Self {
+            name: "exec".to_string(),
+            argv: vec![],
+            redirections: vec![],
+            io_redirection: IoRedirection::default(),
+        }
+    }
+}
+
+impl Command for ExecCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn take_redirections(&mut self, redirections: Vec<Redirection>) -> Vec<Redirection> {
+        self.redirections = redirections;
+        Vec::new()
+    }
+
+    // The command being replaced by `exec` takes its own options.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::process::CommandExt;
+
+        for redirection in &self.redirections {
+            if let Err(e) = redirection.apply_to_shell() {
+                self.io_redirection.write_err(&format!("exec: {}: {}\n", redirection.target, e))?;
+                return Err(ExitStatusError { status: 1, message: String::new() }.into());
+            }
+        }
+        let Some((name, args)) = self.argv.split_first() else {
+            return Ok(());
+        };
+        let _ = std::io::stdout().flush();
+        // Only returns if the program could not be started.
+        let mut command = lookup::process(name);
+        job::default_signals(command.args(args));
+        let error = command.exec();
+        let (status, message) = launch_error(name, &error).unwrap_or((1, format!("exec: {}: {}", name, error)));
+        self.io_redirection.write_err(&format!("{}\n", message))?;
+        Err(ExitStatusError { status, message: String::new() }.into())
+    }
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Replace the shell with a command, or redirect the shell's files".to_string(),
+            long_desc: "Run COMMAND in place of the shell, which does not come back. Redirections such as \
+                       3>file, 4<file, 5>>file, 6<>file, 2>&1 and 3>&- apply to the shell itself, so \
+                       the commands it runs afterwards inherit them.".to_string(),
+            usage: "exec [command [args...]] [redirection...]".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+pub struct TrapCommand {
+    pub name: String,
+    pub argv: Vec<String>,
+    pub io_redirection: IoRedirection,
+}
+
+impl TrapCommand {
+    fn new() -> Self {
+        Self { name: "trap".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
+    }
+
+    fn print(&self, traps: &[(Condition, String)]) -> Result<(), Box<dyn std::error::Error>> {
+        for (condition, action) in traps {
+            self.io_redirection.write_out(&format!("{}\n", trap::definition(*condition, action)))?;
+        }
+        Ok(())
+    }
+}
+
+impl Command for TrapCommand {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn get_argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut words = self.argv.as_slice();
+        match words.first().map(String::as_str) {
+            Some("-l") => {
+                for (name, signal) in trap::SIGNALS {
+                    self.io_redirection.write_out(&format!("{:2}) SIG{}\n", signal, name))?;
+                }
+                return Ok(());
+            }
+            Some("-p") | None => {
+                let traps = trap::all();
+                let names = words.get(1..).unwrap_or_default();
+                if names.is_empty() {
+                    return self.print(&traps);
+                }
+                let wanted: Vec<Option<Condition>> = names.iter().map(|name| Condition::parse(name)).collect();
+                return self.print(&traps.into_iter().filter(|(condition, _)| wanted.contains(&Some(*condition))).collect::<Vec<_>>());
+            }
+            Some("--") => words = &words[1..],
+            _ => {}
+        }
+
+        // A lone condition, or `-` as the action, resets the conditions.
+        let (action, names) = match words {
+            [name] if Condition::parse(name).is_some() => (None, words),
+            [action, names @ ..] if action == "-" => (None, names),
+            [action, names @ ..] => (Some(action.as_str()), names),
+            [] => return Ok(()),
+        };
+        if names.is_empty() {
+            return Err("trap: usage: trap [-lp] [[action] condition...]".into());
+        }
+        let mut failed = false;
+        for name in names {
+            match Condition::parse(name) {
+                Some(condition) => trap::set(condition, action),
+                None => {
+                    self.io_redirection.write_err(&format!("trap: {}: invalid signal specification\n", name))?;
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            return Err(ExitStatusError { status: 1, message: String::new() }.into());
+        }
         Ok(())
     }
 
     fn get_help(&self) -> CommandHelp {
         CommandHelp {
-            short_desc: "Print the current working directory".to_string(),
-            long_desc: "Display the full path of the current working directory.".to_string(),
-            usage: "pwd [flags]".to_string(),
+            short_desc: "Run a command on signals, on exit or on errors".to_string(),
+            long_desc: "Run ACTION when the shell receives one of the signals (such as INT, TERM or HUP), \
+                       when it exits (EXIT) or after a command line fails (ERR). An empty action ignores \
+                       the signals and - restores their default. Without arguments, print the traps.".to_string(),
+            usage: "trap [-lp] [[action] condition...]".to_string(),
             flags: vec![
                 ("--help, -h".to_string(), "Show this help message".to_string()),
+                ("-l".to_string(), "List the signal names and numbers".to_string()),
+                ("-p".to_string(), "Print the traps for the conditions, or all of them".to_string()),
             ],
         }
     }
 }
 
-pub struct ChangeDirCommand {
+/// `test` and its `[` form.
+pub struct TestCommand {
     pub name: String,
-    pub args: Vec<String>,
-    pub flags: Vec<Flag>,
+    pub argv: Vec<String>,
     pub io_redirection: IoRedirection,
 }
 
-impl ChangeDirCommand {
-    pub fn new() -> Self {
-        Self { name: "cd".to_string(), args: vec![], flags: vec![], io_redirection: IoRedirection::default() }
+impl TestCommand {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), argv: vec![], io_redirection: IoRedirection::default() }
     }
 }
 
-impl Command for ChangeDirCommand {
+impl Command for TestCommand {
     fn get_name(&self) -> &str {
         &self.name
     }
 
-    fn get_args(&self) -> &[String] {
-        &self.args
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
+    }
+
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
     }
 
-    fn get_flags(&self) -> &[Flag] {
-        &self.flags
+    fn get_argv(&self) -> &[String] {
+        &self.argv
     }
 
-    fn get_args_mut(&mut self) -> &mut Vec<String> {
-        &mut self.args
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
     }
 
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag> {
-        &mut self.flags
+    // `-h` is the symbolic link test.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
     }
 
     fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let path = self.args.get(0).ok_or("No path provided")?;
-        std::env::set_current_dir(path)?;
-        Ok(())
+        let mut args: Vec<&str> = self.argv.iter().map(String::as_str).collect();
+        if self.name == "[" && args.pop() != Some("]") {
+            self.io_redirection.write_err("[: missing `]'\n")?;
+            return Err(ExitStatusError { status: 2, message: String::new() }.into());
+        }
+        match conditional::test(&args) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ExitStatusError { status: 1, message: String::new() }.into()),
+            Err(e) => {
+                self.io_redirection.write_err(&format!("{}: {}\n", self.name, e))?;
+                Err(ExitStatusError { status: 2, message: String::new() }.into())
+            }
+        }
     }
-    
+
+    fn get_help(&self) -> CommandHelp {
+        CommandHelp {
+            short_desc: "Evaluate a conditional expression".to_string(),
+            long_desc: "Succeed if EXPRESSION is true. Files are tested with -e, -f, -d, -L, -r, -w, \
+                       -x, -s and similar, strings with -z, -n, =, != , < and >, integers with -eq, \
+                       -ne, -lt, -le, -gt and -ge. Expressions combine with !, -a, -o and \
+                       parentheses. The [ form needs ] as its last argument.".to_string(),
+            usage: "test expression | [ expression ]".to_string(),
+            flags: vec![],
+        }
+    }
+}
+
+/// `[[ expression ]]`
+pub struct ExtendedTestCommand {
+    expr: conditional::Expr,
+    io_redirection: IoRedirection,
+}
+
+impl Command for ExtendedTestCommand {
+    fn get_name(&self) -> &str {
+        "[["
+    }
+
     fn get_io_redirection(&mut self) -> &mut IoRedirection {
         &mut self.io_redirection
     }
 
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.expr.eval() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ExitStatusError { status: 1, message: String::new() }.into()),
+            Err(e) => {
+                self.io_redirection.write_err(&format!("[[: {}\n", e))?;
+                Err(ExitStatusError { status: 2, message: String::new() }.into())
+            }
+        }
+    }
+
     fn get_help(&self) -> CommandHelp {
         CommandHelp {
-            short_desc: "Change the current working directory".to_string(),
-            long_desc: "Change the shell's current working directory to the specified path. \
-                       If no path is provided, an error will be displayed.".to_string(),
-            usage: "cd [flags] <path>".to_string(),
-            flags: vec![
-                ("--help, -h".to_string(), "Show this help message".to_string()),
-                ("--follow-symlinks".to_string(), "Follow symbolic links".to_string()),
-            ],
+            short_desc: "Evaluate an extended conditional expression".to_string(),
+            long_desc: "Like test, but the right side of == and != is a pattern unless quoted, =~ \
+                       matches a regular expression and stores the match and its groups in the \
+                       array BASH_REMATCH, and expressions combine \
+                       with !, &&, || and parentheses.".to_string(),
+            usage: "[[ expression ]]".to_string(),
+            flags: vec![],
         }
     }
 }
 
-pub struct HistoryCommand {
-    pub name: String, 
-    pub args: Vec<String>,
-    pub flags: Vec<Flag>,
+pub struct BindCommand {
+    pub name: String,
+    pub argv: Vec<String>,
     pub io_redirection: IoRedirection,
 }
-impl HistoryCommand {
+
+impl BindCommand {
     fn new() -> Self {
-        Self { name: "history".to_string(), args: vec![], flags: vec![], io_redirection: IoRedirection::default() }
+        Self { name: "bind".to_string(), argv: vec![], io_redirection: IoRedirection::default() }
     }
 }
 
-impl Command for HistoryCommand {
+impl Command for BindCommand {
     fn get_name(&self) -> &str {
         &self.name
     }
 
-    fn get_args(&self) -> &[String] {
-        &self.args
-    }
-
-    fn get_flags(&self) -> &[Flag] {
-        &self.flags
-    }
-
     fn get_io_redirection(&mut self) -> &mut IoRedirection {
         &mut self.io_redirection
     }
 
-    fn get_args_mut(&mut self) -> &mut Vec<String> {
-        &mut self.args
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
     }
 
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag> {
-        &mut self.flags
+    fn get_argv(&self) -> &[String] {
+        &self.argv
     }
 
-    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let history = History::load_from_disk()?;
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
+    }
 
-        if self.get_flag("--clear").is_some() || self.get_flag("-c").is_some() {
-            let mut history = History::load_from_disk()?;
-            history.clear();
-            history.save();
-            return Ok(());
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut keymap_name = None;
+        let mut shell_command = false;
+        let mut list = false;
+        let mut remove = Vec::new();
+        let mut specs = Vec::new();
+        let mut options = Options::new(&self.argv);
+        while let Some(opt) = options.next() {
+            match opt {
+                Opt::Flag("-m" | "--keymap") => {
+                    let name = options.value("-m")?;
+                    keymap_name = Some(KeymapName::parse(name).ok_or_else(|| format!("bind: unknown keymap: {}", name))?);
+                }
+                Opt::Flag("-l" | "--list-actions") => {
+                    for name in Action::names() {
+                        self.io_redirection.write_out(&format!("{}\n", name))?;
+                    }
+                    return Ok(());
+                }
+                Opt::Flag("-p" | "--print") => list = true,
+                Opt::Flag("-x" | "--execute") => shell_command = true,
+                Opt::Flag("-r" | "--remove") => remove.push(options.value("-r")?),
+                Opt::Flag(other) => return Err(format!("bind: unknown option: {}", other).into()),
+                Opt::Operand(spec) => specs.push(spec),
+            }
         }
 
-        for command in history {
-            println!("{}", command);
+        let mut keymaps = keymap::keymaps();
+        let keymap = keymaps.get_mut(keymap_name.unwrap_or_else(keymap::initial_keymap));
+        for keys in &remove {
+            let keys = keymap::parse_key_sequence(keys.trim_matches('"'))?;
+            if !keymap.unbind(&keys) {
+                return Err(format!("bind: \"{}\" is not bound", keymap::format_key_sequence(&keys)).into());
+            }
+        }
+        for spec in &specs {
+            let (keys, value) = keymap::parse_binding(spec)?;
+            let binding = if shell_command {
+                Binding::Command(value)
+            } else {
+                Binding::Action(Action::from_name(&value).ok_or_else(|| format!("bind: unknown action: {}", value))?)
+            };
+            keymap.bind(keys, binding);
+        }
+        if list || (specs.is_empty() && remove.is_empty()) {
+            for (keys, binding) in keymap.bindings() {
+                self.io_redirection.write_out(&format!("{}\n", keymap::bind_command(keymap_name, keys, binding)))?;
+            }
         }
         Ok(())
     }
 
     fn get_help(&self) -> CommandHelp {
         CommandHelp {
-            short_desc: "Display or manage the command history".to_string(),
-            long_desc: "Show the history of commands that have been executed. \
-                       You can also clear the history using the --clear flag.".to_string(),
-            usage: "history [flags]".to_string(),
+            short_desc: "Bind keys to editor actions or shell commands".to_string(),
+            long_desc: "Binds a key sequence such as \"\\C-x\\C-e\" or \"\\ef\" to one of the line \
+                       editor's actions, or with -x to a shell command that can read and change \
+                       the line through $READLINE_LINE and $READLINE_POINT. Bindings go to the \
+                       keymap of the current editing mode unless -m names another. Without \
+                       bindings, the keymap is listed as bind commands.".to_string(),
+            usage: "bind [-m <keymap>] [-x] '\"<keys>\": <action or command>'...\n  \
+                    bind [-m <keymap>] -r <keys>\n  \
+                    bind [-m <keymap>] [-p | -l]".to_string(),
             flags: vec![
                 ("--help, -h".to_string(), "Show this help message".to_string()),
-                ("--clear, -c".to_string(), "Clear the command history".to_string()),
+                ("--keymap, -m".to_string(), "Use the emacs, vi-insert or vi-command keymap".to_string()),
+                ("--execute, -x".to_string(), "Bind the keys to a shell command".to_string()),
+                ("--remove, -r".to_string(), "Remove the binding of a key sequence".to_string()),
+                ("--print, -p".to_string(), "List the bindings of the keymap".to_string()),
+                ("--list-actions, -l".to_string(), "List the names of all editor actions".to_string()),
             ],
         }
     }
@@ -555,8 +4280,7 @@ impl Command for HistoryCommand {
 
 pub struct SystemCommand {
     pub name: String,
-    pub args: Vec<String>,
-    pub flags: Vec<Flag>,
+    pub argv: Vec<String>,
     pub io_redirection: IoRedirection,
 }
 
@@ -564,66 +4288,175 @@ impl SystemCommand {
     pub fn new(name: String) -> Self {
         Self {
             name,
-            args: vec![],
-            flags: vec![],
+            argv: vec![],
             io_redirection: IoRedirection::default(),
         }
     }
 }
 
+/// Where a program writes `output`: to the file itself, to the shell's own
+/// stream, or to a pipe the shell collects it from.
+fn program_output(output: &RefCell<Option<Output>>) -> std::io::Result<std::process::Stdio> {
+    Ok(match &*output.borrow() {
+        Some(Output::File(file)) => file.try_clone()?.into(),
+        Some(Output::Writer(_)) => std::process::Stdio::piped(),
+        None => std::process::Stdio::inherit(),
+    })
+}
+
+impl SystemCommand {
+    /// Runs `command_not_found_handler` with the command and its arguments
+    /// if there is such a command on `PATH`, or reports the missing command
+    /// with the names it may have been meant to be.
+    fn not_found(&self, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(handler) = lookup::find(NOT_FOUND_HANDLER) {
+            let mut command = std::process::Command::new(handler);
+            job::foreground(command.arg(&self.name).args(args));
+            let mut child = command.spawn()?;
+            let owner = job::give_terminal(child.id());
+            let status = child.wait();
+            if owner {
+                job::take_terminal();
+            }
+            let status = status?;
+            return match status.success() {
+                true => Ok(()),
+                false => {
+                    job::killed_by(status);
+                    Err(ExitStatusError { status: job::status_code(status), message: String::new() }.into())
+                }
+            };
+        }
+        self.io_redirection.write_err(&format!("{}\n", lookup::not_found_message(&self.name)))?;
+        Err(ExitStatusError { status: NOT_FOUND_STATUS, message: String::new() }.into())
+    }
+}
+
 impl Command for SystemCommand {
     fn get_name(&self) -> &str {
         &self.name
     }
 
-    fn get_args(&self) -> &[String] {
-        &self.args
+    fn get_io_redirection(&mut self) -> &mut IoRedirection {
+        &mut self.io_redirection
     }
 
-    fn get_flags(&self) -> &[Flag] {
-        &self.flags
+    fn get_io(&self) -> &IoRedirection {
+        &self.io_redirection
     }
 
-    fn get_args_mut(&mut self) -> &mut Vec<String> {
-        &mut self.args
+    fn get_argv(&self) -> &[String] {
+        &self.argv
     }
 
-    fn get_flags_mut(&mut self) -> &mut Vec<Flag> {
-        &mut self.flags
+    fn set_argv(&mut self, argv: Vec<String>) {
+        self.argv = argv;
     }
 
-    fn get_io_redirection(&mut self) -> &mut IoRedirection {
-        &mut self.io_redirection
+    /// Programs get every word, `--help` and `-h` included.
+    fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute_impl()
     }
 
-    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut command = std::process::Command::new(&self.name);
-        
-        command.args(&self.args);
-        
-        for flag in &self.flags {
-            if let Some(value) = &flag.value {
-                command.arg(flag.ident.to_string() + "=" + value);
+    fn process(&self) -> Option<std::process::Command> {
+        let io = &self.io_redirection;
+        let mut command = lookup::process(&self.name);
+        command.args(&self.argv);
+        match &*io.from.borrow() {
+            Some(Input::File(file)) => {
+                command.stdin(file.try_clone().ok()?);
+            }
+            Some(Input::Reader(_)) => return None,
+            None => {}
+        }
+        for (output, error) in [(&io.to, false), (&io.error, true)] {
+            let file = match &*output.borrow() {
+                Some(Output::File(file)) => file.try_clone().ok()?,
+                Some(Output::Writer(_)) => return None,
+                None => continue,
+            };
+            if error {
+                command.stderr(file);
             } else {
-                command.arg(flag.ident.to_string());
+                command.stdout(file);
             }
         }
+        Some(command)
+    }
+
+    fn execute_impl(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.name.contains('/') && lookup::find(&self.name).is_none() {
+            return self.not_found(&self.argv);
+        }
 
-        let output = command.output()?;
+        let mut command = lookup::process(&self.name);
+        command.args(&self.argv);
+        // The program gets files and the shell's own streams as they are;
+        // the shell only feeds it and collects its output for the others.
+        let input = match &mut *self.io_redirection.from.borrow_mut() {
+            Some(Input::File(file)) => {
+                command.stdin(file.try_clone()?);
+                None
+            }
+            Some(Input::Reader(reader)) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                command.stdin(std::process::Stdio::piped());
+                Some(data)
+            }
+            None => None,
+        };
+        job::foreground(&mut command);
+        let spawned = command
+            .stdout(program_output(&self.io_redirection.to)?)
+            .stderr(program_output(&self.io_redirection.error)?)
+            .spawn()
+            .and_then(|mut child| {
+                let owner = job::give_terminal(child.id());
+                // Written from another thread so that a command producing
+                // output while it reads cannot block on a full pipe.
+                let writer = child.stdin.take().zip(input).map(|(mut stdin, data)| {
+                    std::thread::spawn(move || {
+                        let _ = stdin.write_all(&data);
+                    })
+                });
+                let output = child.wait_with_output();
+                if owner {
+                    job::take_terminal();
+                }
+                if let Some(writer) = writer {
+                    let _ = writer.join();
+                }
+                output
+            });
+        let output = match spawned {
+            Ok(output) => output,
+            Err(e) => match launch_error(&self.name, &e) {
+                Some((status, message)) => {
+                    self.io_redirection.write_err(&format!("{}\n", message))?;
+                    return Err(ExitStatusError { status, message: String::new() }.into());
+                }
+                None => return Err(e.into()),
+            },
+        };
         
         if !output.stdout.is_empty() {
-            std::io::stdout().write_all(&output.stdout)?;
+            self.io_redirection.write_out_bytes(&output.stdout)?;
         }
         
         if !output.stderr.is_empty() {
-            std::io::stderr().write_all(&output.stderr)?;
+            self.io_redirection.write_err_bytes(&output.stderr)?;
         }
 
         if !output.status.success() {
-            return Err(format!("Command '{}' failed with exit code: {}", 
-                self.name, 
-                output.status.code().unwrap_or(-1))
-                .into());
+            job::killed_by(output.status);
+            // The status says why the program failed; only a signal other
+            // than Ctrl+C is worth a word.
+            let message = match output.status.signal() {
+                Some(signal) if signal != libc::SIGINT => format!("Command '{}' was killed by signal {}", self.name, signal),
+                _ => String::new(),
+            };
+            return Err(Box::new(ExitStatusError { status: job::status_code(output.status), message }));
         }
 
         Ok(())
@@ -641,8 +4474,10 @@ impl Command for SystemCommand {
     }
 }
 
+#[derive(Default)]
 pub struct Pipeline {
-    commands: Vec<Box<dyn Command>>,
+    /// The stages, in cells so that a running pipeline can connect them.
+    commands: Vec<RefCell<Box<dyn Command>>>,
 }
 
 impl Pipeline {
@@ -651,7 +4486,113 @@ impl Pipeline {
     }
 
     pub fn add_command(&mut self, command: Box<dyn Command>) {
-        self.commands.push(command);
+        self.commands.push(RefCell::new(command));
+    }
+
+    /// Starts the programs of the pipeline side by side over OS pipes, then
+    /// runs the stages that run inside the shell in turn: builtins,
+    /// compound commands and programs the shell has to feed or collect
+    /// the output of, e.g. at the ends of a pipeline in a loop body. The
+    /// programs are left in `children` for the caller to wait for. Returns
+    /// the status of the last stage unless that is a program.
+    fn start_and_run(&self, children: &mut Vec<(usize, std::process::Child)>) -> Result<(), Box<dyn std::error::Error>> {
+        let last = self.commands.len() - 1;
+        let mut programs: Vec<Option<std::process::Command>> =
+            self.commands.iter().map(|command| command.borrow().process()).collect();
+        let in_shell: Vec<bool> = programs.iter().map(Option::is_none).collect();
+
+        let mut result = Ok(());
+        let mut shell_stages = Vec::new();
+        let mut input = None;
+        for (i, program) in programs.iter_mut().enumerate() {
+            let (output, next_input) = if i == last {
+                (None, None)
+            } else if in_shell[i] && in_shell[i + 1] {
+                let buffer = SharedBuffer::default();
+                (Some(StageOutput::Buffer(buffer.clone())), Some(StageInput::Buffer(buffer)))
+            } else {
+                let (reader, writer) = std::io::pipe()?;
+                let reader = std::fs::File::from(std::os::fd::OwnedFd::from(reader));
+                (Some(StageOutput::Pipe(writer)), Some(StageInput::Pipe(reader)))
+            };
+            let mut input = std::mem::replace(&mut input, next_input);
+            let Some(mut process) = program.take() else {
+                // Only the first of these stages reads a pipe directly: the
+                // programs feeding a later one must not wait for the shell
+                // while it runs the stages before it.
+                if !shell_stages.is_empty()
+                    && let Some(StageInput::Pipe(reader)) = input
+                {
+                    input = Some(StageInput::Pipe(relay(reader)?));
+                }
+                shell_stages.push((i, input, output));
+                continue;
+            };
+            if let Some(StageInput::Pipe(reader)) = input {
+                process.stdin(reader);
+            }
+            if let Some(StageOutput::Pipe(writer)) = output {
+                process.stdout(writer);
+            }
+            job::foreground(&mut process);
+            // Dropping the process closes the shell's ends of its pipes.
+            match process.spawn() {
+                Ok(child) => {
+                    job::give_terminal(child.id());
+                    children.push((i, child));
+                }
+                Err(e) => {
+                    let error = spawn_failed(self.commands[i].borrow().get_name(), e);
+                    if i == last {
+                        result = Err(error);
+                    }
+                }
+            }
+        }
+
+        for (i, input, output) in shell_stages {
+            let stage = self.run_stage(i, input, output);
+            match stage {
+                Err(e) if i != last => report_error(e.as_ref()),
+                stage if i == last => result = stage,
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Runs stage `i` inside the shell between `input` and `output`.
+    fn run_stage(&self, i: usize, input: Option<StageInput>, output: Option<StageOutput>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut command = self.commands[i].borrow_mut();
+        let detach_input = input.is_some();
+        match input {
+            Some(StageInput::Pipe(file)) => command.set_input_file(file),
+            Some(StageInput::Buffer(buffer)) => command.set_input(Box::new(std::io::Cursor::new(buffer.take()))),
+            None => {}
+        }
+        let detach_output = output.is_some();
+        match output {
+            Some(StageOutput::Pipe(writer)) => command.set_output_file(std::os::fd::OwnedFd::from(writer).into()),
+            Some(StageOutput::Buffer(buffer)) => command.set_output(Box::new(buffer)),
+            None => {}
+        }
+        // Like a subshell, `exit` in a pipeline only ends its stage.
+        let result = match unwind_with(command.execute(), 0) {
+            Err(e) if e.is::<Unwind>() => {
+                let status = exit_status(&Err(e));
+                Err(ExitStatusError { status, message: String::new() }.into())
+            }
+            other => other,
+        };
+        // Closes the stage's ends of its pipes, so that the programs around
+        // it see the end of their input or a broken pipe.
+        if detach_output {
+            command.set_output(Box::new(std::io::sink()));
+        }
+        if detach_input {
+            command.set_input_file(std::fs::File::open("/dev/null")?);
+        }
+        result
     }
 
     pub fn execute(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -661,7 +4602,7 @@ impl Pipeline {
 
         // Single command case - no pipes needed
         if self.commands.len() == 1 {
-            return self.commands[0].execute();
+            return self.commands[0].get_mut().execute();
         }
 
         // Multiple commands case - need to set up pipes
@@ -676,14 +4617,14 @@ impl Pipeline {
             let is_last = i == command_count - 1;
             
             // Take ownership of the command temporarily
-            let mut cmd = std::mem::replace(&mut self.commands[i], Box::new(SystemCommand::new("dummy".to_string())));
+            let cmd = std::mem::replace(self.commands[i].get_mut(), Box::new(SystemCommand::new("dummy".to_string())));
             
             match cmd.get_name() {
                 // Handle built-in commands
-                "cd" | "pwd" | "history" => {
+                name if BUILTINS.contains(&name) => {
                     // Create pipes for I/O
                     let mut process = ProcessCommand::new("sh");
-                    process.arg("-c").arg(format!("{} {}", cmd.get_name(), cmd.get_args().join(" ")));
+                    process.arg("-c").arg(format!("{} {}", cmd.get_name(), cmd.get_argv().join(" ")));
                     
                     // Set up stdin from previous command's output
                     if let Some(data) = previous_output.take() {
@@ -692,7 +4633,7 @@ impl Pipeline {
                             stdin.write_all(&data)?;
                         }
                         process = ProcessCommand::new("sh");
-                        process.arg("-c").arg(format!("{} {}", cmd.get_name(), cmd.get_args().join(" ")));
+                        process.arg("-c").arg(format!("{} {}", cmd.get_name(), cmd.get_argv().join(" ")));
                     }
                     
                     // Set up stdout pipe if not the last command
@@ -721,17 +4662,17 @@ impl Pipeline {
                 
                 // Handle system commands
                 _ => {
-                    let mut process = ProcessCommand::new(cmd.get_name());
-                    process.args(cmd.get_args());
+                    let mut process = lookup::process(cmd.get_name());
+                    process.args(cmd.get_argv());
                     
                     // Set up stdin from previous command's output
                     if let Some(data) = previous_output.take() {
-                        let mut child = process.stdin(Stdio::piped()).spawn()?;
+                        let mut child = process.stdin(Stdio::piped()).spawn().map_err(|e| spawn_failed(cmd.get_name(), e))?;
                         if let Some(mut stdin) = child.stdin.take() {
                             stdin.write_all(&data)?;
                         }
-                        process = ProcessCommand::new(cmd.get_name());
-                        process.args(cmd.get_args());
+                        process = lookup::process(cmd.get_name());
+                        process.args(cmd.get_argv());
                     }
                     
                     // Set up stdout pipe if not the last command
@@ -740,7 +4681,7 @@ impl Pipeline {
                     }
                     
                     // Spawn the process
-                    let mut child = process.spawn()?;
+                    let mut child = process.spawn().map_err(|e| spawn_failed(cmd.get_name(), e))?;
                     
                     // Save stdout for the next command if not the last
                     if !is_last {
@@ -760,7 +4701,7 @@ impl Pipeline {
             }
             
             // Restore the command
-            self.commands[i] = cmd;
+            *self.commands[i].get_mut() = cmd;
         }
 
         Ok(())
@@ -784,9 +4725,9 @@ mod tests {
         cmd.set_output(Box::new(std::io::Cursor::new(Vec::new())));
         cmd.set_error(Box::new(std::io::Cursor::new(Vec::new())));
         cmd.set_input(Box::new(std::io::Cursor::new(Vec::new())));
-        assert!(cmd.get_io_redirection().to.is_some());
-        assert!(cmd.get_io_redirection().error.is_some());
-        assert!(cmd.get_io_redirection().from.is_some());
+        assert!(cmd.get_io_redirection().to.get_mut().is_some());
+        assert!(cmd.get_io_redirection().error.get_mut().is_some());
+        assert!(cmd.get_io_redirection().from.get_mut().is_some());
     }
 
     #[test]
@@ -796,8 +4737,7 @@ mod tests {
         let cmd = parser.parse().unwrap();
 
         assert_eq!(cmd.get_name(), "cd");
-        assert_eq!(cmd.get_args(), &["/tmp"]);
-        assert!(cmd.get_flags().is_empty());
+        assert_eq!(cmd.get_argv(), &["/tmp"]);
     }
 
     #[test]
@@ -807,13 +4747,7 @@ mod tests {
         let cmd = parser.parse().unwrap();
 
         assert_eq!(cmd.get_name(), "cd");
-        assert_eq!(cmd.get_args(), &["/tmp"]);
-        
-        let flags = cmd.get_flags();
-        assert_eq!(flags.len(), 1);
-        assert_eq!(flags[0].ident.long, Some("--follow-symlinks".to_string()));
-        assert_eq!(flags[0].ident.short, None);
-        assert_eq!(flags[0].value, None);
+        assert_eq!(cmd.get_argv(), &["--follow-symlinks", "/tmp"]);
     }
 
     #[test]
@@ -823,12 +4757,7 @@ mod tests {
         let cmd = parser.parse().unwrap();
 
         assert_eq!(cmd.get_name(), "cd");
-        assert_eq!(cmd.get_args(), &["/tmp"]);
-        
-        let flags = cmd.get_flags();
-        assert_eq!(flags.len(), 1);
-        assert_eq!(flags[0].ident.long, Some("--format".to_string()));
-        assert_eq!(flags[0].value, Some("list".to_string()));
+        assert_eq!(cmd.get_argv(), &["--format=list", "/tmp"]);
     }
 
     #[test]
@@ -838,13 +4767,7 @@ mod tests {
         let cmd = parser.parse().unwrap();
 
         assert_eq!(cmd.get_name(), "cd");
-        assert_eq!(cmd.get_args(), &["/tmp"]);
-        
-        let flags = cmd.get_flags();
-        assert_eq!(flags.len(), 1);
-        assert_eq!(flags[0].ident.short, Some("-l".to_string()));
-        assert_eq!(flags[0].ident.long, None);
-        assert_eq!(flags[0].value, None);
+        assert_eq!(cmd.get_argv(), &["-l", "/tmp"]);
     }
 
     #[test]
@@ -855,14 +4778,29 @@ mod tests {
         
         // Verify it's treated as a system command
         assert_eq!(cmd.get_name(), "unknown_cmd");
-        assert_eq!(cmd.get_args(), &["arg1", "arg2"]);
-        assert!(cmd.get_flags().is_empty());
+        assert_eq!(cmd.get_argv(), &["arg1", "arg2"]);
+    }
+
+    #[test]
+    fn test_programs_get_words_in_order() {
+        let cmd = parse_line("tail -n 1 file --lines=2").unwrap();
+        assert_eq!(cmd.get_argv(), &["-n", "1", "file", "--lines=2"]);
+
+        let output = SharedBuffer::default();
+        let mut cmd = parse_line("sh -c 'echo hi'").unwrap();
+        cmd.set_output(Box::new(output.clone()));
+        cmd.execute().unwrap();
+        assert_eq!(output.text(), "hi\n");
     }
 
+    /// Serializes the tests that change the process's current directory.
+    static CWD_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
     #[test]
     fn test_cd_command_execution() {
         use std::env;
         use std::path::PathBuf;
+        let _guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         
         // Save current directory
         let original_dir = env::current_dir().unwrap();
@@ -896,41 +4834,418 @@ mod tests {
 
     #[test]
     fn test_cd_command_no_path() {
-        let tokens = create_tokens("cd");
-        let mut parser = CommandParser::new(tokens);
-        let cmd = parser.parse().unwrap();
-        
-        match cmd.execute() {
-            Ok(_) => panic!("Expected error for missing path"),
-            Err(e) => assert_eq!(e.to_string(), "No path provided"),
+        let _guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let original_dir = std::env::current_dir().unwrap();
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+
+        let cmd = parse_line("cd").unwrap();
+        cmd.execute().unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), home.canonicalize().unwrap());
+        assert_eq!(cwd::logical(), home);
+
+        // `cd -` goes back and prints where it went.
+        let mut cmd = parse_line("cd -").unwrap();
+        let output = SharedBuffer::default();
+        cmd.set_output(Box::new(output.clone()));
+        cmd.execute().unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), original_dir);
+        assert_eq!(output.text(), format!("{}\n", cwd::logical().display()));
+
+        match parse_line("cd a b").unwrap().execute() {
+            Ok(_) => panic!("Expected error for too many arguments"),
+            Err(e) => assert_eq!(e.to_string(), "cd: too many arguments"),
+        }
+        std::env::set_current_dir(original_dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory_stack() {
+        let _guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let original_dir = std::env::current_dir().unwrap();
+        let base = std::env::temp_dir().join(format!("msh-dirs-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("a")).unwrap();
+        std::fs::create_dir_all(base.join("b")).unwrap();
+        let base = base.canonicalize().unwrap();
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            cmd.execute().map(|()| output.text()).map_err(|e| e.to_string())
+        };
+
+        std::env::set_current_dir(&base).unwrap();
+        // SAFETY: the tests that touch PWD hold CWD_LOCK.
+        unsafe { std::env::set_var("PWD", &base) };
+        cwd::stack().lock().unwrap().clear();
+        run("pushd a").unwrap();
+        run("pushd ../b").unwrap();
+        let listing = run("dirs -v -l").unwrap();
+        let b = base.join("b").display().to_string();
+        let a = base.join("a").display().to_string();
+        assert_eq!(listing, format!(" 0  {}\n 1  {}\n 2  {}\n", b, a, base.display()));
+        assert_eq!(run("dirs -l +2").unwrap(), format!("{}\n", base.display()));
+
+        run("pushd +2").unwrap();
+        assert_eq!(cwd::logical(), base);
+        assert_eq!(run("dirs -l").unwrap(), format!("{} {} {}\n", base.display(), b, a));
+        assert_eq!(parse_line("ls ~2").unwrap().get_argv(), std::slice::from_ref(&a));
+
+        run("popd").unwrap();
+        assert_eq!(cwd::logical(), base.join("b"));
+        run("popd +1").unwrap();
+        assert_eq!(run("dirs -l").unwrap(), format!("{}\n", b));
+        assert_eq!(run("popd"), Err("popd: directory stack empty".to_string()));
+        assert_eq!(run("pushd"), Err("pushd: no other directory".to_string()));
+        assert_eq!(run("pushd +5"), Err("pushd: +5: directory stack index out of range".to_string()));
+
+        std::env::set_current_dir(original_dir).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_type_and_command_describe_names() {
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            cmd.set_error(Box::new(SharedBuffer::default()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        let sh = lookup::find("sh").unwrap();
+
+        assert_eq!(run("type cd if"), (0, "cd is a shell builtin\nif is a shell keyword\n".to_string()));
+        assert_eq!(run("type -t cd sh"), (0, "builtin\nfile\n".to_string()));
+        assert_eq!(run("type -p sh"), (0, format!("{}\n", sh.display())));
+        assert_eq!(run("type no-such-command-here").0, 1);
+        assert_eq!(run("command -v cd sh"), (0, format!("cd\n{}\n", sh.display())));
+        assert_eq!(run("which sh no-such-command-here"), (1, format!("{}\n", sh.display())));
+        assert_eq!(run("command type -t if"), (0, "keyword\n".to_string()));
+    }
+
+    #[test]
+    fn test_alias_and_unalias() {
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            cmd.set_error(Box::new(SharedBuffer::default()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        let rc = std::env::temp_dir().join(format!("msh-rc-{}", std::process::id()));
+        std::fs::write(&rc, "# aliases\nalias msh_where='type -t'\n\nalias msh_quote=\"echo 'hi'\"\nif\n").unwrap();
+        assert_eq!(source(&rc), vec![format!("{}:5: unexpected end of input", rc.display())]);
+        std::fs::remove_file(&rc).unwrap();
+
+        assert_eq!(run("msh_where cd"), (0, "builtin\n".to_string()));
+        assert_eq!(run("type -t msh_where"), (0, "alias\n".to_string()));
+        assert_eq!(run("type msh_where"), (0, "msh_where is aliased to `type -t'\n".to_string()));
+        assert_eq!(run("alias msh_quote"), (0, "alias msh_quote='echo '\\''hi'\\'''\n".to_string()));
+        assert!(run("alias").1.contains("alias msh_where='type -t'\n"));
+        assert_eq!(run("alias 'a/b=x'").0, 1);
+        assert_eq!(run("unalias msh_where msh_quote"), (0, String::new()));
+        assert_eq!(run("unalias msh_where").0, 1);
+        assert_eq!(run("alias msh_where").0, 1);
+    }
+
+    #[test]
+    fn test_echo_printf_and_test() {
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            cmd.set_error(Box::new(SharedBuffer::default()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+
+        assert_eq!(run("echo -n a  'b  c'"), (0, "a b  c".to_string()));
+        assert_eq!(run(r"echo -e 'x\ty\c' z"), (0, "x\ty".to_string()));
+        assert_eq!(run("echo -nx --help"), (0, "-nx --help\n".to_string()));
+        assert_eq!(run(r"printf '%s:%03d\n' a 7 b"), (0, "a:007\nb:000\n".to_string()));
+        assert_eq!(run("printf %d x").0, 1);
+        assert_eq!(run("test -d / -a 2 -gt 1").0, 0);
+        assert_eq!(run("[ a = b ]").0, 1);
+        assert_eq!(run("[ a = a").0, 2);
+        assert_eq!(run("test 1 -eq x").0, 2);
+        assert_eq!(run("[[ abc == a?c && ! -f / ]]").0, 0);
+        assert_eq!(run("[[ abc == 'a?c' || (x < a) ]]").0, 1);
+        assert_eq!(run("[[ x =~ '(' ]]").0, 1);
+        assert!(matches!(parse_line("[[ a b ]]"), Err(ParseError::Syntax(_))));
+        assert!(matches!(parse_line("[[ -n a"), Err(ParseError::Incomplete)));
+    }
+
+    #[test]
+    fn test_builtins_in_pipelines() {
+        let mut sort = parse_line("sort -r").unwrap();
+        let output = SharedBuffer::default();
+        sort.set_output(Box::new(output.clone()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add_command(parse_line(r"printf '%s\n' a c b").unwrap());
+        pipeline.add_command(sort);
+        assert_eq!(exit_status(&Command::execute(&pipeline)), 0);
+        assert_eq!(output.text(), "c\nb\na\n");
+
+        // Programs stream into loops and stop them when they go away, and
+        // a stage after another one in the shell cannot hold up the
+        // programs between them.
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        assert_eq!(run("yes | head -3 | while read -r l; do echo got; done"), (0, "got\ngot\ngot\n".to_string()));
+        assert_eq!(run("while true; do echo y; done | head -2 | cat"), (0, "y\ny\n".to_string()));
+        assert_eq!(run(r"printf '%01000000d\n' 0 | cat | wc -c").1.trim(), "1000001");
+
+        // Listings and help go down the pipe too.
+        for (line, lines) in [("set -o | wc -l", SHELL_OPTIONS.len()), ("bind -l | wc -l", Action::names().count()), ("pwd --help | head -1", 1)] {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            assert_eq!(exit_status(&cmd.execute()), 0, "{}", line);
+            let text = output.text();
+            if line.ends_with("wc -l") {
+                assert_eq!(text.trim(), lines.to_string(), "{}", line);
+            } else {
+                assert_eq!(text, "PWD:\n", "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_in_while_loop() {
+        let run = |line: &str, input: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_input(Box::new(std::io::Cursor::new(input.to_string())));
+            cmd.set_output(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        // Programs in the body leave the lines they do not read.
+        assert_eq!(
+            run("while read -r MSH_TEST_KEY MSH_TEST_VALUE; do cat; done", "a 1\\\nb 2 3\n"),
+            (0, "b 2 3\n".to_string())
+        );
+        assert_eq!(run("read MSH_TEST_KEY; cat", "first\nrest\n"), (0, "rest\n".to_string()));
+        assert_eq!(vars::get("MSH_TEST_KEY").as_deref(), Some("first"));
+        // The variables are the shell's, not the environment of its programs.
+        assert_eq!(run("read -a MSH_TEST_KEY; printenv MSH_TEST_KEY MSH_TEST_KEY_1", "a b\n"), (1, String::new()));
+        assert_eq!(run("read MSH_TEST_KEY", "partial"), (1, String::new()));
+        assert_eq!(vars::get("MSH_TEST_KEY").as_deref(), Some("partial"));
+        assert_eq!(run("until read MSH_TEST_KEY; do echo never; done", "x\n"), (0, String::new()));
+        assert_eq!(exit_status(&parse_line("read -x").unwrap().execute()), 1);
+    }
+
+    #[test]
+    fn test_words_expand_variables() {
+        let run = |line: &str, input: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_input(Box::new(std::io::Cursor::new(input.to_string())));
+            cmd.set_output(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        assert_eq!(run("read MSH_TEST_X; echo $MSH_TEST_X", "hi there\n"), (0, "hi there\n".to_string()));
+        assert_eq!(
+            run("printf 'a  b\\nc\\n' | while read -r MSH_TEST_LINE2; do echo \"<$MSH_TEST_LINE2>\"; done", ""),
+            (0, "<a  b>\n<c>\n".to_string())
+        );
+        assert_eq!(
+            run("while read MSH_TEST_CMD MSH_TEST_ARG; do $MSH_TEST_CMD $MSH_TEST_ARG | tr a-z A-Z; done", "echo x\nprintf y\n"),
+            (0, "X\nY".to_string())
+        );
+        assert_eq!(run("echo '$MSH_TEST_X' \\$MSH_TEST_X", ""), (0, "$MSH_TEST_X $MSH_TEST_X\n".to_string()));
+        assert_eq!(run("$MSH_TEST_UNSET_NAME", ""), (0, String::new()));
+    }
+
+    #[test]
+    fn test_exit_and_return_unwind() {
+        let unwind = |line: &str| {
+            parse_line(line).unwrap().execute().err().and_then(|e| e.downcast::<Unwind>().ok()).map(|unwind| *unwind)
+        };
+        assert_eq!(unwind("true; exit 3; false"), Some(Unwind::Exit(Some(3))));
+        assert_eq!(unwind("false; exit"), Some(Unwind::Exit(Some(1))));
+        assert_eq!(unwind("while true; do exit 300; done"), Some(Unwind::Exit(Some(44))));
+        assert_eq!(unwind("if true; then exit abc; fi"), Some(Unwind::Exit(Some(2))));
+        assert_eq!(unwind("exit 1 2"), None);
+        assert_eq!(unwind("echo x | exit 4"), None);
+        assert_eq!(exit_status(&parse_line("echo x | exit 4").unwrap().execute()), 4);
+        assert_eq!(exit_status(&parse_line("return").unwrap().execute()), 1);
+
+        let path = std::env::temp_dir().join(format!("msh-return-{}", std::process::id()));
+        std::fs::write(&path, "alias msh_before_return=ls\nfalse || return\nalias msh_after_return=ls\n").unwrap();
+        assert!(source(&path).is_empty());
+        assert!(alias::get("msh_before_return").is_some());
+        assert!(alias::get("msh_after_return").is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exec_redirects_shell_descriptors() {
+        let tokens = create_tokens("exec 250>log 2>&1 <&- >>out");
+        let (redirections, words) = Redirection::parse(&tokens[1..tokens.len() - 1]).unwrap();
+        assert!(words.is_empty());
+        let redirection = |fd, kind, target: &str| Redirection { fd, kind, target: target.to_string() };
+        assert_eq!(redirections, vec![
+            redirection(250, RedirectionKind::Output, "log"),
+            redirection(2, RedirectionKind::Duplicate, "1"),
+            redirection(0, RedirectionKind::Duplicate, "-"),
+            redirection(1, RedirectionKind::Append, "out"),
+        ]);
+        assert!(parse_line("exec 3>").is_err());
+
+        let path = std::env::temp_dir().join(format!("msh-exec-{}", std::process::id()));
+        assert!(parse_line(&format!("exec 250>{}", path.display())).unwrap().execute().is_ok());
+        assert_eq!(std::fs::read_link("/proc/self/fd/250").unwrap(), path);
+        assert!(parse_line("exec 250>&-").unwrap().execute().is_ok());
+        assert!(std::fs::read_link("/proc/self/fd/250").is_err());
+        assert_eq!(exit_status(&parse_line("exec 250</no/such/file").unwrap().execute()), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redirections_apply_to_every_command() {
+        let dir = std::env::temp_dir().join(format!("msh-redirect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("out");
+        let run = |line: &str| {
+            let mut cmd = parse_line(&line.replace("FILE", &file.display().to_string())).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        assert_eq!(run("echo hi > FILE"), (0, String::new()));
+        assert_eq!(run("printf '%s\\n' more >> FILE"), (0, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "hi\nmore\n");
+        assert_eq!(run("cat < FILE | wc -l"), (0, "2\n".to_string()));
+        assert_eq!(run("read -r MSH_TEST_LINE < FILE"), (0, String::new()));
+        assert_eq!(vars::get("MSH_TEST_LINE").as_deref(), Some("hi"));
+        // Errors join the output of the stage, in a pipeline too.
+        assert_eq!(run("printf %d x 2>&1 | cat"), (0, "0printf: x: invalid number\n".to_string()));
+        assert_eq!(run("cat FILE 2>&1 >/dev/null"), (0, String::new()));
+        // Other descriptors are the shell's for the run only.
+        assert_eq!(run("echo three 251>FILE 1>&251"), (0, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "three\n");
+        assert!(std::fs::read_link("/proc/self/fd/251").is_err());
+        assert_eq!(run("echo no > /no/such/dir/file"), (1, String::new()));
+        // Programs write to the file itself, not through the shell.
+        assert_eq!(run("sh -c 'test -f /proc/self/fd/1' > FILE"), (0, String::new()));
+        assert_eq!(run("sh -c 'test -f /proc/self/fd/2' 2>&1 > /dev/null 2> FILE"), (0, String::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compound_commands_take_redirections() {
+        let dir = std::env::temp_dir().join(format!("msh-compound-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("lines");
+        std::fs::write(&file, "one\ntwo\n").unwrap();
+        let run = |line: &str| {
+            let mut cmd = parse_line(&line.replace("FILE", &file.display().to_string())).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        assert_eq!(
+            run("while read -r MSH_TEST_ITEM; do echo \"<$MSH_TEST_ITEM>\"; done < FILE"),
+            (0, "<one>\n<two>\n".to_string())
+        );
+        assert_eq!(run("{ echo a; printf %d x; } > FILE 2>&1"), (1, String::new()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "a\n0printf: x: invalid number\n");
+        assert_eq!(run("if true; then echo b; fi >> FILE; wc -l < FILE"), (0, "3\n".to_string()));
+        assert!(matches!(parse_line("{ echo a; } b"), Err(ParseError::Syntax(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failing_program_has_no_message() {
+        let result = parse_line("sh -c 'exit 3'").unwrap().execute();
+        assert_eq!(exit_status(&result), 3);
+        assert_eq!(result.unwrap_err().to_string(), "");
+    }
+
+    #[test]
+    fn test_list_reports_only_unhandled_failures() {
+        // Errors go to the real stdout, so the lines run in a copy of this
+        // test whose output is read back.
+        if std::env::var_os("MSH_TEST_REPORT").is_some() {
+            for line in ["false || true", "cd /msh/no/such || true", "cd /msh/no/such && true || true", "cd /msh/no/such; true"] {
+                parse_line(line).unwrap().execute().unwrap();
+            }
+            return;
         }
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "command::tests::test_list_reports_only_unhandled_failures", "--nocapture", "--quiet"])
+            .env("MSH_TEST_REPORT", "1")
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert_eq!(stdout.matches("Error: ").count(), 1, "{}", stdout);
+    }
+
+    #[test]
+    fn test_trap_sets_and_prints_traps() {
+        let run = |line: &str| {
+            let mut cmd = parse_line(line).unwrap();
+            let output = SharedBuffer::default();
+            cmd.set_output(Box::new(output.clone()));
+            cmd.set_error(Box::new(output.clone()));
+            (exit_status(&cmd.execute()), output.text())
+        };
+        let usr1 = Condition::Signal(libc::SIGUSR1);
+        assert_eq!(run("trap 'echo hi' SIGUSR1 ERR"), (0, String::new()));
+        assert_eq!(trap::get(usr1).as_deref(), Some("echo hi"));
+        assert_eq!(run("trap -p USR1"), (0, "trap -- 'echo hi' SIGUSR1\n".to_string()));
+        assert_eq!(run("trap - USR1 ERR"), (0, String::new()));
+        assert_eq!(trap::get(usr1), None);
+        assert_eq!(trap::get(Condition::Err), None);
+        assert_eq!(run("trap 'echo hi' NOPE"), (1, "trap: NOPE: invalid signal specification\n".to_string()));
+        assert!(run("trap -l").1.contains(" 2) SIGINT\n"));
     }
 
     #[test]
-    fn test_flag_ident_creation() {
-        let flag = FlagIdent::new(Some("-l".to_string()), None);
-        assert_eq!(flag.short, Some("-l".to_string()));
-        assert_eq!(flag.long, None);
+    fn test_missing_command_status() {
+        let mut cmd = parse_line("no-such-command-here --flag").unwrap();
+        let errors = SharedBuffer::default();
+        cmd.set_error(Box::new(errors.clone()));
+        assert_eq!(exit_status(&cmd.execute()), NOT_FOUND_STATUS);
+        assert!(errors.text().starts_with("msh: command not found: no-such-command-here\n"));
+
+        let file = std::env::temp_dir().join(format!("msh-not-executable-{}", std::process::id()));
+        std::fs::write(&file, "echo hi\n").unwrap();
+        let mut cmd = parse_line(&file.display().to_string()).unwrap();
+        let errors = SharedBuffer::default();
+        cmd.set_error(Box::new(errors.clone()));
+        assert_eq!(exit_status(&cmd.execute()), NOT_EXECUTABLE_STATUS);
+        assert_eq!(errors.text(), format!("msh: permission denied: {}\n", file.display()));
+        std::fs::remove_file(&file).unwrap();
+    }
 
-        let flag = FlagIdent::new(None, Some("--long".to_string()));
-        assert_eq!(flag.short, None);
-        assert_eq!(flag.long, Some("--long".to_string()));
+    #[test]
+    fn test_pwd_writes_to_output() {
+        let _guard = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut cmd = parse_line("pwd -P").unwrap();
+        let output = SharedBuffer::default();
+        cmd.set_output(Box::new(output.clone()));
+        cmd.execute().unwrap();
+        assert_eq!(output.text(), format!("{}\n", std::env::current_dir().unwrap().canonicalize().unwrap().display()));
+        assert!(parse_line("pwd extra").unwrap().execute().is_err());
     }
 
     #[test]
     fn test_command_creation() {
         let cmd = ChangeDirCommand::new();
         assert_eq!(cmd.get_name(), "cd");
-        assert!(cmd.get_args().is_empty());
-        assert!(cmd.get_flags().is_empty());
+        assert!(cmd.get_argv().is_empty());
     }
 
     #[test]
-    fn test_command_get_args_len() {
+    fn test_command_set_argv() {
         let mut cmd = ChangeDirCommand::new();
-        cmd.get_args_mut().push("arg1".to_string());
-        cmd.get_args_mut().push("arg2".to_string());
-        assert_eq!(cmd.get_args_len(), 2);
+        cmd.set_argv(vec!["arg1".to_string(), "arg2".to_string()]);
+        assert_eq!(cmd.get_argv(), &["arg1", "arg2"]);
     }
 
     #[test]
@@ -1008,5 +5323,106 @@ mod tests {
         let result = cmd.execute();
         assert!(result.is_ok());
     }
-}
 
+    #[test]
+    fn test_options_keep_word_order() {
+        let words: Vec<String> = ["-c", "git", "--flag=--format", "-a", "json yaml", "--", "-x"]
+            .iter().map(|s| s.to_string()).collect();
+        let mut options = Options::new(&words);
+
+        assert_eq!(options.next(), Some(Opt::Flag("-c")));
+        assert_eq!(options.value("-c"), Ok("git"));
+        assert_eq!(options.next(), Some(Opt::Flag("--flag")));
+        assert_eq!(options.value("--flag"), Ok("--format"));
+        assert_eq!(options.next(), Some(Opt::Flag("-a")));
+        assert_eq!(options.value("-a"), Ok("json yaml"));
+        assert_eq!(options.next(), Some(Opt::Operand("-x")));
+        assert_eq!(options.next(), None);
+        assert!(options.value("-a").is_err());
+    }
+
+    #[test]
+    fn test_complete_command_adds_spec() {
+        let tokens = create_tokens("complete -c parsertool -a 'alpha beta'");
+        let mut parser = CommandParser::new(tokens);
+        let cmd = parser.parse().unwrap();
+        assert!(cmd.execute().is_ok());
+
+        let specs = completion::specs();
+        let spec = specs.iter().find(|spec| spec.command == "parsertool").unwrap();
+        assert_eq!(spec.source, completion::SpecSource::Words(vec!["alpha".to_string(), "beta".to_string()]));
+    }
+
+    #[test]
+    fn test_set_command_switches_edit_mode() {
+        let run = |line: &str| CommandParser::new(create_tokens(line)).parse().unwrap().execute();
+        assert!(run("set -o vi").is_ok());
+        assert_eq!(keymap::edit_mode(), EditMode::Vi);
+        assert!(run("set +o vi").is_ok());
+        assert_eq!(keymap::edit_mode(), EditMode::Emacs);
+        assert!(run("set -o nonsense").is_err());
+    }
+
+    #[test]
+    fn test_bind_command_adds_and_removes_bindings() {
+        let run = |line: &str| CommandParser::new(create_tokens(line)).parse().unwrap().execute();
+        let keys = [crate::editor::Key::Ctrl('g')];
+        assert!(run(r#"bind -m vi-command -x '"\C-g": git status'"#).is_ok());
+        assert_eq!(keymap::keymaps().vi_command.get(&keys), Some(&Binding::Command("git status".to_string())));
+        assert!(run(r#"bind -m vi-command '"\C-g": undo'"#).is_ok());
+        assert_eq!(keymap::keymaps().vi_command.get(&keys), Some(&Binding::Action(Action::Undo)));
+        assert!(run(r#"bind -m vi-command -r '\C-g'"#).is_ok());
+        assert_eq!(keymap::keymaps().vi_command.get(&keys), None);
+        assert!(run(r#"bind '"\C-g": no-such-action'"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_reports_incomplete_input() {
+        for line in ["echo 'abc", "ls |", "true &&", "false ||\n", "echo a \\", "if true; then", "if true\nthen echo\nelse", "{ echo a;", "while true; do"] {
+            assert_eq!(parse_line(line).err(), Some(ParseError::Incomplete), "{:?}", line);
+        }
+        for line in ["| ls", "echo a; ;", "if then", "fi", "{ echo a; } b", "sleep 2 & echo x", "sleep 2 &"] {
+            assert!(matches!(parse_line(line), Err(ParseError::Syntax(_))), "{:?}", line);
+        }
+        assert!(!is_incomplete("echo a \\\nb"));
+    }
+
+    #[test]
+    fn test_parse_lists_and_compound_commands() {
+        assert_eq!(parse_line("ls -l").unwrap().get_name(), "ls");
+        assert_eq!(parse_line("ls | wc").unwrap().get_name(), "pipeline");
+        assert_eq!(parse_line("true && ls; pwd").unwrap().get_name(), "list");
+        assert_eq!(parse_line("{ ls; }").unwrap().get_name(), "ls");
+        assert_eq!(parse_line("{ ls; pwd; }").unwrap().get_name(), "list");
+        assert_eq!(parse_line("if true; then ls; elif false\nthen pwd; else ls; fi").unwrap().get_name(), "if");
+        assert_eq!(parse_line("while read x\ndo ls; done").unwrap().get_name(), "while");
+    }
+
+    #[test]
+    fn test_lists_and_if_use_exit_status() {
+        let status = |line: &str| exit_status(&parse_line(line).unwrap().execute());
+        assert_eq!(status("false || true"), 0);
+        assert_eq!(status("true && false"), 1);
+        assert_eq!(status("false && true || true"), 0);
+        assert_eq!(status("false; true"), 0);
+        assert_eq!(status("if false; then true; else false; fi"), 1);
+        assert_eq!(status("if false; then false; elif true; then true; fi"), 0);
+        assert_eq!(status("if false; then false; fi"), 0);
+    }
+
+    #[test]
+    fn test_history_command_deletes_and_clears() {
+        let run = |line: &str| parse_line(line).unwrap().execute();
+        let shared = history::shared();
+        shared.lock().unwrap().entries = ["ls", "pwd", "date", "uptime"].map(HistoryEntry::new).to_vec();
+
+        assert!(run("history -d 2").is_ok());
+        assert!(run("history -d -1").is_ok());
+        assert_eq!(shared.lock().unwrap().commands().collect::<Vec<_>>(), vec!["ls", "date"]);
+        assert!(run("history -d 5").is_err());
+        assert!(run("history --regex '('").is_err());
+        assert!(run("history 1 --contains da --json").is_ok());
+        assert!(run("history -c").is_ok());
+        assert!(shared.lock().unwrap().entries.is_empty());
+    }
+}
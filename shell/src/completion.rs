@@ -0,0 +1,818 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+use tokenizer::Tokenizer;
+
+use crate::alias;
+use crate::command::{builtin, Opt, Options, BUILTINS};
+use crate::lookup;
+
+static SPECS: Mutex<Vec<CompletionSpec>> = Mutex::new(Vec::new());
+static FUNCTIONS: LazyLock<Mutex<HashMap<String, Box<dyn Completer + Send>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A single completion candidate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    /// The completed word, without any quoting.
+    pub value: String,
+    /// How the candidate is shown in the completion menu.
+    pub display: String,
+    /// Whether a space is inserted after the word once it is accepted.
+    pub append_space: bool,
+}
+
+impl Candidate {
+    pub fn new<T: Into<String>>(value: T) -> Self {
+        let value = value.into();
+        Self { display: value.clone(), value, append_space: true }
+    }
+}
+
+/// The word under the cursor and the command it belongs to.
+#[derive(Debug, Default)]
+pub struct CompletionContext {
+    /// The unquoted words of the current command up to the cursor.
+    /// The last entry is the (possibly empty) word being completed.
+    pub words: Vec<String>,
+    /// Byte offset in the line at which the word being completed starts.
+    pub start: usize,
+    /// The quote character left open inside the current word, if any.
+    pub quote: Option<char>,
+    /// Whether the word is the target of a `<` or `>` redirection.
+    pub after_redirection: bool,
+}
+
+impl CompletionContext {
+    /// Splits `line` up to `cursor` the same way the tokenizer would,
+    /// honouring quotes, backslash escapes and command separators.
+    pub fn parse(line: &str, cursor: usize) -> Self {
+        let mut ctx = Self::default();
+        let mut word = String::new();
+        let mut in_word = false;
+        let mut redirect_next = false;
+        let mut chars = line[..cursor].char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            if let Some(q) = ctx.quote {
+                if c == q {
+                    ctx.quote = None;
+                } else if q == '"' && c == '\\' {
+                    if let Some((_, next)) = chars.next() {
+                        word.push(next);
+                    }
+                } else {
+                    word.push(c);
+                }
+                continue;
+            }
+            match c {
+                ' ' | '\t' | '\n' | '|' | '&' | ';' | '<' | '>' => {
+                    if in_word {
+                        ctx.words.push(std::mem::take(&mut word));
+                        in_word = false;
+                        redirect_next = false;
+                        // A command follows keywords such as `then`.
+                        if let [keyword] = ctx.words.as_slice()
+                            && matches!(keyword.as_str(), "if" | "then" | "elif" | "else" | "{")
+                        {
+                            ctx.words.clear();
+                        }
+                    }
+                    match c {
+                        '|' | '&' | ';' | '\n' => {
+                            ctx.words.clear();
+                            redirect_next = false;
+                        }
+                        '<' | '>' => redirect_next = true,
+                        _ => {}
+                    }
+                }
+                _ => {
+                    if !in_word {
+                        in_word = true;
+                        ctx.start = i;
+                    }
+                    match c {
+                        '\'' | '"' => ctx.quote = Some(c),
+                        '\\' => {
+                            if let Some((_, next)) = chars.next().filter(|&(_, next)| next != '\n') {
+                                word.push(next);
+                            }
+                        }
+                        _ => word.push(c),
+                    }
+                }
+            }
+        }
+
+        if !in_word {
+            ctx.start = cursor;
+        }
+        ctx.after_redirection = redirect_next;
+        ctx.words.push(word);
+        ctx
+    }
+
+    /// The unquoted prefix of the word being completed.
+    pub fn word(&self) -> &str {
+        self.words.last().map(String::as_str).unwrap_or("")
+    }
+
+    /// The position of the word being completed; 0 is the command name.
+    pub fn word_index(&self) -> usize {
+        self.words.len() - 1
+    }
+
+    /// The command whose arguments are being completed.
+    pub fn command(&self) -> Option<&str> {
+        if self.word_index() == 0 {
+            None
+        } else {
+            self.words.first().map(String::as_str)
+        }
+    }
+}
+
+/// Produces candidates for the arguments of a command.
+///
+/// Completers are registered per command name on the [`CompletionEngine`].
+pub trait Completer {
+    fn complete(&self, ctx: &CompletionContext) -> Vec<Candidate>;
+}
+
+impl<F> Completer for F
+where
+    F: Fn(&CompletionContext) -> Vec<Candidate>,
+{
+    fn complete(&self, ctx: &CompletionContext) -> Vec<Candidate> {
+        self(ctx)
+    }
+}
+
+/// Where the candidates of a [`CompletionSpec`] come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpecSource {
+    /// A fixed list of words (`complete -a`).
+    Words(Vec<String>),
+    /// Each line printed by a command run through `sh -c` (`complete -C`).
+    Command(String),
+    /// A completer registered with [`register_function`] (`complete -F`).
+    Function(String),
+}
+
+/// A completion rule declared with the `complete` builtin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompletionSpec {
+    pub command: String,
+    /// Only complete the word at this position; 1 is the first argument.
+    pub position: Option<usize>,
+    /// Only complete the value following this flag.
+    pub flag: Option<String>,
+    pub source: SpecSource,
+}
+
+/// What a `complete` invocation asks for.
+#[derive(Debug, PartialEq)]
+pub enum SpecAction {
+    Add(CompletionSpec),
+    Erase(String),
+}
+
+impl CompletionSpec {
+    /// Parses the arguments of the `complete` builtin.
+    pub fn parse(words: &[String]) -> Result<SpecAction, String> {
+        let mut command = None;
+        let mut position = None;
+        let mut flag = None;
+        let mut source = None;
+        let mut erase = false;
+
+        let mut options = Options::new(words);
+        while let Some(opt) = options.next() {
+            match opt {
+                Opt::Flag(name @ ("-c" | "--command")) => command = Some(options.value(name)?.to_string()),
+                Opt::Flag(name @ ("-a" | "--arguments")) => {
+                    let words = options.value(name)?.split_whitespace().map(String::from).collect();
+                    source = Some(SpecSource::Words(words));
+                }
+                Opt::Flag(name @ ("-C" | "--output-of")) => source = Some(SpecSource::Command(options.value(name)?.to_string())),
+                Opt::Flag(name @ ("-F" | "--function")) => source = Some(SpecSource::Function(options.value(name)?.to_string())),
+                Opt::Flag(name @ ("-p" | "--position")) => {
+                    let value = options.value(name)?;
+                    position = Some(value.parse().map_err(|_| format!("Invalid position: {}", value))?);
+                }
+                Opt::Flag(name @ ("-f" | "--flag")) => flag = Some(options.value(name)?.to_string()),
+                Opt::Flag("-e" | "--erase") => erase = true,
+                Opt::Flag(other) => return Err(format!("Unknown option: {}", other)),
+                Opt::Operand(operand) => return Err(format!("Unexpected argument: {}", operand)),
+            }
+        }
+
+        let command = command.ok_or("No command given, use -c <command>")?;
+        if erase {
+            return Ok(SpecAction::Erase(command));
+        }
+        let source = source.ok_or("No candidates given, use -a, -C or -F")?;
+        Ok(SpecAction::Add(Self { command, position, flag, source }))
+    }
+
+    /// Returns the text to keep in front of each candidate and the prefix
+    /// the candidates must match, or `None` if the spec does not apply.
+    fn matches<'a>(&self, ctx: &'a CompletionContext) -> Option<(String, &'a str)> {
+        let word = ctx.word();
+        if let Some(flag) = &self.flag {
+            let previous = ctx.words.len().checked_sub(2).map(|i| ctx.words[i].as_str());
+            if previous == Some(flag.as_str()) {
+                return Some((String::new(), word));
+            }
+            let inline = word.strip_prefix(flag.as_str())?.strip_prefix('=')?;
+            return Some((format!("{}=", flag), inline));
+        }
+        if word.starts_with('-') {
+            return None;
+        }
+        match self.position {
+            Some(position) if position != ctx.word_index() => None,
+            _ => Some((String::new(), word)),
+        }
+    }
+
+    fn candidates(&self, ctx: &CompletionContext) -> Vec<Candidate> {
+        let Some((keep, prefix)) = self.matches(ctx) else {
+            return Vec::new();
+        };
+        let values: Vec<String> = match &self.source {
+            SpecSource::Words(words) => words.clone(),
+            SpecSource::Command(command) => std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("COMP_WORDS", ctx.words.join(" "))
+                .env("COMP_CWORD", ctx.word_index().to_string())
+                .env("COMP_WORD", ctx.word())
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect())
+                .unwrap_or_default(),
+            SpecSource::Function(name) => FUNCTIONS
+                .lock()
+                .ok()
+                .and_then(|functions| functions.get(name).map(|f| f.complete(ctx)))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|candidate| candidate.value)
+                .collect(),
+        };
+        values
+            .into_iter()
+            .filter(|value| !value.is_empty() && value.starts_with(prefix))
+            .map(|value| Candidate {
+                value: format!("{}{}", keep, value),
+                display: value,
+                append_space: true,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for CompletionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quoted = |text: &str| format!("{}'", quote(text, Some('\'')));
+        write!(f, "complete -c {}", quote(&self.command, None))?;
+        if let Some(position) = self.position {
+            write!(f, " -p {}", position)?;
+        }
+        if let Some(flag) = &self.flag {
+            write!(f, " -f {}", flag)?;
+        }
+        match &self.source {
+            SpecSource::Words(words) => write!(f, " -a {}", quoted(&words.join(" "))),
+            SpecSource::Command(command) => write!(f, " -C {}", quoted(command)),
+            SpecSource::Function(name) => write!(f, " -F {}", quoted(name)),
+        }
+    }
+}
+
+/// The completion specs declared so far.
+pub fn specs() -> MutexGuard<'static, Vec<CompletionSpec>> {
+    SPECS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Adds `spec`, replacing an earlier spec for the same command, position
+/// and flag.
+pub fn add_spec(spec: CompletionSpec) {
+    let mut specs = specs();
+    specs.retain(|s| !(s.command == spec.command && s.position == spec.position && s.flag == spec.flag));
+    specs.push(spec);
+}
+
+/// Registers a completion function that specs can refer to with
+/// `complete -F <name>`.
+pub fn register_function<C: Completer + Send + 'static>(name: &str, completer: C) {
+    if let Ok(mut functions) = FUNCTIONS.lock() {
+        functions.insert(name.to_string(), Box::new(completer));
+    }
+}
+
+/// The directory completion spec files are loaded from at startup:
+/// `$MSH_COMPLETIONS_DIR`, or `~/.msh/completions`.
+pub fn spec_dir() -> PathBuf {
+    match std::env::var_os("MSH_COMPLETIONS_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => expand_tilde("~/.msh/completions"),
+    }
+}
+
+/// Loads every file in `dir` as a list of `complete` commands, one per
+/// line. Blank lines and lines starting with `#` are skipped. Returns a
+/// message for every line that could not be loaded.
+pub fn load_spec_dir(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).filter(|path| path.is_file()).collect();
+    paths.sort();
+
+    let mut errors = Vec::new();
+    for path in paths {
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            errors.push(format!("{}: could not be read", path.display()));
+            continue;
+        };
+        for (number, line) in contents.lines().enumerate() {
+            if let Err(e) = load_spec_line(line) {
+                errors.push(format!("{}:{}: {}", path.display(), number + 1, e));
+            }
+        }
+    }
+    errors
+}
+
+fn load_spec_line(line: &str) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let mut tokenizer = Tokenizer::new(line.to_string());
+    tokenizer.scan_tokens();
+    let mut words = tokenizer.tokens.into_iter().map(|token| token.lexeme).filter(|lexeme| !lexeme.is_empty());
+    if words.next().as_deref() != Some("complete") {
+        return Err("Only complete commands are allowed in completion files".to_string());
+    }
+    match CompletionSpec::parse(&words.collect::<Vec<_>>())? {
+        SpecAction::Add(spec) => add_spec(spec),
+        SpecAction::Erase(command) => specs().retain(|spec| spec.command != command),
+    }
+    Ok(())
+}
+
+/// The result of completing the word under the cursor.
+#[derive(Debug, Default)]
+pub struct Completions {
+    /// Byte range of the line that a replacement is written over.
+    pub start: usize,
+    pub end: usize,
+    pub candidates: Vec<Candidate>,
+    quote: Option<char>,
+    verbatim: bool,
+}
+
+impl Completions {
+    /// The text that replaces `start..end` when `candidate` is accepted.
+    pub fn replacement(&self, candidate: &Candidate) -> String {
+        let mut text = if self.verbatim {
+            self.quote.map(String::from).unwrap_or_default() + &candidate.value
+        } else {
+            quote(&candidate.value, self.quote)
+        };
+        if candidate.append_space {
+            if let Some(q) = self.quote {
+                text.push(q);
+            }
+            text.push(' ');
+        }
+        text
+    }
+
+    /// The longest prefix shared by every candidate, quoted like the word
+    /// it replaces, or `None` if there is nothing in common.
+    pub fn common_prefix(&self) -> Option<String> {
+        let first = &self.candidates.first()?.value;
+        let mut len = first.len();
+        for candidate in &self.candidates[1..] {
+            len = first
+                .char_indices()
+                .zip(candidate.value.chars())
+                .take_while(|((_, a), b)| a == b)
+                .map(|((i, a), _)| i + a.len_utf8())
+                .last()
+                .unwrap_or(0)
+                .min(len);
+        }
+        if len == 0 {
+            return None;
+        }
+        let prefix = &first[..len];
+        Some(if self.verbatim {
+            self.quote.map(String::from).unwrap_or_default() + prefix
+        } else {
+            quote(prefix, self.quote)
+        })
+    }
+}
+
+/// Decides what kind of completion applies to the word under the cursor
+/// and dispatches to the matching completer.
+#[derive(Default)]
+pub struct CompletionEngine {
+    completers: HashMap<String, Box<dyn Completer>>,
+}
+
+impl CompletionEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `completer` for the arguments of `command`, replacing any
+    /// completer registered for it before.
+    pub fn register<C: Completer + 'static>(&mut self, command: &str, completer: C) {
+        self.completers.insert(command.to_string(), Box::new(completer));
+    }
+
+    pub fn complete(&self, line: &str, cursor: usize) -> Completions {
+        let ctx = CompletionContext::parse(line, cursor);
+        let word = ctx.word();
+        let mut completions = Completions {
+            start: ctx.start,
+            end: cursor,
+            quote: ctx.quote,
+            ..Default::default()
+        };
+
+        if word.starts_with('$') && ctx.quote != Some('\'') {
+            completions.candidates = complete_variables(word);
+            completions.verbatim = true;
+            return completions;
+        }
+
+        completions.candidates = match ctx.command() {
+            _ if ctx.after_redirection => complete_paths(word, false),
+            None if word.contains('/') => complete_paths(word, false),
+            None => complete_commands(word),
+            Some(command) => {
+                let mut candidates = self
+                    .completers
+                    .get(command)
+                    .map(|completer| completer.complete(&ctx))
+                    .unwrap_or_default();
+                if candidates.is_empty() {
+                    candidates = complete_from_specs(command, &ctx);
+                }
+                if candidates.is_empty() && word.starts_with('-') {
+                    candidates = complete_flags(command, word);
+                }
+                if candidates.is_empty() {
+                    candidates = complete_paths(word, command == "cd");
+                }
+                candidates
+            }
+        };
+        completions
+    }
+}
+
+/// Completes from the specs declared for `command`. Specs for the value of
+/// a flag take precedence over positional ones.
+pub fn complete_from_specs(command: &str, ctx: &CompletionContext) -> Vec<Candidate> {
+    let specs: Vec<CompletionSpec> = specs().iter().filter(|spec| spec.command == command).cloned().collect();
+    let (flagged, positional): (Vec<_>, Vec<_>) = specs.iter().partition(|spec| spec.flag.is_some());
+    for group in [flagged, positional] {
+        let mut candidates: Vec<Candidate> = group.iter().flat_map(|spec| spec.candidates(ctx)).collect();
+        if !candidates.is_empty() {
+            candidates.sort_by(|a, b| a.value.cmp(&b.value));
+            candidates.dedup();
+            return candidates;
+        }
+    }
+    Vec::new()
+}
+
+/// Completes builtin names, aliases and executables found on `PATH`.
+pub fn complete_commands(prefix: &str) -> Vec<Candidate> {
+    let mut names: BTreeSet<String> = BUILTINS
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| name.to_string())
+        .collect();
+    names.extend(alias::all().into_iter().map(|(name, _)| name).filter(|name| name.starts_with(prefix)));
+
+    names.extend(lookup::executables(prefix));
+
+    names.into_iter().map(Candidate::new).collect()
+}
+
+/// Completes file and directory names. A leading `~` is expanded for the
+/// lookup but kept in the completed word.
+pub fn complete_paths(prefix: &str, dirs_only: bool) -> Vec<Candidate> {
+    let (dir_part, file_part) = match prefix.rfind('/') {
+        Some(i) => prefix.split_at(i + 1),
+        None => ("", prefix),
+    };
+    let dir = if dir_part.is_empty() {
+        PathBuf::from(".")
+    } else {
+        expand_tilde(dir_part)
+    };
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<Candidate> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(file_part) || (name.starts_with('.') && !file_part.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            if dirs_only && !is_dir {
+                return None;
+            }
+            let suffix = if is_dir { "/" } else { "" };
+            Some(Candidate {
+                value: format!("{}{}{}", dir_part, name, suffix),
+                display: format!("{}{}", name, suffix),
+                append_space: !is_dir,
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.value.cmp(&b.value));
+    candidates
+}
+
+/// Completes `$NAME` and `${NAME}` references to environment variables.
+pub fn complete_variables(prefix: &str) -> Vec<Candidate> {
+    let (open, close, name) = match prefix.strip_prefix("${") {
+        Some(name) => ("${", "}", name),
+        None => ("$", "", &prefix[1..]),
+    };
+    let mut names: Vec<String> = std::env::vars_os()
+        .map(|(key, _)| key.to_string_lossy().to_string())
+        .filter(|key| key.starts_with(name))
+        .collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|key| Candidate {
+            value: format!("{}{}{}", open, key, close),
+            display: key,
+            append_space: true,
+        })
+        .collect()
+}
+
+/// Completes the flags a builtin lists in its [`CommandHelp`](crate::command::CommandHelp).
+pub fn complete_flags(command: &str, prefix: &str) -> Vec<Candidate> {
+    let Some(cmd) = builtin(command) else {
+        return Vec::new();
+    };
+    let mut flags: Vec<String> = cmd
+        .get_help()
+        .flags
+        .iter()
+        .flat_map(|(flags, _)| flags.split(',').map(|flag| flag.trim().to_string()).collect::<Vec<_>>())
+        .map(|flag| flag.split(['=', ' ']).next().unwrap_or_default().to_string())
+        .filter(|flag| flag.starts_with('-') && flag.starts_with(prefix))
+        .collect();
+    flags.sort();
+    flags.dedup();
+    flags.into_iter().map(Candidate::new).collect()
+}
+
+/// Quotes `value` so the tokenizer reads it back unchanged, either inside
+/// the still-open `quote` or with backslash escapes. The closing quote is
+/// left for the caller to add.
+pub fn quote(value: &str, quote: Option<char>) -> String {
+    let mut result = String::new();
+    match quote {
+        Some('\'') => {
+            result.push('\'');
+            result.push_str(&value.replace('\'', "'\\''"));
+        }
+        Some(q) => {
+            result.push(q);
+            for c in value.chars() {
+                if matches!(c, '"' | '\\' | '$' | '`') {
+                    result.push('\\');
+                }
+                result.push(c);
+            }
+        }
+        None => {
+            for c in value.chars() {
+                let special = matches!(
+                    c,
+                    ' ' | '\t' | '\'' | '"' | '\\' | '|' | '&' | ';' | '<' | '>' | '(' | ')' | '$' | '`' | '*' | '?' | '#' | '!'
+                );
+                if special {
+                    result.push('\\');
+                }
+                result.push(c);
+            }
+        }
+    }
+    result
+}
+
+/// Lays out `items` in as many columns as fit in `width`, filling each
+/// column top to bottom like `ls` does.
+pub fn format_columns(items: &[String], width: usize) -> String {
+    let column_width = items.iter().map(|item| item.chars().count()).max().unwrap_or(0) + 2;
+    let columns = (width / column_width).max(1);
+    let rows = items.len().div_ceil(columns);
+
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut line = String::new();
+        for column in 0..columns {
+            if let Some(item) = items.get(column * rows + row) {
+                line.push_str(&format!("{:<width$}", item, width = column_width));
+            }
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+/// Expands a leading `~` to the home directory.
+pub fn expand_tilde(path: &str) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Finds the executable `name` on `PATH`. Names containing a `/` are
+/// checked as paths instead.
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    if name.is_empty() {
+        return None;
+    }
+    if name.contains('/') {
+        let path = expand_tilde(name);
+        return is_executable(&path).then_some(path);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+pub(crate) fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_splits_quoted_words() {
+        let line = r#"cat "my fi"#;
+        let ctx = CompletionContext::parse(line, line.len());
+        assert_eq!(ctx.words, vec!["cat", "my fi"]);
+        assert_eq!(ctx.start, 4);
+        assert_eq!(ctx.quote, Some('"'));
+        assert_eq!(ctx.command(), Some("cat"));
+    }
+
+    #[test]
+    fn test_context_after_pipe_is_command_position() {
+        let line = "ls -l | gr";
+        let ctx = CompletionContext::parse(line, line.len());
+        assert_eq!(ctx.words, vec!["gr"]);
+        assert_eq!(ctx.command(), None);
+
+        for line in ["true &&\ngr", "if true; then gr", "echo a\ngr"] {
+            let ctx = CompletionContext::parse(line, line.len());
+            assert_eq!(ctx.words, vec!["gr"], "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_context_empty_word() {
+        let line = "cd ";
+        let ctx = CompletionContext::parse(line, line.len());
+        assert_eq!(ctx.words, vec!["cd", ""]);
+        assert_eq!(ctx.start, 3);
+        assert!(!ctx.after_redirection);
+
+        let line = "cat > ";
+        let ctx = CompletionContext::parse(line, line.len());
+        assert!(ctx.after_redirection);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("my file", None), "my\\ file");
+        assert_eq!(quote("~/my file", None), "~/my\\ file");
+        assert_eq!(quote("it's", Some('\'')), "'it'\\''s");
+        assert_eq!(quote("a \"b\"", Some('"')), "\"a \\\"b\\\"");
+    }
+
+    #[test]
+    fn test_builtin_flags() {
+        let flags = complete_flags("history", "--");
+        assert!(flags.contains(&Candidate::new("--clear")));
+        assert!(flags.contains(&Candidate::new("--help")));
+        assert!(complete_flags("not-a-builtin", "-").is_empty());
+    }
+
+    #[test]
+    fn test_registered_completer() {
+        let mut engine = CompletionEngine::new();
+        engine.register("git", |ctx: &CompletionContext| {
+            ["status", "stash", "commit"]
+                .iter()
+                .filter(|sub| sub.starts_with(ctx.word()))
+                .map(|sub| Candidate::new(*sub))
+                .collect()
+        });
+
+        let completions = engine.complete("git st", 6);
+        assert_eq!(completions.start, 4);
+        assert_eq!(completions.candidates.len(), 2);
+        assert_eq!(completions.common_prefix(), Some("sta".to_string()));
+        assert_eq!(completions.replacement(&completions.candidates[0]), "status ");
+    }
+
+    #[test]
+    fn test_format_columns() {
+        let items: Vec<String> = ["a", "b", "c", "d", "e"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(format_columns(&items, 9), "a  c  e\nb  d");
+        assert_eq!(format_columns(&items, 1), "a\nb\nc\nd\ne");
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let action = CompletionSpec::parse(&words("-c mytool -p 1 -a start")).unwrap();
+        assert_eq!(
+            action,
+            SpecAction::Add(CompletionSpec {
+                command: "mytool".to_string(),
+                position: Some(1),
+                flag: None,
+                source: SpecSource::Words(vec!["start".to_string()]),
+            })
+        );
+        assert_eq!(CompletionSpec::parse(&words("-e -c mytool")).unwrap(), SpecAction::Erase("mytool".to_string()));
+        assert!(CompletionSpec::parse(&words("-a foo")).is_err());
+        assert!(CompletionSpec::parse(&words("-c foo")).is_err());
+    }
+
+    #[test]
+    fn test_spec_display_is_reinputtable() {
+        let spec = CompletionSpec {
+            command: "git".to_string(),
+            position: None,
+            flag: Some("--format".to_string()),
+            source: SpecSource::Words(vec!["json".to_string(), "yaml".to_string()]),
+        };
+        assert_eq!(spec.to_string(), "complete -c git -f --format -a 'json yaml'");
+    }
+
+    #[test]
+    fn test_complete_from_specs() {
+        add_spec(CompletionSpec {
+            command: "spectool".to_string(),
+            position: Some(1),
+            flag: None,
+            source: SpecSource::Words(words("build bench check")),
+        });
+        add_spec(CompletionSpec {
+            command: "spectool".to_string(),
+            position: None,
+            flag: Some("--format".to_string()),
+            source: SpecSource::Words(words("json yaml")),
+        });
+        let engine = CompletionEngine::new();
+
+        let values = |line: &str| -> Vec<String> {
+            engine.complete(line, line.len()).candidates.into_iter().map(|c| c.value).collect()
+        };
+        assert_eq!(values("spectool b"), vec!["bench", "build"]);
+        assert_eq!(values("spectool --format j"), vec!["json"]);
+        assert_eq!(values("spectool --format=y"), vec!["--format=yaml"]);
+    }
+}
@@ -0,0 +1,427 @@
+use std::{fs, os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt}, path::Path, time::SystemTime};
+
+use regex::Regex;
+
+use crate::history::glob_match;
+use crate::vars;
+
+/// Unary tests on files, strings and variables, shared by `test` and `[[`.
+const UNARY: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-G", "-h", "-k", "-L", "-n", "-N", "-O", "-p", "-r", "-s", "-S", "-t",
+    "-u", "-v", "-w", "-x", "-z",
+];
+
+/// Binary tests that `test` understands. `[[` adds `=~`.
+const BINARY: &[&str] = &["=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef"];
+
+pub fn is_unary(op: &str) -> bool {
+    UNARY.contains(&op)
+}
+
+pub fn is_binary(op: &str) -> bool {
+    BINARY.contains(&op)
+}
+
+/// Applies the unary test `op`, such as `-f` or `-z`, to `operand`.
+pub fn unary(op: &str, operand: &str) -> Result<bool, String> {
+    let path = Path::new(operand);
+    let metadata = || fs::metadata(path).ok();
+    let mode = |bits: u32| metadata().is_some_and(|m| m.permissions().mode() & bits != 0);
+    Ok(match op {
+        "-a" | "-e" => metadata().is_some(),
+        "-b" => metadata().is_some_and(|m| m.file_type().is_block_device()),
+        "-c" => metadata().is_some_and(|m| m.file_type().is_char_device()),
+        "-d" => metadata().is_some_and(|m| m.is_dir()),
+        "-f" => metadata().is_some_and(|m| m.is_file()),
+        "-g" => mode(0o2000),
+        "-u" => mode(0o4000),
+        "-k" => mode(0o1000),
+        "-h" | "-L" => fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()),
+        "-p" => metadata().is_some_and(|m| m.file_type().is_fifo()),
+        "-S" => metadata().is_some_and(|m| m.file_type().is_socket()),
+        "-s" => metadata().is_some_and(|m| m.len() > 0),
+        "-N" => metadata().is_some_and(|m| m.mtime() > m.atime()),
+        // SAFETY: the ids are plain integers read from the process.
+        "-O" => metadata().is_some_and(|m| m.uid() == unsafe { libc::geteuid() }),
+        "-G" => metadata().is_some_and(|m| m.gid() == unsafe { libc::getegid() }),
+        "-r" => access(path, libc::R_OK),
+        "-w" => access(path, libc::W_OK),
+        "-x" => access(path, libc::X_OK),
+        "-t" => {
+            let fd: i32 = operand.parse().map_err(|_| format!("{}: integer expression expected", operand))?;
+            // SAFETY: isatty only inspects the descriptor.
+            unsafe { libc::isatty(fd) == 1 }
+        }
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-v" => vars::is_set(operand),
+        _ => return Err(format!("{}: unary operator expected", op)),
+    })
+}
+
+/// Whether the shell may access `path` as `how`, checked against the
+/// effective ids like the kernel would.
+fn access(path: &Path, how: i32) -> bool {
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid NUL-terminated string.
+    unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), how, libc::AT_EACCESS) == 0 }
+}
+
+fn integer(text: &str) -> Result<i64, String> {
+    text.trim().parse().map_err(|_| format!("{}: integer expression expected", text))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Applies the binary test `op` to `left` and `right`. `=`, `==` and `!=`
+/// compare strings exactly; `[[` handles patterns itself.
+pub fn binary(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" => integer(left)? == integer(right)?,
+        "-ne" => integer(left)? != integer(right)?,
+        "-lt" => integer(left)? < integer(right)?,
+        "-le" => integer(left)? <= integer(right)?,
+        "-gt" => integer(left)? > integer(right)?,
+        "-ge" => integer(left)? >= integer(right)?,
+        "-nt" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left > right,
+            (left, right) => left.is_some() && right.is_none(),
+        },
+        "-ot" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left < right,
+            (left, right) => left.is_none() && right.is_some(),
+        },
+        "-ef" => match (fs::metadata(left), fs::metadata(right)) {
+            (Ok(left), Ok(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        },
+        _ => return Err(format!("{}: binary operator expected", op)),
+    })
+}
+
+/// Evaluates the arguments of `test` or `[` (without the closing `]`).
+/// Up to four arguments are read the way POSIX specifies, which keeps
+/// e.g. `test ! = x` unambiguous; longer expressions are parsed with
+/// `!`, `-a`, `-o` and parentheses.
+pub fn test(args: &[&str]) -> Result<bool, String> {
+    match *args {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        ["!", word] => Ok(word.is_empty()),
+        [op, operand] if is_unary(op) => unary(op, operand),
+        [op, _] => Err(format!("{}: unary operator expected", op)),
+        [left, op, right] if is_binary(op) => binary(left, op, right),
+        [left, "-a", right] => Ok(!left.is_empty() && !right.is_empty()),
+        [left, "-o", right] => Ok(!left.is_empty() || !right.is_empty()),
+        ["!", ..] if args.len() <= 4 => Ok(!test(&args[1..])?),
+        ["(", word, ")"] => Ok(!word.is_empty()),
+        ["(", first, second, ")"] => test(&[first, second]),
+        _ => {
+            let mut parser = TestParser { args, pos: 0 };
+            let result = parser.or()?;
+            match parser.args.get(parser.pos) {
+                Some(extra) => Err(format!("{}: unexpected argument", extra)),
+                None => Ok(result),
+            }
+        }
+    }
+}
+
+/// Recursive descent over `test` arguments, for expressions longer than
+/// the POSIX rules cover.
+struct TestParser<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.pos).copied();
+        self.pos += 1;
+        arg
+    }
+
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.args.get(self.pos + offset).copied()
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.pos += 1;
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.pos += 1;
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.peek(0) == Some("!") && self.peek(1).is_some() {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        if let (Some(left), Some(op), Some(right)) = (self.peek(0), self.peek(1), self.peek(2))
+            && is_binary(op)
+        {
+            self.pos += 3;
+            return binary(left, op, right);
+        }
+        match self.next() {
+            Some("(") => {
+                let result = self.or()?;
+                match self.next() {
+                    Some(")") => Ok(result),
+                    _ => Err("`)' expected".to_string()),
+                }
+            }
+            Some(op) if is_unary(op) => match self.next() {
+                Some(operand) => unary(op, operand),
+                None => Err(format!("{}: argument expected", op)),
+            },
+            Some(word) => Ok(!word.is_empty()),
+            None => Err("argument expected".to_string()),
+        }
+    }
+}
+
+/// A word of a `[[` test. Operators, patterns and regular expressions
+/// only count as such when they are not quoted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Word {
+    pub text: String,
+    pub quoted: bool,
+}
+
+impl Word {
+    fn is(&self, op: &str) -> bool {
+        !self.quoted && self.text == op
+    }
+}
+
+/// A parsed `[[` expression.
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Word(Word),
+    Unary(String, Word),
+    Binary(Word, String, Word),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// Parses the words between `[[` and `]]`.
+pub fn parse(words: &[Word]) -> Result<Expr, String> {
+    if words.is_empty() {
+        return Err("expression expected".to_string());
+    }
+    let mut parser = ExprParser { words, pos: 0 };
+    let expr = parser.or()?;
+    match words.get(parser.pos) {
+        Some(extra) => Err(format!("unexpected token: {}", extra.text)),
+        None => Ok(expr),
+    }
+}
+
+struct ExprParser<'a> {
+    words: &'a [Word],
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a Word> {
+        self.words.get(self.pos + offset)
+    }
+
+    fn at(&self, op: &str) -> bool {
+        self.peek(0).is_some_and(|word| word.is(op))
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.at("||") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.at("&&") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.at("!") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn operand(&mut self) -> Result<Word, String> {
+        match self.peek(0) {
+            Some(word) if !["&&", "||", "(", ")"].iter().any(|op| word.is(op)) => {
+                self.pos += 1;
+                Ok(word.clone())
+            }
+            Some(word) => Err(format!("unexpected token: {}", word.text)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.at("(") {
+            self.pos += 1;
+            let expr = self.or()?;
+            if !self.at(")") {
+                return Err("`)' expected".to_string());
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+        if let Some(op) = self.peek(1)
+            && !op.quoted
+            && (is_binary(&op.text) || op.text == "=~")
+        {
+            let op = op.text.clone();
+            let left = self.operand()?;
+            self.pos += 1;
+            return Ok(Expr::Binary(left, op, self.operand()?));
+        }
+        let word = self.operand()?;
+        if !word.quoted && is_unary(&word.text) {
+            return Ok(Expr::Unary(word.text, self.operand()?));
+        }
+        Ok(Expr::Word(word))
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression. A successful `=~` match stores the matched
+    /// text and then each group in the array `BASH_REMATCH`.
+    pub fn eval(&self) -> Result<bool, String> {
+        match self {
+            Expr::Word(word) => Ok(!word.text.is_empty()),
+            Expr::Unary(op, operand) => unary(op, &operand.text),
+            Expr::Binary(left, op, right) => match op.as_str() {
+                "=" | "==" => Ok(matches_pattern(right, &left.text)),
+                "!=" => Ok(!matches_pattern(right, &left.text)),
+                "=~" => regex_match(&left.text, right),
+                _ => binary(&left.text, op, &right.text),
+            },
+            Expr::Not(expr) => Ok(!expr.eval()?),
+            Expr::And(left, right) => Ok(left.eval()? && right.eval()?),
+            Expr::Or(left, right) => Ok(left.eval()? || right.eval()?),
+        }
+    }
+}
+
+/// Matches `text` against `pattern` as a glob, or literally if quoted.
+fn matches_pattern(pattern: &Word, text: &str) -> bool {
+    if pattern.quoted {
+        return pattern.text == text;
+    }
+    glob_match(&pattern.text, text)
+}
+
+fn regex_match(text: &str, pattern: &Word) -> Result<bool, String> {
+    let source = if pattern.quoted { regex::escape(&pattern.text) } else { pattern.text.clone() };
+    let regex = Regex::new(&source).map_err(|_| format!("{}: invalid regular expression", pattern.text))?;
+    let captures = regex.captures(text);
+    let groups: Vec<String> = captures
+        .iter()
+        .flat_map(|captures| captures.iter())
+        .map(|group| group.map_or(String::new(), |group| group.as_str().to_string()))
+        .collect();
+    vars::set_array("BASH_REMATCH", &groups);
+    Ok(captures.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<Word> {
+        line.split_whitespace()
+            .map(|word| match word.strip_prefix('\'').and_then(|word| word.strip_suffix('\'')) {
+                Some(text) => Word { text: text.to_string(), quoted: true },
+                None => Word { text: word.to_string(), quoted: false },
+            })
+            .collect()
+    }
+
+    fn eval(line: &str) -> Result<bool, String> {
+        parse(&words(line))?.eval()
+    }
+
+    #[test]
+    fn test_posix_test() {
+        assert_eq!(test(&[]), Ok(false));
+        assert_eq!(test(&[""]), Ok(false));
+        assert_eq!(test(&["-n"]), Ok(true));
+        assert_eq!(test(&["!", ""]), Ok(true));
+        assert_eq!(test(&["-z", ""]), Ok(true));
+        assert_eq!(test(&["-d", "/"]), Ok(true));
+        assert_eq!(test(&["-f", "/"]), Ok(false));
+        assert_eq!(test(&["10", "-gt", "9"]), Ok(true));
+        assert_eq!(test(&["abc", "<", "abd"]), Ok(true));
+        assert_eq!(test(&["!", "=", "x"]), Ok(false));
+        assert_eq!(test(&["!", "a", "=", "a"]), Ok(false));
+        assert_eq!(test(&["(", "-d", "/", ")", "-a", "1", "-eq", "2"]), Ok(false));
+        assert_eq!(test(&["-d", "/", "-o", "1", "-eq", "2", "-a", "x"]), Ok(true));
+        assert!(test(&["a", "-eq", "1"]).is_err());
+        assert!(test(&["-q", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_extended_test() {
+        assert_eq!(eval("abc == a*"), Ok(true));
+        assert_eq!(eval("abc == 'a*'"), Ok(false));
+        assert_eq!(eval("a* == 'a*'"), Ok(true));
+        assert_eq!(eval("-d / && ( x != y || -z x )"), Ok(true));
+        assert_eq!(eval("! -e /no/such/file"), Ok(true));
+        assert_eq!(eval("2 -lt 10 && b > a"), Ok(true));
+        assert!(parse(&words("( a")).is_err());
+        assert!(parse(&words("a b")).is_err());
+    }
+
+    #[test]
+    fn test_regex_match_sets_rematch() {
+        assert_eq!(eval("key=value =~ ^([a-z]+)=(.*)$"), Ok(true));
+        let groups = ["key=value", "key", "value"].map(String::from).to_vec();
+        assert_eq!(vars::get_array("BASH_REMATCH"), Some(groups));
+        assert!(std::env::var_os("BASH_REMATCH").is_none());
+        let line = "[[ key=value =~ ^([a-z]+)= ]] && printf -v MSH_TEST_REMATCH %s ${BASH_REMATCH[1]}";
+        crate::command::parse_line(line).unwrap().execute().unwrap();
+        assert_eq!(vars::get("MSH_TEST_REMATCH").as_deref(), Some("key"));
+
+        assert_eq!(eval("a.c =~ 'a.c'"), Ok(true));
+        assert_eq!(eval("abc =~ 'a.c'"), Ok(false));
+        assert!(!vars::is_set("BASH_REMATCH"));
+        assert!(eval("x =~ (").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::{io, os::unix::fs::MetadataExt, path::{Component, Path, PathBuf}, sync::{Arc, LazyLock, Mutex}};
+
+static STACK: LazyLock<Arc<Mutex<Vec<PathBuf>>>> = LazyLock::new(|| Arc::new(Mutex::new(Vec::new())));
+
+/// The directories saved by `pushd`, most recent first. The current
+/// directory is not part of it; see [`dir_stack`].
+pub fn stack() -> Arc<Mutex<Vec<PathBuf>>> {
+    STACK.clone()
+}
+
+/// The whole directory stack as `dirs` shows it: the current directory,
+/// then the saved ones.
+pub fn dir_stack() -> Vec<PathBuf> {
+    let mut dirs = vec![logical()];
+    dirs.extend(STACK.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned());
+    dirs
+}
+
+/// The position in a stack of `len` entries that `+N` (counting from the
+/// top, which is 0) or `-N` (counting from the bottom) refers to.
+pub fn stack_index(spec: &str, len: usize) -> Option<usize> {
+    let (from_bottom, n) = match spec.split_at_checked(1)? {
+        ("+", n) => (false, n),
+        ("-", n) => (true, n),
+        _ => return None,
+    };
+    let n: usize = n.parse().ok()?;
+    if n >= len {
+        return None;
+    }
+    Some(if from_bottom { len - 1 - n } else { n })
+}
+
+/// Expands a tilde prefix at the start of an unquoted word: `~` and
+/// `~/...` to the home directory, `~+` to `$PWD`, `~-` to `$OLDPWD`, and
+/// `~N`, `~+N` and `~-N` to entries of the directory stack. Words whose
+/// prefix cannot be expanded are left as they are.
+pub fn expand_tilde(word: &str) -> String {
+    if !word.starts_with('~') {
+        return word.to_string();
+    }
+    expand_tilde_with(word, &dir_stack())
+}
+
+fn expand_tilde_with(word: &str, stack: &[PathBuf]) -> String {
+    let Some(rest) = word.strip_prefix('~') else {
+        return word.to_string();
+    };
+    let (prefix, suffix) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let dir = match prefix {
+        "" => std::env::var_os("HOME").map(PathBuf::from).or_else(dirs::home_dir),
+        "+" => stack.first().cloned(),
+        "-" => std::env::var_os("OLDPWD").map(PathBuf::from),
+        _ if prefix.starts_with(['+', '-']) => stack_index(prefix, stack.len()).map(|i| stack[i].clone()),
+        _ if prefix.starts_with(|c: char| c.is_ascii_digit()) => {
+            stack_index(&format!("+{}", prefix), stack.len()).map(|i| stack[i].clone())
+        }
+        _ => None,
+    };
+    match dir {
+        Some(dir) => format!("{}{}", dir.display(), suffix),
+        None => word.to_string(),
+    }
+}
+
+/// The current directory as the user reached it, through any symbolic
+/// links (`$PWD`), or the physical directory if `$PWD` does not name it.
+pub fn logical() -> PathBuf {
+    let physical = std::env::current_dir().unwrap_or_default();
+    match std::env::var_os("PWD").map(PathBuf::from) {
+        Some(pwd) if pwd.is_absolute() && same_file(&pwd, &physical) => pwd,
+        _ => physical,
+    }
+}
+
+/// The current directory with all symbolic links resolved.
+pub fn physical() -> io::Result<PathBuf> {
+    std::env::current_dir()?.canonicalize()
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Removes `.` components and resolves `..` against the component before
+/// it, without looking at the file system.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        normalized.push(".");
+    }
+    normalized
+}
+
+/// Where `dir` leads from `pwd`. Logically, `..` goes back over the link
+/// that was followed; physically, links are resolved.
+pub fn resolve(pwd: &Path, dir: &Path, physical: bool) -> PathBuf {
+    let joined = pwd.join(dir);
+    if physical {
+        joined.canonicalize().unwrap_or(joined)
+    } else {
+        normalize(&joined)
+    }
+}
+
+/// The directories `cd` tries for `dir`: those under each entry of
+/// `cdpath` that exist, then `dir` itself. Paths starting with `/`, `.`
+/// or `..` do not use `cdpath`; an empty entry stands for `.`.
+pub fn candidates(dir: &str, cdpath: Option<&str>) -> Vec<PathBuf> {
+    let first = Path::new(dir).components().next();
+    let searched = !matches!(first, Some(Component::RootDir | Component::CurDir | Component::ParentDir) | None);
+    let mut candidates = Vec::new();
+    if let Some(cdpath) = cdpath.filter(|_| searched) {
+        for entry in cdpath.split(':') {
+            let base = if entry.is_empty() { Path::new(".") } else { Path::new(entry) };
+            let candidate = base.join(dir);
+            if candidate.is_dir() && !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    candidates.push(PathBuf::from(dir));
+    candidates
+}
+
+/// Changes into `dir`, relative to the logical current directory, and
+/// updates `$OLDPWD` and `$PWD`. Returns the new `$PWD`.
+pub fn change_dir(dir: &Path, physical: bool) -> io::Result<PathBuf> {
+    let old = logical();
+    let target = resolve(&old, dir, physical);
+    if let Err(e) = std::env::set_current_dir(&target) {
+        // A logical path can fail where the physical one works, e.g. with
+        // `..` after a link into a directory that is not readable.
+        if physical || std::env::set_current_dir(dir).is_err() {
+            return Err(e);
+        }
+    }
+    let pwd = if physical || !same_file(&target, Path::new(".")) { self::physical()? } else { target };
+    // SAFETY: the shell only touches its environment from this thread.
+    unsafe {
+        std::env::set_var("OLDPWD", &old);
+        std::env::set_var("PWD", &pwd);
+    }
+    crate::frecency::record(&pwd);
+    Ok(pwd)
+}
+
+/// The message for `e` without the `(os error N)` suffix.
+pub fn error_text(e: &io::Error) -> String {
+    let text = e.to_string();
+    match text.find(" (os error") {
+        Some(end) => text[..end].to_string(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_index_and_tilde() {
+        assert_eq!(stack_index("+0", 3), Some(0));
+        assert_eq!(stack_index("+2", 3), Some(2));
+        assert_eq!(stack_index("-0", 3), Some(2));
+        assert_eq!(stack_index("-2", 3), Some(0));
+        assert_eq!(stack_index("+3", 3), None);
+        assert_eq!(stack_index("2", 3), None);
+
+        let stack = [PathBuf::from("/cwd"), PathBuf::from("/one"), PathBuf::from("/two")];
+        assert_eq!(expand_tilde_with("~1", &stack), "/one");
+        assert_eq!(expand_tilde_with("~+2/src", &stack), "/two/src");
+        assert_eq!(expand_tilde_with("~-0", &stack), "/two");
+        assert_eq!(expand_tilde_with("~+", &stack), "/cwd");
+        assert_eq!(expand_tilde_with("~5", &stack), "~5");
+        assert_eq!(expand_tilde_with("~nosuchuser", &stack), "~nosuchuser");
+        assert_eq!(expand_tilde("plain"), "plain");
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(Path::new("/usr/lib/../bin/./")), PathBuf::from("/usr/bin"));
+        assert_eq!(normalize(Path::new("/..")), PathBuf::from("/"));
+        assert_eq!(normalize(Path::new("a/..")), PathBuf::from("."));
+        assert_eq!(normalize(Path::new("../a")), PathBuf::from("../a"));
+        assert_eq!(resolve(Path::new("/link/sub"), Path::new(".."), false), PathBuf::from("/link"));
+        assert_eq!(resolve(Path::new("/tmp"), Path::new("/etc"), false), PathBuf::from("/etc"));
+    }
+
+    #[test]
+    fn test_candidates_search_cdpath() {
+        let base = std::env::temp_dir().join(format!("msh-cdpath-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("one/project")).unwrap();
+        std::fs::create_dir_all(base.join("two/project")).unwrap();
+        let cdpath = format!("{}:{}", base.join("one").display(), base.join("two").display());
+
+        let found = candidates("project", Some(&cdpath));
+        assert_eq!(found, vec![base.join("one/project"), base.join("two/project"), PathBuf::from("project")]);
+        assert_eq!(candidates("./project", Some(&cdpath)), vec![PathBuf::from("./project")]);
+        assert_eq!(candidates("missing", Some(&cdpath)), vec![PathBuf::from("missing")]);
+        assert_eq!(candidates("project", None), vec![PathBuf::from("project")]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,1092 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::sync::Mutex;
+
+use crate::abbr;
+use crate::command;
+use crate::completion::{format_columns, CompletionEngine};
+use crate::highlight::Highlighter;
+use crate::keymap::{self, Action, Binding, EditMode, KeymapName, Keymaps, Lookup};
+use crate::trap;
+use crate::vi::{Outcome, Vi};
+use crate::History;
+
+/// A key press decoded from the terminal's input stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    Enter,
+    Tab,
+    BackTab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Esc,
+    Unknown,
+}
+
+/// Puts the terminal into raw mode for as long as it is alive.
+struct RawMode {
+    original: libc::termios,
+    raw: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { original, raw })
+    }
+
+    /// Restores the original settings until [`RawMode::resume`], e.g.
+    /// while a bound shell command runs.
+    fn suspend(&self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+        }
+    }
+
+    fn resume(&self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.raw);
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.original);
+        }
+    }
+}
+
+/// Returns whether both stdin and stdout are attached to a terminal.
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// The width of the terminal in columns, falling back to `$COLUMNS` or 80.
+pub fn terminal_width() -> usize {
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        return size.ws_col as usize;
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// The number of columns `text` occupies, ignoring ANSI escape sequences.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip a CSI sequence up to and including its final byte.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else if !c.is_control() {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Reads a single byte from stdin, waiting at most `timeout_ms` if given.
+fn read_byte(timeout_ms: Option<i32>) -> io::Result<Option<u8>> {
+    if let Some(timeout) = timeout_ms {
+        let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut fds, 1, timeout) } <= 0 {
+            return Ok(None);
+        }
+    }
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let err = io::Error::last_os_error();
+                // A trapped signal ends the line so that its trap can run.
+                if err.kind() != ErrorKind::Interrupted || trap::signal_pending() {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Reads and decodes the next key press. Returns `None` at end of input.
+fn read_key() -> io::Result<Option<Key>> {
+    decode_key(&mut read_byte)
+}
+
+/// Decodes one key from bytes supplied by `read`, which is passed a
+/// timeout for bytes that continue a sequence. Returns `None` when `read`
+/// has no more bytes.
+pub(crate) fn decode_key(read: &mut impl FnMut(Option<i32>) -> io::Result<Option<u8>>) -> io::Result<Option<Key>> {
+    let Some(byte) = read(None)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f | 0x08 => Key::Backspace,
+        0x1b => decode_escape(read)?,
+        1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0x1c..=0x1f => Key::Ctrl((byte + 0x40) as char),
+        _ if byte < 0x80 => Key::Char(byte as char),
+        _ => {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                _ => 4,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read(Some(50))?);
+            }
+            String::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+                .map(Key::Char)
+                .unwrap_or(Key::Unknown)
+        }
+    };
+    Ok(Some(key))
+}
+
+/// Decodes the rest of an escape sequence after an initial ESC byte.
+fn decode_escape(read: &mut impl FnMut(Option<i32>) -> io::Result<Option<u8>>) -> io::Result<Key> {
+    let Some(next) = read(Some(50))? else {
+        return Ok(Key::Esc);
+    };
+    match next {
+        b'[' => {
+            let mut params = String::new();
+            let final_byte = loop {
+                match read(Some(50))? {
+                    Some(b) if (0x40..=0x7e).contains(&b) => break b,
+                    Some(b) => params.push(b as char),
+                    None => return Ok(Key::Unknown),
+                }
+            };
+            Ok(match (final_byte, params.as_str()) {
+                (b'A', _) => Key::Up,
+                (b'B', _) => Key::Down,
+                (b'C', _) => Key::Right,
+                (b'D', _) => Key::Left,
+                (b'H', _) => Key::Home,
+                (b'F', _) => Key::End,
+                (b'Z', _) => Key::BackTab,
+                (b'~', "1" | "7") => Key::Home,
+                (b'~', "4" | "8") => Key::End,
+                (b'~', "3") => Key::Delete,
+                _ => Key::Unknown,
+            })
+        }
+        b'O' => Ok(match read(Some(50))? {
+            Some(b'A') => Key::Up,
+            Some(b'B') => Key::Down,
+            Some(b'C') => Key::Right,
+            Some(b'D') => Key::Left,
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
+            _ => Key::Unknown,
+        }),
+        b if b < 0x80 => Ok(Key::Alt(b as char)),
+        _ => Ok(Key::Unknown),
+    }
+}
+
+/// The line being edited and the cursor position within it.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pub text: String,
+    /// Byte offset of the cursor, always on a char boundary.
+    pub cursor: usize,
+}
+
+impl LineBuffer {
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    pub fn replace(&mut self, start: usize, end: usize, with: &str) {
+        self.text.replace_range(start..end, with);
+        self.cursor = start + with.len();
+    }
+
+    pub fn set(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.text[..self.cursor].chars().next_back().map_or(0, |c| self.cursor - c.len_utf8())
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..].chars().next().map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.prev_boundary();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.prev_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    /// Moves to the end of the next whitespace-separated word.
+    pub fn word_right(&mut self) {
+        self.cursor += next_word_len(&self.text[self.cursor..]);
+    }
+
+    /// Moves to the start of the previous whitespace-separated word.
+    pub fn word_left(&mut self) {
+        let trimmed = self.text[..self.cursor].trim_end();
+        self.cursor = trimmed.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    }
+
+    /// Swaps the characters before and at the cursor, or the last two
+    /// characters at the end of the line, and moves past them.
+    pub fn transpose(&mut self) {
+        let at = if self.cursor == self.text.len() { self.prev_boundary() } else { self.cursor };
+        let start = self.text[..at].chars().next_back().map_or(at, |c| at - c.len_utf8());
+        let end = self.text[at..].chars().next().map_or(at, |c| at + c.len_utf8());
+        if start == at || end == at {
+            return;
+        }
+        let swapped = format!("{}{}", &self.text[at..end], &self.text[start..at]);
+        self.text.replace_range(start..end, &swapped);
+        self.cursor = end;
+    }
+
+    /// Byte offset of the start of the line the cursor is on, for text
+    /// that spans several lines.
+    pub fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    /// Byte offset of the end of the line the cursor is on.
+    pub fn line_end(&self) -> usize {
+        self.text[self.cursor..].find('\n').map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /// Moves to the same column of the previous line. Returns `false` on
+    /// the first line.
+    pub fn up(&mut self) -> bool {
+        let start = self.line_start();
+        if start == 0 {
+            return false;
+        }
+        let column = self.text[start..self.cursor].chars().count();
+        self.cursor = start - 1;
+        self.cursor = self.column(self.line_start(), column);
+        true
+    }
+
+    /// Moves to the same column of the next line. Returns `false` on the
+    /// last line.
+    pub fn down(&mut self) -> bool {
+        let end = self.line_end();
+        if end == self.text.len() {
+            return false;
+        }
+        let column = self.text[self.line_start()..self.cursor].chars().count();
+        self.cursor = self.column(end + 1, column);
+        true
+    }
+
+    /// The offset of `column` chars into the line starting at `start`,
+    /// or of the line's end if it is shorter.
+    fn column(&self, start: usize, column: usize) -> usize {
+        self.text[start..]
+            .char_indices()
+            .take_while(|&(_, c)| c != '\n')
+            .nth(column)
+            .map_or_else(|| self.text[start..].find('\n').map_or(self.text.len(), |i| start + i), |(i, _)| start + i)
+    }
+
+    /// Deletes the whitespace-separated word before the cursor.
+    pub fn delete_word_before(&mut self) {
+        let before = &self.text[..self.cursor];
+        let trimmed = before.trim_end();
+        let start = trimmed.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+}
+
+/// Replaces the abbreviation that ends at the cursor with its expansion.
+fn expand_abbreviation(line: &mut LineBuffer) {
+    if let Some((start, end, expansion)) = abbr::expand_at(&line.text, line.cursor) {
+        line.replace(start, end, &expansion);
+    }
+}
+
+/// The byte length of any leading whitespace plus the word that follows it.
+fn next_word_len(text: &str) -> usize {
+    let skipped = text.len() - text.trim_start().len();
+    skipped + text[skipped..].find(char::is_whitespace).unwrap_or(text.len() - skipped)
+}
+
+/// What is drawn around the line being edited.
+#[derive(Clone, Debug, Default)]
+pub struct PromptLine {
+    /// The prompt in front of the input. May span several lines.
+    pub left: String,
+    /// Shown right-aligned on the input row while the line leaves room.
+    pub right: String,
+    /// Shown in front of the second and later lines of a multi-line command.
+    pub continuation: String,
+    /// If set, the prompt is collapsed to this once the line is submitted.
+    pub transient: Option<String>,
+    /// Shown in front of the prompt in vi insert mode.
+    pub vi_insert: String,
+    /// Shown in front of the prompt in vi command mode.
+    pub vi_command: String,
+}
+
+impl From<&str> for PromptLine {
+    fn from(left: &str) -> Self {
+        Self { left: left.to_string(), ..Default::default() }
+    }
+}
+
+/// Where the cursor ends up after drawing a line: the row the drawn text
+/// ends on and the row and column of the cursor, all relative to the
+/// first input row. `lines` holds the display width of each line of the
+/// input including its prompt, and `cursor` the width up to the cursor
+/// within line `cursor_line`.
+fn layout(lines: &[usize], cursor_line: usize, cursor: usize, width: usize) -> (usize, usize, usize) {
+    let width = width.max(1);
+    let mut start = 0;
+    let mut cursor_start = 0;
+    for (i, &line) in lines.iter().enumerate() {
+        if i == cursor_line {
+            cursor_start = start;
+        }
+        if i + 1 == lines.len() {
+            return (start + line / width, cursor_start + cursor / width, cursor % width);
+        }
+        // A line that exactly fills its rows leaves the next one to start
+        // on the following row, like any other line.
+        start += line.div_ceil(width).max(1);
+    }
+    (0, 0, 0)
+}
+
+/// How the read loop continues after a key.
+enum Flow {
+    Continue,
+    Accept,
+    Undo,
+    Eof,
+}
+
+/// The state of a single [`LineEditor::read_line`] call.
+struct Session<'a> {
+    prompt: &'a PromptLine,
+    /// The lines of a multi-line prompt above the input row.
+    above: Option<&'a str>,
+    /// The last line of the left prompt, which is redrawn on every change.
+    left: &'a str,
+    /// Rows printed above the input by a multi-line prompt.
+    rows_above: usize,
+    line: LineBuffer,
+    suggestion: Option<String>,
+    /// Row of the cursor relative to the first input row.
+    cursor_row: usize,
+    keymap: KeymapName,
+    /// The keys typed so far of a multi-key binding.
+    pending: Vec<Key>,
+    vi: Vi,
+    /// Earlier states of the line and cursor, most recent last.
+    undo: Vec<(String, usize)>,
+    /// Whether the last key typed a character, so runs of typing undo at once.
+    typing: bool,
+    /// Text removed by the kill actions, for `yank`.
+    killed: String,
+    /// The shell's history, locked only while an entry is read from it,
+    /// so commands run from the line editor can still use it.
+    history: &'a Mutex<History>,
+    /// How many entries the history had when the line was started.
+    history_len: usize,
+    history_index: usize,
+    saved_line: String,
+}
+
+impl Session<'_> {
+    /// The command of history entry `index`, if it is still there.
+    fn history_entry(&self, index: usize) -> Option<String> {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.entries.get(index).map(|entry| entry.command.clone())
+    }
+
+    fn previous_history(&mut self) {
+        if self.history_index == 0 {
+            return;
+        }
+        let Some(entry) = self.history_entry(self.history_index - 1) else {
+            return;
+        };
+        if self.history_index == self.history_len {
+            self.saved_line = self.line.text.clone();
+        }
+        self.history_index -= 1;
+        self.line.set(&entry);
+    }
+
+    fn next_history(&mut self) {
+        if self.history_index == self.history_len {
+            return;
+        }
+        self.history_index += 1;
+        match self.history_entry(self.history_index).filter(|_| self.history_index < self.history_len) {
+            Some(entry) => self.line.set(&entry),
+            None => {
+                self.history_index = self.history_len;
+                self.line.set(&self.saved_line);
+            }
+        }
+    }
+
+    /// Records the line as it was before `key` for undo. Typing in a row,
+    /// and everything typed in one visit to vi insert mode, undo together.
+    fn remember(&mut self, before: (String, usize), keymap: KeymapName, key: Key) {
+        let changed = self.line.text != before.0;
+        let entered_insert = keymap == KeymapName::ViCommand && self.keymap == KeymapName::ViInsert;
+        let typing = matches!(key, Key::Char(_)) && keymap != KeymapName::ViCommand;
+        let grouped = (keymap == KeymapName::ViInsert && self.keymap == KeymapName::ViInsert) || (typing && self.typing);
+        self.typing = typing;
+        if (changed || entered_insert) && !grouped && self.undo.last() != Some(&before) {
+            self.undo.push(before);
+        }
+    }
+
+    /// In vi command mode the cursor rests on a character, not after the line.
+    fn clamp_cursor(&mut self) {
+        if self.keymap == KeymapName::ViCommand && !self.line.text.is_empty() && self.line.cursor == self.line.text.len() {
+            self.line.left();
+        }
+    }
+}
+
+/// Reads lines from the terminal with editing, history navigation, tab
+/// completion and suggestions from history. Keys are handled through the
+/// emacs or vi keymaps from [`keymap`]. Falls back to plain buffered reads
+/// when stdin is not a TTY.
+#[derive(Default)]
+pub struct LineEditor {
+    pub completion: CompletionEngine,
+    pub highlighter: Highlighter,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows `prompt` and reads one line. Returns `Ok(None)` at end of input
+    /// and an `Interrupted` error when the line is abandoned with Ctrl+C or
+    /// a trapped signal arrives.
+    ///
+    /// While typing, the best matching `history` entry is shown dimmed after
+    /// the cursor; Right or End accepts it and Alt+F accepts one word of it.
+    pub fn read_line(&mut self, prompt: &PromptLine, history: &Mutex<History>) -> io::Result<Option<String>> {
+        if !is_interactive() {
+            return self.read_plain_line(&prompt.left);
+        }
+
+        let raw = RawMode::enable()?;
+        // Only the last line of a multi-line prompt is redrawn while editing.
+        let (above, left) = match prompt.left.rsplit_once('\n') {
+            Some((above, last)) => (Some(above), last),
+            None => (None, prompt.left.as_str()),
+        };
+        let keymaps = keymap::keymaps().clone();
+        let history_len = history.lock().unwrap_or_else(|e| e.into_inner()).entries.len();
+        let keymap = keymap::initial_keymap();
+        let mut session = Session {
+            prompt,
+            above,
+            left,
+            rows_above: above.map_or(0, |above| above.matches('\n').count() + 1),
+            line: LineBuffer::default(),
+            suggestion: None,
+            cursor_row: 0,
+            keymap,
+            pending: Vec::new(),
+            vi: Vi::new(),
+            undo: if keymap == KeymapName::ViInsert { vec![(String::new(), 0)] } else { Vec::new() },
+            typing: false,
+            killed: String::new(),
+            history,
+            history_len,
+            history_index: history_len,
+            saved_line: String::new(),
+        };
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let mut queue = VecDeque::new();
+        self.draw_prompt(&mut session)?;
+        self.refresh(&mut session)?;
+
+        loop {
+            let key = match queue.pop_front() {
+                Some(key) => key,
+                None => match read_key() {
+                    Ok(Some(key)) => key,
+                    Ok(None) => return Ok(None),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {
+                        self.move_to_end(&mut session)?;
+                        write_out("\r\n")?;
+                        return Err(e);
+                    }
+                    Err(e) => return Err(e),
+                },
+            };
+            if key == Key::Ctrl('c') {
+                session.pending.clear();
+                session.vi.reset();
+                self.move_to_end(&mut session)?;
+                write_out("^C\r\n")?;
+                return Err(io::Error::new(ErrorKind::Interrupted, "line discarded"));
+            }
+
+            let before = (session.line.text.clone(), session.line.cursor);
+            let keymap = session.keymap;
+            match self.handle_key(&mut session, &keymaps, key, &mut queue, &raw)? {
+                Flow::Continue => session.remember(before, keymap, key),
+                Flow::Undo => match session.undo.pop() {
+                    Some((text, cursor)) => {
+                        session.line = LineBuffer { text, cursor };
+                        session.clamp_cursor();
+                    }
+                    None => write_out("\x07")?,
+                },
+                Flow::Accept => {
+                    self.finish(&mut session)?;
+                    return Ok(Some(session.line.text));
+                }
+                Flow::Eof => {
+                    write_out("\r\n")?;
+                    return Ok(None);
+                }
+            }
+
+            let line = &session.line;
+            session.suggestion = if line.cursor == line.text.len() && session.keymap != KeymapName::ViCommand {
+                let history = history.lock().unwrap_or_else(|e| e.into_inner());
+                history.suggest(&line.text, &cwd).map(|entry| entry[line.text.len()..].to_string())
+            } else {
+                None
+            };
+            self.refresh(&mut session)?;
+        }
+    }
+
+    fn read_plain_line(&self, prompt: &str) -> io::Result<Option<String>> {
+        write_out(prompt)?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+    }
+
+    /// Looks `key` up in the active keymap. Keys that start a longer binding
+    /// are held until the sequence is complete; if it turns out not to be
+    /// bound, the first key is handled on its own and the rest are queued.
+    fn handle_key(
+        &self,
+        session: &mut Session,
+        keymaps: &Keymaps,
+        key: Key,
+        queue: &mut VecDeque<Key>,
+        raw: &RawMode,
+    ) -> io::Result<Flow> {
+        if session.keymap == KeymapName::ViCommand && session.vi.is_pending() {
+            return self.vi_command(session, key);
+        }
+
+        session.pending.push(key);
+        let keymap = keymaps.get(session.keymap);
+        match keymap.lookup(&session.pending) {
+            Lookup::Prefix => return Ok(Flow::Continue),
+            Lookup::Bound(binding) => {
+                session.pending.clear();
+                return self.run_binding(session, binding, key, raw);
+            }
+            Lookup::Unbound => {}
+        }
+        let first = session.pending.remove(0);
+        for key in session.pending.drain(..).rev() {
+            queue.push_front(key);
+        }
+        if let Some(binding) = keymap.get(&[first]) {
+            return self.run_binding(session, binding, first, raw);
+        }
+
+        match (session.keymap, first) {
+            (KeymapName::ViCommand, key) if Vi::handles(key) => self.vi_command(session, key),
+            (KeymapName::ViCommand, key) => match keymaps.vi_insert.get(&[key]) {
+                Some(binding) => self.run_binding(session, binding, key, raw),
+                None => Ok(Flow::Continue),
+            },
+            // Esc followed quickly by a key arrives as Alt+key.
+            (KeymapName::ViInsert, Key::Alt(c)) => {
+                queue.push_front(Key::Char(c));
+                self.perform(session, Action::ViMovementMode, first)
+            }
+            (_, Key::Char(_)) => self.perform(session, Action::SelfInsert, first),
+            _ => Ok(Flow::Continue),
+        }
+    }
+
+    fn run_binding(&self, session: &mut Session, binding: &Binding, key: Key, raw: &RawMode) -> io::Result<Flow> {
+        match binding {
+            Binding::Action(action) => self.perform(session, *action, key),
+            Binding::Command(command) => {
+                self.run_command(session, command, raw)?;
+                Ok(Flow::Continue)
+            }
+        }
+    }
+
+    fn perform(&self, session: &mut Session, action: Action, key: Key) -> io::Result<Flow> {
+        let line = &mut session.line;
+        match action {
+            Action::SelfInsert => {
+                if let Key::Char(c) = key {
+                    if c == ' ' {
+                        expand_abbreviation(line);
+                    }
+                    line.insert(c);
+                    session.vi.record(key);
+                }
+            }
+            Action::AcceptLine => {
+                expand_abbreviation(line);
+                // An unfinished command continues on a new line instead.
+                if !command::is_incomplete(&line.text) {
+                    return Ok(Flow::Accept);
+                }
+                line.insert('\n');
+            }
+            Action::BeginningOfLine => line.cursor = line.line_start(),
+            Action::EndOfLine | Action::ForwardChar if session.suggestion.is_some() => {
+                line.insert_str(&session.suggestion.take().unwrap_or_default());
+            }
+            Action::EndOfLine => line.cursor = line.line_end(),
+            Action::ForwardChar => line.right(),
+            Action::BackwardChar => line.left(),
+            Action::ForwardWord => match &session.suggestion {
+                Some(rest) => line.insert_str(&rest[..next_word_len(rest)]),
+                None => line.word_right(),
+            },
+            Action::BackwardWord => line.word_left(),
+            Action::DeleteChar if line.text.is_empty() && key == Key::Ctrl('d') => return Ok(Flow::Eof),
+            Action::DeleteChar => line.delete(),
+            Action::BackwardDeleteChar => {
+                line.backspace();
+                session.vi.record(Key::Backspace);
+            }
+            Action::KillLine => {
+                // At the end of a line the newline itself is killed.
+                let end = match line.line_end() {
+                    end if end == line.cursor && end < line.text.len() => end + 1,
+                    end => end,
+                };
+                session.killed = line.text[line.cursor..end].to_string();
+                line.text.replace_range(line.cursor..end, "");
+            }
+            Action::UnixLineDiscard => {
+                let start = line.line_start();
+                session.killed = line.text[start..line.cursor].to_string();
+                line.replace(start, line.cursor, "");
+            }
+            Action::UnixWordRubout => {
+                let (text, end) = (line.text.clone(), line.cursor);
+                line.delete_word_before();
+                session.killed = text[line.cursor..end].to_string();
+            }
+            Action::KillWord => {
+                let end = line.cursor + next_word_len(&line.text[line.cursor..]);
+                session.killed = line.text[line.cursor..end].to_string();
+                line.replace(line.cursor, end, "");
+            }
+            Action::Yank => line.insert_str(&session.killed),
+            Action::TransposeChars => line.transpose(),
+            Action::ClearScreen => {
+                write_out("\x1b[H\x1b[2J")?;
+                self.draw_prompt(session)?;
+            }
+            // Up and Down move between the lines of a multi-line command
+            // before they move through history.
+            Action::PreviousHistory => {
+                if !line.up() {
+                    session.previous_history();
+                }
+            }
+            Action::NextHistory => {
+                if !line.down() {
+                    session.next_history();
+                }
+            }
+            Action::Complete => self.complete(session)?,
+            Action::Undo => return Ok(Flow::Undo),
+            Action::ViMovementMode => {
+                if session.keymap == KeymapName::ViInsert {
+                    session.vi.end_insert(line);
+                    session.keymap = KeymapName::ViCommand;
+                }
+            }
+            Action::ViInsertionMode => session.keymap = KeymapName::ViInsert,
+            Action::ViEditingMode => {
+                keymap::set_edit_mode(EditMode::Vi);
+                session.keymap = KeymapName::ViInsert;
+            }
+            Action::EmacsEditingMode => {
+                keymap::set_edit_mode(EditMode::Emacs);
+                session.keymap = KeymapName::Emacs;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn vi_command(&self, session: &mut Session, key: Key) -> io::Result<Flow> {
+        match session.vi.feed(key, &mut session.line) {
+            Outcome::Continue | Outcome::Pending => {}
+            Outcome::Insert => session.keymap = KeymapName::ViInsert,
+            Outcome::Undo => return Ok(Flow::Undo),
+            Outcome::PreviousHistory(count) => {
+                for _ in 0..count {
+                    if !session.line.up() {
+                        session.previous_history();
+                        session.line.cursor = 0;
+                    }
+                }
+            }
+            Outcome::NextHistory(count) => {
+                for _ in 0..count {
+                    if !session.line.down() {
+                        session.next_history();
+                        session.line.cursor = 0;
+                    }
+                }
+            }
+            Outcome::Bell => write_out("\x07")?,
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Runs a shell command bound with `bind -x`. The command sees the line
+    /// in `$READLINE_LINE` and the cursor in `$READLINE_POINT`, and changes
+    /// it makes to them are picked up afterwards.
+    fn run_command(&self, session: &mut Session, command: &str, raw: &RawMode) -> io::Result<()> {
+        let point = session.line.cursor;
+        session.suggestion = None;
+        self.move_to_end(session)?;
+        write_out("\r\n")?;
+        // SAFETY: the shell only touches its environment from this thread.
+        unsafe {
+            std::env::set_var("READLINE_LINE", &session.line.text);
+            std::env::set_var("READLINE_POINT", point.to_string());
+        }
+
+        raw.suspend();
+        match command::parse_line(command) {
+            Ok(cmd) => {
+                if let Err(e) = cmd.execute() {
+                    command::report_error(e.as_ref());
+                }
+            }
+            Err(e) => println!("Error: {}", e),
+        }
+        io::stdout().flush()?;
+        raw.resume();
+
+        if let Ok(text) = std::env::var("READLINE_LINE") {
+            session.line.text = text;
+        }
+        let text = &session.line.text;
+        session.line.cursor = std::env::var("READLINE_POINT")
+            .ok()
+            .and_then(|point| point.parse().ok())
+            .filter(|&point| point <= text.len() && text.is_char_boundary(point))
+            .unwrap_or(text.len());
+        self.draw_prompt(session)
+    }
+
+    /// Prints the lines of a multi-line prompt above the input row.
+    fn draw_prompt(&self, session: &mut Session) -> io::Result<()> {
+        session.cursor_row = 0;
+        match session.above {
+            Some(above) => write_out(&format!("\r{}\r\n", above.replace('\n', "\r\n"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Completes the word under the cursor. A unique match is inserted,
+    /// otherwise the common prefix is, and if there is none the candidates
+    /// are listed in a menu below the line.
+    fn complete(&self, session: &mut Session) -> io::Result<()> {
+        let line = &mut session.line;
+        let completions = self.completion.complete(&line.text, line.cursor);
+        match completions.candidates.as_slice() {
+            [] => write_out("\x07"),
+            [candidate] => {
+                line.replace(completions.start, completions.end, &completions.replacement(candidate));
+                Ok(())
+            }
+            candidates => {
+                let current = &line.text[completions.start..completions.end];
+                if let Some(prefix) = completions.common_prefix().filter(|prefix| prefix.len() > current.len()) {
+                    line.replace(completions.start, completions.end, &prefix);
+                    return Ok(());
+                }
+                let items: Vec<String> = candidates.iter().map(|c| c.display.clone()).collect();
+                let menu = format_columns(&items, terminal_width());
+                let cursor = line.cursor;
+                self.move_to_end(session)?;
+                session.line.cursor = cursor;
+                write_out(&format!("\r\n{}\r\n", menu.replace('\n', "\r\n")))?;
+                session.cursor_row = 0;
+                session.rows_above = 0;
+                Ok(())
+            }
+        }
+    }
+
+    /// Redraws the input rows: the right prompt if the line leaves room for
+    /// it, then the vi mode indicator, the prompt, the highlighted line and
+    /// any suggestion. Lines after the first of a multi-line command get the
+    /// continuation prompt.
+    fn refresh(&self, session: &mut Session) -> io::Result<()> {
+        let width = terminal_width();
+        let line = &session.line;
+        let suggestion = session.suggestion.as_deref().unwrap_or_default();
+        let indicator = match session.keymap {
+            KeymapName::Emacs => "",
+            KeymapName::ViInsert => &session.prompt.vi_insert,
+            KeymapName::ViCommand => &session.prompt.vi_command,
+        };
+        let continuation = &session.prompt.continuation;
+        let prompt_width = display_width(indicator) + display_width(session.left);
+        let mut widths: Vec<usize> = format!("{}{}", line.text, suggestion).split('\n').map(display_width).collect();
+        widths[0] += prompt_width;
+        for width in &mut widths[1..] {
+            *width += display_width(continuation);
+        }
+        let new_line = format!("\r\n{}", continuation);
+
+        let mut out = String::new();
+        if session.cursor_row > 0 {
+            out.push_str(&format!("\x1b[{}A", session.cursor_row));
+        }
+        out.push_str("\r\x1b[J");
+
+        let right_width = display_width(&session.prompt.right);
+        if right_width > 0 && widths[0] + 1 + right_width <= width {
+            out.push_str(&format!("\x1b[{}C{}\r", width - right_width, session.prompt.right));
+        }
+
+        out.push_str(indicator);
+        out.push_str(session.left);
+        out.push_str(&self.highlighter.highlight(&line.text).replace('\n', &new_line));
+        if !suggestion.is_empty() {
+            out.push_str(&self.highlighter.suggestion(suggestion).replace('\n', &new_line));
+        }
+
+        let cursor_line = line.text[..line.cursor].matches('\n').count();
+        let cursor_width = display_width(&line.text[line.line_start()..line.cursor])
+            + if cursor_line == 0 { prompt_width } else { display_width(continuation) };
+        let (end_row, cursor_row, cursor_column) = layout(&widths, cursor_line, cursor_width, width);
+        let end = widths.last().copied().unwrap_or_default();
+        if end > 0 && end.is_multiple_of(width.max(1)) {
+            // The terminal has not wrapped yet; force it so rows add up.
+            out.push_str("\r\n");
+        }
+        if end_row > cursor_row {
+            out.push_str(&format!("\x1b[{}A", end_row - cursor_row));
+        }
+        out.push('\r');
+        if cursor_column > 0 {
+            out.push_str(&format!("\x1b[{}C", cursor_column));
+        }
+        session.cursor_row = cursor_row;
+        write_out(&out)
+    }
+
+    /// Moves the terminal cursor to the last row of the input.
+    fn move_to_end(&self, session: &mut Session) -> io::Result<()> {
+        session.line.cursor = session.line.text.len();
+        self.refresh(session)
+    }
+
+    /// Draws the submitted line, collapsing the prompt to its transient
+    /// form if one is configured, and moves below it.
+    fn finish(&self, session: &mut Session) -> io::Result<()> {
+        session.suggestion = None;
+        match &session.prompt.transient {
+            Some(transient) => {
+                let rows_up = session.cursor_row + session.rows_above;
+                let mut out = String::new();
+                if rows_up > 0 {
+                    out.push_str(&format!("\x1b[{}A", rows_up));
+                }
+                out.push_str("\r\x1b[J");
+                out.push_str(transient);
+                let continuation = format!("\r\n{}", session.prompt.continuation);
+                out.push_str(&self.highlighter.highlight(&session.line.text).replace('\n', &continuation));
+                out.push_str("\r\n");
+                write_out(&out)
+            }
+            None => {
+                self.move_to_end(session)?;
+                write_out("\r\n")
+            }
+        }
+    }
+}
+
+fn write_out(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(text.as_bytes())?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_width_ignores_escapes() {
+        assert_eq!(display_width("shell> "), 7);
+        assert_eq!(display_width("\x1b[32mok\x1b[0m "), 3);
+    }
+
+    #[test]
+    fn test_line_buffer_editing() {
+        let mut line = LineBuffer::default();
+        for c in "ls -lä".chars() {
+            line.insert(c);
+        }
+        line.backspace();
+        line.left();
+        line.insert('a');
+        assert_eq!(line.text, "ls -al");
+
+        line.cursor = line.text.len();
+        line.delete_word_before();
+        assert_eq!(line.text, "ls ");
+        assert_eq!(line.cursor, 3);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut line = LineBuffer::default();
+        line.set("sl");
+        line.transpose();
+        assert_eq!((line.text.as_str(), line.cursor), ("ls", 2));
+        line.set("abc");
+        line.cursor = 1;
+        line.transpose();
+        assert_eq!((line.text.as_str(), line.cursor), ("bac", 2));
+        line.set("a");
+        line.transpose();
+        assert_eq!(line.text, "a");
+    }
+
+    #[test]
+    fn test_layout_wraps_rows() {
+        assert_eq!(layout(&[10], 0, 8, 80), (0, 0, 8));
+        assert_eq!(layout(&[27], 0, 27, 10), (2, 2, 7));
+        assert_eq!(layout(&[20], 0, 10, 10), (2, 1, 0));
+        assert_eq!(layout(&[0], 0, 0, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_layout_multiple_lines() {
+        assert_eq!(layout(&[12, 2, 5], 1, 2, 10), (3, 2, 2));
+        assert_eq!(layout(&[10, 3], 1, 3, 10), (1, 1, 3));
+        assert_eq!(layout(&[0, 0], 0, 0, 10), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_moving_between_lines() {
+        let mut line = LineBuffer::default();
+        line.set("if true\nthen echo yes\nfi");
+        assert_eq!((line.line_start(), line.line_end()), (22, 24));
+        assert!(line.up());
+        assert_eq!(line.cursor, 10);
+        assert_eq!((line.line_start(), line.line_end()), (8, 21));
+        assert!(line.up());
+        assert_eq!(line.cursor, 2);
+        assert!(!line.up());
+        line.cursor = 7;
+        assert!(line.down());
+        assert_eq!(line.cursor, 15);
+        assert!(line.down());
+        assert_eq!(line.cursor, 24);
+        assert!(!line.down());
+    }
+
+    #[test]
+    fn test_word_movement() {
+        let mut line = LineBuffer::default();
+        line.set("git commit  -m msg");
+        line.cursor = 0;
+        line.word_right();
+        assert_eq!(line.cursor, 3);
+        line.word_right();
+        assert_eq!(line.cursor, 10);
+        line.word_left();
+        assert_eq!(line.cursor, 4);
+        assert_eq!(next_word_len("  -m msg"), 4);
+    }
+}
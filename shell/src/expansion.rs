@@ -0,0 +1,372 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokenizer::{TokenType, Tokenizer};
+
+use crate::History;
+
+static HISTEXPAND: AtomicBool = AtomicBool::new(true);
+
+/// Whether `!` history expansion is applied to typed lines (`set -o histexpand`).
+pub fn histexpand() -> bool {
+    HISTEXPAND.load(Ordering::Relaxed)
+}
+
+pub fn set_histexpand(enable: bool) {
+    HISTEXPAND.store(enable, Ordering::Relaxed);
+}
+
+/// A line after history expansion.
+#[derive(Debug, PartialEq)]
+pub struct Expanded {
+    pub line: String,
+    /// A `:p` modifier was used: the line is shown and stored, not run.
+    pub print_only: bool,
+}
+
+/// Applies csh-style history expansion to `line`: event designators
+/// (`!!`, `!n`, `!-n`, `!prefix`, `!?text?`), word designators (`:0`,
+/// `:n`, `^`, `$`, `*`, `x-y`), modifiers (`:h`, `:t`, `:r`, `:e`, `:p`,
+/// `:s/old/new/`, `:gs/old/new/`) and the `^old^new^` quick substitution.
+///
+/// Returns `Ok(None)` if the line has nothing to expand. Nothing is
+/// expanded inside single quotes or after a backslash.
+pub fn expand_history(line: &str, history: &History) -> Result<Option<Expanded>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut parser = Parser { chars: &chars, pos: 0, history, print_only: false };
+    let mut out = String::new();
+
+    if chars.first() == Some(&'^') {
+        parser.pos = 1;
+        let event = parser.event_text(history.entries.len().checked_sub(1))?;
+        out.push_str(&parser.substitute(&event, '^', false)?);
+    }
+
+    let mut expanded = parser.pos > 0;
+    let (mut single, mut double) = (false, false);
+    while let Some(&c) = chars.get(parser.pos) {
+        parser.pos += 1;
+        match c {
+            '\\' if !single => {
+                out.push(c);
+                if let Some(&next) = chars.get(parser.pos) {
+                    out.push(next);
+                    parser.pos += 1;
+                }
+            }
+            '\'' if !double => {
+                single = !single;
+                out.push(c);
+            }
+            '"' if !single => {
+                double = !double;
+                out.push(c);
+            }
+            '!' if !single && !parser.literal_bang(double) => {
+                out.push_str(&parser.expansion()?);
+                expanded = true;
+            }
+            _ => out.push(c),
+        }
+    }
+
+    Ok(expanded.then_some(Expanded { line: out, print_only: parser.print_only }))
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+    history: &'a History,
+    print_only: bool,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Whether the `!` just read stands for itself, as before a blank, `=`,
+    /// `(` or an operator, at the end of the line, or before the closing
+    /// double quote.
+    fn literal_bang(&self, double: bool) -> bool {
+        match self.peek() {
+            None => true,
+            Some(c) => c.is_whitespace() || "=(;|&<>)".contains(c) || (double && c == '"'),
+        }
+    }
+
+    /// Parses one `!` expansion after the `!` and returns its text.
+    fn expansion(&mut self) -> Result<String, String> {
+        let start = self.pos - 1;
+        let entries = self.history.entries.len();
+        let index = match self.peek() {
+            Some('!') => {
+                self.pos += 1;
+                entries.checked_sub(1)
+            }
+            // `!$`, `!^`, `!*` and `!:n` select words of the previous command.
+            Some('$' | '^' | '*' | ':') => entries.checked_sub(1),
+            Some('-') if self.chars.get(self.pos + 1).is_some_and(char::is_ascii_digit) => {
+                self.pos += 1;
+                let n = self.number().unwrap_or_default();
+                entries.checked_sub(n)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let n = self.number().unwrap_or_default();
+                n.checked_sub(1).filter(|&i| i < entries)
+            }
+            Some('?') => {
+                self.pos += 1;
+                let text = self.take_while(|c| c != '?' && c != '\n');
+                if self.peek() == Some('?') {
+                    self.pos += 1;
+                }
+                self.history.entries.iter().rposition(|entry| entry.command.contains(&text))
+            }
+            _ => {
+                let prefix = self.take_while(|c| !c.is_whitespace() && !":;|&<>()\"'".contains(c));
+                self.history.entries.iter().rposition(|entry| entry.command.starts_with(&prefix))
+            }
+        };
+        let designator: String = self.chars[start..self.pos].iter().collect();
+        let text = self.event_text(index).map_err(|_| format!("{}: event not found", designator))?;
+
+        let words = words(&text);
+        let text = match self.word_designator(words.len()) {
+            Some((_, last)) if last >= words.len() as isize => {
+                let spec: String = self.chars[start..self.pos].iter().collect();
+                return Err(format!("{}: bad word specifier", spec));
+            }
+            // `!*` of a command without arguments selects nothing.
+            Some((first, last)) if last < first as isize => String::new(),
+            Some((first, last)) => words[first..=last as usize].join(" "),
+            None => text,
+        };
+        self.modifiers(text)
+    }
+
+    fn event_text(&self, index: Option<usize>) -> Result<String, String> {
+        index
+            .and_then(|index| self.history.entries.get(index))
+            .map(|entry| entry.command.clone())
+            .ok_or_else(|| "event not found".to_string())
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        self.take_while(|c| c.is_ascii_digit()).parse().ok()
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// Parses a word designator for an event of `count` words and returns
+    /// the first and last word it selects.
+    fn word_designator(&mut self, count: usize) -> Option<(usize, isize)> {
+        let after_colon = self.chars.get(self.pos + 1).copied();
+        match self.peek()? {
+            '^' | '$' | '*' => {}
+            ':' if after_colon.is_some_and(|c| c.is_ascii_digit() || "^$*-".contains(c)) => self.pos += 1,
+            _ => return None,
+        }
+        let end = count as isize - 1;
+        let first = match self.peek()? {
+            '^' => {
+                self.pos += 1;
+                1
+            }
+            '$' => {
+                self.pos += 1;
+                return Some((count.saturating_sub(1), end));
+            }
+            '*' => {
+                self.pos += 1;
+                return Some((1, end));
+            }
+            '-' => 0,
+            _ => self.number()?,
+        };
+        match self.peek() {
+            Some('*') => {
+                self.pos += 1;
+                Some((first, end))
+            }
+            Some('-') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some('$') => {
+                        self.pos += 1;
+                        Some((first, end))
+                    }
+                    Some(c) if c.is_ascii_digit() => Some((first, self.number()? as isize)),
+                    _ => Some((first, end - 1)),
+                }
+            }
+            _ => Some((first, first as isize)),
+        }
+    }
+
+    /// Applies any `:h`, `:t`, `:r`, `:e`, `:p`, `:s` and `:gs` modifiers.
+    fn modifiers(&mut self, mut text: String) -> Result<String, String> {
+        while self.peek() == Some(':') {
+            let Some(modifier) = self.chars.get(self.pos + 1).copied() else {
+                break;
+            };
+            self.pos += 2;
+            match modifier {
+                'h' => {
+                    if let Some(i) = text.rfind('/') {
+                        text.truncate(if i == 0 { 1 } else { i });
+                    }
+                }
+                't' => {
+                    if let Some(i) = text.rfind('/') {
+                        text = text[i + 1..].to_string();
+                    }
+                }
+                'r' => {
+                    if let Some(i) = text.rfind('.').filter(|&i| !text[i..].contains('/')) {
+                        text.truncate(i);
+                    }
+                }
+                'e' => {
+                    text = match text.rfind('.').filter(|&i| !text[i..].contains('/')) {
+                        Some(i) => text[i..].to_string(),
+                        None => String::new(),
+                    };
+                }
+                'p' => self.print_only = true,
+                's' | 'g' => {
+                    let global = modifier == 'g';
+                    if global && self.peek() != Some('s') {
+                        return Err(format!("{}: unrecognized history modifier", modifier));
+                    }
+                    if global {
+                        self.pos += 1;
+                    }
+                    let Some(delimiter) = self.peek() else {
+                        return Err("s: missing delimiter".to_string());
+                    };
+                    self.pos += 1;
+                    text = self.substitute(&text, delimiter, global)?;
+                }
+                other => return Err(format!("{}: unrecognized history modifier", other)),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Reads `old<d>new[<d>]` and replaces `old` with `new` in `text`, once
+    /// or everywhere. An `&` in `new` stands for `old`.
+    fn substitute(&mut self, text: &str, delimiter: char, global: bool) -> Result<String, String> {
+        let old = self.delimited(delimiter);
+        let new = self.delimited(delimiter).replace('&', &old);
+        if old.is_empty() || !text.contains(&old) {
+            return Err(format!("{}{}{}: substitution failed", old, delimiter, new));
+        }
+        Ok(if global { text.replace(&old, &new) } else { text.replacen(&old, &new, 1) })
+    }
+
+    /// Reads up to an unescaped `delimiter` or the end of the line and
+    /// consumes the delimiter.
+    fn delimited(&mut self, delimiter: char) -> String {
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                _ if c == delimiter => break,
+                '\\' if self.peek() == Some(delimiter) => {
+                    out.push(delimiter);
+                    self.pos += 1;
+                }
+                '\n' => {
+                    self.pos -= 1;
+                    break;
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+/// Splits a command into its words, keeping any quotes as they were typed.
+fn words(command: &str) -> Vec<String> {
+    let mut tokenizer = Tokenizer::new(command.to_string());
+    tokenizer.scan_tokens();
+    tokenizer
+        .tokens
+        .iter()
+        .filter(|token| !matches!(token.kind, TokenType::Eof | TokenType::Newline))
+        .map(|token| command[token.start..token.end].to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        let mut history = History::new();
+        history.append("cd /usr/local/lib");
+        history.append("vim src/main.rs notes.txt");
+        history.append("make install");
+        history
+    }
+
+    fn expand(line: &str) -> Result<Option<String>, String> {
+        expand_history(line, &history()).map(|expanded| expanded.map(|expanded| expanded.line))
+    }
+
+    #[test]
+    fn test_event_designators() {
+        assert_eq!(expand("sudo !!"), Ok(Some("sudo make install".to_string())));
+        assert_eq!(expand("!1"), Ok(Some("cd /usr/local/lib".to_string())));
+        assert_eq!(expand("!-2"), Ok(Some("vim src/main.rs notes.txt".to_string())));
+        assert_eq!(expand("!vi"), Ok(Some("vim src/main.rs notes.txt".to_string())));
+        assert_eq!(expand("!?local?"), Ok(Some("cd /usr/local/lib".to_string())));
+        assert_eq!(expand("!nope"), Err("!nope: event not found".to_string()));
+        assert_eq!(expand("!9"), Err("!9: event not found".to_string()));
+    }
+
+    #[test]
+    fn test_word_designators() {
+        assert_eq!(expand("ls !$"), Ok(Some("ls install".to_string())));
+        assert_eq!(expand("echo !-2:*"), Ok(Some("echo src/main.rs notes.txt".to_string())));
+        assert_eq!(expand("echo !vim:1"), Ok(Some("echo src/main.rs".to_string())));
+        assert_eq!(expand("echo !vim:^ !-2:0-1"), Ok(Some("echo src/main.rs vim src/main.rs".to_string())));
+        assert_eq!(expand("echo !vim:1-"), Ok(Some("echo src/main.rs".to_string())));
+        assert!(expand("echo !!:5").is_err());
+    }
+
+    #[test]
+    fn test_modifiers() {
+        assert_eq!(expand("cd !1:$:h"), Ok(Some("cd /usr/local".to_string())));
+        assert_eq!(expand("echo !1:$:t"), Ok(Some("echo lib".to_string())));
+        assert_eq!(expand("echo !vim:1:r !vim:1:e"), Ok(Some("echo src/main .rs".to_string())));
+        assert_eq!(expand("!!:s/install/clean/"), Ok(Some("make clean".to_string())));
+        assert_eq!(expand("!vim:gs/s/S"), Ok(Some("vim Src/main.rS noteS.txt".to_string())));
+        assert_eq!(expand("!!:s/x/y/"), Err("x/y: substitution failed".to_string()));
+
+        let expanded = expand_history("!!:p", &history()).unwrap().unwrap();
+        assert!(expanded.print_only);
+        assert_eq!(expanded.line, "make install");
+    }
+
+    #[test]
+    fn test_quick_substitution() {
+        assert_eq!(expand("^install^test^ -j4"), Ok(Some("make test -j4".to_string())));
+        assert_eq!(expand("^install^&-strip"), Ok(Some("make install-strip".to_string())));
+    }
+
+    #[test]
+    fn test_no_expansion() {
+        for line in ["echo hi", "echo 'a!!'", "echo \\!!", "[ a != b ]", "echo wow!", "echo \"wow!\""] {
+            assert_eq!(expand(line), Ok(None), "{:?}", line);
+        }
+        assert_eq!(expand("echo \"!!\""), Ok(Some("echo \"make install\"".to_string())));
+    }
+}
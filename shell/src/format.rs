@@ -0,0 +1,447 @@
+use crate::completion;
+
+/// Where backslash escapes are being interpreted. They differ in how
+/// octal escapes are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Escapes {
+    /// A `printf` format: `\NNN`.
+    Format,
+    /// `echo -e` and `printf %b`: `\0NNN`, and `\c` stops all output.
+    Echo,
+}
+
+/// Interprets the backslash escapes in `text`. Returns the result and
+/// whether a `\c` asked for output to stop there.
+pub fn unescape(text: &str, escapes: Escapes) -> (String, bool) {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let Some(next) = chars.next() else {
+            out.push('\\');
+            break;
+        };
+        match next {
+            'a' => out.push('\x07'),
+            'b' => out.push('\x08'),
+            'e' | 'E' => out.push('\x1b'),
+            'f' => out.push('\x0c'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'v' => out.push('\x0b'),
+            '\\' => out.push('\\'),
+            '"' if escapes == Escapes::Format => out.push('"'),
+            '\'' if escapes == Escapes::Format => out.push('\''),
+            'c' if escapes == Escapes::Echo => return (out, true),
+            '0'..='7' => {
+                // `\0NNN` for echo, `\NNN` in a format.
+                let (mut value, mut max) = match (escapes, next) {
+                    (Escapes::Echo, '0') => (0, 3),
+                    (Escapes::Echo, _) => {
+                        out.push('\\');
+                        out.push(next);
+                        continue;
+                    }
+                    (Escapes::Format, _) => (next.to_digit(8).unwrap_or(0), 2),
+                };
+                while max > 0
+                    && let Some(digit) = chars.peek().and_then(|c| c.to_digit(8))
+                {
+                    value = value * 8 + digit;
+                    chars.next();
+                    max -= 1;
+                }
+                out.push(char::from((value & 0xff) as u8));
+            }
+            'x' | 'u' | 'U' => {
+                let max = match next {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                let mut digits = String::new();
+                while digits.len() < max
+                    && let Some(&c) = chars.peek()
+                    && c.is_ascii_hexdigit()
+                {
+                    digits.push(c);
+                    chars.next();
+                }
+                match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+                    Some(c) => out.push(c),
+                    None => {
+                        out.push('\\');
+                        out.push(next);
+                        out.push_str(&digits);
+                    }
+                }
+            }
+            other => {
+                out.push('\\');
+                out.push(other);
+            }
+        }
+    }
+    (out, false)
+}
+
+/// Quotes `text` so that the shell reads it back as one word, like
+/// `printf %q`.
+pub fn shell_quote(text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+    if text.chars().any(|c| c.is_control()) {
+        return format!("'{}'", text.replace('\'', r"'\''"));
+    }
+    completion::quote(text, None)
+}
+
+/// One `%` conversion of a format.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: Option<usize>,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+impl Spec {
+    /// Pads `body`, whose sign or prefix is `sign`, to the field width.
+    fn pad(&self, sign: &str, body: &str, numeric: bool) -> String {
+        let len = sign.chars().count() + body.chars().count();
+        let fill = self.width.unwrap_or(0).saturating_sub(len);
+        if self.left {
+            format!("{}{}{}", sign, body, " ".repeat(fill))
+        } else if self.zero && numeric {
+            format!("{}{}{}", sign, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), sign, body)
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+}
+
+/// The result of running a `printf` format.
+#[derive(Debug, Default, PartialEq)]
+pub struct Printed {
+    pub output: String,
+    /// Arguments that were not valid numbers, as messages.
+    pub errors: Vec<String>,
+}
+
+/// Formats `args` with `format` like `printf`: escapes in the format are
+/// interpreted, each conversion consumes one argument (missing ones count
+/// as empty or zero), and the format is reused until every argument has
+/// been consumed.
+pub fn printf(format: &str, args: &[String]) -> Result<Printed, String> {
+    let mut printed = Printed::default();
+    let mut args = args.iter();
+    loop {
+        let before = args.len();
+        if run_format(format, &mut args, &mut printed)? {
+            break;
+        }
+        // Stop once the arguments are used up, or if the format takes none.
+        if args.len() == 0 || args.len() == before {
+            break;
+        }
+    }
+    Ok(printed)
+}
+
+/// Runs the format once. Returns whether `\c` stopped all output.
+fn run_format(format: &str, args: &mut std::slice::Iter<String>, printed: &mut Printed) -> Result<bool, String> {
+    let mut rest = format;
+    while let Some(percent) = rest.find('%') {
+        let (literal, stop) = unescape(&rest[..percent], Escapes::Format);
+        printed.output.push_str(&literal);
+        if stop {
+            return Ok(true);
+        }
+        rest = &rest[percent + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            printed.output.push('%');
+            rest = after;
+            continue;
+        }
+
+        let mut spec = Spec::default();
+        let mut chars = rest.char_indices().peekable();
+        while let Some(&(_, c)) = chars.peek() {
+            match c {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alternate = true,
+                '0' => spec.zero = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        // A width or precision, literal or from an argument for `*`. Like
+        // bash, anything beyond an int is refused rather than padded to.
+        let mut number = |chars: &mut std::iter::Peekable<std::str::CharIndices>, printed: &mut Printed, what: &str| {
+            let (value, text) = if chars.peek().is_some_and(|&(_, c)| c == '*') {
+                chars.next();
+                let arg = args.next().map_or("", String::as_str);
+                (integer(arg, printed), arg.to_string())
+            } else {
+                let mut digits = String::new();
+                while let Some(&(_, c)) = chars.peek()
+                    && c.is_ascii_digit()
+                {
+                    digits.push(c);
+                    chars.next();
+                }
+                if digits.is_empty() {
+                    return Ok(None);
+                }
+                (digits.parse().unwrap_or(i64::MAX), digits)
+            };
+            if value.unsigned_abs() > i32::MAX as u64 {
+                return Err(format!("{}: invalid {}", text, what));
+            }
+            Ok(Some(value))
+        };
+        if let Some(width) = number(&mut chars, printed, "field width")? {
+            if width < 0 {
+                spec.left = true;
+            }
+            spec.width = Some(width.unsigned_abs() as usize);
+        }
+        if chars.peek().is_some_and(|&(_, c)| c == '.') {
+            chars.next();
+            spec.precision = Some(number(&mut chars, printed, "precision")?.unwrap_or(0).max(0) as usize);
+        }
+        // Length modifiers mean nothing here.
+        while chars.peek().is_some_and(|&(_, c)| matches!(c, 'h' | 'l' | 'L' | 'j' | 'z' | 't')) {
+            chars.next();
+        }
+        let Some((index, conversion)) = chars.next() else {
+            return Err("missing format character".to_string());
+        };
+        spec.conversion = conversion;
+        rest = &rest[index + conversion.len_utf8()..];
+
+        let arg = args.next().map_or("", String::as_str);
+        if convert(&spec, arg, printed)? {
+            return Ok(true);
+        }
+    }
+    let (literal, stop) = unescape(rest, Escapes::Format);
+    printed.output.push_str(&literal);
+    Ok(stop)
+}
+
+/// Appends `arg` converted by `spec`. Returns whether a `\c` in a `%b`
+/// argument stopped all output.
+fn convert(spec: &Spec, arg: &str, printed: &mut Printed) -> Result<bool, String> {
+    let truncate = |text: &str| match spec.precision {
+        Some(precision) => text.chars().take(precision).collect(),
+        None => text.to_string(),
+    };
+    let text = match spec.conversion {
+        's' => spec.pad("", &truncate(arg), false),
+        'q' => spec.pad("", &shell_quote(arg), false),
+        'b' => {
+            let (text, stop) = unescape(arg, Escapes::Echo);
+            printed.output.push_str(&spec.pad("", &truncate(&text), false));
+            return Ok(stop);
+        }
+        'c' => spec.pad("", &arg.chars().next().map(String::from).unwrap_or_default(), false),
+        'd' | 'i' => {
+            let value = integer(arg, printed);
+            let mut digits = value.unsigned_abs().to_string();
+            if let Some(precision) = spec.precision {
+                digits = format!("{:0>width$}", digits, width = precision);
+            }
+            spec.pad(spec.sign(value < 0), &digits, spec.precision.is_none())
+        }
+        'o' | 'u' | 'x' | 'X' => {
+            let value = integer(arg, printed) as u64;
+            let mut digits = match spec.conversion {
+                'o' => format!("{:o}", value),
+                'u' => value.to_string(),
+                'x' => format!("{:x}", value),
+                _ => format!("{:X}", value),
+            };
+            if let Some(precision) = spec.precision {
+                digits = format!("{:0>width$}", digits, width = precision);
+            }
+            let prefix = match spec.conversion {
+                'o' if spec.alternate && !digits.starts_with('0') => "0",
+                'x' if spec.alternate && value != 0 => "0x",
+                'X' if spec.alternate && value != 0 => "0X",
+                _ => "",
+            };
+            spec.pad(prefix, &digits, spec.precision.is_none())
+        }
+        'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+            let value = float(arg, printed);
+            let body = format_float(value.abs(), spec);
+            spec.pad(spec.sign(value.is_sign_negative() && value != 0.0), &body, value.is_finite())
+        }
+        other => return Err(format!("%{}: invalid format character", other)),
+    };
+    printed.output.push_str(&text);
+    Ok(false)
+}
+
+/// Reads a numeric argument. A leading quote gives the code of the
+/// character after it. Invalid numbers count as 0 and are reported.
+fn integer(arg: &str, printed: &mut Printed) -> i64 {
+    let trimmed = arg.trim();
+    if let Some(rest) = trimmed.strip_prefix(['\'', '"']) {
+        return rest.chars().next().map_or(0, |c| c as i64);
+    }
+    let (negative, digits) = match trimmed.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    match parsed {
+        Ok(value) if negative => -value,
+        Ok(value) => value,
+        Err(_) if trimmed.is_empty() => 0,
+        Err(_) => {
+            printed.errors.push(format!("{}: invalid number", arg));
+            0
+        }
+    }
+}
+
+fn float(arg: &str, printed: &mut Printed) -> f64 {
+    let trimmed = arg.trim();
+    if trimmed.starts_with(['\'', '"']) {
+        return integer(arg, printed) as f64;
+    }
+    match trimmed.parse() {
+        Ok(value) => value,
+        Err(_) if trimmed.is_empty() => 0.0,
+        Err(_) => {
+            printed.errors.push(format!("{}: invalid number", arg));
+            0.0
+        }
+    }
+}
+
+/// Formats a non-negative `value` for `%f`, `%e` or `%g`.
+fn format_float(value: f64, spec: &Spec) -> String {
+    let upper = spec.conversion.is_ascii_uppercase();
+    if !value.is_finite() {
+        let text = if value.is_nan() { "nan" } else { "inf" };
+        return if upper { text.to_uppercase() } else { text.to_string() };
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let text = match spec.conversion.to_ascii_lowercase() {
+        'f' => format!("{:.*}", precision, value),
+        'e' => exponent(value, precision),
+        _ => {
+            // %g picks %e or %f by the exponent, then drops trailing zeros.
+            let precision = precision.max(1);
+            let exp = exponent(value, precision - 1);
+            let power: i32 = exp.rsplit_once('e').and_then(|(_, power)| power.parse().ok()).unwrap_or(0);
+            let text = if power < -4 || power >= precision as i32 {
+                exp
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - power) as usize, value)
+            };
+            if spec.alternate { text } else { trim_zeros(&text) }
+        }
+    };
+    if upper { text.to_uppercase() } else { text }
+}
+
+/// `value` in C's `%e` form, e.g. `1.500000e+00`.
+fn exponent(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, power) = text.split_once('e').unwrap_or((&text, "0"));
+    let power: i32 = power.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if power < 0 { '-' } else { '+' }, power.abs())
+}
+
+/// Removes trailing zeros after the decimal point, and the point itself.
+fn trim_zeros(text: &str) -> String {
+    let (number, exponent) = match text.find('e') {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let number = if number.contains('.') { number.trim_end_matches('0').trim_end_matches('.') } else { number };
+    format!("{}{}", number, exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printf_ok(format: &str, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let printed = printf(format, &args).unwrap();
+        assert_eq!(printed.errors, Vec::<String>::new());
+        printed.output
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r"a\tb\n", Escapes::Format), ("a\tb\n".to_string(), false));
+        assert_eq!(unescape(r"\101\x42☺", Escapes::Format), ("AB\u{263a}".to_string(), false));
+        assert_eq!(unescape(r"\0101\101", Escapes::Echo), (r"A\101".to_string(), false));
+        assert_eq!(unescape(r"one\ctwo", Escapes::Echo), ("one".to_string(), true));
+        assert_eq!(unescape(r"\q\", Escapes::Echo), (r"\q\".to_string(), false));
+    }
+
+    #[test]
+    fn test_printf_conversions() {
+        assert_eq!(printf_ok("%s-%5s|%-5s|%.2s\n", &["a", "b", "c", "xyz"]), "a-    b|c    |xy\n");
+        assert_eq!(printf_ok("%d %+d %05d %.3d %x %#o %#X", &["42", "7", "-42", "5", "255", "8", "255"]), "42 +7 -0042 005 ff 010 0XFF");
+        assert_eq!(printf_ok("%*d|%-*d|", &["4", "1", "3", "2"]), "   1|2  |");
+        assert_eq!(printf_ok("%c%c %d", &["hello", "w", "'A"]), "hw 65");
+        assert_eq!(printf_ok("%.2f %e %g %g %G", &["3.14159", "1500", "0.0001", "1e10", "2.5"]), "3.14 1.500000e+03 0.0001 1e+10 2.5");
+        assert_eq!(printf_ok("%b|%s", &[r"a\tb", r"a\tb"]), "a\tb|a\\tb");
+        assert_eq!(printf_ok("100%%\n", &[]), "100%\n");
+    }
+
+    #[test]
+    fn test_printf_reuses_format() {
+        assert_eq!(printf_ok("%s=%s\n", &["a", "1", "b"]), "a=1\nb=\n");
+        assert_eq!(printf_ok("x\n", &["ignored"]), "x\n");
+        assert_eq!(printf_ok("%s %b\n", &["a", r"stop\c", "b", "c"]), "a stop");
+    }
+
+    #[test]
+    fn test_printf_quote_and_errors() {
+        assert_eq!(printf_ok("%q %q %q", &["a b", "", "it's"]), r"a\ b '' it\'s");
+        let printed = printf("%d|%d", &["12abc".to_string(), "3".to_string()]).unwrap();
+        assert_eq!(printed.output, "0|3");
+        assert_eq!(printed.errors, vec!["12abc: invalid number".to_string()]);
+        assert!(printf("%y", &[]).is_err());
+        assert_eq!(printf("%*d|", &["99999999999".to_string(), "1".to_string()]).err(), Some("99999999999: invalid field width".to_string()));
+        assert!(printf("%.99999999999f", &["1".to_string()]).is_err());
+    }
+}
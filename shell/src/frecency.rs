@@ -0,0 +1,299 @@
+use std::{fs::OpenOptions, io::{self, BufRead, BufReader, Write}, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::history::{self, History};
+use crate::vars;
+
+/// Once the ranks in the database add up to more than this, all of them
+/// are aged so that directories that are no longer visited drop out.
+pub const MAX_TOTAL_RANK: f64 = 9000.0;
+/// Entries whose rank falls below this when aging are forgotten.
+const MIN_RANK: f64 = 1.0;
+
+/// A directory that was changed into: how often (decayed by aging) and
+/// when last, in seconds since the epoch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub rank: f64,
+    pub time: u64,
+}
+
+impl DirEntry {
+    /// Ranks the entry by how often and how recently it was visited: visits
+    /// in the last hour count four times, in the last day twice, in the
+    /// last week half and older ones a quarter.
+    pub fn frecency(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.time);
+        match age {
+            0..3600 => self.rank * 4.0,
+            3600..86400 => self.rank * 2.0,
+            86400..604800 => self.rank / 2.0,
+            _ => self.rank / 4.0,
+        }
+    }
+}
+
+/// How candidates are ordered by [`Database::matches`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    #[default]
+    Frecency,
+    /// Only by how often a directory was visited (`z -r`).
+    Rank,
+    /// Only by when a directory was last visited (`z -t`).
+    Recent,
+}
+
+/// The directories the shell has changed into, stored one `path|rank|time`
+/// line each, the format `z` uses.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    pub entries: Vec<DirEntry>,
+}
+
+impl Database {
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let mut fields = line.rsplitn(3, '|');
+            let (Some(time), Some(rank), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            if let (Ok(rank), Ok(time)) = (rank.parse(), time.parse()) {
+                entries.push(DirEntry { path: PathBuf::from(path), rank, time });
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for entry in &self.entries {
+            writeln!(out, "{}|{}|{}", entry.path.display(), entry.rank, entry.time)?;
+        }
+        out.flush()
+    }
+
+    /// Counts a visit to `dir` at `now`, aging all entries once the total
+    /// rank grows past [`MAX_TOTAL_RANK`].
+    pub fn visit(&mut self, dir: &Path, now: u64) {
+        match self.entries.iter_mut().find(|entry| entry.path == dir) {
+            Some(entry) => {
+                entry.rank += 1.0;
+                entry.time = now;
+            }
+            None => self.entries.push(DirEntry { path: dir.to_path_buf(), rank: 1.0, time: now }),
+        }
+        if self.entries.iter().map(|entry| entry.rank).sum::<f64>() > MAX_TOTAL_RANK {
+            for entry in &mut self.entries {
+                entry.rank *= 0.99;
+            }
+            self.entries.retain(|entry| entry.rank >= MIN_RANK);
+        }
+    }
+
+    /// Forgets directories that no longer exist. Returns whether any were.
+    pub fn prune(&mut self) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.path.is_dir());
+        self.entries.len() != len
+    }
+
+    /// Forgets `dir`. Returns whether it was known.
+    pub fn remove(&mut self, dir: &Path) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.path != dir);
+        self.entries.len() != len
+    }
+
+    /// The entries whose path contains all of `fragments` in order, best
+    /// first. Matching is case-sensitive if any fragment has an upper-case
+    /// letter, and the last fragment has to match in the last component.
+    pub fn matches(&self, fragments: &[String], order: Order, now: u64) -> Vec<(f64, &DirEntry)> {
+        let ignore_case = !fragments.iter().any(|fragment| fragment.chars().any(char::is_uppercase));
+        let mut matches: Vec<(f64, &DirEntry)> = self
+            .entries
+            .iter()
+            .filter(|entry| matches_fragments(&entry.path, fragments, ignore_case))
+            .map(|entry| {
+                let score = match order {
+                    Order::Frecency => entry.frecency(now),
+                    Order::Rank => entry.rank,
+                    Order::Recent => entry.time as f64,
+                };
+                (score, entry)
+            })
+            .collect();
+        matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+        matches
+    }
+}
+
+fn matches_fragments(path: &Path, fragments: &[String], ignore_case: bool) -> bool {
+    let fold = |text: &str| if ignore_case { text.to_lowercase() } else { text.to_string() };
+    let path = fold(&path.to_string_lossy());
+    let mut rest = path.as_str();
+    for fragment in fragments {
+        let fragment = fold(fragment);
+        match rest.find(&fragment) {
+            Some(start) => rest = &rest[start + fragment.len()..],
+            None => return false,
+        }
+    }
+    // The last fragment should name the directory, not one of its parents.
+    fragments.last().is_none_or(|last| {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        name.contains(&fold(last)) || last.contains('/')
+    })
+}
+
+/// The database file: `$MSH_Z_DATA` if set, else `.msh_z` next to the
+/// history file. `None` if it is set but empty, or the history file is
+/// disabled. Tests default to a file in the temporary directory, so they
+/// never touch the user's database.
+pub fn database_path() -> Option<PathBuf> {
+    match vars::get("MSH_Z_DATA") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None if cfg!(test) => Some(std::env::temp_dir().join("msh-z-test")),
+        None => History::get_history_file_path().map(|path| path.with_file_name(".msh_z")),
+    }
+}
+
+/// Whether visits to `dir` are not recorded: the home directory, and
+/// directories matching one of the colon-separated globs in
+/// `$MSH_Z_EXCLUDE`.
+pub fn is_excluded(dir: &Path) -> bool {
+    if dirs::home_dir().is_some_and(|home| home == dir) {
+        return true;
+    }
+    let exclude = vars::get("MSH_Z_EXCLUDE").unwrap_or_default();
+    let dir = dir.to_string_lossy();
+    exclude.split(':').filter(|pattern| !pattern.is_empty()).any(|pattern| history::glob_match(pattern, &dir))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Reads the database at `path`. A missing file is an empty database.
+pub fn load(path: &Path) -> io::Result<Database> {
+    match std::fs::File::open(path) {
+        Ok(file) => Database::read(BufReader::new(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Database::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Lets `edit` change the database at `path` while it is locked, and
+/// replaces the file if it returns `true`.
+pub fn update(path: &Path, edit: impl FnOnce(&mut Database) -> bool) -> io::Result<()> {
+    let file = history::open_locked(path)?;
+    let mut database = Database::read(BufReader::new(&file))?;
+    if !edit(&mut database) {
+        return Ok(());
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    let mut out = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+    database.write(&mut out)?;
+    std::fs::rename(&temp, path)
+}
+
+/// Records that the shell changed into `dir`, unless it is excluded or
+/// the database is disabled. Failures are reported, not returned, so they
+/// never fail the `cd` itself.
+pub fn record(dir: &Path) {
+    let Some(path) = database_path() else {
+        return;
+    };
+    if is_excluded(dir) {
+        return;
+    }
+    if let Err(e) = update(&path, |database| {
+        database.visit(dir, now());
+        true
+    }) {
+        eprintln!("Failed to update {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, rank: f64, time: u64) -> DirEntry {
+        DirEntry { path: PathBuf::from(path), rank, time }
+    }
+
+    #[test]
+    fn test_frecency_prefers_recent_visits() {
+        let now = 10_000_000;
+        assert_eq!(entry("/a", 2.0, now - 60).frecency(now), 8.0);
+        assert_eq!(entry("/a", 2.0, now - 7200).frecency(now), 4.0);
+        assert_eq!(entry("/a", 2.0, now - 2 * 86400).frecency(now), 1.0);
+        assert_eq!(entry("/a", 2.0, now - 30 * 86400).frecency(now), 0.5);
+    }
+
+    #[test]
+    fn test_matches_fragments_in_order() {
+        let database = Database {
+            entries: vec![
+                entry("/home/me/src/shell", 10.0, 0),
+                entry("/home/me/src/shell/target", 20.0, 0),
+                entry("/home/me/Documents", 5.0, 0),
+                entry("/srv/shell-old", 1.0, 100),
+            ],
+        };
+        let paths = |fragments: &[&str], order| {
+            let fragments: Vec<String> = fragments.iter().map(|f| f.to_string()).collect();
+            database.matches(&fragments, order, 100).iter().map(|(_, e)| e.path.display().to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&["shell"], Order::Rank), vec!["/home/me/src/shell", "/srv/shell-old"]);
+        assert_eq!(paths(&["src", "tar"], Order::Frecency), vec!["/home/me/src/shell/target"]);
+        assert!(paths(&["tar", "src"], Order::Frecency).is_empty());
+        assert_eq!(paths(&["doc"], Order::Frecency), vec!["/home/me/Documents"]);
+        assert!(paths(&["Doc", "x"], Order::Frecency).is_empty());
+        assert!(paths(&["DOC"], Order::Frecency).is_empty());
+        assert_eq!(paths(&["shell"], Order::Recent)[0], "/srv/shell-old");
+    }
+
+    #[test]
+    fn test_visit_and_aging() {
+        let mut database = Database::default();
+        database.visit(Path::new("/a"), 10);
+        database.visit(Path::new("/a"), 20);
+        assert_eq!(database.entries, vec![entry("/a", 2.0, 20)]);
+
+        database.entries.push(entry("/b", MAX_TOTAL_RANK, 0));
+        database.visit(Path::new("/c"), 30);
+        assert_eq!(database.entries.len(), 2);
+        assert!(database.entries.iter().all(|e| e.path != Path::new("/c")));
+        assert!((database.entries[0].rank - 1.98).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_read_write_and_prune() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("msh-z-missing-{}", std::process::id()));
+        let database = Database { entries: vec![entry(&dir.to_string_lossy(), 1.5, 7), entry(&missing.to_string_lossy(), 3.0, 9)] };
+        let mut out = Vec::new();
+        database.write(&mut out).unwrap();
+        let mut read = Database::read(&out[..]).unwrap();
+        assert_eq!(read.entries, database.entries);
+        assert!(read.prune());
+        assert_eq!(read.entries.len(), 1);
+        assert!(!read.prune());
+    }
+
+    #[test]
+    fn test_record_uses_database_path() {
+        let path = database_path().unwrap();
+        assert!(path.starts_with(std::env::temp_dir()));
+        let dir = std::env::temp_dir().join(format!("msh-z-record-{}", std::process::id()));
+        record(&dir);
+        assert!(load(&path).unwrap().entries.iter().any(|e| e.path == dir));
+        update(&path, |database| database.remove(&dir)).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use tokenizer::{TokenType, Tokenizer};
+
+use crate::abbr;
+use crate::alias;
+use crate::command::BUILTINS;
+use crate::lookup;
+use crate::vars;
+
+const RESET: &str = "\x1b[0m";
+
+/// The colours used for each kind of word, as ANSI SGR parameters such as
+/// `"32"` or `"1;31"`. An empty string leaves that kind uncoloured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub command: String,
+    pub unknown_command: String,
+    pub argument: String,
+    pub flag: String,
+    pub string: String,
+    pub variable: String,
+    pub operator: String,
+    pub error: String,
+    /// Used for the history suggestion shown after the cursor.
+    pub suggestion: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            command: "32".to_string(),
+            unknown_command: "31".to_string(),
+            argument: String::new(),
+            flag: "36".to_string(),
+            string: "33".to_string(),
+            variable: "35".to_string(),
+            operator: "1;34".to_string(),
+            error: "1;41".to_string(),
+            suggestion: "90".to_string(),
+        }
+    }
+}
+
+impl Theme {
+    /// The default theme with overrides from `$MSH_HIGHLIGHT`, a
+    /// colon-separated list like `command=1;32:flag=36:string=`.
+    pub fn from_env() -> Self {
+        let mut theme = Self::default();
+        if let Some(spec) = vars::get("MSH_HIGHLIGHT") {
+            for entry in spec.split(':') {
+                if let Some((kind, color)) = entry.split_once('=') {
+                    theme.set(kind.trim(), color.trim());
+                }
+            }
+        }
+        theme
+    }
+
+    /// Sets the colour of `kind`. Returns `false` for an unknown kind.
+    pub fn set(&mut self, kind: &str, color: &str) -> bool {
+        let slot = match kind {
+            "command" => &mut self.command,
+            "unknown_command" => &mut self.unknown_command,
+            "argument" => &mut self.argument,
+            "flag" => &mut self.flag,
+            "string" => &mut self.string,
+            "variable" => &mut self.variable,
+            "operator" => &mut self.operator,
+            "error" => &mut self.error,
+            "suggestion" => &mut self.suggestion,
+            _ => return false,
+        };
+        *slot = color.to_string();
+        true
+    }
+}
+
+/// Colours the input line using the classification from [`Tokenizer`].
+#[derive(Clone, Debug)]
+pub struct Highlighter {
+    pub theme: Theme,
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Self { theme: Theme::from_env() }
+    }
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Colouring is off when `$NO_COLOR` is set to a non-empty value.
+    pub fn enabled(&self) -> bool {
+        vars::get("NO_COLOR").is_none_or(|value| value.is_empty())
+    }
+
+    /// Returns `line` with ANSI colours added. The visible text is unchanged.
+    pub fn highlight(&self, line: &str) -> String {
+        if !self.enabled() || line.is_empty() {
+            return line.to_string();
+        }
+
+        let mut tokenizer = Tokenizer::new(line.to_string());
+        tokenizer.scan_tokens();
+
+        let mut out = String::with_capacity(line.len() * 2);
+        let mut last = 0;
+        for token in &tokenizer.tokens {
+            if token.kind == TokenType::Eof || token.start < last {
+                continue;
+            }
+            out.push_str(&line[last..token.start]);
+            let text = &line[token.start..token.end];
+            match token.kind {
+                TokenType::Cmd => {
+                    let color = if command_exists(&token.lexeme) {
+                        &self.theme.command
+                    } else {
+                        &self.theme.unknown_command
+                    };
+                    self.push_word(&mut out, text, color);
+                }
+                TokenType::Flag | TokenType::LongFlag | TokenType::LongFlagWithValue => {
+                    paint(&mut out, text, &self.theme.flag)
+                }
+                TokenType::Pipe
+                | TokenType::InputRedir
+                | TokenType::OutputRedir
+                | TokenType::Background
+                | TokenType::And
+                | TokenType::Or
+                | TokenType::Semicolon => paint(&mut out, text, &self.theme.operator),
+                TokenType::Keyword => paint(&mut out, text, &self.theme.command),
+                TokenType::Newline => out.push_str(text),
+                TokenType::Arg => self.push_word(&mut out, text, &self.theme.argument),
+                TokenType::Eof => {}
+            }
+            last = token.end;
+        }
+        out.push_str(&line[last..]);
+        out
+    }
+
+    /// Paints a history suggestion. Without colours it is shown dim so it
+    /// still stands apart from the typed text.
+    pub fn suggestion(&self, text: &str) -> String {
+        let mut out = String::new();
+        paint(&mut out, text, if self.enabled() { &self.theme.suggestion } else { "2" });
+        out
+    }
+
+    /// Paints a word, picking out quoted strings and `$variables` inside it.
+    /// A quote left open runs to the end of the word in the error colour.
+    fn push_word(&self, out: &mut String, word: &str, color: &str) {
+        let mut plain_start = 0;
+        let mut chars = word.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let segment_end = match c {
+                '\\' => {
+                    chars.next();
+                    continue;
+                }
+                '\'' | '"' => {
+                    let end = word[i + 1..].find(c).map(|j| i + 1 + j + 1);
+                    paint(out, &word[plain_start..i], color);
+                    match end {
+                        Some(end) => {
+                            self.push_string(out, &word[i..end], c);
+                            end
+                        }
+                        None => {
+                            paint(out, &word[i..], &self.theme.error);
+                            word.len()
+                        }
+                    }
+                }
+                '$' => {
+                    let len = variable_len(&word[i..]);
+                    if len <= 1 {
+                        continue;
+                    }
+                    paint(out, &word[plain_start..i], color);
+                    paint(out, &word[i..i + len], &self.theme.variable);
+                    i + len
+                }
+                _ => continue,
+            };
+            plain_start = segment_end;
+            while chars.peek().is_some_and(|&(j, _)| j < segment_end) {
+                chars.next();
+            }
+        }
+        paint(out, &word[plain_start..], color);
+    }
+
+    /// Paints a complete quoted string; double-quoted ones may contain
+    /// variables.
+    fn push_string(&self, out: &mut String, string: &str, quote: char) {
+        if quote == '\'' {
+            return paint(out, string, &self.theme.string);
+        }
+        let mut plain_start = 0;
+        let mut i = 0;
+        while i < string.len() {
+            if string[i..].starts_with('$') {
+                let len = variable_len(&string[i..]);
+                if len > 1 {
+                    paint(out, &string[plain_start..i], &self.theme.string);
+                    paint(out, &string[i..i + len], &self.theme.variable);
+                    i += len;
+                    plain_start = i;
+                    continue;
+                }
+            }
+            i += string[i..].chars().next().map_or(1, char::len_utf8);
+        }
+        paint(out, &string[plain_start..], &self.theme.string);
+    }
+}
+
+/// The length of a `$NAME`, `${NAME}` or `$?`-style reference at the start
+/// of `text`, or 1 if the `$` does not start one.
+fn variable_len(text: &str) -> usize {
+    let rest = &text[1..];
+    if let Some(braced) = rest.strip_prefix('{') {
+        return braced.find('}').map_or(1, |end| end + 3);
+    }
+    if rest.starts_with(['?', '$', '!', '#', '@', '*']) || rest.starts_with(|c: char| c.is_ascii_digit()) {
+        return 2;
+    }
+    1 + rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len())
+}
+
+fn paint(out: &mut String, text: &str, color: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if color.is_empty() {
+        out.push_str(text);
+    } else {
+        out.push_str(&format!("\x1b[{}m{}{}", color, text, RESET));
+    }
+}
+
+fn command_exists(name: &str) -> bool {
+    BUILTINS.contains(&name) || alias::get(name).is_some() || abbr::get(name).is_some() || lookup::find(name).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theme() -> Theme {
+        Theme {
+            command: "C".to_string(),
+            unknown_command: "U".to_string(),
+            argument: String::new(),
+            flag: "F".to_string(),
+            string: "S".to_string(),
+            variable: "V".to_string(),
+            operator: "O".to_string(),
+            error: "E".to_string(),
+            suggestion: "G".to_string(),
+        }
+    }
+
+    /// Renders colours as `<code|text>` so expectations stay readable.
+    fn highlight(line: &str) -> String {
+        let colored = Highlighter { theme: theme() }.highlight(line).replace(RESET, ">");
+        let mut out = String::new();
+        let mut parts = colored.split("\x1b[");
+        out.push_str(parts.next().unwrap_or_default());
+        for part in parts {
+            let (code, rest) = part.split_once('m').unwrap();
+            out.push_str(&format!("<{}|{}", code, rest));
+        }
+        out
+    }
+
+    #[test]
+    fn test_highlight_commands_flags_and_operators() {
+        assert_eq!(highlight("cd -L /tmp | not_a_real_command_xyz"), "<C|cd> <F|-L> /tmp <O||> <U|not_a_real_command_xyz>");
+    }
+
+    #[test]
+    fn test_highlight_strings_and_variables() {
+        assert_eq!(highlight(r#"cd "$HOME/x" 'y'"#), r#"<C|cd> <S|"><V|$HOME><S|/x"> <S|'y'>"#);
+        assert_eq!(highlight("cd $HOME"), "<C|cd> <V|$HOME>");
+    }
+
+    #[test]
+    fn test_highlight_unterminated_quote() {
+        assert_eq!(highlight("cd 'abc"), "<C|cd> <E|'abc>");
+    }
+
+    #[test]
+    fn test_theme_set() {
+        let mut theme = Theme::default();
+        assert!(theme.set("flag", "1;36"));
+        assert_eq!(theme.flag, "1;36");
+        assert!(!theme.set("nope", "1"));
+    }
+}
@@ -0,0 +1,245 @@
+//! The terminal's foreground process group. An interactive shell runs in
+//! its own group and ignores the keyboard signals, handing the terminal to
+//! each command it runs so that Ctrl+C and Ctrl+\ reach the command
+//! instead of the shell. There is no job control, so Ctrl+Z stops nothing.
+
+use std::{os::unix::process::{CommandExt, ExitStatusExt}, process::ExitStatus, sync::{atomic::{AtomicBool, AtomicI32, Ordering}, Mutex}};
+
+use crate::{command::Unwind, trap::{self, Condition}};
+
+/// The signals the terminal sends from the keyboard, plus the ones it sends
+/// to background groups that use it.
+const KEYBOARD_SIGNALS: [i32; 5] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+/// The keyboard signals that the commands the shell runs get back. The
+/// ones that stop a program stay ignored, as the shell could not resume a
+/// stopped program and would wait for it forever.
+const FOREGROUND_SIGNALS: [i32; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The shell's own process group.
+static SHELL_GROUP: AtomicI32 = AtomicI32::new(0);
+/// The group that had the terminal before the shell took it.
+static ORIGINAL_GROUP: AtomicI32 = AtomicI32::new(0);
+/// Whether Ctrl+C stopped a program of the command line that is running.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The programs in the group that has the terminal.
+static PROGRAMS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+/// The group of the programs that have the terminal, or 0 while the shell
+/// has it. Programs that the stages of a pipeline start join it.
+static FOREGROUND_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the shell hands the terminal to the commands it runs.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Puts the shell in its own process group in the foreground of the
+/// terminal on standard input, and ignores the keyboard signals that are
+/// not trapped.
+pub fn init() {
+    // SAFETY: plain system calls on the shell's own process and terminal.
+    unsafe {
+        let original = libc::tcgetpgrp(libc::STDIN_FILENO);
+        if original < 0 {
+            return;
+        }
+        for signal in KEYBOARD_SIGNALS {
+            if trap::get(Condition::Signal(signal)).is_none() {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+        }
+        // Fails for a session leader, which already leads its own group.
+        libc::setpgid(0, 0);
+        let group = libc::getpgrp();
+        libc::tcsetpgrp(libc::STDIN_FILENO, group);
+        ORIGINAL_GROUP.store(original, Ordering::SeqCst);
+        SHELL_GROUP.store(group, Ordering::SeqCst);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Gives the terminal back to the group that had it before `init`.
+pub fn release() {
+    if !ENABLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let original = ORIGINAL_GROUP.load(Ordering::SeqCst);
+    if original != SHELL_GROUP.load(Ordering::SeqCst) {
+        // SAFETY: the shell ignores SIGTTOU, so this cannot stop it.
+        unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, original) };
+    }
+}
+
+/// What the shell does with `signal` when it is not trapped: the keyboard
+/// signals are ignored while the shell owns the terminal.
+pub fn default_handler(signal: i32) -> libc::sighandler_t {
+    if enabled() && KEYBOARD_SIGNALS.contains(&signal) {
+        libc::SIG_IGN
+    } else {
+        libc::SIG_DFL
+    }
+}
+
+/// Makes `command` run in the foreground: in the group of the programs
+/// that have the terminal, or else in a new process group, with Ctrl+C and
+/// Ctrl+\ restored unless they are trapped with an empty action.
+pub fn foreground(command: &mut std::process::Command) {
+    if !enabled() {
+        return;
+    }
+    let group = FOREGROUND_GROUP.load(Ordering::SeqCst);
+    let restore = FOREGROUND_SIGNALS.map(|signal| trap::get(Condition::Signal(signal)).is_none_or(|action| !action.is_empty()));
+    // SAFETY: only async-signal-safe calls run between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpgid(0, group);
+            // Also done by the shell after the fork; whichever runs first
+            // wins the race with a command that reads the terminal at once.
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+            for (signal, restore) in FOREGROUND_SIGNALS.iter().zip(restore) {
+                if restore {
+                    libc::signal(*signal, libc::SIG_DFL);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Restores the default keyboard signals for a program that replaces the
+/// shell with `exec`.
+pub fn default_signals(command: &mut std::process::Command) {
+    if !enabled() {
+        return;
+    }
+    // SAFETY: only async-signal-safe calls run between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            for signal in KEYBOARD_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Whether programs have the terminal rather than the shell.
+pub fn terminal_given() -> bool {
+    FOREGROUND_GROUP.load(Ordering::SeqCst) != 0
+}
+
+/// Puts the process `pid` that `foreground` started in its group. Returns
+/// whether that is a new group, which then gets the terminal until
+/// `take_terminal`.
+pub fn give_terminal(pid: u32) -> bool {
+    if !enabled() {
+        return false;
+    }
+    let pid = pid as libc::pid_t;
+    let group = FOREGROUND_GROUP.load(Ordering::SeqCst);
+    PROGRAMS.lock().unwrap_or_else(|e| e.into_inner()).push(pid);
+    // SAFETY: plain system calls on a child of the shell.
+    unsafe {
+        if group != 0 {
+            libc::setpgid(pid, group);
+            return false;
+        }
+        libc::setpgid(pid, pid);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pid);
+    }
+    FOREGROUND_GROUP.store(pid, Ordering::SeqCst);
+    true
+}
+
+/// Takes the terminal back once the foreground programs have finished.
+pub fn take_terminal() {
+    if enabled() {
+        FOREGROUND_GROUP.store(0, Ordering::SeqCst);
+        PROGRAMS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        // SAFETY: the shell ignores SIGTTOU, so this cannot stop it.
+        unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, SHELL_GROUP.load(Ordering::SeqCst)) };
+    }
+}
+
+/// The status the shell reports for a finished command: its exit code, or
+/// 128 plus the signal that killed it, e.g. 130 for Ctrl+C.
+pub fn status_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Reacts to a command that `signal` killed as if the shell had received
+/// it: a trapped signal runs its trap, and an untrapped Ctrl+C ends the
+/// line the command was printing on and abandons the command line.
+pub fn killed_by(status: ExitStatus) {
+    let Some(signal) = status.signal() else {
+        return;
+    };
+    let interrupt = signal == libc::SIGINT && enabled();
+    // Once for every program that the same Ctrl+C stopped.
+    if interrupt && !INTERRUPTED.load(Ordering::SeqCst) {
+        eprintln!();
+    }
+    if trap::get(Condition::Signal(signal)).is_some_and(|action| !action.is_empty()) {
+        trap::raise(signal);
+    } else if interrupt {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Fails once Ctrl+C stopped a program, so that the loops and lists
+/// around it stop too, as they would in a shell that Ctrl+C killed. This
+/// includes the programs of a pipeline that a loop in the shell reads
+/// from, which have not been waited for yet.
+pub fn check_interrupt() -> Result<(), Unwind> {
+    let mut programs = PROGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    // Programs that were already waited for are gone.
+    programs.retain(|pid| exit_info(*pid).is_some());
+    let killed = programs.iter().filter_map(|pid| exit_info(*pid)).any(|info| {
+        // SAFETY: waitid filled in `info` for a child that changed state.
+        info.si_code == libc::CLD_KILLED && unsafe { info.si_status() } == libc::SIGINT
+    });
+    if killed && !INTERRUPTED.swap(true, Ordering::SeqCst) {
+        eprintln!();
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(Unwind::Interrupt);
+    }
+    Ok(())
+}
+
+/// How the child `pid` ended, left for it to be waited for: `si_pid` is 0
+/// while it runs, and None once it was waited for.
+fn exit_info(pid: libc::pid_t) -> Option<libc::siginfo_t> {
+    // SAFETY: `info` is plain data that waitid fills in.
+    unsafe {
+        let mut info = std::mem::zeroed::<libc::siginfo_t>();
+        let result = libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOHANG | libc::WNOWAIT);
+        (result == 0).then_some(info)
+    }
+}
+
+/// Forgets a Ctrl+C once the command line it stopped is over.
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_code_of_killed_command() {
+        let status = std::process::Command::new("sleep").arg("5").spawn().and_then(|mut child| {
+            // SAFETY: signals a child of this process.
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+            child.wait()
+        });
+        assert_eq!(status_code(status.unwrap()), 130);
+        let status = std::process::Command::new("false").status().unwrap();
+        assert_eq!(status_code(status), 1);
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+use crate::completion::quote;
+use crate::editor::{decode_key, Key};
+
+/// The editing style chosen with `set -o emacs` or `set -o vi`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+/// The keymaps that bindings can be added to. Vi command mode is handled
+/// by [`crate::vi`]; its keymap only holds user bindings, which take
+/// precedence over the built-in vi commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeymapName {
+    Emacs,
+    ViInsert,
+    ViCommand,
+}
+
+impl KeymapName {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "emacs" | "emacs-standard" => Some(Self::Emacs),
+            "vi-insert" => Some(Self::ViInsert),
+            "vi" | "vi-command" | "vi-move" => Some(Self::ViCommand),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Emacs => "emacs",
+            Self::ViInsert => "vi-insert",
+            Self::ViCommand => "vi-command",
+        }
+    }
+}
+
+/// An editor action that keys can be bound to, named as in readline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    SelfInsert,
+    AcceptLine,
+    BeginningOfLine,
+    EndOfLine,
+    ForwardChar,
+    BackwardChar,
+    ForwardWord,
+    BackwardWord,
+    DeleteChar,
+    BackwardDeleteChar,
+    KillLine,
+    UnixLineDiscard,
+    UnixWordRubout,
+    KillWord,
+    Yank,
+    TransposeChars,
+    ClearScreen,
+    PreviousHistory,
+    NextHistory,
+    Complete,
+    Undo,
+    ViMovementMode,
+    ViInsertionMode,
+    ViEditingMode,
+    EmacsEditingMode,
+}
+
+const ACTIONS: &[(&str, Action)] = &[
+    ("self-insert", Action::SelfInsert),
+    ("accept-line", Action::AcceptLine),
+    ("beginning-of-line", Action::BeginningOfLine),
+    ("end-of-line", Action::EndOfLine),
+    ("forward-char", Action::ForwardChar),
+    ("backward-char", Action::BackwardChar),
+    ("forward-word", Action::ForwardWord),
+    ("backward-word", Action::BackwardWord),
+    ("delete-char", Action::DeleteChar),
+    ("backward-delete-char", Action::BackwardDeleteChar),
+    ("kill-line", Action::KillLine),
+    ("unix-line-discard", Action::UnixLineDiscard),
+    ("unix-word-rubout", Action::UnixWordRubout),
+    ("kill-word", Action::KillWord),
+    ("yank", Action::Yank),
+    ("transpose-chars", Action::TransposeChars),
+    ("clear-screen", Action::ClearScreen),
+    ("previous-history", Action::PreviousHistory),
+    ("next-history", Action::NextHistory),
+    ("complete", Action::Complete),
+    ("undo", Action::Undo),
+    ("vi-movement-mode", Action::ViMovementMode),
+    ("vi-insertion-mode", Action::ViInsertionMode),
+    ("vi-editing-mode", Action::ViEditingMode),
+    ("emacs-editing-mode", Action::EmacsEditingMode),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ACTIONS.iter().find(|(n, _)| *n == name).map(|&(_, action)| action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTIONS.iter().find(|(_, a)| *a == self).map_or("", |(name, _)| name)
+    }
+
+    /// The names of all actions, for `bind -l`.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        ACTIONS.iter().map(|(name, _)| *name)
+    }
+}
+
+/// What a key sequence does: run an editor action, or run a shell command
+/// with the line available in `$READLINE_LINE` and `$READLINE_POINT`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+    Action(Action),
+    Command(String),
+}
+
+/// The result of looking up the keys typed so far.
+#[derive(Debug, PartialEq)]
+pub enum Lookup<'a> {
+    Bound(&'a Binding),
+    /// The keys start a longer binding; wait for more.
+    Prefix,
+    Unbound,
+}
+
+/// Maps key sequences to bindings.
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    bindings: HashMap<Vec<Key>, Binding>,
+}
+
+impl Keymap {
+    fn with_actions(actions: &[(&[Key], Action)]) -> Self {
+        let mut keymap = Self::default();
+        for (keys, action) in actions {
+            keymap.bind(keys.to_vec(), Binding::Action(*action));
+        }
+        keymap
+    }
+
+    /// The default emacs bindings.
+    pub fn emacs() -> Self {
+        use Action::*;
+        Self::with_actions(&[
+            (&[Key::Enter], AcceptLine),
+            (&[Key::Tab], Complete),
+            (&[Key::Ctrl('a')], BeginningOfLine),
+            (&[Key::Home], BeginningOfLine),
+            (&[Key::Ctrl('e')], EndOfLine),
+            (&[Key::End], EndOfLine),
+            (&[Key::Ctrl('f')], ForwardChar),
+            (&[Key::Right], ForwardChar),
+            (&[Key::Ctrl('b')], BackwardChar),
+            (&[Key::Left], BackwardChar),
+            (&[Key::Alt('f')], ForwardWord),
+            (&[Key::Alt('b')], BackwardWord),
+            (&[Key::Ctrl('d')], DeleteChar),
+            (&[Key::Delete], DeleteChar),
+            (&[Key::Backspace], BackwardDeleteChar),
+            (&[Key::Ctrl('k')], KillLine),
+            (&[Key::Ctrl('u')], UnixLineDiscard),
+            (&[Key::Ctrl('w')], UnixWordRubout),
+            (&[Key::Alt('d')], KillWord),
+            (&[Key::Ctrl('y')], Yank),
+            (&[Key::Ctrl('t')], TransposeChars),
+            (&[Key::Ctrl('l')], ClearScreen),
+            (&[Key::Ctrl('p')], PreviousHistory),
+            (&[Key::Up], PreviousHistory),
+            (&[Key::Ctrl('n')], NextHistory),
+            (&[Key::Down], NextHistory),
+            (&[Key::Ctrl('_')], Undo),
+            (&[Key::Ctrl('x'), Key::Ctrl('u')], Undo),
+        ])
+    }
+
+    /// The default bindings of vi insert mode.
+    pub fn vi_insert() -> Self {
+        use Action::*;
+        Self::with_actions(&[
+            (&[Key::Enter], AcceptLine),
+            (&[Key::Tab], Complete),
+            (&[Key::Esc], ViMovementMode),
+            (&[Key::Home], BeginningOfLine),
+            (&[Key::End], EndOfLine),
+            (&[Key::Right], ForwardChar),
+            (&[Key::Left], BackwardChar),
+            (&[Key::Ctrl('d')], DeleteChar),
+            (&[Key::Delete], DeleteChar),
+            (&[Key::Backspace], BackwardDeleteChar),
+            (&[Key::Ctrl('u')], UnixLineDiscard),
+            (&[Key::Ctrl('w')], UnixWordRubout),
+            (&[Key::Ctrl('y')], Yank),
+            (&[Key::Ctrl('t')], TransposeChars),
+            (&[Key::Ctrl('l')], ClearScreen),
+            (&[Key::Up], PreviousHistory),
+            (&[Key::Down], NextHistory),
+        ])
+    }
+
+    pub fn bind(&mut self, keys: Vec<Key>, binding: Binding) {
+        self.bindings.insert(keys, binding);
+    }
+
+    /// Removes the binding of `keys`. Returns `false` if there was none.
+    pub fn unbind(&mut self, keys: &[Key]) -> bool {
+        self.bindings.remove(keys).is_some()
+    }
+
+    /// The binding of exactly `keys`.
+    pub fn get(&self, keys: &[Key]) -> Option<&Binding> {
+        self.bindings.get(keys)
+    }
+
+    /// Looks up the keys typed so far. Keys that are bound but also start a
+    /// longer binding report [`Lookup::Prefix`].
+    pub fn lookup(&self, keys: &[Key]) -> Lookup<'_> {
+        if self.bindings.keys().any(|bound| bound.len() > keys.len() && bound.starts_with(keys)) {
+            return Lookup::Prefix;
+        }
+        match self.bindings.get(keys) {
+            Some(binding) => Lookup::Bound(binding),
+            None => Lookup::Unbound,
+        }
+    }
+
+    /// All bindings, ordered by key sequence.
+    pub fn bindings(&self) -> Vec<(&[Key], &Binding)> {
+        let mut bindings: Vec<_> = self.bindings.iter().map(|(keys, binding)| (keys.as_slice(), binding)).collect();
+        bindings.sort_by_cached_key(|(keys, _)| format_key_sequence(keys));
+        bindings
+    }
+}
+
+/// The keymaps the line editor uses.
+#[derive(Clone, Debug)]
+pub struct Keymaps {
+    pub emacs: Keymap,
+    pub vi_insert: Keymap,
+    pub vi_command: Keymap,
+}
+
+impl Default for Keymaps {
+    fn default() -> Self {
+        Self { emacs: Keymap::emacs(), vi_insert: Keymap::vi_insert(), vi_command: Keymap::default() }
+    }
+}
+
+impl Keymaps {
+    pub fn get(&self, name: KeymapName) -> &Keymap {
+        match name {
+            KeymapName::Emacs => &self.emacs,
+            KeymapName::ViInsert => &self.vi_insert,
+            KeymapName::ViCommand => &self.vi_command,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: KeymapName) -> &mut Keymap {
+        match name {
+            KeymapName::Emacs => &mut self.emacs,
+            KeymapName::ViInsert => &mut self.vi_insert,
+            KeymapName::ViCommand => &mut self.vi_command,
+        }
+    }
+}
+
+static EDIT_MODE: Mutex<EditMode> = Mutex::new(EditMode::Emacs);
+static KEYMAPS: LazyLock<Mutex<Keymaps>> = LazyLock::new(|| Mutex::new(Keymaps::default()));
+
+pub fn edit_mode() -> EditMode {
+    *EDIT_MODE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn set_edit_mode(mode: EditMode) {
+    *EDIT_MODE.lock().unwrap_or_else(|e| e.into_inner()) = mode;
+}
+
+/// The keymap that new lines start in for the current edit mode.
+pub fn initial_keymap() -> KeymapName {
+    match edit_mode() {
+        EditMode::Emacs => KeymapName::Emacs,
+        EditMode::Vi => KeymapName::ViInsert,
+    }
+}
+
+/// The keymaps, including bindings added with `bind`.
+pub fn keymaps() -> MutexGuard<'static, Keymaps> {
+    KEYMAPS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Parses a readline-style key sequence such as `\C-x\C-e`, `\ef` or
+/// `\e[A`. `\C-` and `\M-` prefix a key with Control or Meta, and `\e`,
+/// `\t`, `\r`, `\n`, `\\`, `\"` and `\'` stand for themselves.
+pub fn parse_key_sequence(text: &str) -> Result<Vec<Key>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('C') if chars.peek() == Some(&'-') => {
+                chars.next();
+                match chars.next() {
+                    Some('?') => bytes.push(0x7f),
+                    Some(c) if c.is_ascii() => bytes.push(c.to_ascii_lowercase() as u8 & 0x1f),
+                    _ => return Err(format!("invalid control key in `{}`", text)),
+                }
+            }
+            Some('M') if chars.peek() == Some(&'-') => {
+                chars.next();
+                bytes.push(0x1b);
+            }
+            Some('e') => bytes.push(0x1b),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some(c @ ('\\' | '"' | '\'')) => bytes.push(c as u8),
+            Some(c) => return Err(format!("unknown escape `\\{}` in `{}`", c, text)),
+            None => return Err(format!("trailing backslash in `{}`", text)),
+        }
+    }
+
+    let mut bytes = bytes.into_iter();
+    let mut keys = Vec::new();
+    while let Some(key) = decode_key(&mut |_| Ok(bytes.next())).map_err(|e| e.to_string())? {
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err("empty key sequence".to_string());
+    }
+    Ok(keys)
+}
+
+/// Formats keys so that [`parse_key_sequence`] reads them back.
+pub fn format_key_sequence(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| match key {
+            Key::Char(c) => escape_char(*c),
+            Key::Ctrl(c) => format!("\\C-{}", c),
+            Key::Alt(c) => format!("\\e{}", escape_char(*c)),
+            Key::Enter => "\\r".to_string(),
+            Key::Tab => "\\t".to_string(),
+            Key::BackTab => "\\e[Z".to_string(),
+            Key::Backspace => "\\C-?".to_string(),
+            Key::Delete => "\\e[3~".to_string(),
+            Key::Left => "\\e[D".to_string(),
+            Key::Right => "\\e[C".to_string(),
+            Key::Up => "\\e[A".to_string(),
+            Key::Down => "\\e[B".to_string(),
+            Key::Home => "\\e[H".to_string(),
+            Key::End => "\\e[F".to_string(),
+            Key::Esc => "\\e".to_string(),
+            Key::Unknown => String::new(),
+        })
+        .collect()
+}
+
+fn escape_char(c: char) -> String {
+    match c {
+        '\\' | '"' | '\'' => format!("\\{}", c),
+        c => c.to_string(),
+    }
+}
+
+/// The `bind` command that recreates a binding, for listing bindings in a
+/// form that can be read back.
+pub fn bind_command(keymap: Option<KeymapName>, keys: &[Key], binding: &Binding) -> String {
+    let mut command = "bind".to_string();
+    if let Some(keymap) = keymap {
+        command.push_str(&format!(" -m {}", keymap.name()));
+    }
+    let value = match binding {
+        Binding::Action(action) => action.name().to_string(),
+        Binding::Command(shell_command) => {
+            command.push_str(" -x");
+            shell_command.clone()
+        }
+    };
+    let spec = format!("\"{}\": {}", format_key_sequence(keys), value);
+    format!("{} {}'", command, quote(&spec, Some('\'')))
+}
+
+/// Splits a binding such as `"\C-a": beginning-of-line` into its key
+/// sequence and value. Quotes around the value are removed.
+pub fn parse_binding(spec: &str) -> Result<(Vec<Key>, String), String> {
+    let spec = spec.trim();
+    let (keys, rest) = match spec.strip_prefix('"') {
+        Some(quoted) => {
+            let mut escaped = false;
+            let end = quoted
+                .char_indices()
+                .find(|&(_, c)| {
+                    let closing = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    closing
+                })
+                .map(|(i, _)| i)
+                .ok_or_else(|| format!("unterminated key sequence: {}", spec))?;
+            (&quoted[..end], &quoted[end + 1..])
+        }
+        None => spec.split_at(spec.find(':').ok_or_else(|| format!("missing `:` in binding: {}", spec))?),
+    };
+    let value = rest
+        .trim_start()
+        .strip_prefix(':')
+        .ok_or_else(|| format!("missing `:` in binding: {}", spec))?
+        .trim();
+    let value = ['"', '\'']
+        .iter()
+        .find_map(|&q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+        .unwrap_or(value);
+    Ok((parse_key_sequence(keys)?, value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_sequence() {
+        assert_eq!(parse_key_sequence(r"\C-x\C-e"), Ok(vec![Key::Ctrl('x'), Key::Ctrl('e')]));
+        assert_eq!(parse_key_sequence(r"\ef\M-b"), Ok(vec![Key::Alt('f'), Key::Alt('b')]));
+        assert_eq!(parse_key_sequence(r"\e[A\t\C-?"), Ok(vec![Key::Up, Key::Tab, Key::Backspace]));
+        assert_eq!(parse_key_sequence("jk"), Ok(vec![Key::Char('j'), Key::Char('k')]));
+        assert!(parse_key_sequence(r"\q").is_err());
+        assert!(parse_key_sequence("").is_err());
+    }
+
+    #[test]
+    fn test_format_key_sequence_round_trips() {
+        for text in [r"\C-a", r"\ef", r"\e[A\e[3~", r#"a\"\\"#, r"\C-_", r"\r\t\C-?"] {
+            let keys = parse_key_sequence(text).unwrap();
+            assert_eq!(format_key_sequence(&keys), text);
+        }
+    }
+
+    #[test]
+    fn test_parse_binding() {
+        assert_eq!(
+            parse_binding(r#""\C-a": beginning-of-line"#),
+            Ok((vec![Key::Ctrl('a')], "beginning-of-line".to_string()))
+        );
+        assert_eq!(parse_binding(r#""\":x" : "git status""#), Ok((vec![Key::Char('"'), Key::Char(':'), Key::Char('x')], "git status".to_string())));
+        assert_eq!(parse_binding(r"\C-g:abort"), Ok((vec![Key::Ctrl('g')], "abort".to_string())));
+        assert!(parse_binding(r#""\C-a" beginning-of-line"#).is_err());
+    }
+
+    #[test]
+    fn test_bind_command() {
+        let keys = parse_key_sequence(r"\C-g").unwrap();
+        assert_eq!(
+            bind_command(None, &keys, &Binding::Command("echo 'hi'".to_string())),
+            r#"bind -x '"\C-g": echo '\''hi'\'''"#
+        );
+        assert_eq!(
+            bind_command(Some(KeymapName::ViCommand), &keys, &Binding::Action(Action::Undo)),
+            r#"bind -m vi-command '"\C-g": undo'"#
+        );
+    }
+
+    #[test]
+    fn test_lookup_prefixes() {
+        let mut keymap = Keymap::emacs();
+        keymap.bind(vec![Key::Char('j'), Key::Char('k')], Binding::Action(Action::ViMovementMode));
+
+        assert_eq!(keymap.lookup(&[Key::Ctrl('x')]), Lookup::Prefix);
+        assert_eq!(keymap.lookup(&[Key::Ctrl('x'), Key::Ctrl('u')]), Lookup::Bound(&Binding::Action(Action::Undo)));
+        assert_eq!(keymap.lookup(&[Key::Char('j')]), Lookup::Prefix);
+        assert_eq!(keymap.lookup(&[Key::Char('q')]), Lookup::Unbound);
+    }
+
+    #[test]
+    fn test_action_names() {
+        assert_eq!(Action::from_name("kill-line"), Some(Action::KillLine));
+        assert_eq!(Action::KillLine.name(), "kill-line");
+        assert_eq!(Action::names().count(), ACTIONS.len());
+    }
+}
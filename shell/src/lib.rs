@@ -1,91 +1,91 @@
+pub mod abbr;
+pub mod alias;
 pub mod command;
+pub mod completion;
+pub mod conditional;
+pub mod cwd;
+pub mod editor;
+pub mod expansion;
+pub mod format;
+pub mod frecency;
+pub mod highlight;
+pub mod history;
+pub mod job;
+pub mod keymap;
+pub mod lookup;
+pub mod prompt;
+pub mod read;
+pub mod trap;
+pub mod vars;
+pub mod vi;
 
-use std::{fs::File, io::{BufRead, BufReader, ErrorKind, Write}, path::PathBuf, process, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
-use command::CommandParser;
-use tokenizer::Tokenizer;
+use std::{io::{ErrorKind, Write}, path::PathBuf, sync::{atomic::{AtomicBool, AtomicI32, Ordering}, Arc, LazyLock, Mutex}, time::{Duration, Instant, SystemTime}};
+use command::{exit_status, is_unwind, Unwind};
+use completion::Completer;
+use editor::{LineEditor, PromptLine};
+pub use history::{History, HistoryEntry};
+use prompt::{Prompt, PromptContext};
 
 static RUNNING: AtomicBool = AtomicBool::new(true);
+/// The status `exit` asked the shell to exit with.
+static EXIT_STATUS: AtomicI32 = AtomicI32::new(0);
+static VERBOSE: LazyLock<AtomicBool> =
+    LazyLock::new(|| AtomicBool::new(std::env::var_os("MSH_VERBOSE").is_some_and(|v| !v.is_empty())));
 
-pub struct History {
-    pub commands: Vec<String>,
+/// Whether the shell reports what it does behind the scenes, such as
+/// loading and saving history (`set -o verbose`, or `$MSH_VERBOSE` at
+/// startup).
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
 }
 
-impl Iterator for History {
-    type Item = String;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.commands.pop()
-    }
+pub fn set_verbose(enable: bool) {
+    VERBOSE.store(enable, Ordering::Relaxed);
 }
 
-impl History {
-    pub fn new() -> Self {
-        Self { commands: Vec::new() }
-    }
-
-    pub fn append<T: ToString>(&mut self, command: T) {
-        self.commands.push(command.to_string());
-    }
-
-    pub fn clear(&mut self) {
-        self.commands.clear();
-    }
-
-    fn get_history_file_path() -> PathBuf {
-        let mut path = if let Some(home) = dirs::home_dir() {
-            home
-        } else {
-            PathBuf::from(".")
-        };
-        path.push(".msh_history");
-        path
-    }
-
-    pub fn load_from_disk() -> Result<Self, Box<dyn std::error::Error>> {
-        let history_path = Self::get_history_file_path();
-        let mut history = Self::new();
-        if let Ok(file) = File::open(&history_path) {
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                history.commands.push(line?);
-            }
-        } else {
-            println!("No history file found at {}", history_path.display());
-        }
-
-        Ok(history)
-    }
-
-    pub fn save(&self) {
-        let history_path = Self::get_history_file_path();
-        if let Ok(mut file) = File::create(&history_path) {
-            for command in self.commands.iter() {
-                let _ = writeln!(file, "{}", command);
-            }
-            let _ = file.flush();
-            println!("History saved to {}", history_path.display());
-        } else {
-            eprintln!("Failed to save history to {}", history_path.display());
-        }
-    }
+/// Makes the shell exit with `status` instead of reading another line,
+/// e.g. for `exit` in the rc file.
+pub fn request_exit(status: i32) {
+    EXIT_STATUS.store(status, Ordering::SeqCst);
+    RUNNING.store(false, Ordering::SeqCst);
 }
 
 pub struct Shell {
     pub base_path: String, 
     pub history: Arc<Mutex<History>>,
+    /// The directories saved by `pushd`, most recent first.
+    pub dir_stack: Arc<Mutex<Vec<PathBuf>>>,
+    pub editor: LineEditor,
+    pub prompt: Prompt,
+    /// Exit status of the last command line.
+    pub last_status: i32,
+    /// How long the last command line ran.
+    pub last_duration: Option<Duration>,
+    /// Identifies this shell's entries in the history file.
+    pub session_id: String,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shell {
     pub fn new() -> Self {
-        let history = Arc::new(Mutex::new(History::new()));
-        let history_clone = Arc::clone(&history);
+        if editor::is_interactive() {
+            job::init();
+        }
+        let history = history::shared();
+        *history.lock().unwrap_or_else(|e| e.into_inner()) = History::load_from_disk().unwrap_or_default();
 
-        ctrlc::set_handler(move || {
-            println!("\nReceived Ctrl+C! Saving history and exiting...");
-            if let Ok(history) = history_clone.lock() {
-                history.save();
-            }
-            process::exit(0);
-        }).expect("Error setting Ctrl+C handler");
+        for error in completion::load_spec_dir(&completion::spec_dir()) {
+            eprintln!("{}", error);
+        }
+        for error in command::source(&command::rc_path()) {
+            eprintln!("{}", error);
+        }
+        abbr::set_persistent(true);
 
         Self { 
             base_path: std::env::current_dir()
@@ -93,75 +93,206 @@ impl Shell {
                 .to_str()
                 .unwrap_or(".")
                 .to_string(), 
-            history 
+            history,
+            dir_stack: cwd::stack(),
+            editor: LineEditor::new(),
+            prompt: Prompt::new(),
+            last_status: 0,
+            last_duration: None,
+            session_id: history::new_session_id(),
         }
     }
 
+    /// Registers a tab completer for the arguments of `command`.
+    pub fn register_completer<C: Completer + 'static>(&mut self, command: &str, completer: C) {
+        self.editor.completion.register(command, completer);
+    }
+
+    /// Registers a completion function that `complete -F <name>` can use.
+    pub fn register_completion_function<C: Completer + Send + 'static>(&mut self, name: &str, completer: C) {
+        completion::register_function(name, completer);
+    }
+
     pub fn put_line(&self, msg: &str) {
         print!("{}", msg);
         let _ = std::io::stdout().flush();
     } 
 
     pub fn put_prefixed_line(&self, msg: &str) {
-        print!("shell> {}", msg);
+        print!("{}{}", self.prompt.primary(&self.prompt_context()), msg);
         let _ = std::io::stdout().flush();
     }
 
+    /// Reads and runs command lines until `exit` or the end of input, then
+    /// runs the `EXIT` trap. The status to exit with is left in
+    /// `last_status`.
     pub fn eval(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("History will be saved to {}", History::get_history_file_path().display());
-        self.put_prefixed_line("");
+        if verbose()
+            && let Some(path) = self.history.lock().ok().and_then(|history| history.path.clone())
+        {
+            println!("History will be saved to {}", path.display());
+        }
         while RUNNING.load(Ordering::SeqCst) {
-            match self.read_line() {
-                Some(line) => {
-                    let trimmed = line.trim();
-                    if trimmed == "exit" || trimmed == "quit" {
-                        println!("\nGoodbye!");
-                        if let Ok(history) = self.history.lock() {
-                            history.save();
-                        }
-                        return Ok(());
+            job::clear_interrupt();
+            if let Err(e) = trap::run_pending() {
+                self.unwind(e.as_ref());
+                continue;
+            }
+            let line = match self.read_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                    // Ctrl+C discarded the line, unless a trapped signal
+                    // cut the read short.
+                    if !trap::signal_pending() {
+                        self.last_status = 130;
                     }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-                    let mut tokenizer = Tokenizer::new(line);
-                    tokenizer.scan_tokens();
-                    
-                    let mut parser = CommandParser::new(tokenizer.tokens);
-                    match parser.parse() {
-                        Ok(cmd) => {
-                            if let Err(e) = cmd.execute() {
-                                println!("Error: {}", e);
-                            }
-                            if let Ok(mut history) = self.history.lock() {
-                                // Ignore history commands
-                                if cmd.get_name() != "history" {
-                                    history.append(cmd.to_string());
-                                }
-                            }
+            let Some(line) = self.expand_history(line) else {
+                continue;
+            };
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            match command::parse_line(&line) {
+                Ok(cmd) => {
+                    let cwd = std::env::current_dir().ok();
+                    let started_at = SystemTime::now();
+                    let started = Instant::now();
+                    let mut result = cmd.execute();
+                    self.last_duration = Some(started.elapsed());
+                    if !is_unwind(&result) && exit_status(&result) != 0 {
+                        if let Err(e) = &result {
+                            command::report_error(e.as_ref());
+                        }
+                        result = trap::run_err().and(result);
+                    }
+                    match &result {
+                        Err(e) if e.is::<Unwind>() => self.unwind(e.as_ref()),
+                        _ => self.last_status = exit_status(&result),
+                    }
+                    if let Ok(mut history) = self.history.lock() {
+                        // Ignore history commands and what HISTCONTROL/HISTIGNORE exclude
+                        if cmd.get_name() != "history" && !history.ignores(&line) {
+                            history.add(HistoryEntry {
+                                command: trimmed.to_string(),
+                                started: Some(started_at),
+                                duration: self.last_duration,
+                                cwd,
+                                status: Some(self.last_status),
+                                session: Some(self.session_id.clone()),
+                            });
                         }
-                        Err(e) => println!("Error: {}", e),
                     }
-                    
-                    self.put_prefixed_line("");
                 }
-                None => {
-                    self.put_prefixed_line("");
+                Err(e) => {
+                    self.last_status = 2;
+                    println!("Error: {}", e);
                 }
             }
         }
+        if let Err(e) = trap::run_exit() {
+            self.unwind(e.as_ref());
+        }
+        if !RUNNING.load(Ordering::SeqCst) {
+            self.last_status = EXIT_STATUS.load(Ordering::SeqCst);
+        }
+        job::release();
+        println!("\nGoodbye!");
         Ok(())
     }
 
-    fn read_line(&self) -> Option<String> { 
-        let mut line = String::new();
-        match std::io::stdin().read_line(&mut line) {
-            Ok(_) => Some(line),
+    /// Handles `exit` or `return` that reached the top level. `return`
+    /// outside a sourced file only sets the status.
+    fn unwind(&mut self, error: &(dyn std::error::Error + 'static)) {
+        let Some(unwind) = error.downcast_ref::<Unwind>() else {
+            return;
+        };
+        let status = unwind.status().unwrap_or(self.last_status);
+        self.last_status = status;
+        if let Unwind::Exit(_) = unwind {
+            request_exit(status);
+        }
+    }
+
+    /// Applies `!` history expansion to a typed line and echoes the result.
+    /// Returns `None` if the line should not run: expansion failed or a
+    /// `:p` modifier asked only to print it.
+    fn expand_history(&mut self, line: String) -> Option<String> {
+        if !editor::is_interactive() || !expansion::histexpand() {
+            return Some(line);
+        }
+        let Ok(mut history) = self.history.lock() else {
+            return Some(line);
+        };
+        match expansion::expand_history(&line, &history) {
+            Ok(None) => Some(line),
+            Ok(Some(expanded)) => {
+                println!("{}", expanded.line);
+                if !expanded.print_only {
+                    return Some(expanded.line);
+                }
+                if history.ignores(&expanded.line) {
+                    return None;
+                }
+                history.add(HistoryEntry {
+                    command: expanded.line.trim().to_string(),
+                    cwd: std::env::current_dir().ok(),
+                    session: Some(self.session_id.clone()),
+                    ..Default::default()
+                });
+                None
+            }
             Err(e) => {
-                if e.kind() == ErrorKind::Interrupted {
-                    None
-                } else {
-                    Some(line)
+                println!("Error: {}", e);
+                self.last_status = 1;
+                None
+            }
+        }
+    }
+
+    /// The state that prompt escapes such as `\?` are rendered from.
+    pub fn prompt_context(&self) -> PromptContext {
+        PromptContext { last_status: self.last_status, jobs: 0, duration: self.last_duration }
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        if history::share_history()
+            && let Ok(mut history) = self.history.lock()
+            && let Err(e) = history.import(&self.session_id)
+        {
+            eprintln!("Failed to read history: {}", e);
+        }
+        let history = &*self.history;
+        let ctx = self.prompt_context();
+        let prompt = PromptLine {
+            left: self.prompt.primary(&ctx),
+            right: self.prompt.right(&ctx),
+            continuation: self.prompt.continuation(&ctx),
+            transient: self.prompt.transient(&ctx),
+            vi_insert: self.prompt.vi_mode(&ctx, false),
+            vi_command: self.prompt.vi_mode(&ctx, true),
+        };
+        let Some(mut line) = self.editor.read_line(&prompt, history)? else {
+            return Ok(None);
+        };
+        // The editor keeps unfinished commands open itself; input that is
+        // not a terminal arrives one line at a time.
+        while command::is_incomplete(&line) {
+            match self.editor.read_line(&PromptLine::from(prompt.continuation.as_str()), history)? {
+                Some(more) => {
+                    line.push('\n');
+                    line.push_str(&more);
                 }
+                None => break,
             }
         }
+        Ok(Some(line))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,338 @@
+use std::{collections::{BTreeMap, BTreeSet}, ffi::{OsStr, OsString}, os::unix::process::CommandExt, path::PathBuf, sync::{LazyLock, Mutex, MutexGuard}, time::SystemTime};
+
+use crate::alias;
+use crate::command::BUILTINS;
+use crate::completion::{complete_commands, find_executable, is_executable};
+
+static CACHE: LazyLock<Mutex<PathCache>> = LazyLock::new(|| Mutex::new(PathCache::default()));
+
+/// What a command name refers to, in the order the shell tries them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolution {
+    /// An alias and the text it stands for.
+    Alias(String),
+    Keyword,
+    Builtin,
+    /// An executable found on `PATH`; `hashed` if it was already cached.
+    File { path: PathBuf, hashed: bool },
+}
+
+/// A remembered `PATH` lookup and how often it was used to run a command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub hits: usize,
+}
+
+/// The names of the executables on `PATH`, with the modification times of
+/// the directories they were read from.
+#[derive(Debug, PartialEq)]
+struct Executables {
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    names: BTreeSet<String>,
+}
+
+/// The cached `PATH` lookups, valid for the `PATH` they were made with.
+#[derive(Debug, Default)]
+struct PathCache {
+    path_var: Option<OsString>,
+    entries: BTreeMap<String, CacheEntry>,
+    executables: Option<Executables>,
+}
+
+impl PathCache {
+    /// Forgets every lookup if `PATH` is no longer `path_var`.
+    fn validate(&mut self, path_var: Option<&OsStr>) {
+        if self.path_var.as_deref() != path_var {
+            self.entries.clear();
+            self.executables = None;
+            self.path_var = path_var.map(OsStr::to_os_string);
+        }
+    }
+
+    /// The names of the executables in the directories of `path_var`. The
+    /// directories are read again only when one of them has changed.
+    fn executables(&mut self, path_var: Option<&OsStr>) -> &BTreeSet<String> {
+        self.validate(path_var);
+        let modified = |dir: PathBuf| {
+            let time = dir.metadata().and_then(|metadata| metadata.modified()).ok();
+            (dir, time)
+        };
+        let dirs: Vec<_> = path_var.map(|path_var| std::env::split_paths(path_var).map(modified).collect()).unwrap_or_default();
+        if self.executables.as_ref().is_some_and(|executables| executables.dirs != dirs) {
+            self.executables = None;
+        }
+        &self
+            .executables
+            .get_or_insert_with(|| {
+                let mut names = BTreeSet::new();
+                for entry in dirs.iter().filter_map(|(dir, _)| std::fs::read_dir(dir).ok()).flatten().flatten() {
+                    if is_executable(&entry.path()) {
+                        names.insert(entry.file_name().to_string_lossy().to_string());
+                    }
+                }
+                Executables { dirs, names }
+            })
+            .names
+    }
+
+    /// Looks `name` up in the cache, if it still names an executable.
+    fn cached(&mut self, name: &str, path_var: Option<&OsStr>) -> Option<&mut CacheEntry> {
+        self.validate(path_var);
+        if self.entries.get(name).is_some_and(|entry| !is_executable(&entry.path)) {
+            self.entries.remove(name);
+        }
+        self.entries.get_mut(name)
+    }
+
+    /// Looks `name` up, from the cache or else on `PATH`, and remembers it.
+    fn find(&mut self, name: &str, path_var: Option<&OsStr>) -> Option<&mut CacheEntry> {
+        if self.cached(name, path_var).is_none() {
+            let path = search(name, path_var?).into_iter().next()?;
+            self.entries.insert(name.to_string(), CacheEntry { path, hits: 0 });
+        }
+        self.entries.get_mut(name)
+    }
+}
+
+fn path_var() -> Option<OsString> {
+    std::env::var_os("PATH")
+}
+
+fn cache() -> MutexGuard<'static, PathCache> {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.validate(path_var().as_deref());
+    cache
+}
+
+/// Every executable called `name` in the directories of `path_var`, in order.
+fn search(name: &str, path_var: &OsStr) -> Vec<PathBuf> {
+    std::env::split_paths(path_var).map(|dir| dir.join(name)).filter(|path| is_executable(path)).collect()
+}
+
+/// Finds the executable `name`, from the lookup cache if it is there.
+/// Names containing a `/` are checked as paths.
+pub fn find(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return find_executable(name);
+    }
+    let path_var = path_var();
+    if let Some(entry) = cache().cached(name, path_var.as_deref()) {
+        return Some(entry.path.clone());
+    }
+    search(name, path_var.as_deref()?).into_iter().next()
+}
+
+/// Finds the executable `name` and remembers where it is (`hash name`).
+/// Names containing a `/` are never cached.
+pub fn hash(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return find_executable(name);
+    }
+    cache().find(name, path_var().as_deref()).map(|entry| entry.path.clone())
+}
+
+/// The names of the executables on `PATH` that start with `prefix`, as
+/// read when `PATH` or one of its directories last changed.
+pub fn executables(prefix: &str) -> Vec<String> {
+    let path_var = path_var();
+    cache().executables(path_var.as_deref()).iter().filter(|name| name.starts_with(prefix)).cloned().collect()
+}
+
+/// Like [`find`], but counts a hit for `name` because it is about to run.
+fn find_to_run(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return find_executable(name);
+    }
+    let mut cache = cache();
+    let entry = cache.find(name, path_var().as_deref())?;
+    entry.hits += 1;
+    Some(entry.path.clone())
+}
+
+/// Every executable called `name` on `PATH`, bypassing the cache.
+pub fn find_all(name: &str) -> Vec<PathBuf> {
+    if name.contains('/') {
+        return find_executable(name).into_iter().collect();
+    }
+    path_var().map(|path_var| search(name, &path_var)).unwrap_or_default()
+}
+
+/// What `name` runs as: an alias, a keyword, a builtin or an executable.
+pub fn resolve(name: &str) -> Option<Resolution> {
+    if let Some(value) = alias::get(name) {
+        return Some(Resolution::Alias(value));
+    }
+    if tokenizer::KEYWORDS.contains(&name) {
+        return Some(Resolution::Keyword);
+    }
+    if BUILTINS.contains(&name) {
+        return Some(Resolution::Builtin);
+    }
+    let hashed = is_hashed(name);
+    find(name).map(|path| Resolution::File { path, hashed })
+}
+
+/// Everything `name` could run as, in the order the shell tries them,
+/// including every executable of that name on `PATH`.
+pub fn resolve_all(name: &str) -> Vec<Resolution> {
+    let mut resolutions = Vec::new();
+    if let Some(value) = alias::get(name) {
+        resolutions.push(Resolution::Alias(value));
+    }
+    if tokenizer::KEYWORDS.contains(&name) {
+        resolutions.push(Resolution::Keyword);
+    }
+    if BUILTINS.contains(&name) {
+        resolutions.push(Resolution::Builtin);
+    }
+    resolutions.extend(find_all(name).into_iter().map(|path| Resolution::File { path, hashed: false }));
+    resolutions
+}
+
+/// Whether the lookup of `name` is cached.
+pub fn is_hashed(name: &str) -> bool {
+    cache().entries.contains_key(name)
+}
+
+/// The cached lookups by name.
+pub fn hashed() -> Vec<(String, CacheEntry)> {
+    cache().entries.iter().map(|(name, entry)| (name.clone(), entry.clone())).collect()
+}
+
+/// Remembers `path` for `name` without searching `PATH` (`hash -p`).
+pub fn remember(name: &str, path: PathBuf) {
+    cache().entries.insert(name.to_string(), CacheEntry { path, hits: 0 });
+}
+
+/// Forgets the lookup of `name`. Returns whether it was cached.
+pub fn forget(name: &str) -> bool {
+    cache().entries.remove(name).is_some()
+}
+
+/// Forgets every cached lookup and executable name (`hash -r`).
+pub fn clear() {
+    let mut cache = cache();
+    cache.entries.clear();
+    cache.executables = None;
+}
+
+/// Builtins and executables on `PATH` that `name` may be a misspelling
+/// of, closest first. `name` itself is never suggested, e.g. for an alias
+/// that leads nowhere.
+pub fn suggest(name: &str) -> Vec<String> {
+    let limit = if name.chars().count() <= 4 { 1 } else { 2 };
+    let mut matches: Vec<(usize, String)> = complete_commands("")
+        .into_iter()
+        .filter(|candidate| candidate.value != name)
+        .map(|candidate| (edit_distance(name, &candidate.value), candidate.value))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// The optimal string alignment distance: insertions, deletions,
+/// substitutions and swaps of neighbouring characters each count one.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// What the shell prints for a command it cannot find, e.g.
+/// `msh: command not found: gti` and `msh: did you mean git?`.
+pub fn not_found_message(name: &str) -> String {
+    let mut message = format!("msh: command not found: {}", name);
+    let suggestions = suggest(name);
+    if let Some((last, rest)) = suggestions.split_last() {
+        let names = if rest.is_empty() { last.clone() } else { format!("{} or {}", rest.join(", "), last) };
+        message.push_str(&format!("\nmsh: did you mean {}?", names));
+    }
+    message
+}
+
+/// A process for the external command `name`, run from the path it
+/// resolves to while still seeing `name` as its `argv[0]`.
+pub fn process(name: &str) -> std::process::Command {
+    match find_to_run(name) {
+        Some(path) => {
+            let mut command = std::process::Command::new(path);
+            command.arg0(name);
+            command
+        }
+        None => std::process::Command::new(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn executable(dir: &std::path::Path, name: &str) -> PathBuf {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("gti", "git"), 1);
+        assert_eq!(edit_distance("sl", "ls"), 1);
+        assert_eq!(edit_distance("carg", "cargo"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(suggest("histroy").first().map(String::as_str), Some("history"));
+        assert!(!suggest("history").contains(&"history".to_string()));
+        assert!(not_found_message("cdd").starts_with("msh: command not found: cdd\nmsh: did you mean cd"));
+    }
+
+    #[test]
+    fn test_cache_follows_path_changes() {
+        let base = std::env::temp_dir().join(format!("msh-lookup-{}", std::process::id()));
+        let first = executable(&base.join("one"), "tool");
+        let second = executable(&base.join("two"), "tool");
+        let both = std::env::join_paths([base.join("one"), base.join("two")]).unwrap();
+        let only_second = base.join("two").into_os_string();
+
+        let mut cache = PathCache::default();
+        assert_eq!(cache.find("tool", Some(&both)).map(|e| e.path.clone()), Some(first.clone()));
+        assert_eq!(search("tool", &both), vec![first.clone(), second.clone()]);
+        assert!(cache.entries.contains_key("tool"));
+
+        // A new PATH drops what was looked up with the old one.
+        assert_eq!(cache.find("tool", Some(&only_second)).map(|e| e.path.clone()), Some(second.clone()));
+
+        // A cached executable that went away is looked up again.
+        std::fs::remove_file(&second).unwrap();
+        assert!(cache.find("tool", Some(&only_second)).is_none());
+        assert!(cache.entries.is_empty());
+        assert!(cache.find("missing", None).is_none());
+
+        // The names for completion are read once for each PATH.
+        assert!(cache.executables(Some(&both)).contains("tool"));
+        assert!(cache.executables(Some(&only_second)).is_empty());
+        assert!(cache.executables(None).is_empty());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::vars;
+
+/// The default primary prompt, used when `$PS1` is not set.
+pub const DEFAULT_PS1: &str = "shell> ";
+/// The default continuation prompt, used when `$PS2` is not set.
+pub const DEFAULT_PS2: &str = "> ";
+/// Shown before the prompt in vi insert mode unless `$VI_INS_MODE_STRING` is set.
+pub const DEFAULT_VI_INS_MODE_STRING: &str = "(ins) ";
+/// Shown before the prompt in vi command mode unless `$VI_CMD_MODE_STRING` is set.
+pub const DEFAULT_VI_CMD_MODE_STRING: &str = "(cmd) ";
+
+/// Shell state the prompt escapes can refer to.
+#[derive(Clone, Debug, Default)]
+pub struct PromptContext {
+    pub last_status: i32,
+    pub jobs: usize,
+    /// How long the last command line ran, if one has run.
+    pub duration: Option<Duration>,
+}
+
+type Segment = Box<dyn Fn() -> String>;
+
+/// Renders `PS1`/`PS2` templates.
+///
+/// Besides the bash-style escapes (`\u`, `\h`, `\w`, `\$`, `\t`, ...) a
+/// template may use `\?` for the last exit status, `\E` for how long the
+/// last command took, `\c{green}` for named
+/// colours and `\{name}` for a dynamic segment registered with
+/// [`Prompt::register_segment`]. Segments only run when the prompt is
+/// drawn, never while a line is being edited.
+pub struct Prompt {
+    segments: HashMap<String, Segment>,
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        let mut prompt = Self { segments: HashMap::new() };
+        prompt.register_segment("git_branch", || {
+            std::env::current_dir().ok().and_then(|dir| git_branch(&dir)).unwrap_or_default()
+        });
+        prompt
+    }
+}
+
+impl Prompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the output of `segment` available as `\{name}` in templates.
+    pub fn register_segment<F: Fn() -> String + 'static>(&mut self, name: &str, segment: F) {
+        self.segments.insert(name.to_string(), Box::new(segment));
+    }
+
+    /// Runs `$PROMPT_COMMAND` in the shell, if set, and renders `$PS1`.
+    pub fn primary(&self, ctx: &PromptContext) -> String {
+        if let Some(command) = vars::get("PROMPT_COMMAND")
+            && !command.trim().is_empty()
+        {
+            let result = crate::command::parse_line(&command).map_err(|e| e.to_string()).and_then(|cmd| cmd.execute().map_err(|e| e.to_string()));
+            if let Err(e) = result
+                && !e.is_empty()
+            {
+                eprintln!("PROMPT_COMMAND: {}", e);
+            }
+        }
+        self.expand(&vars::get("PS1").unwrap_or_else(|| DEFAULT_PS1.to_string()), ctx)
+    }
+
+    /// Renders `$PS2`, shown while a command continues on the next line.
+    pub fn continuation(&self, ctx: &PromptContext) -> String {
+        self.expand(&vars::get("PS2").unwrap_or_else(|| DEFAULT_PS2.to_string()), ctx)
+    }
+
+    /// Renders `$RPROMPT`, shown right-aligned on the input row.
+    pub fn right(&self, ctx: &PromptContext) -> String {
+        vars::get("RPROMPT").map(|template| self.expand(&template, ctx)).unwrap_or_default()
+    }
+
+    /// Renders `$TRANSIENT_PROMPT`, which replaces the prompt of a line once
+    /// it is submitted. `None` keeps the full prompt in the scrollback.
+    pub fn transient(&self, ctx: &PromptContext) -> Option<String> {
+        vars::get("TRANSIENT_PROMPT").map(|template| self.expand(&template, ctx))
+    }
+
+    /// Renders the vi mode indicator for insert or command mode.
+    pub fn vi_mode(&self, ctx: &PromptContext, command: bool) -> String {
+        let (variable, default) = if command {
+            ("VI_CMD_MODE_STRING", DEFAULT_VI_CMD_MODE_STRING)
+        } else {
+            ("VI_INS_MODE_STRING", DEFAULT_VI_INS_MODE_STRING)
+        };
+        self.expand(&vars::get(variable).unwrap_or_else(|| default.to_string()), ctx)
+    }
+
+    /// Replaces the escapes in `template`. Unknown escapes are kept as is.
+    pub fn expand(&self, template: &str, ctx: &PromptContext) -> String {
+        let mut out = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            let Some(escape) = chars.next() else {
+                out.push('\\');
+                break;
+            };
+            match escape {
+                'u' => out.push_str(&user_name()),
+                'h' => out.push_str(host_name().split('.').next().unwrap_or_default()),
+                'H' => out.push_str(&host_name()),
+                'w' => out.push_str(&abbreviate_home(&current_dir())),
+                'W' => {
+                    let dir = abbreviate_home(&current_dir());
+                    out.push_str(if dir == "/" { "/" } else { dir.rsplit('/').next().unwrap_or_default() });
+                }
+                '$' => out.push(if unsafe { libc::geteuid() } == 0 { '#' } else { '$' }),
+                '?' => out.push_str(&ctx.last_status.to_string()),
+                'j' => out.push_str(&ctx.jobs.to_string()),
+                'E' => out.push_str(&ctx.duration.map(format_duration).unwrap_or_default()),
+                't' => out.push_str(&format_time("%H:%M:%S")),
+                'T' => out.push_str(&format_time("%I:%M:%S")),
+                '@' => out.push_str(&format_time("%I:%M %p")),
+                'A' => out.push_str(&format_time("%H:%M")),
+                'd' => out.push_str(&format_time("%a %b %d")),
+                'n' => out.push('\n'),
+                'e' => out.push('\x1b'),
+                '\\' => out.push('\\'),
+                // Non-printing markers; the editor measures width without
+                // escape sequences, so they are not needed.
+                '[' | ']' => {}
+                'D' | 'c' | '{' => {
+                    if escape != '{' && chars.peek() != Some(&'{') {
+                        out.push('\\');
+                        out.push(escape);
+                        continue;
+                    }
+                    if escape != '{' {
+                        chars.next();
+                    }
+                    let argument: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    match escape {
+                        'D' => out.push_str(&format_time(if argument.is_empty() { "%X" } else { &argument })),
+                        'c' => out.push_str(&color(&argument)),
+                        _ => {
+                            if let Some(segment) = self.segments.get(&argument) {
+                                out.push_str(&segment());
+                            }
+                        }
+                    }
+                }
+                other => {
+                    out.push('\\');
+                    out.push(other);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The ANSI sequence for a named colour or style, or nothing if colours
+/// are disabled with `$NO_COLOR` or the name is unknown.
+fn color(name: &str) -> String {
+    if vars::get("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return String::new();
+    }
+    let code = match name {
+        "reset" => "0",
+        "bold" => "1",
+        "dim" => "2",
+        "underline" => "4",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        _ => return String::new(),
+    };
+    format!("\x1b[{}m", code)
+}
+
+fn current_dir() -> PathBuf {
+    crate::cwd::logical()
+}
+
+/// Replaces the home directory at the start of `path` with `~`.
+pub fn abbreviate_home(path: &Path) -> String {
+    if let Some(home) = dirs::home_dir()
+        && let Ok(rest) = path.strip_prefix(&home)
+    {
+        return if rest.as_os_str().is_empty() {
+            "~".to_string()
+        } else {
+            format!("~/{}", rest.display())
+        };
+    }
+    path.display().to_string()
+}
+
+fn user_name() -> String {
+    if let Ok(user) = std::env::var("USER") {
+        return user;
+    }
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    if passwd.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr((*passwd).pw_name) }.to_string_lossy().to_string()
+}
+
+fn host_name() -> String {
+    let mut buffer = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len()) } != 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy().to_string()
+}
+
+/// Formats the current local time with a `strftime` format.
+pub fn format_time(format: &str) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let mut buffer = [0 as libc::c_char; 128];
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(buffer.as_mut_ptr(), buffer.len(), format.as_ptr(), &tm)
+    };
+    let bytes: Vec<u8> = buffer[..len].iter().map(|&c| c as u8).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Formats a command duration compactly: `850ms`, `4.2s`, `2m03s`, `1h05m`.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    let seconds = duration.as_secs();
+    match seconds {
+        0 => format!("{}ms", millis),
+        1..60 => format!("{}.{}s", seconds, millis % 1000 / 100),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Reads the current branch from the `.git/HEAD` of `dir` or one of its
+/// parents. Detached heads show the abbreviated commit.
+pub fn git_branch(dir: &Path) -> Option<String> {
+    let head = dir.ancestors().find_map(|dir| std::fs::read_to_string(dir.join(".git").join("HEAD")).ok())?;
+    let head = head.trim();
+    match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) => Some(branch.to_string()),
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_escapes() {
+        let prompt = Prompt::new();
+        let ctx = PromptContext { last_status: 3, jobs: 2, duration: Some(Duration::from_millis(1500)) };
+        assert_eq!(prompt.expand(r"[\?] \j\\ \x \E", &ctx), r"[3] 2\ \x 1.5s");
+        assert_eq!(prompt.expand(r"a\nb\[\]", &ctx), "a\nb");
+        assert_eq!(prompt.expand(r"\D{%%}", &ctx), "%");
+    }
+
+    #[test]
+    fn test_expand_segments() {
+        let mut prompt = Prompt::new();
+        prompt.register_segment("answer", || "42".to_string());
+        let ctx = PromptContext::default();
+        assert_eq!(prompt.expand(r"<\{answer}\{missing}>", &ctx), "<42>");
+    }
+
+    #[test]
+    fn test_prompt_command_runs_in_the_shell() {
+        vars::set("PROMPT_COMMAND", "printf -v MSH_TEST_PROMPT_COMMAND %s ran");
+        Prompt::new().primary(&PromptContext::default());
+        vars::set("PROMPT_COMMAND", "");
+        assert_eq!(vars::get("MSH_TEST_PROMPT_COMMAND").as_deref(), Some("ran"));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_duration(Duration::from_millis(4230)), "4.2s");
+        assert_eq!(format_duration(Duration::from_secs(123)), "2m03s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h05m");
+    }
+
+    #[test]
+    fn test_abbreviate_home() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(abbreviate_home(&home), "~");
+        assert_eq!(abbreviate_home(&home.join("src")), "~/src");
+        assert_eq!(abbreviate_home(Path::new("/")), "/");
+    }
+
+    #[test]
+    fn test_git_branch() {
+        let dir = std::env::temp_dir().join(format!("msh-prompt-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join(".git").join("HEAD"), "ref: refs/heads/main\n").unwrap();
+
+        assert_eq!(git_branch(&dir.join("sub")), Some("main".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+//! Reading a line of input into variables, for the `read` builtin.
+
+use std::{io, sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
+
+use crate::{job, trap::{self, Condition}, vars};
+
+/// The characters `IFS` splits on when it is unset.
+pub const DEFAULT_IFS: &str = " \t\n";
+
+/// The arguments of a `read` invocation.
+#[derive(Debug, PartialEq)]
+pub struct ReadOptions {
+    /// Backslashes are ordinary characters (`-r`).
+    pub raw: bool,
+    /// Typed characters are not echoed (`-s`).
+    pub silent: bool,
+    pub prompt: Option<String>,
+    pub timeout: Option<Duration>,
+    /// Stop after this many characters (`-n`, or `-N`).
+    pub count: Option<usize>,
+    /// Read exactly `count` characters, ignoring the delimiter (`-N`).
+    pub exact: bool,
+    pub delimiter: u8,
+    /// Store every field in this array instead (`-a`).
+    pub array: Option<String>,
+    pub names: Vec<String>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            raw: false,
+            silent: false,
+            prompt: None,
+            timeout: None,
+            count: None,
+            exact: false,
+            delimiter: b'\n',
+            array: None,
+            names: Vec::new(),
+        }
+    }
+}
+
+impl ReadOptions {
+    /// Parses the arguments of `read`. Short options may be grouped, and
+    /// an option's value may follow its letter, as in `read -rn1 key`.
+    pub fn parse(words: &[String]) -> Result<Self, String> {
+        let mut options = ReadOptions::default();
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            if word == "--" {
+                break;
+            }
+            let Some(letters) = word.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+                options.names.push(word.clone());
+                break;
+            };
+            for (i, letter) in letters.char_indices() {
+                if matches!(letter, 'r' | 's') {
+                    if letter == 'r' {
+                        options.raw = true;
+                    } else {
+                        options.silent = true;
+                    }
+                    continue;
+                }
+                if !"ptnNda".contains(letter) {
+                    return Err(format!("-{}: invalid option", letter));
+                }
+                let rest = &letters[i + letter.len_utf8()..];
+                let value = if !rest.is_empty() {
+                    rest
+                } else {
+                    words.next().ok_or_else(|| format!("-{}: option requires an argument", letter))?
+                };
+                match letter {
+                    'p' => options.prompt = Some(value.to_string()),
+                    't' => options.timeout = Some(parse_timeout(value)?),
+                    'n' | 'N' => {
+                        let count = value.parse().map_err(|_| format!("{}: invalid number", value))?;
+                        options.count = Some(count);
+                        options.exact = letter == 'N';
+                    }
+                    // An empty delimiter ends the input at a NUL byte.
+                    'd' => options.delimiter = value.bytes().next().unwrap_or(0),
+                    _ => options.array = Some(value.to_string()),
+                }
+                break;
+            }
+        }
+        options.names.extend(words.cloned());
+        if let Some(name) = options.array.iter().chain(&options.names).find(|name| !vars::is_valid_name(name)) {
+            return Err(format!("`{}': not a valid identifier", name));
+        }
+        Ok(options)
+    }
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("{}: invalid timeout specification", value))
+}
+
+/// Why reading stopped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ending {
+    Delimiter,
+    /// The requested number of characters was read.
+    Count,
+    Eof,
+    Timeout,
+    /// Ctrl+C was pressed.
+    Interrupt,
+}
+
+/// The characters read, each with whether a backslash escaped it, and
+/// what ended the input.
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub chars: Vec<(char, bool)>,
+    pub ending: Ending,
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        self.chars.iter().map(|(c, _)| c).collect()
+    }
+}
+
+/// Reads a line with the bytes `next` returns, which is `None` at the end
+/// of input and fails with [`io::ErrorKind::TimedOut`] when time is up or
+/// [`io::ErrorKind::Interrupted`] on Ctrl+C.
+/// Unless `raw`, a backslash escapes the next character and a backslash
+/// before a newline joins the next line.
+pub fn read_line(options: &ReadOptions, mut next: impl FnMut() -> io::Result<Option<u8>>) -> io::Result<Line> {
+    let mut chars = Vec::new();
+    // The bytes of a character that is still incomplete.
+    let mut pending = Vec::new();
+    let mut escaped = false;
+    let ending = loop {
+        if pending.is_empty() && options.count.is_some_and(|count| chars.len() >= count) {
+            break Ending::Count;
+        }
+        let byte = match next() {
+            Ok(Some(byte)) => byte,
+            Ok(None) => break Ending::Eof,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ending::Timeout,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => break Ending::Interrupt,
+            Err(e) => return Err(e),
+        };
+        if pending.is_empty() && !escaped {
+            if !options.raw && byte == b'\\' {
+                escaped = true;
+                continue;
+            }
+            if !options.exact && byte == options.delimiter {
+                break Ending::Delimiter;
+            }
+        }
+        if escaped && byte == b'\n' {
+            escaped = false;
+            continue;
+        }
+        pending.push(byte);
+        match std::str::from_utf8(&pending) {
+            Ok(text) => chars.extend(text.chars().map(|c| (c, escaped))),
+            Err(e) if e.error_len().is_none() => continue,
+            Err(_) => chars.push((char::REPLACEMENT_CHARACTER, escaped)),
+        }
+        pending.clear();
+        escaped = false;
+    };
+    if !pending.is_empty() {
+        chars.push((char::REPLACEMENT_CHARACTER, escaped));
+    }
+    Ok(Line { chars, ending })
+}
+
+/// Splits `chars` into at most `max` fields at unescaped characters of
+/// `ifs`. Whitespace in `ifs` is trimmed and runs of it separate fields
+/// once; every other character of `ifs` separates two fields. The last
+/// field takes the rest of the line.
+pub fn split_fields(chars: &[(char, bool)], ifs: &str, max: usize) -> Vec<String> {
+    let is_space = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c) && c.is_whitespace();
+    let is_separator = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c);
+    let text = |chars: &[(char, bool)]| chars.iter().map(|(c, _)| c).collect::<String>();
+
+    let mut fields = Vec::new();
+    let mut rest = chars;
+    while rest.first().is_some_and(is_space) {
+        rest = &rest[1..];
+    }
+    while !rest.is_empty() && max > 0 {
+        if fields.len() == max - 1 {
+            let end = rest.iter().rposition(|c| !is_space(c)).map_or(0, |i| i + 1);
+            fields.push(text(&rest[..end]));
+            break;
+        }
+        let end = rest.iter().position(is_separator).unwrap_or(rest.len());
+        fields.push(text(&rest[..end]));
+        rest = &rest[end..];
+        while rest.first().is_some_and(is_space) {
+            rest = &rest[1..];
+        }
+        if rest.first().is_some_and(|c| is_separator(c) && !is_space(c)) {
+            rest = &rest[1..];
+            while rest.first().is_some_and(is_space) {
+                rest = &rest[1..];
+            }
+        }
+    }
+    fields
+}
+
+/// Stores `line` in the variables `options` names: one field per name with
+/// the rest in the last, every field in the array, or the whole line in
+/// `REPLY`.
+pub fn assign(options: &ReadOptions, line: &Line) {
+    let ifs = vars::get("IFS").unwrap_or_else(|| DEFAULT_IFS.to_string());
+    if let Some(array) = &options.array {
+        vars::set_array(array, &split_fields(&line.chars, &ifs, usize::MAX));
+        return;
+    }
+    if options.names.is_empty() {
+        vars::set("REPLY", &line.text());
+        return;
+    }
+    let mut fields = split_fields(&line.chars, &ifs, options.names.len()).into_iter();
+    for name in &options.names {
+        vars::set(name, &fields.next().unwrap_or_default());
+    }
+}
+
+/// Whether stdin is a terminal.
+pub fn stdin_is_terminal() -> bool {
+    // SAFETY: isatty only inspects the descriptor.
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Reads bytes from the terminal on stdin, with echo and line editing
+/// turned off as the options ask for as long as it is alive.
+pub struct Terminal {
+    original: Option<libc::termios>,
+    deadline: Option<Instant>,
+    /// What SIGINT did before the terminal started catching it.
+    interrupt: Option<libc::sigaction>,
+}
+
+/// Whether Ctrl+C was pressed while a [`Terminal`] waited for input.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+impl Terminal {
+    pub fn open(options: &ReadOptions) -> Self {
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+        let interrupt = catch_interrupt();
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Self { original: None, deadline, interrupt };
+        }
+        let mut settings = original;
+        if options.silent {
+            settings.c_lflag &= !libc::ECHO;
+        }
+        // Without line editing each key arrives as it is typed.
+        if options.count.is_some() || options.delimiter != b'\n' {
+            settings.c_lflag &= !libc::ICANON;
+            settings.c_cc[libc::VMIN] = 1;
+            settings.c_cc[libc::VTIME] = 0;
+        }
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &settings) };
+        Self { original: Some(original), deadline, interrupt }
+    }
+
+    /// The next byte typed, `None` at the end of input.
+    pub fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fds = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+            let timeout = remaining.as_millis().min(i32::MAX as u128) as i32;
+            if unsafe { libc::poll(&mut fds, 1, timeout) } <= 0 {
+                if INTERRUPTED.swap(false, Ordering::SeqCst) {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+        }
+        let mut byte = 0u8;
+        loop {
+            match unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+                1 => return Ok(Some(byte)),
+                0 => return Ok(None),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted || INTERRUPTED.swap(false, Ordering::SeqCst) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, original) };
+        }
+        if let Some(interrupt) = &self.interrupt {
+            unsafe { libc::sigaction(libc::SIGINT, interrupt, std::ptr::null_mut()) };
+        }
+    }
+}
+
+/// Makes Ctrl+C cut a read short while the shell otherwise ignores it,
+/// returning what SIGINT did before.
+fn catch_interrupt() -> Option<libc::sigaction> {
+    if !job::enabled() || trap::get(Condition::Signal(libc::SIGINT)).is_some() {
+        return None;
+    }
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    // SAFETY: the action is fully initialised and the handler only
+    // touches an atomic.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGINT, &action, &mut previous);
+        Some(previous)
+    }
+}
+
+/// Reads one byte from `input`, `None` at its end.
+pub fn next_byte(input: &mut dyn io::Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn read(input: &str, args: &str) -> Line {
+        let options = ReadOptions::parse(&words(args)).unwrap();
+        let mut input = input.as_bytes();
+        read_line(&options, || next_byte(&mut input)).unwrap()
+    }
+
+    fn split(text: &str, ifs: &str, max: usize) -> Vec<String> {
+        let chars: Vec<(char, bool)> = text.chars().map(|c| (c, false)).collect();
+        split_fields(&chars, ifs, max)
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = ReadOptions::parse(&words("-rsn1 -p > -t 0.5 key rest")).unwrap();
+        assert!(options.raw && options.silent);
+        assert_eq!(options.count, Some(1));
+        assert_eq!(options.prompt.as_deref(), Some(">"));
+        assert_eq!(options.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(options.names, words("key rest"));
+        assert_eq!(ReadOptions::parse(&words("-d :")).unwrap().delimiter, b':');
+        assert_eq!(ReadOptions::parse(&words("-a")), Err("-a: option requires an argument".to_string()));
+        assert_eq!(ReadOptions::parse(&words("-t x")), Err("x: invalid timeout specification".to_string()));
+        assert_eq!(ReadOptions::parse(&words("a-b")), Err("`a-b': not a valid identifier".to_string()));
+    }
+
+    #[test]
+    fn test_read_line() {
+        assert_eq!(read("one\ntwo\n", "").text(), "one");
+        assert_eq!(read("a\\ b\\\nc\n", "").text(), "a bc");
+        assert_eq!(read("a\\ b\n", "-r").text(), "a\\ b");
+        assert_eq!(read("yes", "-n 1"), Line { chars: vec![('y', false)], ending: Ending::Count });
+        assert_eq!(read("ab\ncd", "-N 4").text(), "ab\nc");
+        assert_eq!(read("a:b", "-d :").ending, Ending::Delimiter);
+        assert_eq!(read("héllo", "-n 2").text(), "hé");
+        assert_eq!(read("tail", "").ending, Ending::Eof);
+    }
+
+    #[test]
+    fn test_split_fields() {
+        assert_eq!(split("  one two  three  ", DEFAULT_IFS, 2), ["one", "two  three"]);
+        assert_eq!(split("a b c", DEFAULT_IFS, usize::MAX), words("a b c"));
+        assert_eq!(split("root:x::0", ":", 4), ["root", "x", "", "0"]);
+        assert_eq!(split("a : b", " :", 3), ["a", "b"]);
+        assert_eq!(split(" a b ", "", 2), [" a b "]);
+        let escaped = [('a', false), (' ', true), ('b', false), (' ', false), ('c', false)];
+        assert_eq!(split_fields(&escaped, DEFAULT_IFS, 2), ["a b", "c"]);
+    }
+}
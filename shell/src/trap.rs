@@ -0,0 +1,224 @@
+//! Commands that `trap` runs when the shell receives a signal, exits or a
+//! command line fails. Signal handlers only note the signal; the shell runs
+//! the command between commands and before it reads the next line.
+
+use std::{collections::BTreeMap, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Mutex}};
+
+use crate::{command::{self, Unwind}, job};
+
+/// The signals `trap` accepts by name, without the `SIG` prefix.
+pub const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("WINCH", libc::SIGWINCH),
+];
+
+static TRAPS: Mutex<BTreeMap<Condition, String>> = Mutex::new(BTreeMap::new());
+/// One bit per signal that arrived and has not been handled yet.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+/// Whether a trap command is running, which delays other traps.
+static IN_TRAP: AtomicBool = AtomicBool::new(false);
+
+/// When a trap runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    /// When the shell exits.
+    Exit,
+    /// After a command line fails.
+    Err,
+    Signal(i32),
+}
+
+impl Condition {
+    /// Parses a name such as `INT`, `SIGINT`, `int`, `2`, `EXIT` or `ERR`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "EXIT" | "0" => return Some(Condition::Exit),
+            "ERR" => return Some(Condition::Err),
+            _ => {}
+        }
+        let signal = match upper.parse::<i32>() {
+            Ok(number) => SIGNALS.iter().find(|(_, signal)| *signal == number),
+            Err(_) => {
+                let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+                SIGNALS.iter().find(|(signal_name, _)| *signal_name == name)
+            }
+        };
+        signal.map(|(_, signal)| Condition::Signal(*signal))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Condition::Exit => "EXIT".to_string(),
+            Condition::Err => "ERR".to_string(),
+            Condition::Signal(signal) => match SIGNALS.iter().find(|(_, number)| number == signal) {
+                Some((name, _)) => format!("SIG{}", name),
+                None => signal.to_string(),
+            },
+        }
+    }
+}
+
+/// The command trapped for `condition`. An empty command ignores it.
+pub fn get(condition: Condition) -> Option<String> {
+    TRAPS.lock().unwrap_or_else(|e| e.into_inner()).get(&condition).cloned()
+}
+
+/// Every trap, exit and error traps first.
+pub fn all() -> Vec<(Condition, String)> {
+    TRAPS.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|(condition, action)| (*condition, action.clone())).collect()
+}
+
+/// Runs `action` for `condition` from now on: an empty action ignores a
+/// signal, and `None` restores what the signal normally does to the shell.
+pub fn set(condition: Condition, action: Option<&str>) {
+    if let Condition::Signal(signal) = condition {
+        let handler = match action {
+            None => job::default_handler(signal),
+            Some("") => libc::SIG_IGN,
+            Some(_) => on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        };
+        install(signal, handler);
+    }
+    let mut traps = TRAPS.lock().unwrap_or_else(|e| e.into_inner());
+    match action {
+        Some(action) => traps.insert(condition, action.to_string()),
+        None => traps.remove(&condition),
+    };
+}
+
+/// The `trap` command that sets `action` for `condition`.
+pub fn definition(condition: Condition, action: &str) -> String {
+    format!("trap -- '{}' {}", action.replace('\'', r"'\''"), condition.name())
+}
+
+fn install(signal: i32, handler: libc::sighandler_t) {
+    // SAFETY: the action is fully initialised and the handler only
+    // touches an atomic.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Notes `signal` as if it had arrived, e.g. when it killed a foreground
+/// command instead of the shell.
+pub fn raise(signal: i32) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Whether a trapped signal arrived that has not been handled yet.
+pub fn signal_pending() -> bool {
+    PENDING.load(Ordering::SeqCst) != 0
+}
+
+/// Runs the traps of the signals that arrived since the last call. Fails
+/// only if a trap command ran `exit`.
+pub fn run_pending() -> Result<(), Box<dyn std::error::Error>> {
+    if IN_TRAP.load(Ordering::SeqCst) || !signal_pending() {
+        return Ok(());
+    }
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    for (_, signal) in SIGNALS {
+        if pending & (1 << signal) != 0
+            && let Some(action) = get(Condition::Signal(*signal))
+        {
+            run(&action)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `ERR` trap.
+pub fn run_err() -> Result<(), Box<dyn std::error::Error>> {
+    match get(Condition::Err) {
+        Some(action) => run(&action),
+        None => Ok(()),
+    }
+}
+
+/// Runs the `EXIT` trap, once.
+pub fn run_exit() -> Result<(), Box<dyn std::error::Error>> {
+    let action = TRAPS.lock().unwrap_or_else(|e| e.into_inner()).remove(&Condition::Exit);
+    match action {
+        Some(action) => run(&action),
+        None => Ok(()),
+    }
+}
+
+/// Runs a trap command, reporting its errors. Only `exit` and `return`
+/// get past it.
+fn run(action: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if action.trim().is_empty() {
+        return Ok(());
+    }
+    let cmd = match command::parse_line(action) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            eprintln!("trap: {}", e);
+            return Ok(());
+        }
+    };
+    let was_in_trap = IN_TRAP.swap(true, Ordering::SeqCst);
+    let result = cmd.execute();
+    IN_TRAP.store(was_in_trap, Ordering::SeqCst);
+    match result {
+        Err(e) if e.is::<Unwind>() => Err(e),
+        Err(e) => {
+            command::report_error(e.as_ref());
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_conditions() {
+        assert_eq!(Condition::parse("INT"), Some(Condition::Signal(libc::SIGINT)));
+        assert_eq!(Condition::parse("sigterm"), Some(Condition::Signal(libc::SIGTERM)));
+        assert_eq!(Condition::parse("1"), Some(Condition::Signal(libc::SIGHUP)));
+        assert_eq!(Condition::parse("0"), Some(Condition::Exit));
+        assert_eq!(Condition::parse("ERR"), Some(Condition::Err));
+        assert_eq!(Condition::parse("KILL"), None);
+        assert_eq!(Condition::Signal(libc::SIGUSR1).name(), "SIGUSR1");
+        assert_eq!(definition(Condition::Exit, "echo 'bye'"), r"trap -- 'echo '\''bye'\''' EXIT");
+    }
+
+    #[test]
+    fn test_signal_runs_trap() {
+        let file = std::env::temp_dir().join(format!("msh-trap-{}", std::process::id()));
+        let usr2 = Condition::Signal(libc::SIGUSR2);
+        set(usr2, Some(&format!("touch {}", file.display())));
+        // SAFETY: the handler only records the signal.
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(signal_pending());
+        run_pending().unwrap();
+        assert!(file.exists());
+        set(usr2, None);
+        assert_eq!(get(usr2), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+//! Shell variables. A variable that came with the environment stays
+//! exported and changes there; any other lives in a table of the shell's
+//! own that the programs it runs do not see, and so does every array, like
+//! `BASH_REMATCH`. Words of a command expand `$name`, `${name}` and
+//! `${name[i]}` each time it runs, see [`Word`].
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+enum Value {
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+static VARIABLES: Mutex<BTreeMap<String, Value>> = Mutex::new(BTreeMap::new());
+
+/// Whether `name` can be a variable: a letter or `_`, then letters,
+/// digits and `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The value of `name`, or the first element if it is an array.
+pub fn get(name: &str) -> Option<String> {
+    match VARIABLES.lock().unwrap_or_else(|e| e.into_inner()).get(name) {
+        Some(Value::Scalar(value)) => Some(value.clone()),
+        Some(Value::Array(values)) => values.first().cloned(),
+        None => std::env::var(name).ok(),
+    }
+}
+
+/// The elements of the array `name`; a plain variable is an array of one.
+pub fn get_array(name: &str) -> Option<Vec<String>> {
+    match VARIABLES.lock().unwrap_or_else(|e| e.into_inner()).get(name) {
+        Some(Value::Scalar(value)) => Some(vec![value.clone()]),
+        Some(Value::Array(values)) => Some(values.clone()),
+        None => std::env::var(name).ok().map(|value| vec![value]),
+    }
+}
+
+/// Whether `name` is set, in the shell or in the environment.
+pub fn is_set(name: &str) -> bool {
+    VARIABLES.lock().unwrap_or_else(|e| e.into_inner()).contains_key(name) || std::env::var_os(name).is_some()
+}
+
+/// Sets `name` to `value`, in the environment if it is exported there.
+pub fn set(name: &str, value: &str) {
+    let mut variables = VARIABLES.lock().unwrap_or_else(|e| e.into_inner());
+    if std::env::var_os(name).is_some() {
+        variables.remove(name);
+        // SAFETY: the shell only touches its environment from this thread.
+        unsafe { std::env::set_var(name, value) };
+    } else {
+        variables.insert(name.to_string(), Value::Scalar(value.to_string()));
+    }
+}
+
+/// Replaces the array `name` with `values`. No values removes it.
+pub fn set_array(name: &str, values: &[String]) {
+    let mut variables = VARIABLES.lock().unwrap_or_else(|e| e.into_inner());
+    if values.is_empty() {
+        variables.remove(name);
+    } else {
+        variables.insert(name.to_string(), Value::Array(values.to_vec()));
+    }
+}
+
+/// A piece of a [`Word`].
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    /// `$name`, `${name}` or `${name[index]}`; `quoted` if inside double
+    /// quotes, where the value is not split into fields.
+    Variable { name: String, index: Option<String>, quoted: bool },
+}
+
+/// A word as typed, with the variables in it left to expand when the
+/// command runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Word {
+    parts: Vec<Part>,
+    /// Whether the word had quotes, so it stays a word when empty.
+    quoted: bool,
+}
+
+impl Word {
+    /// A word that expands to `text` as it is.
+    pub fn literal(text: &str) -> Self {
+        Self { parts: vec![Part::Text(text.to_string())], quoted: true }
+    }
+
+    /// Reads the word `raw` as it appears in the source, quotes included.
+    /// Returns `None` if nothing in it expands. Nothing expands inside
+    /// single quotes or after a backslash.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let mut word = Self { parts: Vec::new(), quoted: false };
+        let mut text = String::new();
+        let mut quote = None;
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), _) if c == q => quote = None,
+                (Some('\''), _) => text.push(c),
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    word.quoted = true;
+                }
+                (None, '\\') => match chars.next() {
+                    Some('\n') => {}
+                    Some(next) => text.push(next),
+                    None => text.push(c),
+                },
+                (Some(_), '\\') => match chars.peek() {
+                    Some(&next @ ('"' | '\\' | '$' | '`')) => {
+                        chars.next();
+                        text.push(next);
+                    }
+                    Some('\n') => {
+                        chars.next();
+                    }
+                    _ => text.push(c),
+                },
+                (_, '$') => match variable(&mut chars, quote.is_some()) {
+                    Some(variable) => {
+                        word.parts.push(Part::Text(std::mem::take(&mut text)));
+                        word.parts.push(variable);
+                    }
+                    None => text.push(c),
+                },
+                _ => text.push(c),
+            }
+        }
+        word.parts.push(Part::Text(text));
+        word.parts.retain(|part| *part != Part::Text(String::new()));
+        word.parts.iter().any(|part| matches!(part, Part::Variable { .. })).then_some(word)
+    }
+
+    /// Applies `f` to the text before the first variable, such as to
+    /// expand a leading `~`.
+    pub fn map_prefix(&mut self, f: impl FnOnce(&str) -> String) {
+        if let Some(Part::Text(text)) = self.parts.first_mut() {
+            *text = f(text);
+        }
+    }
+
+    /// The fields the word expands to. The value of a variable outside
+    /// double quotes is split at the characters of `$IFS`, so an unset one
+    /// leaves no field at all unless the word has quotes.
+    pub fn expand(&self) -> Vec<String> {
+        let separators = get("IFS").unwrap_or_else(|| crate::read::DEFAULT_IFS.to_string());
+        let mut fields = Vec::new();
+        let mut field = self.quoted.then(String::new);
+        for part in &self.parts {
+            let (values, quoted) = match part {
+                Part::Text(text) => (vec![text.clone()], true),
+                Part::Variable { name, index, quoted } => (lookup(name, index.as_deref()), *quoted),
+            };
+            for (i, value) in values.into_iter().enumerate() {
+                // The elements of `"${name[@]}"` are fields of their own.
+                if i > 0 {
+                    fields.extend(field.take());
+                }
+                if quoted {
+                    field.get_or_insert_default().push_str(&value);
+                    continue;
+                }
+                let starts_new = value.starts_with(|c| separators.contains(c));
+                let ends_new = value.ends_with(|c| separators.contains(c));
+                if starts_new {
+                    fields.extend(field.take());
+                }
+                let mut words = value.split(|c| separators.contains(c)).filter(|word| !word.is_empty()).peekable();
+                while let Some(word) = words.next() {
+                    field.get_or_insert_default().push_str(word);
+                    if words.peek().is_some() {
+                        fields.extend(field.take());
+                    }
+                }
+                if ends_new {
+                    fields.extend(field.take());
+                }
+            }
+        }
+        fields.extend(field);
+        fields
+    }
+}
+
+/// Reads the variable after a `$`: a name, or a name and an optional
+/// `[index]` in braces. Leaves `chars` alone if there is none.
+fn variable(chars: &mut std::iter::Peekable<std::str::Chars>, quoted: bool) -> Option<Part> {
+    let is_start = |c: &char| c.is_ascii_alphabetic() || *c == '_';
+    let is_name = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    if chars.peek().is_some_and(is_start) {
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(is_name) {
+            name.push(c);
+        }
+        return Some(Part::Variable { name, index: None, quoted });
+    }
+    if chars.peek() != Some(&'{') {
+        return None;
+    }
+    let mut rest = chars.clone();
+    rest.next();
+    let mut inner = String::new();
+    loop {
+        match rest.next()? {
+            '}' => break,
+            c => inner.push(c),
+        }
+    }
+    let (name, index) = match inner.strip_suffix(']').and_then(|inner| inner.split_once('[')) {
+        Some((name, index)) => (name, Some(index.to_string())),
+        None => (inner.as_str(), None),
+    };
+    if !is_valid_name(name) {
+        return None;
+    }
+    *chars = rest;
+    Some(Part::Variable { name: name.to_string(), index, quoted })
+}
+
+/// The values of `name`, or of the element `index` of it. `@` and `*` are
+/// every element, `*` joined by spaces into one value.
+fn lookup(name: &str, index: Option<&str>) -> Vec<String> {
+    match index {
+        None => get(name).into_iter().collect(),
+        Some("@") => get_array(name).unwrap_or_default(),
+        Some("*") => get_array(name).map(|values| vec![values.join(" ")]).unwrap_or_default(),
+        Some(index) => index
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| get_array(name)?.into_iter().nth(i))
+            .into_iter()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_array_replaces_elements() {
+        let words = |text: &str| text.split(' ').map(String::from).collect::<Vec<_>>();
+        set_array("MSH_TEST_ARRAY", &words("a b c"));
+        set_array("MSH_TEST_ARRAY", &words("x y"));
+        assert_eq!(get("MSH_TEST_ARRAY").as_deref(), Some("x"));
+        assert_eq!(get_array("MSH_TEST_ARRAY"), Some(words("x y")));
+        assert!(std::env::var("MSH_TEST_ARRAY").is_err());
+        assert!(std::env::var("MSH_TEST_ARRAY_1").is_err());
+        set_array("MSH_TEST_ARRAY", &[]);
+        assert!(!is_set("MSH_TEST_ARRAY"));
+        assert!(is_valid_name("_line2"));
+        assert!(!is_valid_name("2line"));
+    }
+
+    #[test]
+    fn test_set_keeps_shell_variables_out_of_the_environment() {
+        set("MSH_TEST_LOCAL", "one");
+        assert_eq!(get("MSH_TEST_LOCAL").as_deref(), Some("one"));
+        assert!(std::env::var_os("MSH_TEST_LOCAL").is_none());
+
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var("MSH_TEST_EXPORTED", "old") };
+        set("MSH_TEST_EXPORTED", "new");
+        assert_eq!(std::env::var("MSH_TEST_EXPORTED").as_deref(), Ok("new"));
+        assert_eq!(get("MSH_TEST_EXPORTED").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn test_word_expands_variables_when_asked() {
+        let expand = |raw: &str| Word::parse(raw).map(|word| word.expand());
+        let fields = |text: &str| Some(text.split(',').map(String::from).collect::<Vec<_>>());
+        set("MSH_TEST_WORD", "a  b");
+        set_array("MSH_TEST_WORDS", &["x".to_string(), "y z".to_string()]);
+        assert_eq!(expand("$MSH_TEST_WORD"), fields("a,b"));
+        assert_eq!(expand("\"$MSH_TEST_WORD\""), fields("a  b"));
+        assert_eq!(expand("<${MSH_TEST_WORD}>"), fields("<a,b>"));
+        assert_eq!(expand("${MSH_TEST_WORDS[1]}"), fields("y,z"));
+        assert_eq!(expand("\"${MSH_TEST_WORDS[@]}\""), fields("x,y z"));
+        assert_eq!(expand("$MSH_TEST_UNSET"), Some(vec![]));
+        assert_eq!(expand("\"$MSH_TEST_UNSET\""), fields(""));
+        assert_eq!(expand("'$MSH_TEST_WORD'"), None);
+        assert_eq!(expand("\\$MSH_TEST_WORD"), None);
+        assert_eq!(expand("cost: $5 ${"), None);
+    }
+}
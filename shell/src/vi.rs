@@ -0,0 +1,746 @@
+use crate::editor::{Key, LineBuffer};
+
+/// What the editor should do after a key typed in vi command mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The line may have changed; stay in command mode.
+    Continue,
+    /// The key starts a longer command; wait for more.
+    Pending,
+    /// Switch to insert mode.
+    Insert,
+    Undo,
+    PreviousHistory(usize),
+    NextHistory(usize),
+    Bell,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `w`/`W`; `true` moves by blank-separated WORDs.
+    WordForward(bool),
+    WordBackward(bool),
+    WordEnd(bool),
+    Find { forward: bool, till: bool, target: char },
+    /// `;` or, if reversed, `,`.
+    RepeatFind(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Motion(Motion),
+    Line,
+    Object { inner: bool, kind: char },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Move(Motion),
+    Operate(Operator, Target),
+    /// `i`, `a`, `I` or `A`.
+    Insert(char),
+    /// `x`, or `X` when deleting before the cursor.
+    DeleteChar(bool),
+    Substitute,
+    Replace(char),
+    /// `p`, or `P` when putting before the cursor.
+    Put(bool),
+    ToggleCase,
+    Undo,
+    Repeat,
+    PreviousHistory,
+    NextHistory,
+}
+
+impl Command {
+    fn is_change(self) -> bool {
+        !matches!(
+            self,
+            Command::Move(_)
+                | Command::Operate(Operator::Yank, _)
+                | Command::Undo
+                | Command::Repeat
+                | Command::PreviousHistory
+                | Command::NextHistory
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Parsed {
+    count: Option<usize>,
+    command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+enum Parse {
+    Incomplete,
+    Invalid,
+    /// A command and the number of keys it used.
+    Done(Parsed, usize),
+}
+
+const TEXT_OBJECTS: &str = "wW\"'`()b[]{}B<>";
+
+/// Vi command mode: counts, motions, operators, text objects and `.`.
+#[derive(Debug, Default)]
+pub struct Vi {
+    pending: Vec<Key>,
+    last_change: Vec<Key>,
+    /// The keys of a change that entered insert mode, while it is typed.
+    recording: Option<Vec<Key>>,
+    last_find: Option<Motion>,
+    register: String,
+}
+
+impl Vi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a command has been started but not finished.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Whether `key` means anything in command mode on its own. Other keys
+    /// fall through to the insert mode bindings.
+    pub fn handles(key: Key) -> bool {
+        matches!(normalize(key), Key::Char(_))
+    }
+
+    /// Handles a key typed in command mode.
+    pub fn feed(&mut self, key: Key, line: &mut LineBuffer) -> Outcome {
+        self.pending.push(key);
+        match parse(&self.pending) {
+            Parse::Incomplete => Outcome::Pending,
+            Parse::Invalid => {
+                self.pending.clear();
+                Outcome::Bell
+            }
+            Parse::Done(parsed, _) => {
+                let keys = std::mem::take(&mut self.pending);
+                if parsed.command == Command::Repeat {
+                    return self.repeat(line, parsed.count);
+                }
+                let outcome = self.apply(line, parsed.count.unwrap_or(1), parsed.command);
+                if parsed.command.is_change() && outcome != Outcome::Bell {
+                    if outcome == Outcome::Insert {
+                        self.recording = Some(keys);
+                    } else {
+                        self.last_change = keys;
+                    }
+                }
+                outcome
+            }
+        }
+    }
+
+    /// Records a key typed in insert mode so `.` can replay the insertion.
+    pub fn record(&mut self, key: Key) {
+        if let Some(keys) = &mut self.recording {
+            keys.push(key);
+        }
+    }
+
+    /// Leaves insert mode: finishes the change being recorded and moves
+    /// the cursor back onto the last inserted character.
+    pub fn end_insert(&mut self, line: &mut LineBuffer) {
+        if let Some(mut keys) = self.recording.take() {
+            keys.push(Key::Esc);
+            self.last_change = keys;
+        }
+        line.left();
+    }
+
+    /// Drops a half-typed command.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    fn repeat(&mut self, line: &mut LineBuffer, count: Option<usize>) -> Outcome {
+        let keys = self.last_change.clone();
+        let Parse::Done(parsed, used) = parse(&keys) else {
+            return Outcome::Bell;
+        };
+        let outcome = self.apply(line, count.or(parsed.count).unwrap_or(1), parsed.command);
+        if outcome == Outcome::Insert {
+            for key in &keys[used..] {
+                match key {
+                    Key::Char(c) => line.insert(*c),
+                    Key::Backspace => line.backspace(),
+                    Key::Esc => break,
+                    _ => {}
+                }
+            }
+            line.left();
+            return Outcome::Continue;
+        }
+        outcome
+    }
+
+    fn apply(&mut self, line: &mut LineBuffer, count: usize, command: Command) -> Outcome {
+        let mut chars: Vec<char> = line.text.chars().collect();
+        let mut pos = line.text[..line.cursor].chars().count();
+        let len = chars.len();
+        let outcome = match command {
+            Command::Move(motion) => match self.motion_target(&chars, pos, motion, count) {
+                Some((target, _)) => {
+                    pos = target;
+                    Outcome::Continue
+                }
+                None => Outcome::Bell,
+            },
+            Command::Operate(operator, target) => {
+                let range = match target {
+                    Target::Line => Some((0, len)),
+                    Target::Object { inner, kind } => text_object(&chars, pos, inner, kind),
+                    Target::Motion(Motion::WordForward(big))
+                        if operator == Operator::Change && chars.get(pos).is_some_and(|c| !c.is_whitespace()) =>
+                    {
+                        // `cw` on a word changes to its end, like `ce`.
+                        let mut end = pos;
+                        while end + 1 < len && class(chars[end + 1], big) == class(chars[pos], big) {
+                            end += 1;
+                        }
+                        for _ in 1..count {
+                            end = word_end(&chars, end, big);
+                        }
+                        Some((pos, (end + 1).min(len)))
+                    }
+                    Target::Motion(motion) => self.motion_range(&chars, pos, motion, count),
+                };
+                match range {
+                    Some((start, end)) => {
+                        self.register = chars[start..end].iter().collect();
+                        pos = start;
+                        match operator {
+                            Operator::Yank => Outcome::Continue,
+                            Operator::Delete => {
+                                chars.drain(start..end);
+                                Outcome::Continue
+                            }
+                            Operator::Change => {
+                                chars.drain(start..end);
+                                Outcome::Insert
+                            }
+                        }
+                    }
+                    None => Outcome::Bell,
+                }
+            }
+            Command::Insert(kind) => {
+                pos = match kind {
+                    'a' => (pos + 1).min(len),
+                    'I' => first_non_blank(&chars),
+                    'A' => len,
+                    _ => pos,
+                };
+                Outcome::Insert
+            }
+            Command::DeleteChar(_) | Command::Substitute if len > 0 => {
+                let (start, end) = if command == Command::DeleteChar(true) {
+                    (pos - count.min(pos), pos)
+                } else {
+                    (pos, (pos + count).min(len))
+                };
+                if start == end {
+                    return Outcome::Bell;
+                }
+                self.register = chars.drain(start..end).collect();
+                pos = start;
+                if command == Command::Substitute { Outcome::Insert } else { Outcome::Continue }
+            }
+            Command::Replace(c) if pos + count <= len => {
+                chars[pos..pos + count].fill(c);
+                pos += count - 1;
+                Outcome::Continue
+            }
+            Command::Put(before) if !self.register.is_empty() => {
+                let at = if before { pos } else { (pos + 1).min(len) };
+                let text: Vec<char> = self.register.repeat(count).chars().collect();
+                pos = at + text.len() - 1;
+                chars.splice(at..at, text);
+                Outcome::Continue
+            }
+            Command::ToggleCase if pos < len => {
+                let end = (pos + count).min(len);
+                let toggled: Vec<char> = chars[pos..end]
+                    .iter()
+                    .flat_map(|c| {
+                        if c.is_uppercase() { c.to_lowercase().collect::<Vec<_>>() } else { c.to_uppercase().collect() }
+                    })
+                    .collect();
+                pos += toggled.len();
+                chars.splice(pos - toggled.len()..end, toggled);
+                Outcome::Continue
+            }
+            Command::Undo => Outcome::Undo,
+            Command::PreviousHistory => Outcome::PreviousHistory(count),
+            Command::NextHistory => Outcome::NextHistory(count),
+            _ => Outcome::Bell,
+        };
+
+        if outcome != Outcome::Insert {
+            pos = pos.min(chars.len().saturating_sub(1));
+        }
+        line.text = chars.iter().collect();
+        line.cursor = chars[..pos.min(chars.len())].iter().map(|c| c.len_utf8()).sum();
+        outcome
+    }
+
+    /// The range an operator covers when combined with `motion`.
+    fn motion_range(&mut self, chars: &[char], pos: usize, motion: Motion, count: usize) -> Option<(usize, usize)> {
+        let (target, inclusive) = self.motion_target(chars, pos, motion, count)?;
+        if target >= pos {
+            Some((pos, if inclusive { (target + 1).min(chars.len()) } else { target }))
+        } else {
+            Some((target, pos))
+        }
+    }
+
+    /// Where `motion` moves the cursor to, and whether the character there
+    /// is included when an operator uses the motion.
+    fn motion_target(&mut self, chars: &[char], pos: usize, motion: Motion, count: usize) -> Option<(usize, bool)> {
+        let len = chars.len();
+        let mut target = pos;
+        match motion {
+            Motion::Left if pos > 0 => return Some((pos - count.min(pos), false)),
+            Motion::Right if pos < len => return Some(((pos + count).min(len), false)),
+            Motion::Left | Motion::Right => return None,
+            Motion::LineStart => return Some((0, false)),
+            Motion::FirstNonBlank => return Some((first_non_blank(chars), false)),
+            Motion::LineEnd => return Some((len, false)),
+            Motion::WordForward(big) => {
+                for _ in 0..count {
+                    target = word_forward(chars, target, big);
+                }
+            }
+            Motion::WordBackward(big) => {
+                for _ in 0..count {
+                    target = word_backward(chars, target, big);
+                }
+            }
+            Motion::WordEnd(big) => {
+                for _ in 0..count {
+                    target = word_end(chars, target, big);
+                }
+                return Some((target, true));
+            }
+            Motion::Find { forward, till, target: c } => {
+                self.last_find = Some(motion);
+                for _ in 0..count {
+                    target = if forward {
+                        (target + 1..len).find(|&i| chars[i] == c)?
+                    } else {
+                        (0..target).rev().find(|&i| chars[i] == c)?
+                    };
+                }
+                if till {
+                    target = if forward { target - 1 } else { target + 1 };
+                }
+                return Some((target, forward));
+            }
+            Motion::RepeatFind(reverse) => {
+                let Some(Motion::Find { forward, till, target: c }) = self.last_find else {
+                    return None;
+                };
+                let find = Motion::Find { forward: forward != reverse, till, target: c };
+                let result = self.motion_target(chars, pos, find, count);
+                self.last_find = Some(Motion::Find { forward, till, target: c });
+                return result;
+            }
+        }
+        Some((target, false))
+    }
+}
+
+/// Arrows and editing keys act like their vi command counterparts.
+fn normalize(key: Key) -> Key {
+    match key {
+        Key::Left | Key::Backspace => Key::Char('h'),
+        Key::Right => Key::Char('l'),
+        Key::Up => Key::Char('k'),
+        Key::Down => Key::Char('j'),
+        Key::Home => Key::Char('0'),
+        Key::End => Key::Char('$'),
+        Key::Delete => Key::Char('x'),
+        key => key,
+    }
+}
+
+/// Reads a count starting at `i`. A leading `0` is a motion, not a count.
+fn parse_count(keys: &[Key], mut i: usize) -> (Option<usize>, usize) {
+    let mut count: Option<usize> = None;
+    while let Some(Key::Char(c @ '0'..='9')) = keys.get(i) {
+        if *c == '0' && count.is_none() {
+            break;
+        }
+        let digit = *c as usize - '0' as usize;
+        count = Some((count.unwrap_or(0) * 10 + digit).min(999));
+        i += 1;
+    }
+    (count, i)
+}
+
+fn parse(keys: &[Key]) -> Parse {
+    let (count, i) = parse_count(keys, 0);
+    let Some(&key) = keys.get(i) else {
+        return Parse::Incomplete;
+    };
+    let Key::Char(c) = normalize(key) else {
+        return Parse::Invalid;
+    };
+    let done = |command, used| Parse::Done(Parsed { count, command }, used);
+    match c {
+        'd' | 'c' | 'y' => {
+            let operator = match c {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                _ => Operator::Yank,
+            };
+            let (inner_count, j) = parse_count(keys, i + 1);
+            let count = match (count, inner_count) {
+                (Some(a), Some(b)) => Some(a * b),
+                (a, b) => a.or(b),
+            };
+            let done = |target, used| Parse::Done(Parsed { count, command: Command::Operate(operator, target) }, used);
+            match keys.get(j).copied().map(normalize) {
+                None => Parse::Incomplete,
+                Some(Key::Char(same)) if same == c => done(Target::Line, j + 1),
+                Some(Key::Char(scope @ ('i' | 'a'))) => match keys.get(j + 1) {
+                    None => Parse::Incomplete,
+                    Some(Key::Char(kind)) if TEXT_OBJECTS.contains(*kind) => {
+                        done(Target::Object { inner: scope == 'i', kind: *kind }, j + 2)
+                    }
+                    Some(_) => Parse::Invalid,
+                },
+                Some(_) => match parse_motion(keys, j) {
+                    Ok(Some((motion, used))) => done(Target::Motion(motion), used),
+                    Ok(None) => Parse::Incomplete,
+                    Err(()) => Parse::Invalid,
+                },
+            }
+        }
+        'D' => done(Command::Operate(Operator::Delete, Target::Motion(Motion::LineEnd)), i + 1),
+        'C' => done(Command::Operate(Operator::Change, Target::Motion(Motion::LineEnd)), i + 1),
+        'Y' => done(Command::Operate(Operator::Yank, Target::Motion(Motion::LineEnd)), i + 1),
+        'S' => done(Command::Operate(Operator::Change, Target::Line), i + 1),
+        'x' => done(Command::DeleteChar(false), i + 1),
+        'X' => done(Command::DeleteChar(true), i + 1),
+        's' => done(Command::Substitute, i + 1),
+        'r' => match keys.get(i + 1) {
+            None => Parse::Incomplete,
+            Some(Key::Char(replacement)) => done(Command::Replace(*replacement), i + 2),
+            Some(_) => Parse::Invalid,
+        },
+        'p' => done(Command::Put(false), i + 1),
+        'P' => done(Command::Put(true), i + 1),
+        '~' => done(Command::ToggleCase, i + 1),
+        'i' | 'a' | 'I' | 'A' => done(Command::Insert(c), i + 1),
+        'u' => done(Command::Undo, i + 1),
+        '.' => done(Command::Repeat, i + 1),
+        'k' | '-' => done(Command::PreviousHistory, i + 1),
+        'j' | '+' => done(Command::NextHistory, i + 1),
+        _ => match parse_motion(keys, i) {
+            Ok(Some((motion, used))) => done(Command::Move(motion), used),
+            Ok(None) => Parse::Incomplete,
+            Err(()) => Parse::Invalid,
+        },
+    }
+}
+
+/// Parses a motion at `i`. `Ok(None)` means more keys are needed.
+fn parse_motion(keys: &[Key], i: usize) -> Result<Option<(Motion, usize)>, ()> {
+    let Some(&key) = keys.get(i) else {
+        return Ok(None);
+    };
+    let Key::Char(c) = normalize(key) else {
+        return Err(());
+    };
+    let motion = match c {
+        'h' => Motion::Left,
+        'l' | ' ' => Motion::Right,
+        '0' => Motion::LineStart,
+        '^' => Motion::FirstNonBlank,
+        '$' => Motion::LineEnd,
+        'w' | 'W' => Motion::WordForward(c == 'W'),
+        'b' | 'B' => Motion::WordBackward(c == 'B'),
+        'e' | 'E' => Motion::WordEnd(c == 'E'),
+        ';' => Motion::RepeatFind(false),
+        ',' => Motion::RepeatFind(true),
+        'f' | 'F' | 't' | 'T' => {
+            return match keys.get(i + 1) {
+                None => Ok(None),
+                Some(Key::Char(target)) => Ok(Some((
+                    Motion::Find { forward: c.is_lowercase(), till: c.eq_ignore_ascii_case(&'t'), target: *target },
+                    i + 2,
+                ))),
+                Some(_) => Err(()),
+            };
+        }
+        _ => return Err(()),
+    };
+    Ok(Some((motion, i + 1)))
+}
+
+/// 0 for blanks, 1 for word characters and 2 for other characters. With
+/// `big`, every non-blank is a word character.
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn first_non_blank(chars: &[char]) -> usize {
+    chars.iter().position(|c| !c.is_whitespace()).unwrap_or(0)
+}
+
+fn word_forward(chars: &[char], mut i: usize, big: bool) -> usize {
+    let len = chars.len();
+    if i >= len {
+        return len;
+    }
+    let start = class(chars[i], big);
+    if start != 0 {
+        while i < len && class(chars[i], big) == start {
+            i += 1;
+        }
+    }
+    while i < len && class(chars[i], big) == 0 {
+        i += 1;
+    }
+    i
+}
+
+fn word_backward(chars: &[char], mut i: usize, big: bool) -> usize {
+    while i > 0 && class(chars[i - 1], big) == 0 {
+        i -= 1;
+    }
+    if i == 0 {
+        return 0;
+    }
+    let word = class(chars[i - 1], big);
+    while i > 0 && class(chars[i - 1], big) == word {
+        i -= 1;
+    }
+    i
+}
+
+fn word_end(chars: &[char], i: usize, big: bool) -> usize {
+    let len = chars.len();
+    let mut i = i + 1;
+    while i < len && class(chars[i], big) == 0 {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1);
+    }
+    let word = class(chars[i], big);
+    while i + 1 < len && class(chars[i + 1], big) == word {
+        i += 1;
+    }
+    i
+}
+
+/// The range of the text object `kind` around `pos`, as for `diw` or `ca"`.
+fn text_object(chars: &[char], pos: usize, inner: bool, kind: char) -> Option<(usize, usize)> {
+    match kind {
+        'w' | 'W' => word_object(chars, pos, inner, kind == 'W'),
+        '"' | '\'' | '`' => quote_object(chars, pos, inner, kind),
+        '(' | ')' | 'b' => bracket_object(chars, pos, inner, '(', ')'),
+        '[' | ']' => bracket_object(chars, pos, inner, '[', ']'),
+        '{' | '}' | 'B' => bracket_object(chars, pos, inner, '{', '}'),
+        _ => bracket_object(chars, pos, inner, '<', '>'),
+    }
+}
+
+fn word_object(chars: &[char], pos: usize, inner: bool, big: bool) -> Option<(usize, usize)> {
+    let len = chars.len();
+    if pos >= len {
+        return None;
+    }
+    let kind = class(chars[pos], big);
+    let (mut start, mut end) = (pos, pos + 1);
+    while start > 0 && class(chars[start - 1], big) == kind {
+        start -= 1;
+    }
+    while end < len && class(chars[end], big) == kind {
+        end += 1;
+    }
+    if inner {
+        return Some((start, end));
+    }
+    if kind == 0 {
+        // Blanks take the following word with them.
+        if end < len {
+            let next = class(chars[end], big);
+            while end < len && class(chars[end], big) == next {
+                end += 1;
+            }
+        }
+    } else if end < len && chars[end].is_whitespace() {
+        while end < len && chars[end].is_whitespace() {
+            end += 1;
+        }
+    } else {
+        while start > 0 && chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+    }
+    Some((start, end))
+}
+
+fn quote_object(chars: &[char], pos: usize, inner: bool, quote: char) -> Option<(usize, usize)> {
+    let quotes: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == quote).collect();
+    let pairs = || quotes.chunks_exact(2).map(|pair| (pair[0], pair[1]));
+    let (open, close) = pairs()
+        .find(|&(open, close)| open <= pos && pos <= close)
+        .or_else(|| pairs().find(|&(open, _)| open > pos))?;
+    if inner {
+        return Some((open + 1, close));
+    }
+    let mut end = close + 1;
+    while end < chars.len() && chars[end].is_whitespace() {
+        end += 1;
+    }
+    Some((open, end))
+}
+
+fn bracket_object(chars: &[char], pos: usize, inner: bool, open: char, close: char) -> Option<(usize, usize)> {
+    if chars.is_empty() {
+        return None;
+    }
+    let mut depth = 0;
+    let mut start = None;
+    for i in (0..=pos.min(chars.len() - 1)).rev() {
+        if chars[i] == close && i != pos {
+            depth += 1;
+        } else if chars[i] == open {
+            if depth == 0 {
+                start = Some(i);
+                break;
+            }
+            depth -= 1;
+        }
+    }
+    let start = start?;
+    let mut depth = 0;
+    let end = (start + 1..chars.len()).find(|&i| {
+        if chars[i] == open {
+            depth += 1;
+        } else if chars[i] == close {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+        false
+    })?;
+    Some(if inner { (start + 1, end) } else { (start, end + 1) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs vi command keys on `text` with the cursor at char `cursor` and
+    /// returns the text with `|` marking the cursor.
+    fn run(text: &str, cursor: usize, keys: &str) -> String {
+        let mut line = LineBuffer::default();
+        line.set(text);
+        line.cursor = text.char_indices().nth(cursor).map_or(text.len(), |(i, _)| i);
+        let mut vi = Vi::new();
+        let mut inserting = false;
+        for c in keys.chars() {
+            let key = if c == '\x1b' { Key::Esc } else { Key::Char(c) };
+            if inserting {
+                if key == Key::Esc {
+                    vi.end_insert(&mut line);
+                    inserting = false;
+                } else {
+                    vi.record(key);
+                    line.insert(c);
+                }
+            } else if vi.feed(key, &mut line) == Outcome::Insert {
+                inserting = true;
+            }
+        }
+        format!("{}|{}", &line.text[..line.cursor], &line.text[line.cursor..])
+    }
+
+    #[test]
+    fn test_motions() {
+        assert_eq!(run("git commit -m msg", 0, "w"), "git |commit -m msg");
+        assert_eq!(run("git commit -m msg", 0, "2w"), "git commit |-m msg");
+        assert_eq!(run("git commit -m msg", 0, "e"), "gi|t commit -m msg");
+        assert_eq!(run("git commit -m msg", 0, "$"), "git commit -m ms|g");
+        assert_eq!(run("git commit -m msg", 16, "b"), "git commit -m |msg");
+        assert_eq!(run("a.b c", 0, "W"), "a.b |c");
+        assert_eq!(run("echo hello", 0, "fl;"), "echo hel|lo");
+        assert_eq!(run("echo hello", 9, "Fe"), "echo h|ello");
+        assert_eq!(run("  ls", 3, "^"), "  |ls");
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(run("git commit -m msg", 4, "dw"), "git |-m msg");
+        assert_eq!(run("git commit -m msg", 4, "d3w"), "git |msg");
+        assert_eq!(run("git commit -m msg", 4, "d2W"), "git |msg");
+        assert_eq!(run("git commit -m msg", 4, "cwpush\x1b"), "git pus|h -m msg");
+        assert_eq!(run("git commit -m msg", 4, "D"), "git| ");
+        assert_eq!(run("git commit", 4, "dd"), "|");
+        assert_eq!(run("echo hello", 0, "dtl"), "|llo");
+        assert_eq!(run("echo hello", 0, "ywP"), "echo| echo hello");
+        assert_eq!(run("abc", 0, "x2~"), "B|C");
+        assert_eq!(run("abc", 0, "rz"), "|zbc");
+        assert_eq!(run("abc", 1, "xp"), "ac|b");
+    }
+
+    #[test]
+    fn test_text_objects() {
+        assert_eq!(run(r#"echo "hello world" x"#, 8, r#"di""#), r#"echo "|" x"#);
+        assert_eq!(run(r#"echo "hello world" x"#, 8, r#"da""#), "echo |x");
+        assert_eq!(run("f(a, (b), c)", 3, "ci(z\x1b"), "f(|z)");
+        assert_eq!(run("f(a, (b), c)", 6, "dab"), "f(a, |, c)");
+        assert_eq!(run("one two three", 5, "daw"), "one |three");
+        assert_eq!(run("one two three", 5, "ciwX\x1b"), "one |X three");
+    }
+
+    #[test]
+    fn test_insert_and_repeat() {
+        assert_eq!(run("ls", 1, "A -l\x1b"), "ls -|l");
+        assert_eq!(run("ls", 0, "Isudo \x1b"), "sudo| ls");
+        assert_eq!(run("a b c", 0, "x."), "|b c");
+        assert_eq!(run("aa bb cc", 0, "cwX\x1bw."), "X |X cc");
+        assert_eq!(run("a b c d", 0, "dw2."), "|d");
+    }
+
+    #[test]
+    fn test_counts_and_invalid_keys() {
+        let mut vi = Vi::new();
+        let mut line = LineBuffer::default();
+        line.set("abc");
+        assert_eq!(vi.feed(Key::Char('2'), &mut line), Outcome::Pending);
+        assert_eq!(vi.feed(Key::Char('d'), &mut line), Outcome::Pending);
+        assert_eq!(vi.feed(Key::Char('Q'), &mut line), Outcome::Bell);
+        assert!(!vi.is_pending());
+        assert_eq!(vi.feed(Key::Char('k'), &mut line), Outcome::PreviousHistory(1));
+    }
+}
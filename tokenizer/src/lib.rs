@@ -6,16 +6,29 @@ pub enum TokenType {
     LongFlag,
     LongFlagWithValue,
     Pipe,           // |
-    InputRedir,     // <
-    OutputRedir,    // >
+    InputRedir,     // < <& <>
+    OutputRedir,    // > >> >& >|
     Background,     // &
+    And,            // &&
+    Or,             // ||
+    Semicolon,      // ;
+    Newline,
+    /// A reserved word such as `if` or `{` in command position.
+    Keyword,
     Eof,
 }
 
+/// Words that start or end a compound command when they appear unquoted
+/// in command position.
+pub const KEYWORDS: &[&str] = &["if", "then", "elif", "else", "fi", "while", "until", "do", "done", "{", "}", "[[", "]]"];
+
 #[derive(Debug)]
 pub struct Token {
     pub kind: TokenType,
     pub lexeme: String,
+    /// Byte offsets of the token in the source, including any quotes.
+    pub start: usize,
+    pub end: usize,
 }
 
 pub struct Tokenizer {
@@ -24,6 +37,13 @@ pub struct Tokenizer {
     pub start: usize,
     pub current: usize,
     had_cmd: bool,
+    incomplete: bool,
+    /// Inside `[[ ... ]]`, where `&&`, `||`, `<`, `>`, `(` and `)` are
+    /// operators of the test rather than of the command line.
+    in_test: bool,
+    /// The next word is the regular expression after `=~`, which may
+    /// contain `|` and parentheses.
+    in_regex: bool,
 }
 
 impl Tokenizer {
@@ -34,9 +54,18 @@ impl Tokenizer {
             start: 0,
             current: 0,
             had_cmd: false,
+            incomplete: false,
+            in_test: false,
+            in_regex: false,
         }
     }
 
+    /// Whether the source ended inside a quote or right after a backslash,
+    /// so more input is needed before it can be parsed.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
     pub fn scan_tokens(&mut self) {
         while !self.is_at_end() {
             self.start = self.current;
@@ -45,6 +74,8 @@ impl Tokenizer {
         self.tokens.push(Token {
             kind: TokenType::Eof,
             lexeme: "".to_string(),
+            start: self.source.len(),
+            end: self.source.len(),
         });
     }
 
@@ -55,7 +86,22 @@ impl Tokenizer {
     fn scan_token(&mut self) {
         let c = self.advance();
         match c {
-            ' ' | '\r' | '\t' | '\n' => self.skip_whitespace(),
+            ' ' | '\r' | '\t' => self.skip_whitespace(),
+            '\n' => {
+                self.tokens.push(Token {
+                    kind: TokenType::Newline,
+                    lexeme: "\n".to_string(),
+                    start: self.start,
+                    end: self.current,
+                });
+                self.had_cmd = self.in_test;
+            }
+            // A backslash before a newline joins the lines.
+            '\\' if self.peek() == Some('\n') => {
+                self.advance();
+            }
+            _ if self.in_regex => self.handle_word(),
+            '(' | ')' if self.in_test => self.add_token(TokenType::Arg),
             '-' => {
                 if self.match_char('-') {
                     self.handle_long_flag();
@@ -64,12 +110,27 @@ impl Tokenizer {
                 }
             }
             '|' => {
-                self.add_token(TokenType::Pipe);
-                self.had_cmd = false; // Reset had_cmd after pipe to allow new command
+                let kind = if self.match_char('|') { TokenType::Or } else { TokenType::Pipe };
+                self.add_token(kind);
+                self.had_cmd = self.in_test; // Reset had_cmd after pipe to allow new command
             },
-            '<' => self.add_token(TokenType::InputRedir),
-            '>' => self.add_token(TokenType::OutputRedir),
-            '&' => self.add_token(TokenType::Background),
+            '<' => {
+                let _ = self.match_char('&') || self.match_char('>');
+                self.add_token(TokenType::InputRedir);
+            }
+            '>' => {
+                let _ = self.match_char('>') || self.match_char('&') || self.match_char('|');
+                self.add_token(TokenType::OutputRedir);
+            }
+            '&' => {
+                let kind = if self.match_char('&') { TokenType::And } else { TokenType::Background };
+                self.add_token(kind);
+                self.had_cmd = self.in_test;
+            }
+            ';' => {
+                self.add_token(TokenType::Semicolon);
+                self.had_cmd = false;
+            }
             _ => self.handle_word(),
         }
     }
@@ -77,7 +138,7 @@ impl Tokenizer {
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
             match c {
-                ' ' | '\r' | '\t' | '\n' => { self.advance(); }
+                ' ' | '\r' | '\t' => { self.advance(); }
                 _ => break,
             }
         }
@@ -114,7 +175,7 @@ impl Tokenizer {
                     in_quotes = !in_quotes;
                     self.advance();
                 }
-                ' ' if !in_quotes => break,
+                ' ' | '\t' | '\n' | ';' | '|' | '&' if !in_quotes => break,
                 _ => {
                     self.advance();
                 }
@@ -127,6 +188,8 @@ impl Tokenizer {
         self.tokens.push(Token {
             kind: TokenType::LongFlagWithValue,
             lexeme: format!("{}={}", flag, value),
+            start: self.start,
+            end: self.current,
         });
     }
 
@@ -142,50 +205,127 @@ impl Tokenizer {
     }
 
     fn handle_word(&mut self) {
+        // Re-scan from the start so a leading quote or backslash is handled
+        // like any other part of the word.
+        self.current = self.start;
+        let mut text = String::new();
+        let mut quote: Option<char> = None;
+        let mut quoted = false;
+        let regex = std::mem::take(&mut self.in_regex);
+        let mut depth = 0;
+
         while let Some(c) = self.peek() {
-            if c.is_ascii_alphanumeric() || c == '_' || c == '/' || c == '.' {
-                self.advance();
-            } else {
-                break;
+            match quote {
+                Some(q) if c == q => {
+                    quote = None;
+                    self.advance();
+                }
+                Some('"') if c == '\\' => {
+                    self.advance();
+                    match self.peek() {
+                        Some(next @ ('"' | '\\' | '$' | '`')) => {
+                            self.advance();
+                            text.push(next);
+                        }
+                        Some('\n') => {
+                            self.advance();
+                        }
+                        _ => text.push('\\'),
+                    }
+                }
+                Some(_) => {
+                    self.advance();
+                    text.push(c);
+                }
+                None => match c {
+                    ' ' | '\r' | '\t' | '\n' if regex && depth == 0 => break,
+                    '(' | ')' if regex => {
+                        depth += if c == '(' { 1 } else { -1 };
+                        self.advance();
+                        text.push(c);
+                    }
+                    _ if regex && !matches!(c, ' ' | '\r' | '\t' | '\n' | '\'' | '"' | '\\') => {
+                        self.advance();
+                        text.push(c);
+                    }
+                    ' ' | '\r' | '\t' | '\n' | '|' | '<' | '>' | '&' | ';' => break,
+                    '(' | ')' if self.in_test => break,
+                    '\'' | '"' => {
+                        quote = Some(c);
+                        quoted = true;
+                        self.advance();
+                    }
+                    '\\' => {
+                        self.advance();
+                        match self.peek() {
+                            Some('\n') => {
+                                self.advance();
+                            }
+                            Some(next) => {
+                                self.advance();
+                                text.push(next);
+                            }
+                            None => self.incomplete = true,
+                        }
+                    }
+                    _ => {
+                        self.advance();
+                        text.push(c);
+                    }
+                },
             }
         }
-        
-        if !self.had_cmd {
-            self.add_token(TokenType::Cmd);
+
+        if quote.is_some() {
+            self.incomplete = true;
+        }
+        if text.is_empty() && !quoted {
+            return;
+        }
+
+        let kind = if self.in_test && !quoted && text == "]]" {
+            self.in_test = false;
+            TokenType::Keyword
+        } else if self.in_test {
+            self.in_regex = !quoted && text == "=~";
+            TokenType::Arg
+        } else if !self.had_cmd && !quoted && KEYWORDS.contains(&text.as_str()) {
+            // Words after a keyword like `then` are in command position
+            // again; the words of a `[[` test are its operands.
+            self.had_cmd = matches!(text.as_str(), "fi" | "done" | "}" | "[[" | "]]");
+            self.in_test = text == "[[";
+            TokenType::Keyword
+        } else if !self.had_cmd {
             self.had_cmd = true;
+            TokenType::Cmd
         } else {
-            self.add_token(TokenType::Arg);
-        }
+            TokenType::Arg
+        };
+        self.tokens.push(Token { kind, lexeme: text, start: self.start, end: self.current });
     }
 
     fn peek(&self) -> Option<char> {
-        if self.is_at_end() {
-            None
-        } else {
-            Some(self.source.chars().nth(self.current).unwrap())
-        }
+        self.source[self.current..].chars().next()
     }
 
     fn match_char(&mut self, expected: char) -> bool {
-        if let Some(c) = self.peek() {
-            if c == expected {
-                self.advance();
-                return true;
-            }
+        if self.peek() == Some(expected) {
+            self.advance();
+            return true;
         }
         false
     }
 
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek().unwrap();
+        self.current += c.len_utf8();
         c
     }
 
     fn add_token(&mut self, kind: TokenType) {
         let text = self.source[self.start..self.current].trim().to_string();
         if !text.is_empty() {
-            self.tokens.push(Token { kind, lexeme: text });
+            self.tokens.push(Token { kind, lexeme: text, start: self.start, end: self.current });
         }
     }
 }
@@ -309,6 +449,21 @@ mod tests {
         assert_eq!(tokenizer.tokens[5].kind, TokenType::Eof);
     }
 
+    #[test]
+    fn test_redirections_with_descriptors() {
+        let mut tokenizer = Tokenizer::new("exec 3>>log 2>&1 <&- 4<>f".to_string());
+        tokenizer.scan_tokens();
+
+        let tokens: Vec<(&TokenType, &str)> = tokenizer.tokens.iter().map(|t| (&t.kind, t.lexeme.as_str())).collect();
+        assert_eq!(tokens, vec![
+            (&TokenType::Cmd, "exec"), (&TokenType::Arg, "3"), (&TokenType::OutputRedir, ">>"),
+            (&TokenType::Arg, "log"), (&TokenType::Arg, "2"), (&TokenType::OutputRedir, ">&"),
+            (&TokenType::Arg, "1"), (&TokenType::InputRedir, "<&"), (&TokenType::Flag, "-"),
+            (&TokenType::Arg, "4"), (&TokenType::InputRedir, "<>"), (&TokenType::Arg, "f"),
+            (&TokenType::Eof, ""),
+        ]);
+    }
+
     #[test]
     fn test_background_operator() {
         let mut tokenizer = Tokenizer::new("sleep 10 &".to_string());
@@ -348,4 +503,111 @@ mod tests {
         assert_eq!(tokenizer.tokens[7].lexeme, "&");
         assert_eq!(tokenizer.tokens[8].kind, TokenType::Eof);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_quoted_words() {
+        let mut tokenizer = Tokenizer::new(r#"echo 'Hello, World!' "a \"b\"" c\ d"#.to_string());
+        tokenizer.scan_tokens();
+
+        assert_eq!(tokenizer.tokens.len(), 5);
+        assert_eq!(tokenizer.tokens[0].kind, TokenType::Cmd);
+        assert_eq!(tokenizer.tokens[0].lexeme, "echo");
+        assert_eq!(tokenizer.tokens[1].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[1].lexeme, "Hello, World!");
+        assert_eq!(tokenizer.tokens[2].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[2].lexeme, "a \"b\"");
+        assert_eq!(tokenizer.tokens[3].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[3].lexeme, "c d");
+        assert_eq!(tokenizer.tokens[4].kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_words_with_dashes_and_unicode() {
+        let mut tokenizer = Tokenizer::new("cat my-file.txt ünïcode.md".to_string());
+        tokenizer.scan_tokens();
+
+        assert_eq!(tokenizer.tokens.len(), 4);
+        assert_eq!(tokenizer.tokens[1].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[1].lexeme, "my-file.txt");
+        assert_eq!(tokenizer.tokens[2].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[2].lexeme, "ünïcode.md");
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let source = r#"ls  --color=auto 'a b' | wc"#;
+        let mut tokenizer = Tokenizer::new(source.to_string());
+        tokenizer.scan_tokens();
+
+        let spans: Vec<&str> = tokenizer.tokens.iter().map(|t| &source[t.start..t.end]).collect();
+        assert_eq!(spans, vec!["ls", "--color=auto", "'a b'", "|", "wc", ""]);
+    }
+
+    #[test]
+    fn test_control_operators_and_keywords() {
+        let mut tokenizer = Tokenizer::new("if true; then a && b || c\nfi".to_string());
+        tokenizer.scan_tokens();
+
+        let kinds: Vec<&TokenType> = tokenizer.tokens.iter().map(|t| &t.kind).collect();
+        assert_eq!(kinds, vec![
+            &TokenType::Keyword, &TokenType::Cmd, &TokenType::Semicolon,
+            &TokenType::Keyword, &TokenType::Cmd, &TokenType::And, &TokenType::Cmd,
+            &TokenType::Or, &TokenType::Cmd, &TokenType::Newline, &TokenType::Keyword,
+            &TokenType::Eof,
+        ]);
+        assert!(!tokenizer.is_incomplete());
+    }
+
+    #[test]
+    fn test_loop_keywords() {
+        let mut tokenizer = Tokenizer::new("while read x; do echo done; done | wc".to_string());
+        tokenizer.scan_tokens();
+
+        let tokens: Vec<(&TokenType, &str)> = tokenizer.tokens.iter().map(|t| (&t.kind, t.lexeme.as_str())).collect();
+        assert_eq!(tokens, vec![
+            (&TokenType::Keyword, "while"), (&TokenType::Cmd, "read"), (&TokenType::Arg, "x"),
+            (&TokenType::Semicolon, ";"), (&TokenType::Keyword, "do"), (&TokenType::Cmd, "echo"),
+            (&TokenType::Arg, "done"), (&TokenType::Semicolon, ";"), (&TokenType::Keyword, "done"),
+            (&TokenType::Pipe, "|"), (&TokenType::Cmd, "wc"), (&TokenType::Eof, ""),
+        ]);
+    }
+
+    #[test]
+    fn test_keywords_only_in_command_position() {
+        let mut tokenizer = Tokenizer::new("echo if 'fi'".to_string());
+        tokenizer.scan_tokens();
+
+        assert_eq!(tokenizer.tokens[1].kind, TokenType::Arg);
+        assert_eq!(tokenizer.tokens[2].kind, TokenType::Arg);
+    }
+
+    #[test]
+    fn test_extended_test_operands() {
+        let mut tokenizer = Tokenizer::new("[[ (a < b) && x =~ ^(y|z)$ ]] || ls".to_string());
+        tokenizer.scan_tokens();
+
+        let tokens: Vec<(&TokenType, &str)> = tokenizer.tokens.iter().map(|t| (&t.kind, t.lexeme.as_str())).collect();
+        assert_eq!(tokens, vec![
+            (&TokenType::Keyword, "[["), (&TokenType::Arg, "("), (&TokenType::Arg, "a"),
+            (&TokenType::InputRedir, "<"), (&TokenType::Arg, "b"), (&TokenType::Arg, ")"),
+            (&TokenType::And, "&&"), (&TokenType::Arg, "x"), (&TokenType::Arg, "=~"),
+            (&TokenType::Arg, "^(y|z)$"), (&TokenType::Keyword, "]]"), (&TokenType::Or, "||"),
+            (&TokenType::Cmd, "ls"), (&TokenType::Eof, ""),
+        ]);
+    }
+
+    #[test]
+    fn test_incomplete_input() {
+        for source in ["echo 'abc", "echo \"a\nb", "echo a \\"] {
+            let mut tokenizer = Tokenizer::new(source.to_string());
+            tokenizer.scan_tokens();
+            assert!(tokenizer.is_incomplete(), "{:?}", source);
+        }
+
+        let mut tokenizer = Tokenizer::new("echo a \\\nb".to_string());
+        tokenizer.scan_tokens();
+        assert!(!tokenizer.is_incomplete());
+        let lexemes: Vec<&str> = tokenizer.tokens.iter().map(|t| t.lexeme.as_str()).collect();
+        assert_eq!(lexemes, vec!["echo", "a", "b", ""]);
+    }
+}